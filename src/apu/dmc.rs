@@ -0,0 +1,210 @@
+use crate::cartridge::Region;
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
+// タイマー周期 (CPUサイクル)
+const NTSC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_RATE_TABLE: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+// DMC (delta modulation channel, $4010~$4013)
+// CPUのメモリ($8000~$FFFF)からサンプルを1バイトずつDMAで読み, 1bitごとに出力レベルを±2する
+// DMAの読み込みはBusがやる (dma_addrで要求を見て, fill_sample_bufferで渡す)
+pub struct Dmc {
+    rate_table: &'static [u16; 16],
+    irq_enabled: bool,
+    loop_flag: bool,
+    timer_period: u16,
+    timer: u16,
+    // 0~127
+    output_level: u8,
+    // $4012/$4013で指定したサンプルの先頭と長さ (ループしたらここから読み直す)
+    sample_addr: u16,
+    sample_length: u16,
+    // memory reader
+    current_addr: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    // output unit
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    irq_flag: bool,
+}
+
+impl Dmc {
+    pub fn new(region: Region) -> Self {
+        let rate_table = match region {
+            Region::NTSC => &NTSC_RATE_TABLE,
+            Region::PAL => &PAL_RATE_TABLE,
+        };
+        Dmc {
+            rate_table,
+            irq_enabled: false,
+            loop_flag: false,
+            timer_period: rate_table[0],
+            timer: 0,
+            output_level: 0,
+            sample_addr: 0xC000,
+            sample_length: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq_flag: false,
+        }
+    }
+
+    // addrは0~3 ($4010からのオフセット)
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                self.loop_flag = data & 0b0100_0000 != 0;
+                self.timer_period = self.rate_table[(data & 0b1111) as usize];
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+            }
+            // -DDD DDDD: 出力レベルを直接書く
+            1 => self.output_level = data & 0b0111_1111,
+            // $C000 + A * 64
+            2 => self.sample_addr = 0xC000 | ((data as u16) << 6),
+            // L * 16 + 1 バイト
+            3 => self.sample_length = ((data as u16) << 4) + 1,
+            _ => panic!("dmc register out of range: {}", addr),
+        }
+    }
+
+    // $4015のbit4. 有効にしたとき残りが0ならサンプルの先頭から再生し直す
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.irq_flag
+    }
+
+    // サンプルバッファが空で読むものが残っていれば, DMAで読むアドレス
+    pub fn dma_addr(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    // DMAで読んだ1バイト. 最後のバイトならループするかIRQを立てる
+    pub fn fill_sample_buffer(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        // $FFFFの次は$8000に戻る
+        self.current_addr = self.current_addr.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_output();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 1bitごとに出力レベルを±2. 0~127からはみ出す変化は無視する
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        // 8bit出し切ったらサンプルバッファから次のバイトを取る. 空なら無音
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.shift_register = data;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // 0~127
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+impl SaveState for Dmc {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.irq_enabled);
+        writer.write_bool(self.loop_flag);
+        writer.write_u16(self.timer_period);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.output_level);
+        writer.write_u16(self.sample_addr);
+        writer.write_u16(self.sample_length);
+        writer.write_u16(self.current_addr);
+        writer.write_u16(self.bytes_remaining);
+        writer.write_bool(self.sample_buffer.is_some());
+        writer.write_u8(self.sample_buffer.unwrap_or(0));
+        writer.write_u8(self.shift_register);
+        writer.write_u8(self.bits_remaining);
+        writer.write_bool(self.silence);
+        writer.write_bool(self.irq_flag);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.irq_enabled = reader.read_bool()?;
+        self.loop_flag = reader.read_bool()?;
+        self.timer_period = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.output_level = reader.read_u8()?;
+        self.sample_addr = reader.read_u16()?;
+        self.sample_length = reader.read_u16()?;
+        self.current_addr = reader.read_u16()?;
+        self.bytes_remaining = reader.read_u16()?;
+        let has_sample = reader.read_bool()?;
+        let sample = reader.read_u8()?;
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+        self.shift_register = reader.read_u8()?;
+        self.bits_remaining = reader.read_u8()?;
+        self.silence = reader.read_bool()?;
+        self.irq_flag = reader.read_bool()?;
+        Ok(())
+    }
+}
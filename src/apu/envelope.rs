@@ -0,0 +1,84 @@
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
+// エンベロープ: 15から0に向かって音量を下げる. quarter frameごとに進む
+// constant_volumeが立っていれば periodの値をそのまま音量に使う
+pub struct Envelope {
+    pub start: bool,
+    pub looping: bool,
+    pub constant_volume: bool,
+    pub period: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            looping: false,
+            constant_volume: false,
+            period: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    // --LC VVVV ($4000, $4004, $400C)
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant_volume = data & 0b0001_0000 != 0;
+        self.period = data & 0b0000_1111;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        if self.constant_volume {
+            self.period
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope::new()
+    }
+}
+
+impl SaveState for Envelope {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.start);
+        writer.write_bool(self.looping);
+        writer.write_bool(self.constant_volume);
+        writer.write_u8(self.period);
+        writer.write_u8(self.divider);
+        writer.write_u8(self.decay);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.start = reader.read_bool()?;
+        self.looping = reader.read_bool()?;
+        self.constant_volume = reader.read_bool()?;
+        self.period = reader.read_u8()?;
+        self.divider = reader.read_u8()?;
+        self.decay = reader.read_u8()?;
+        Ok(())
+    }
+}
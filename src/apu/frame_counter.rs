@@ -0,0 +1,129 @@
+use crate::cartridge::Region;
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
+// フレームカウンタ ($4017)
+// CPUサイクルを数えて 約240Hzでquarter frame, 約120Hzでhalf frameを起こす (PALは約200Hz/100Hz)
+// https://www.nesdev.org/wiki/APU_Frame_Counter
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FrameEvent {
+    // エンベロープと三角波の線形カウンタ
+    Quarter,
+    // quarterに加えて長さカウンタとスイープ
+    Half,
+}
+
+// 各ステップのCPUサイクル数
+// quarter, half, quarter, 4-stepの最後(half + IRQ), 5-stepの最後(half)
+// 4-stepのIRQはその前後のサイクルでも立つ
+fn step_cycles(region: Region) -> [usize; 5] {
+    match region {
+        Region::NTSC => [7457, 14913, 22371, 29829, 37281],
+        Region::PAL => [8313, 16627, 24939, 33253, 41565],
+    }
+}
+
+pub struct FrameCounter {
+    steps: [usize; 5],
+    five_step: bool,
+    irq_inhibit: bool,
+    irq: bool,
+    cycles: usize,
+}
+
+impl FrameCounter {
+    pub fn new(region: Region) -> Self {
+        FrameCounter {
+            steps: step_cycles(region),
+            five_step: false,
+            irq_inhibit: false,
+            irq: false,
+            cycles: 0,
+        }
+    }
+
+    // MI-- ----
+    // 5-stepモードは書き込んだ直後にhalf frameを起こす
+    pub fn write(&mut self, data: u8) -> Option<FrameEvent> {
+        self.five_step = data & 0b1000_0000 != 0;
+        self.irq_inhibit = data & 0b0100_0000 != 0;
+        if self.irq_inhibit {
+            self.irq = false;
+        }
+        self.cycles = 0;
+
+        if self.five_step {
+            Some(FrameEvent::Half)
+        } else {
+            None
+        }
+    }
+
+    // CPUサイクルごと
+    pub fn clock(&mut self) -> Option<FrameEvent> {
+        self.cycles += 1;
+        let [quarter1, half1, quarter2, four_step_end, five_step_end] = self.steps;
+        if self.five_step {
+            match self.cycles {
+                c if c == quarter1 || c == quarter2 => Some(FrameEvent::Quarter),
+                c if c == half1 || c == five_step_end => Some(FrameEvent::Half),
+                c if c == five_step_end + 1 => {
+                    self.cycles = 0;
+                    None
+                }
+                _ => None,
+            }
+        } else {
+            match self.cycles {
+                c if c == quarter1 || c == quarter2 => Some(FrameEvent::Quarter),
+                c if c == half1 => Some(FrameEvent::Half),
+                c if c == four_step_end - 1 => {
+                    self.set_irq();
+                    None
+                }
+                c if c == four_step_end => {
+                    self.set_irq();
+                    Some(FrameEvent::Half)
+                }
+                c if c == four_step_end + 1 => {
+                    self.set_irq();
+                    self.cycles = 0;
+                    None
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn set_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.irq = true;
+        }
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    // $4015を読むと消える
+    pub fn clear_irq(&mut self) {
+        self.irq = false;
+    }
+}
+
+impl SaveState for FrameCounter {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.five_step);
+        writer.write_bool(self.irq_inhibit);
+        writer.write_bool(self.irq);
+        writer.write_usize(self.cycles);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.five_step = reader.read_bool()?;
+        self.irq_inhibit = reader.read_bool()?;
+        self.irq = reader.read_bool()?;
+        self.cycles = reader.read_usize()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,579 @@
+use std::collections::VecDeque;
+
+pub mod dmc;
+pub mod envelope;
+pub mod frame_counter;
+pub mod length_counter;
+pub mod noise;
+pub mod pulse;
+pub mod resampler;
+pub mod triangle;
+
+use crate::cartridge::Region;
+use crate::save_state::{SaveState, StateReader, StateWriter};
+use dmc::Dmc;
+use frame_counter::{FrameCounter, FrameEvent};
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+
+// フロントエンドが取りに来るまで溜めておくサンプル数 (1サンプル = 1 CPUサイクル)
+const SAMPLE_BUFFER_SIZE: usize = 0x10000;
+
+// ミキサーで音量を変えられるチャンネル
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// Audio Processing Unit
+// CPUサイクルと同じ周期(約1.79MHz)でサンプルを作ってリングバッファに入れる
+// フレームカウンタとノイズ/DMCの周期はregionで変わる (PALでもゲームの音楽のテンポが合うように)
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    frame_counter: FrameCounter,
+    cycles: usize,
+    samples: VecDeque<f32>,
+    // チャンネルごとの音量 (1.0が元の大きさ) とミュート. ユーザー設定なので保存しない
+    volumes: [f32; 5],
+    muted: [bool; 5],
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Self {
+        Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(region),
+            dmc: Dmc::new(region),
+            frame_counter: FrameCounter::new(region),
+            cycles: 0,
+            samples: VecDeque::with_capacity(SAMPLE_BUFFER_SIZE),
+            volumes: [1.0; 5],
+            muted: [false; 5],
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, data),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, data),
+            // ---D NT21 (書くとDMCのIRQフラグは消える)
+            0x4015 => {
+                self.pulse1.length_counter.set_enabled(data & 0b01 != 0);
+                self.pulse2.length_counter.set_enabled(data & 0b10 != 0);
+                self.triangle.length_counter.set_enabled(data & 0b0100 != 0);
+                self.noise.length_counter.set_enabled(data & 0b1000 != 0);
+                self.dmc.set_enabled(data & 0b0001_0000 != 0);
+            }
+            0x4017 => {
+                let event = self.frame_counter.write(data);
+                self.clock_frame_event(event);
+            }
+            _ => {
+                // println!("Ignoring APU register {:x}", addr);
+            }
+        }
+    }
+
+    // $4015 IF-D NT21
+    // 長さカウンタ(DMCは残りバイト数)が残っているチャンネルのbitと, フレーム/DMCのIRQフラグ
+    // 読むとフレームIRQのフラグが消える (DMCのIRQは$4015への書き込みで消える)
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_counter.clear_irq();
+        status
+    }
+
+    // デバッガ用: 読んでもフレームIRQのフラグを消さない版
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length_counter.is_active() {
+            status |= 0b01;
+        }
+        if self.pulse2.length_counter.is_active() {
+            status |= 0b10;
+        }
+        if self.triangle.length_counter.is_active() {
+            status |= 0b0100;
+        }
+        if self.noise.length_counter.is_active() {
+            status |= 0b1000;
+        }
+        if self.dmc.is_active() {
+            status |= 0b0001_0000;
+        }
+        if self.frame_counter.irq_pending() {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq_pending() {
+            status |= 0b1000_0000;
+        }
+        status
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.cycles += 1;
+            self.triangle.clock_timer();
+            self.noise.clock_timer();
+            self.dmc.clock_timer();
+            let event = self.frame_counter.clock();
+            self.clock_frame_event(event);
+            // 矩形波のタイマーはAPUサイクル(CPU 2サイクル)ごと
+            if self.cycles.is_multiple_of(2) {
+                self.pulse1.clock_timer();
+                self.pulse2.clock_timer();
+            }
+
+            if self.samples.len() == SAMPLE_BUFFER_SIZE {
+                self.samples.pop_front();
+            }
+            let sample = self.output();
+            self.samples.push_back(sample);
+        }
+    }
+
+    fn clock_frame_event(&mut self, event: Option<FrameEvent>) {
+        match event {
+            Some(FrameEvent::Quarter) => self.clock_quarter_frame(),
+            Some(FrameEvent::Half) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            None => {}
+        }
+    }
+
+    // フレームカウンタとDMCのIRQ. CPUのIRQ線につながる
+    pub fn irq_pending(&self) -> bool {
+        self.frame_counter.irq_pending() || self.dmc.irq_pending()
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    pub fn set_channel_volume(&mut self, channel: Channel, volume: f32) {
+        self.volumes[channel.index()] = volume.max(0.0);
+    }
+
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel.index()] = muted;
+    }
+
+    pub fn is_channel_muted(&self, channel: Channel) -> bool {
+        self.muted[channel.index()]
+    }
+
+    // ミキサーに入れる前のチャンネルの出力に音量をかける
+    fn channel_level(&self, channel: Channel, output: u8) -> f32 {
+        if self.muted[channel.index()] {
+            0.0
+        } else {
+            output as f32 * self.volumes[channel.index()]
+        }
+    }
+
+    // 0.0~1.0
+    // https://www.nesdev.org/wiki/APU_Mixer
+    pub fn output(&self) -> f32 {
+        let pulse = self.channel_level(Channel::Pulse1, self.pulse1.output())
+            + self.channel_level(Channel::Pulse2, self.pulse2.output());
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+
+        let tnd = self.channel_level(Channel::Triangle, self.triangle.output()) / 8227.0
+            + self.channel_level(Channel::Noise, self.noise.output()) / 12241.0
+            + self.channel_level(Channel::Dmc, self.dmc.output()) / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+
+        pulse_out + tnd_out
+    }
+
+    // 溜まったサンプルを全部取り出す
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.samples.drain(..).collect()
+    }
+}
+
+// サンプルバッファは保存しない (読み込んだら捨てる)
+impl SaveState for Apu {
+    fn write_state(&self, writer: &mut StateWriter) {
+        self.pulse1.write_state(writer);
+        self.pulse2.write_state(writer);
+        self.triangle.write_state(writer);
+        self.noise.write_state(writer);
+        self.dmc.write_state(writer);
+        self.frame_counter.write_state(writer);
+        writer.write_usize(self.cycles);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.pulse1.read_state(reader)?;
+        self.pulse2.read_state(reader)?;
+        self.triangle.read_state(reader)?;
+        self.noise.read_state(reader)?;
+        self.dmc.read_state(reader)?;
+        self.frame_counter.read_state(reader)?;
+        self.cycles = reader.read_usize()?;
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_counter_load() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b11);
+        // index 1 -> 254, index 3 -> 2
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x4007, 0b0001_1000);
+        assert_eq!(apu.pulse1.length_counter.counter(), 254);
+        assert_eq!(apu.pulse2.length_counter.counter(), 2);
+        assert_eq!(apu.read_status(), 0b11);
+    }
+
+    #[test]
+    fn test_length_counter_decrements_on_half_frame() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4003, 0b0001_1000);
+
+        apu.clock_quarter_frame();
+        assert_eq!(apu.pulse1.length_counter.counter(), 2);
+        apu.clock_half_frame();
+        assert_eq!(apu.pulse1.length_counter.counter(), 1);
+        apu.clock_half_frame();
+        assert_eq!(apu.pulse1.length_counter.counter(), 0);
+        apu.clock_half_frame();
+        assert_eq!(apu.pulse1.length_counter.counter(), 0);
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn test_length_counter_halt_and_disable() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4000, 0b0010_0000);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.clock_half_frame();
+        assert_eq!(apu.pulse1.length_counter.counter(), 254);
+
+        // 無効にすると0になり, 無効な間は読み込まれない
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.pulse1.length_counter.counter(), 0);
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.pulse1.length_counter.counter(), 0);
+    }
+
+    #[test]
+    fn test_pulse_generates_samples() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b01);
+        // duty 50%, constant volume 15, period 0x100
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0x00);
+        apu.write_register(0x4003, 0b0000_1001);
+        apu.tick(200);
+        apu.tick(200);
+
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 400);
+        assert!(samples.iter().any(|s| *s > 0.0));
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_mute_channel() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b0111);
+        // 矩形波1, 2は固定音量15, 三角波は線形カウンタ127
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4003, 0b0000_1001);
+        apu.write_register(0x4004, 0b1011_1111);
+        apu.write_register(0x4007, 0b0000_1001);
+        apu.write_register(0x4008, 0b0111_1111);
+        apu.write_register(0x400B, 0b0000_1000);
+        apu.clock_quarter_frame();
+        // 3つとも鳴っているところまで進める
+        while apu.pulse1.output() == 0 || apu.pulse2.output() == 0 {
+            apu.tick(1);
+        }
+
+        let pulse2 = apu.pulse2.output() as f32;
+        let tnd = apu.triangle.output() as f32 / 8227.0;
+        let without_pulse1 = 95.88 / (8128.0 / pulse2 + 100.0) + 159.79 / (1.0 / tnd + 100.0);
+        assert!(apu.output() > without_pulse1);
+
+        apu.set_channel_muted(Channel::Pulse1, true);
+        assert!(apu.is_channel_muted(Channel::Pulse1));
+        assert_eq!(apu.output(), without_pulse1);
+
+        // 音量0でも同じ
+        apu.set_channel_muted(Channel::Pulse1, false);
+        apu.set_channel_volume(Channel::Pulse1, 0.0);
+        assert_eq!(apu.output(), without_pulse1);
+    }
+
+    #[test]
+    fn test_triangle_sequence() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b0100);
+        // 線形カウンタ 127, period 0 (タイマーを1回叩くごとに1ステップ進む)
+        apu.write_register(0x4008, 0b0111_1111);
+        apu.write_register(0x400A, 0x00);
+        apu.write_register(0x400B, 0b0000_1000);
+        assert_eq!(apu.read_status(), 0b0100);
+
+        // 線形カウンタが0の間は進まない
+        apu.triangle.clock_timer();
+        assert_eq!(apu.triangle.output(), 15);
+
+        apu.clock_quarter_frame();
+        assert_eq!(apu.triangle.linear_counter(), 127);
+
+        let mut outputs = vec![];
+        for _ in 0..32 {
+            apu.triangle.clock_timer();
+            outputs.push(apu.triangle.output());
+        }
+        let expected: Vec<u8> = (0..15).rev().chain(0..16).chain(15..16).collect();
+        assert_eq!(outputs, expected);
+    }
+
+    #[test]
+    fn test_triangle_linear_counter() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b0100);
+        apu.write_register(0x4008, 0b0000_0010);
+        apu.write_register(0x400B, 0b0000_1000);
+
+        apu.clock_quarter_frame();
+        assert_eq!(apu.triangle.linear_counter(), 2);
+        apu.clock_quarter_frame();
+        apu.clock_quarter_frame();
+        assert_eq!(apu.triangle.linear_counter(), 0);
+
+        apu.triangle.clock_timer();
+        assert_eq!(apu.triangle.output(), 15);
+    }
+
+    #[test]
+    fn test_noise_shift_register() {
+        let mut apu = Apu::new(Region::NTSC);
+        // period index 0 (4 CPUサイクルごと)
+        apu.write_register(0x400E, 0x00);
+        let mut values = vec![];
+        for _ in 0..16 {
+            apu.tick(4);
+            values.push(apu.noise.shift_register());
+        }
+        assert_eq!(
+            values,
+            vec![
+                0x4000, 0x2000, 0x1000, 0x0800, 0x0400, 0x0200, 0x0100, 0x0080, 0x0040, 0x0020,
+                0x0010, 0x0008, 0x0004, 0x0002, 0x4001, 0x6000,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_noise_short_mode() {
+        let mut apu = Apu::new(Region::NTSC);
+        // モードフラグ: bit6を使うタップに切り替える
+        apu.write_register(0x400E, 0b1000_0000);
+        let mut values = vec![];
+        for _ in 0..16 {
+            apu.tick(4);
+            values.push(apu.noise.shift_register());
+        }
+        assert_eq!(&values[12..], &[0x0804, 0x0402, 0x0201, 0x4100]);
+    }
+
+    #[test]
+    fn test_dmc_loop_and_irq() {
+        let mut apu = Apu::new(Region::NTSC);
+        // IRQ有効, ループなし, 一番速いレート, $C040から17バイト
+        apu.write_register(0x4010, 0b1000_1111);
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x01);
+        apu.write_register(0x4015, 0b0001_0000);
+        assert_eq!(apu.read_status(), 0b0001_0000);
+        for i in 0..17 {
+            assert_eq!(apu.dmc.dma_addr(), Some(0xC040 + i));
+            apu.dmc.fill_sample_buffer(0);
+            assert_eq!(apu.dmc.dma_addr(), None);
+            // 8bit(432サイクル)出し切るとバッファが空く
+            apu.tick(255);
+            apu.tick(255);
+        }
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_status(), 0b1000_0000);
+        // $4015に書くとIRQフラグは消える
+        apu.write_register(0x4015, 0);
+        assert!(!apu.irq_pending());
+
+        // ループありなら最後のバイトの次は先頭に戻り, IRQは立たない
+        apu.write_register(0x4010, 0b1100_1111);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, 0b0001_0000);
+        apu.dmc.fill_sample_buffer(0);
+        assert!(!apu.irq_pending());
+        assert_eq!(apu.read_status(), 0b0001_0000);
+        apu.tick(255);
+        apu.tick(255);
+        assert_eq!(apu.dmc.dma_addr(), Some(0xC040));
+    }
+
+    #[test]
+    fn test_frame_irq_four_step() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4017, 0x00);
+        apu.tick(200);
+        assert!(!apu.irq_pending());
+
+        for _ in 0..150 {
+            apu.tick(200);
+        }
+        // 30200サイクル
+        assert!(apu.irq_pending());
+        assert_eq!(apu.peek_status() & 0b0100_0000, 0b0100_0000);
+    }
+
+    #[test]
+    fn test_frame_irq_inhibit_and_five_step() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4017, 0b0100_0000);
+        for _ in 0..151 {
+            apu.tick(200);
+        }
+        assert!(!apu.irq_pending());
+
+        apu.write_register(0x4017, 0b1000_0000);
+        for _ in 0..200 {
+            apu.tick(200);
+        }
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn test_frame_counter_clocks_length_counter() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x4017, 0x00);
+        assert_eq!(apu.pulse1.length_counter.counter(), 254);
+
+        // 4-stepモードのhalf frameは14913と29829サイクル目
+        for _ in 0..75 {
+            apu.tick(200);
+        }
+        assert_eq!(apu.pulse1.length_counter.counter(), 253);
+        for _ in 0..75 {
+            apu.tick(200);
+        }
+        assert_eq!(apu.pulse1.length_counter.counter(), 252);
+
+        // 5-stepモードは書き込んだ時点で1回
+        apu.write_register(0x4017, 0b1000_0000);
+        assert_eq!(apu.pulse1.length_counter.counter(), 251);
+    }
+
+    // 長さカウンタが0になるまでのCPUサイクル数
+    fn note_duration(region: Region) -> usize {
+        let mut apu = Apu::new(region);
+        apu.write_register(0x4015, 0b01);
+        // index 3 -> 2
+        apu.write_register(0x4003, 0b0001_1000);
+        apu.write_register(0x4017, 0x00);
+        let mut cycles = 0;
+        while apu.pulse1.length_counter.is_active() {
+            apu.tick(1);
+            cycles += 1;
+        }
+        cycles
+    }
+
+    // 4-stepモードで最初にフレームIRQが立つまでのCPUサイクル数
+    fn frame_irq_period(region: Region) -> usize {
+        let mut apu = Apu::new(region);
+        apu.write_register(0x4017, 0x00);
+        let mut cycles = 0;
+        while !apu.irq_pending() {
+            apu.tick(1);
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_region_frame_counter_timing() {
+        // half frameは14913, 29829サイクル目 (PALは16627, 33253)
+        assert_eq!(note_duration(Region::NTSC), 29829);
+        assert_eq!(note_duration(Region::PAL), 33253);
+
+        assert_eq!(frame_irq_period(Region::NTSC), 29828);
+        assert_eq!(frame_irq_period(Region::PAL), 33252);
+    }
+
+    #[test]
+    fn test_region_noise_period() {
+        let mut ntsc = Apu::new(Region::NTSC);
+        let mut pal = Apu::new(Region::PAL);
+        // period index 2: NTSCは16, PALは14サイクル (どちらも1サイクル目に1回目のシフト)
+        ntsc.write_register(0x400E, 0x02);
+        pal.write_register(0x400E, 0x02);
+        ntsc.tick(15);
+        pal.tick(15);
+        assert_eq!(ntsc.noise.shift_register(), 0x4000);
+        assert_eq!(pal.noise.shift_register(), 0x2000);
+    }
+}
@@ -0,0 +1,126 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::cartridge::Region;
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
+// タイマー周期 (CPUサイクル)
+const NTSC_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+// ノイズチャンネル ($400C~$400F)
+// 15bitのシフトレジスタ(LFSR)で疑似乱数を作る
+pub struct Noise {
+    period_table: &'static [u16; 16],
+    // trueならbit6をフィードバックに使う (周期93/31の短いノイズ)
+    mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+    pub envelope: Envelope,
+    pub length_counter: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(region: Region) -> Self {
+        let period_table = match region {
+            Region::NTSC => &NTSC_PERIOD_TABLE,
+            Region::PAL => &PAL_PERIOD_TABLE,
+        };
+        Noise {
+            period_table,
+            mode: false,
+            timer_period: period_table[0],
+            timer: 0,
+            // 電源投入時は1
+            shift_register: 1,
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(),
+        }
+    }
+
+    // addrは0~3 ($400Cからのオフセット)
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            // --LC VVVV
+            0 => {
+                self.length_counter.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            // 未使用
+            1 => {}
+            // M--- PPPP
+            2 => {
+                self.mode = data & 0b1000_0000 != 0;
+                self.timer_period = self.period_table[(data & 0b1111) as usize];
+            }
+            // LLLL L---
+            3 => {
+                self.length_counter.load(data >> 3);
+                self.envelope.start = true;
+            }
+            _ => panic!("noise register out of range: {}", addr),
+        }
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_shift_register();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // bit0とbit1(モード1ならbit6)のxorを bit14に入れて右シフト
+    fn clock_shift_register(&mut self) {
+        let tap = if self.mode { 6 } else { 1 };
+        let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+        self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+    }
+
+    pub fn shift_register(&self) -> u16 {
+        self.shift_register
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+
+    // 0~15. シフトレジスタのbit0が立っていると無音
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.is_active() || self.shift_register & 1 != 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
+
+impl SaveState for Noise {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.mode);
+        writer.write_u16(self.timer_period);
+        writer.write_u16(self.timer);
+        writer.write_u16(self.shift_register);
+        self.envelope.write_state(writer);
+        self.length_counter.write_state(writer);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.mode = reader.read_bool()?;
+        self.timer_period = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.shift_register = reader.read_u16()?;
+        self.envelope.read_state(reader)?;
+        self.length_counter.read_state(reader)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+// APUのサンプル(CPUクロック 約1.79MHz)を出力デバイスのレートに落とす
+// 出力1サンプル分の入力を平均するだけの簡単なもの
+pub struct Resampler {
+    // 出力1サンプルあたりの入力サンプル数
+    step: f64,
+    position: f64,
+    sum: f32,
+    count: u32,
+}
+
+impl Resampler {
+    pub fn new(input_rate: f64, output_rate: f64) -> Self {
+        Resampler {
+            step: input_rate / output_rate,
+            position: 0.0,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    // 端数は次の呼び出しに持ち越す
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::with_capacity((input.len() as f64 / self.step) as usize + 1);
+        for sample in input {
+            self.sum += sample;
+            self.count += 1;
+            self.position += 1.0;
+            if self.position >= self.step {
+                self.position -= self.step;
+                output.push(self.sum / self.count as f32);
+                self.sum = 0.0;
+                self.count = 0;
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu::Apu;
+    use crate::cartridge::Region;
+
+    #[test]
+    fn test_resample_one_frame() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4003, 0b0000_1001);
+        // NTSCの1フレーム = 29780 CPUサイクル
+        for _ in 0..29780 {
+            apu.tick(1);
+        }
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 29780);
+
+        // 29780 * 44100 / 1789773 = 733.8
+        let mut resampler = Resampler::new(Region::NTSC.cpu_clock_rate(), 44100.0);
+        let output = resampler.process(&samples);
+        assert_eq!(output.len(), 733);
+        assert!(output.iter().all(|s| (0.0..=1.0).contains(s)));
+        assert!(output.iter().any(|s| *s > 0.0));
+
+        // 端数が持ち越されて2フレーム目は734
+        let output = resampler.process(&samples);
+        assert_eq!(output.len(), 734);
+    }
+
+    #[test]
+    fn test_resample_averages() {
+        let mut resampler = Resampler::new(4.0, 1.0);
+        let output = resampler.process(&[0.0, 1.0, 0.0, 1.0, 1.0, 1.0]);
+        assert_eq!(output, vec![0.5]);
+        let output = resampler.process(&[1.0, 1.0]);
+        assert_eq!(output, vec![1.0]);
+    }
+}
@@ -0,0 +1,127 @@
+use super::length_counter::LengthCounter;
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, //
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// 三角波チャンネル ($4008~$400B)
+// 音量は無く, 線形カウンタと長さカウンタの両方が残っている間だけ波形が進む
+pub struct Triangle {
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+    pub length_counter: LengthCounter,
+
+    // controlは長さカウンタのhaltも兼ねる
+    control: bool,
+    linear_reload_value: u8,
+    linear_reload: bool,
+    linear_counter: u8,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            timer_period: 0,
+            timer: 0,
+            step: 0,
+            length_counter: LengthCounter::new(),
+            control: false,
+            linear_reload_value: 0,
+            linear_reload: false,
+            linear_counter: 0,
+        }
+    }
+
+    // addrは0~3 ($4008からのオフセット)
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            // CRRR RRRR
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length_counter.halt = self.control;
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            // 未使用
+            1 => {}
+            // TTTT TTTT
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            // LLLL LTTT
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0b111) << 8);
+                self.length_counter.load(data >> 3);
+                self.linear_reload = true;
+            }
+            _ => panic!("triangle register out of range: {}", addr),
+        }
+    }
+
+    // 三角波のタイマーはCPUサイクルごと
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.linear_counter > 0 && self.length_counter.is_active() {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+
+    pub fn linear_counter(&self) -> u8 {
+        self.linear_counter
+    }
+
+    // 0~15. 止まっている間も最後の値を出し続ける
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
+
+impl Default for Triangle {
+    fn default() -> Self {
+        Triangle::new()
+    }
+}
+
+impl SaveState for Triangle {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.timer_period);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.step);
+        self.length_counter.write_state(writer);
+        writer.write_bool(self.control);
+        writer.write_u8(self.linear_reload_value);
+        writer.write_bool(self.linear_reload);
+        writer.write_u8(self.linear_counter);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.timer_period = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.step = reader.read_u8()?;
+        self.length_counter.read_state(reader)?;
+        self.control = reader.read_bool()?;
+        self.linear_reload_value = reader.read_u8()?;
+        self.linear_reload = reader.read_bool()?;
+        self.linear_counter = reader.read_u8()?;
+        Ok(())
+    }
+}
@@ -1,8 +1,15 @@
-use crate::cartridge::Rom;
+use crate::apu::Apu;
+use crate::cartridge::{Region, Rom};
+use crate::cheat::Cheat;
 use crate::cpu::Mem;
 use crate::joypad::JoyPad;
+use crate::mapper;
+use crate::mapper::SharedMapper;
 use crate::ppu::NesPPU;
 use crate::ppu::PPU;
+use crate::save_state::{SaveState, StateReader, StateWriter};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
 
 //
 // -------  0x2000
@@ -20,60 +27,328 @@ const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
 
+// フレームごとに呼ばれる (描画とコントローラの入力)
+// 'callはcallbackが借用しているもの(SDLのcanvasなど)の寿命. Busはそれより長く生きられない
+// 何も借用しないならBus<'static>になる
+pub type FrameCallback<'call> = Box<dyn FnMut(&NesPPU, &mut JoyPad, &mut JoyPad) + 'call>;
+
+// 電源を入れたときのCPU RAMの中身
+// 実機では不定で, RAMの値を乱数の種にするゲームもある
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RamInit {
+    Zero,
+    // 全部0xFF
+    Ones,
+    // 同じseedなら同じ中身になる (不定値に依存するバグの再現用)
+    Random(u64),
+}
+
+// CPUが最後にしたバスアクセス. DMCのDMAがCPUを止める長さが変わる
+// CPUは命令単位で実行しているので, 命令の最後のアクセスで近似する
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum BusAccess {
+    Read,
+    Write,
+    // $4016/$4017を読んだ (1 or 2)
+    JoypadRead(u8),
+}
+
+// on_accessのcallbackに渡す種類
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// CPUのバスアクセスごとに呼ばれる (種類, アドレス, 値). デバッグ/解析用
+pub type AccessHook<'call> = Box<dyn FnMut(AccessKind, u16, u8) + 'call>;
+
+impl RamInit {
+    // "zero" / "ff" / "random:<seed>"
+    pub fn parse(text: &str) -> Result<RamInit, String> {
+        match text {
+            "zero" => Ok(RamInit::Zero),
+            "ff" => Ok(RamInit::Ones),
+            _ => match text.strip_prefix("random:") {
+                Some(seed) => seed
+                    .parse::<u64>()
+                    .map(RamInit::Random)
+                    .map_err(|_| format!("Invalid RAM seed: {}", seed)),
+                None => Err(format!("Unknown RAM init: {}", text)),
+            },
+        }
+    }
+
+    fn fill(&self, ram: &mut [u8]) {
+        match *self {
+            RamInit::Zero => ram.fill(0),
+            RamInit::Ones => ram.fill(0xFF),
+            RamInit::Random(seed) => StdRng::seed_from_u64(seed).fill_bytes(ram),
+        }
+    }
+}
+
+// CPUから見えるものは全部Busが持つ (PPU/APU/mapper/joypad/サイクル数)
+// CPUはBusを所有するだけで, PPUなどには必ずBus越しにアクセスする
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
+    mapper: SharedMapper,
     ppu: NesPPU,
+    apu: Apu,
+    // カートリッジ上のPRG RAM (0x6000~0x7FFF). batteryがあれば.savに保存する
+    prg_ram: [u8; 0x2000],
+    battery: bool,
+    region: Region,
 
     cycles: usize,
-    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut JoyPad) + 'call>,
+    // PALはCPU 1サイクルでPPUが3.2ドット進むので端数を持ち越す
+    ppu_dot_remainder: u16,
+    // オープンバス: 最後にCPUのバスに乗った値. 書き込み専用/何もつながっていないアドレスを読むとこれが返る
+    open_bus: u8,
+    gameloop_callback: FrameCallback<'call>,
     joypad1: JoyPad,
+    joypad2: JoyPad,
+    // Game Genie (コード, デコードしたもの). セーブステートには含めない
+    cheats: Vec<(String, Cheat)>,
+    last_access: BusAccess,
+    // OAM DMAで止めている残りサイクル (今のサイクルを含む). DMA中でなければ0
+    oam_dma_remaining: u16,
+    // DMCのDMAがコントローラの読み込みと重なると1bit飛ぶ (実機のバグ). デフォルトは無効
+    dmc_read_conflict: bool,
+    // なければ何もしない. セーブステートには含めない
+    access_hook: Option<AccessHook<'call>>,
 }
 
 impl<'a> Bus<'a> {
-    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
+    // 対応していないマッパーのROMはErr
+    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Result<Bus<'call>, String>
+    where
+        F: FnMut(&NesPPU, &mut JoyPad, &mut JoyPad) + 'call,
+    {
+        Bus::new_with_ram_init(rom, RamInit::Zero, gameloop_callback)
+    }
+
+    pub fn new_with_ram_init<'call, F>(
+        rom: Rom,
+        ram_init: RamInit,
+        gameloop_callback: F,
+    ) -> Result<Bus<'call>, String>
     where
-        F: FnMut(&NesPPU, &mut JoyPad) + 'call,
+        F: FnMut(&NesPPU, &mut JoyPad, &mut JoyPad) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        let mirroring = rom.screen_mirroring;
+        let battery = rom.battery;
+        let region = rom.region;
+        let mapper = mapper::from_rom(rom)?;
+        let ppu = NesPPU::new_with_mapper(mapper.clone(), mirroring, region);
+        let mut cpu_vram = [0; 2048];
+        ram_init.fill(&mut cpu_vram);
 
-        Bus {
-            cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
+        Ok(Bus {
+            cpu_vram,
+            mapper,
             ppu: ppu,
+            apu: Apu::new(region),
+            prg_ram: [0; 0x2000],
+            battery,
+            region,
             cycles: 0,
+            ppu_dot_remainder: 0,
+            open_bus: 0,
             gameloop_callback: Box::from(gameloop_callback),
             joypad1: JoyPad::new(),
+            joypad2: JoyPad::new(),
+            cheats: vec![],
+            last_access: BusAccess::Read,
+            oam_dma_remaining: 0,
+            dmc_read_conflict: false,
+            access_hook: None,
+        })
+    }
+
+    // 描画もSDLもいらないとき(テストやツール)用. フレームごとのcallbackは何もしない
+    pub fn new_headless(rom: Rom) -> Result<Bus<'static>, String> {
+        Bus::new(rom, |_: &NesPPU, _: &mut JoyPad, _: &mut JoyPad| {})
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    // player: 1 or 2
+    pub fn joypad_mut(&mut self, player: u8) -> &mut JoyPad {
+        match player {
+            1 => &mut self.joypad1,
+            2 => &mut self.joypad2,
+            _ => panic!("unknown player {}", player),
         }
     }
 
-    pub fn tick(&mut self, cycles: u8) {
-        self.cycles += cycles as usize;
-        let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles * 3);
-        let nmi_after = self.ppu.nmi_interrupt.is_some();
+    // デバッガのメモリビューア用. mem_readと違ってレジスタを読んでも副作用がない
+    // - $2002/$2004/$2007/$4016/$4017: 次に読んだら返る値 (vblankクリアやアドレスのインクリメントはしない)
+    // - 書き込み専用のポート: オープンバスの値 (実機ではPPUのポートはPPU内部のラッチになるので近似)
+    // - $4015: フレームIRQのフラグも含めて現在の値
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b0000_0111_1111_1111) as usize],
+            0x2002 => (self.ppu.peek_status() & 0b1110_0000) | (self.open_bus & 0b0001_1111),
+            0x2004 => self.ppu.peek_oam_data(),
+            0x2007 => self.ppu.peek_data(),
+            0x2000..=0x2007 => self.open_bus,
+            0x2008..=PPU_REGISTERS_MIRRORS_END => self.peek(addr & 0b0010_0000_0000_0111),
+            0x4015 => self.apu.peek_status(),
+            0x4016 => self.joypad1.peek(),
+            0x4017 => self.joypad2.peek(),
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
+            0x8000..=0xFFFF => self.read_prg(addr),
+            _ => self.open_bus,
+        }
+    }
+
+    // CPUの読み書き(OAM DMAの読み込みを含む)を全部callbackに渡す. ミラーは元のアドレスのまま
+    // DMCのDMAはCPUのアクセスではないので呼ばない
+    pub fn on_access<F>(&mut self, hook: F)
+    where
+        F: FnMut(AccessKind, u16, u8) + 'a,
+    {
+        self.access_hook = Some(Box::from(hook));
+    }
 
-        if !nmi_before && nmi_after {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
+    pub fn clear_access_hook(&mut self) {
+        self.access_hook = None;
+    }
+
+    pub fn set_dmc_read_conflict(&mut self, enabled: bool) {
+        self.dmc_read_conflict = enabled;
+    }
+
+    // DMCのサンプル読み込み (DMA)
+    // CPUを止める長さは, そのときCPU(とOAM DMA)がしていたことで変わる
+    // https://www.nesdev.org/wiki/DMA
+    // - 読み込み中: 4サイクル (halt, dummy, alignment, get)
+    // - 書き込み中: 3サイクル (書き込みは止められないので, 終わるのを待つ間にhaltが済む)
+    // - OAM DMA中: 2サイクル (OAM DMAはその分延びる). 最後から2番目のサイクルなら1, 最後なら3
+    fn dmc_dma(&mut self, addr: u16) {
+        let stall = match self.oam_dma_remaining {
+            0 if self.last_access == BusAccess::Write => 3,
+            0 => 4,
+            1 => 3,
+            2 => 1,
+            _ => 2,
+        };
+        // 止まっている間もCPUは同じアドレスを読み直すので, コントローラのシフトレジスタが1回余分に進む
+        if let BusAccess::JoypadRead(player) = self.last_access {
+            if self.dmc_read_conflict {
+                self.joypad_mut(player).read();
+            }
+        }
+        let data = self.read_prg(addr);
+        self.open_bus = data;
+        self.apu.dmc.fill_sample_buffer(data);
+        for _ in 0..stall {
+            self.tick(1);
         }
     }
 
-    pub fn poll_nmi_status(&mut self) -> Option<u8> {
-        self.ppu.poll_nmi_interrupt()
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), String> {
+        let cheat = Cheat::decode(code)?;
+        self.cheats.push((code.to_ascii_uppercase(), cheat));
+        Ok(())
+    }
+
+    pub fn remove_cheat(&mut self, code: &str) {
+        let code = code.to_ascii_uppercase();
+        self.cheats.retain(|(c, _)| *c != code);
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000;
+    // PRG ROM (チートが有効ならその値に差し替える)
+    fn read_prg(&self, addr: u16) -> u8 {
+        let data = self.mapper.borrow().read_prg(addr);
+        self.cheats
+            .iter()
+            .find_map(|(_, cheat)| cheat.apply(addr, data))
+            .unwrap_or(data)
+    }
+
+    // バッテリーバックアップのPRG RAMを読み込む. バッテリーがなければ何もしない
+    // ファイルの読み書きはフロントエンド側でやる (WASMなどファイルがない環境もある)
+    pub fn load_sram(&mut self, data: &[u8]) -> Result<(), String> {
+        if !self.battery {
+            return Ok(());
+        }
+        if data.len() != self.prg_ram.len() {
+            return Err(format!(
+                "Invalid save file size: {} (expected {})",
+                data.len(),
+                self.prg_ram.len()
+            ));
         }
-        self.prg_rom[addr as usize]
+        self.prg_ram.copy_from_slice(data);
+        Ok(())
+    }
+
+    // 保存するべきPRG RAM. バッテリーがなければNone
+    pub fn sram(&self) -> Option<&[u8]> {
+        if !self.battery {
+            return None;
+        }
+        Some(&self.prg_ram[..])
     }
 }
 
-impl Mem for Bus<'_> {
-    fn mem_read(&mut self, addr: u16) -> u8 {
+impl SaveState for Bus<'_> {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.cpu_vram);
+        writer.write_bytes(&self.prg_ram);
+        writer.write_usize(self.cycles);
+        writer.write_u16(self.ppu_dot_remainder);
+        writer.write_u8(self.open_bus);
+        writer.write_u8(match self.last_access {
+            BusAccess::Read => 0,
+            BusAccess::Write => 1,
+            BusAccess::JoypadRead(player) => 1 + player,
+        });
+        self.ppu.write_state(writer);
+        self.apu.write_state(writer);
+        self.mapper.borrow().write_state(writer);
+        self.joypad1.write_state(writer);
+        self.joypad2.write_state(writer);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.read_bytes(&mut self.cpu_vram)?;
+        reader.read_bytes(&mut self.prg_ram)?;
+        self.cycles = reader.read_usize()?;
+        self.ppu_dot_remainder = reader.read_u16()?;
+        self.open_bus = reader.read_u8()?;
+        self.last_access = match reader.read_u8()? {
+            0 => BusAccess::Read,
+            1 => BusAccess::Write,
+            n @ 2..=3 => BusAccess::JoypadRead(n - 1),
+            n => return Err(format!("Invalid bus access: {}", n)),
+        };
+        self.ppu.read_state(reader)?;
+        self.apu.read_state(reader)?;
+        self.mapper.borrow_mut().read_state(reader)?;
+        self.joypad1.read_state(reader)?;
+        self.joypad2.read_state(reader)
+    }
+}
+
+impl Bus<'_> {
+    // アドレスごとの読み込み. ミラーはここで再帰する (access_hookが2回呼ばれないように)
+    fn read_mapped(&mut self, addr: u16) -> u8 {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 // CPUは0x0000~0x2000の13bitをRAM用に確保してる
@@ -81,36 +356,30 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b0000_0111_1111_1111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                // panic!("Attempt to read from write_only PPU address {:x}", addr);
-                0
-            }
-            0x2002 => self.ppu.read_status(),
+            // write only
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => self.open_bus,
+            // 下位5bitは未使用でオープンバスが見える
+            0x2002 => (self.ppu.read_status() & 0b1110_0000) | (self.open_bus & 0b0001_1111),
             0x2004 => self.ppu.read_oam_data(),
             0x2007 => self.ppu.read_data(),
             // 0x2008~0x3FFF
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
-                self.mem_read(mirror_down_addr)
-            }
-            0x4000..=0x4015 => {
-                // println!("Ignoring APU");
-                0
+                self.read_mapped(mirror_down_addr)
             }
+            0x4015 => self.apu.read_status(),
+            // write only
+            0x4000..=0x4013 => self.open_bus,
             0x4016 => self.joypad1.read(),
-            0x4017 => {
-                // println!("Ignoring joypad2");
-                0
-            }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
-            _ => {
-                println!("Ignoring mem access at {:x}", addr);
-                0
-            }
+            0x4017 => self.joypad2.read(),
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
+            0x8000..=0xFFFF => self.read_prg(addr),
+            // 0x4018~0x5FFF 何もつながっていない
+            _ => self.open_bus,
         }
     }
 
-    fn mem_write(&mut self, addr: u16, data: u8) {
+    fn write_mapped(&mut self, addr: u16, data: u8) {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b0000_0111_1111_1111;
@@ -124,14 +393,11 @@ impl Mem for Bus<'_> {
             0x2005 => self.ppu.write_to_scroll(data),
             0x2006 => self.ppu.write_to_ppu_addr(data),
             0x2007 => self.ppu.write_to_data(data),
-            0x4000..=0x4013 | 0x4015 => {
-                // println!("Ignoring APU");
-            }
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, data),
+            // strobeは両方のコントローラに届く
             0x4016 => {
                 self.joypad1.write(data);
-            }
-            0x4017 => {
-                // println!("Ignoring joypad2");
+                self.joypad2.write(data);
             }
             0x4014 => {
                 let mut buffer: [u8; 256] = [0; 256];
@@ -140,16 +406,546 @@ impl Mem for Bus<'_> {
                     buffer[i as usize] = self.mem_read(hi + i);
                 }
                 self.ppu.write_oam_dma(&buffer);
+
+                // DMA中はCPUが止まる: 513cycle, 奇数cycleから始まったら+1
+                let stall = if self.cycles % 2 == 1 { 514 } else { 513 };
+                for i in 0..stall {
+                    self.oam_dma_remaining = stall - i;
+                    self.tick(1);
+                }
+                self.oam_dma_remaining = 0;
             }
             // 0x2008~0x3FFF
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b0010_0000_0000_0111;
-                self.mem_write(mirror_down_addr, data);
+                self.write_mapped(mirror_down_addr, data);
             }
-            0x8000..=0xFFFF => panic!("Attempt to write to chr rom space {:x}", addr),
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize] = data,
+            0x8000..=0xFFFF => self.mapper.borrow_mut().write_prg(addr, data),
             _ => {
                 println!("Ignoring mem write-access at {:x}", addr);
             }
         }
     }
 }
+
+impl Mem for Bus<'_> {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let data = self.read_mapped(addr);
+        self.open_bus = data;
+        self.last_access = match addr {
+            0x4016 => BusAccess::JoypadRead(1),
+            0x4017 => BusAccess::JoypadRead(2),
+            _ => BusAccess::Read,
+        };
+        if let Some(hook) = self.access_hook.as_mut() {
+            hook(AccessKind::Read, addr, data);
+        }
+        data
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
+        self.last_access = BusAccess::Write;
+        if let Some(hook) = self.access_hook.as_mut() {
+            hook(AccessKind::Write, addr, data);
+        }
+        self.write_mapped(addr, data);
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+        let (numerator, denominator) = self.region.ppu_dots_per_cpu_cycle();
+        let dots = cycles as u16 * numerator + self.ppu_dot_remainder;
+        self.ppu_dot_remainder = dots % denominator;
+        // PPUはフレームの最後(pre-renderラインの終わり)で1回だけtrueを返す
+        // NMIが無効なゲームでも描画と入力が止まらないように, NMIではなくこちらで呼ぶ
+        let frame_complete = self.ppu.tick((dots / denominator) as u8);
+        self.apu.tick(cycles);
+        if let Some(addr) = self.apu.dmc.dma_addr() {
+            self.dmc_dma(addr);
+        }
+
+        if frame_complete {
+            (self.gameloop_callback)(&self.ppu, &mut self.joypad1, &mut self.joypad2);
+        }
+    }
+
+    // 起動してからのCPUサイクル数
+    fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    // リセットボタン. RAM/PRG RAMは消さない
+    fn soft_reset(&mut self) {
+        self.ppu.soft_reset();
+        // $4015に0を書いたのと同じで全チャンネル無音になる
+        self.apu.write_register(0x4015, 0);
+    }
+
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        self.ppu.poll_nmi_interrupt()
+    }
+
+    fn poll_irq_status(&self) -> bool {
+        self.mapper.borrow().irq_pending() || self.apu.irq_pending()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::{test_nrom, test_rom};
+    use crate::cartridge::{Mirroring, Region};
+    use crate::cpu::CPU;
+    use crate::joypad::JoyPadButton;
+
+    fn test_bus() -> Bus<'static> {
+        Bus::new_headless(test_nrom()).unwrap()
+    }
+
+    fn battery_bus() -> Bus<'static> {
+        let mut rom = test_nrom();
+        rom.battery = true;
+        Bus::new_headless(rom).unwrap()
+    }
+
+    fn ram_bus(ram_init: RamInit) -> Bus<'static> {
+        Bus::new_with_ram_init(
+            test_nrom(),
+            ram_init,
+            |_: &NesPPU, _: &mut JoyPad, _: &mut JoyPad| {},
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        // test_romはmapper 3
+        match Bus::new_headless(test_rom()) {
+            Ok(_) => panic!("mapper 3 should not be supported"),
+            Err(e) => assert_eq!(e, "Mapper 3 is not supported"),
+        }
+    }
+    #[test]
+    fn test_prg_rom_not_in_banks() {
+        // NES 2.0の指数表記なら24KBも書ける. NROMは16KB単位でしか読めない
+        let mut rom = test_nrom();
+        rom.prg_rom = vec![0; 0x6000];
+        match Bus::new_headless(rom) {
+            Ok(_) => panic!("24KB PRG ROM should not be accepted"),
+            Err(e) => assert_eq!(e, "Mapper 0 needs PRG ROM in 16KB banks, got 24576 bytes"),
+        }
+    }
+    #[test]
+    fn test_axrom_prg_rom_under_32k() {
+        // AxROMは32KB単位で切り替えるので, 16KBではバンクが1つもない
+        let mut rom = test_nrom();
+        rom.mapper = 7;
+        rom.prg_rom = vec![0; 0x4000];
+        match Bus::new_headless(rom) {
+            Ok(_) => panic!("16KB PRG ROM should not be accepted by AxROM"),
+            Err(e) => assert_eq!(e, "Mapper 7 needs PRG ROM in 32KB banks, got 16384 bytes"),
+        }
+    }
+
+    #[test]
+    fn test_ram_init() {
+        assert!(test_bus().cpu_vram.iter().all(|&b| b == 0));
+        assert!(ram_bus(RamInit::Ones).cpu_vram.iter().all(|&b| b == 0xFF));
+
+        let a = ram_bus(RamInit::Random(1));
+        let b = ram_bus(RamInit::Random(1));
+        let c = ram_bus(RamInit::Random(2));
+        assert_eq!(a.cpu_vram, b.cpu_vram);
+        assert_ne!(a.cpu_vram, c.cpu_vram);
+        assert!(a.cpu_vram.iter().any(|&b| b != a.cpu_vram[0]));
+
+        assert_eq!(RamInit::parse("ff"), Ok(RamInit::Ones));
+        assert_eq!(RamInit::parse("random:42"), Ok(RamInit::Random(42)));
+        assert!(RamInit::parse("random:x").is_err());
+        assert!(RamInit::parse("0xFF").is_err());
+    }
+
+    #[test]
+    fn test_frame_callback_borrows_local() {
+        // callbackがローカル変数を借用できる (Busの寿命はframesより短い)
+        let mut frames = 0;
+        {
+            let mut bus = Bus::new(test_nrom(), |_: &NesPPU, _: &mut JoyPad, _: &mut JoyPad| {
+                frames += 1;
+            })
+            .unwrap();
+            // 1フレーム = 341 * 262 / 3 CPUサイクル
+            for _ in 0..29781 {
+                bus.tick(1);
+            }
+        }
+        assert_eq!(frames, 1);
+    }
+
+    #[test]
+    fn test_frame_callback_once_per_frame() {
+        let frames = std::cell::Cell::new(0);
+        let mut bus = Bus::new(test_nrom(), |_: &NesPPU, _: &mut JoyPad, _: &mut JoyPad| {
+            frames.set(frames.get() + 1);
+        })
+        .unwrap();
+        // NMIは有効にしなくても呼ばれる
+        // 命令ごとにサイクル数がばらばらでも1フレームに1回だけ
+        let pattern = [2u8, 3, 4, 5, 6, 7, 2, 2, 4];
+        let mut total = 0usize;
+        let mut i = 0;
+        // 3フレーム = 約89342サイクル, 4フレーム目は約119123サイクル
+        while total < 100_000 {
+            let cycles = pattern[i % pattern.len()];
+            bus.tick(cycles);
+            total += cycles as usize;
+            i += 1;
+        }
+        assert_eq!(frames.get(), 3);
+    }
+
+    #[test]
+    fn test_ppu_data_through_registers() {
+        let mut bus = test_bus();
+        bus.mem_write(0x2006, 0x21);
+        bus.mem_write(0x2006, 0x05);
+        bus.mem_write(0x2007, 0x66);
+
+        bus.mem_write(0x2006, 0x21);
+        bus.mem_write(0x2006, 0x05);
+        bus.mem_read(0x2007); // バッファの読み込み
+        assert_eq!(bus.mem_read(0x2007), 0x66);
+    }
+
+    #[test]
+    fn test_ppu_registers_mirror() {
+        let mut bus = test_bus();
+        // $3FFE -> $2006, $2FFF -> $2007
+        bus.mem_write(0x3FFE, 0x21);
+        bus.mem_write(0x3FFE, 0x05);
+        bus.mem_write(0x2FFF, 0x77);
+
+        bus.mem_write(0x200E, 0x21);
+        bus.mem_write(0x200E, 0x05);
+        bus.mem_read(0x200F);
+        assert_eq!(bus.mem_read(0x3007), 0x77);
+    }
+
+    #[test]
+    fn test_ppu_status_read_resets_latch_through_mirror() {
+        let mut bus = test_bus();
+        bus.mem_write(0x2006, 0x21);
+        // $3A02 -> $2002
+        bus.mem_read(0x3A02);
+        bus.mem_write(0x2006, 0x23);
+        bus.mem_write(0x2006, 0x05);
+        bus.mem_write(0x2007, 0x88);
+
+        bus.mem_write(0x2006, 0x23);
+        bus.mem_write(0x2006, 0x05);
+        bus.mem_read(0x2007);
+        assert_eq!(bus.mem_read(0x2007), 0x88);
+    }
+
+    #[test]
+    fn test_peek_status_keeps_vblank() {
+        let mut bus = test_bus();
+        bus.ppu.status.set_vblank_started(true);
+        for _ in 0..3 {
+            assert_eq!(bus.peek(0x2002) & 0b1000_0000, 0b1000_0000);
+            // ミラーも同じ
+            assert_eq!(bus.peek(0x3A02) & 0b1000_0000, 0b1000_0000);
+        }
+        // 普通に読むとクリアされる
+        assert_eq!(bus.mem_read(0x2002) & 0b1000_0000, 0b1000_0000);
+        assert_eq!(bus.peek(0x2002) & 0b1000_0000, 0);
+    }
+
+    #[test]
+    fn test_peek_data_keeps_address() {
+        let mut bus = test_bus();
+        bus.mem_write(0x2006, 0x21);
+        bus.mem_write(0x2006, 0x05);
+        bus.mem_write(0x2007, 0x66);
+        bus.mem_write(0x2007, 0x77);
+
+        bus.mem_write(0x2006, 0x21);
+        bus.mem_write(0x2006, 0x05);
+        bus.mem_read(0x2007);
+        assert_eq!(bus.peek(0x2007), 0x66);
+        assert_eq!(bus.peek(0x2007), 0x66);
+        assert_eq!(bus.mem_read(0x2007), 0x66);
+        assert_eq!(bus.mem_read(0x2007), 0x77);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut bus = test_bus();
+        bus.mem_write(0x0010, 0xA5);
+        // 書き込み専用のポートは最後にバスに乗った値
+        assert_eq!(bus.mem_read(0x2000), 0xA5);
+        assert_eq!(bus.mem_read(0x4014), 0xA5);
+        assert_eq!(bus.mem_read(0x5000), 0xA5);
+
+        // 読んだ値もバスに残る
+        bus.mem_write(0x0011, 0x3C);
+        bus.mem_read(0x0011);
+        assert_eq!(bus.mem_read(0x2005), 0x3C);
+        assert_eq!(bus.peek(0x2005), 0x3C);
+
+        // $2002の下位5bit
+        bus.ppu.status.set_vblank_started(true);
+        bus.mem_write(0x0012, 0x1F);
+        assert_eq!(bus.mem_read(0x2002), 0b1001_1111);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut bus = test_bus();
+        for i in 0..256u16 {
+            bus.mem_write(0x0200 + i, i as u8);
+        }
+        bus.mem_write(0x4014, 0x02);
+
+        for i in 0..256 {
+            assert_eq!(bus.ppu.oam_data[i], i as u8);
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_stall_cycles() {
+        let mut bus = test_bus();
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.cycles, 513);
+
+        // 奇数cycleから始まると1cycle多く止まる
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.cycles, 513 + 514);
+    }
+
+    #[test]
+    fn test_sram_save_and_load() {
+        let mut bus = battery_bus();
+        bus.mem_write(0x6003, 0x42);
+        let sram = bus.sram().unwrap().to_vec();
+
+        let mut bus = battery_bus();
+        assert_eq!(bus.mem_read(0x6003), 0);
+        bus.load_sram(&sram).unwrap();
+        assert_eq!(bus.mem_read(0x6003), 0x42);
+
+        assert!(bus.load_sram(&sram[..0x100]).is_err());
+    }
+
+    #[test]
+    fn test_sram_without_battery_is_not_saved() {
+        let mut bus = test_bus();
+        bus.mem_write(0x7FFF, 0x42);
+        assert_eq!(bus.mem_read(0x7FFF), 0x42);
+        assert!(bus.sram().is_none());
+        bus.load_sram(&[0; 0x2000]).unwrap();
+        assert_eq!(bus.mem_read(0x7FFF), 0x42);
+    }
+
+    #[test]
+    fn test_cheat() {
+        // test_nromのPRG ROMは全部0x01
+        let mut bus = test_bus();
+        assert_eq!(bus.mem_read(0x91D9), 0x01);
+
+        bus.add_cheat("sxiopo").unwrap();
+        assert_eq!(bus.mem_read(0x91D9), 0xAD);
+        assert_eq!(bus.peek(0x91D9), 0xAD);
+        assert_eq!(bus.mem_read(0x91DA), 0x01);
+
+        // 比較値(0x03)がROMの値と違うので差し替えない
+        bus.add_cheat("ZEXPYGLA").unwrap();
+        assert_eq!(bus.mem_read(0x94A7), 0x01);
+        assert!(bus.add_cheat("HELLO").is_err());
+
+        bus.remove_cheat("SXIOPO");
+        assert_eq!(bus.mem_read(0x91D9), 0x01);
+    }
+
+    #[test]
+    fn test_headless_run() {
+        // LDA #$42; STA $10; LDX #$05; STX $11
+        let program = [0xA9, 0x42, 0x85, 0x10, 0xA2, 0x05, 0x86, 0x11];
+        let mut prg_rom = vec![0xEA; 0x8000];
+        prg_rom[..program.len()].copy_from_slice(&program);
+        prg_rom[0x7FFC] = 0x00;
+        prg_rom[0x7FFD] = 0x80;
+        let rom = Rom {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            mapper: 0,
+            submapper: 0,
+            screen_mirroring: Mirroring::HORIZONTAL,
+            battery: false,
+            region: Region::NTSC,
+            crc32: 0,
+            sha1: [0; 20],
+        };
+
+        let mut cpu = CPU::new(Bus::new_headless(rom).unwrap());
+        cpu.reset();
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+        assert_eq!(cpu.mem_read(0x11), 0x05);
+    }
+
+    #[test]
+    fn test_access_hook() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut bus = test_bus();
+        // LDA #$42; STA $10
+        for (i, data) in [0xA9, 0x42, 0x85, 0x10].iter().enumerate() {
+            bus.mem_write(0x0600 + i as u16, *data);
+        }
+        let log = Rc::new(RefCell::new(vec![]));
+        let hook_log = log.clone();
+        bus.on_access(move |kind, addr, data| hook_log.borrow_mut().push((kind, addr, data)));
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                (AccessKind::Read, 0x0600, 0xA9),
+                (AccessKind::Read, 0x0601, 0x42),
+                (AccessKind::Read, 0x0602, 0x85),
+                (AccessKind::Read, 0x0603, 0x10),
+                (AccessKind::Write, 0x0010, 0x42),
+            ]
+        );
+
+        // ミラーは1回だけ, 元のアドレスで
+        log.borrow_mut().clear();
+        cpu.bus.mem_write(0x2008, 0x00);
+        assert_eq!(*log.borrow(), vec![(AccessKind::Write, 0x2008, 0x00)]);
+
+        cpu.bus.clear_access_hook();
+        cpu.bus.mem_write(0x0010, 0x00);
+        assert_eq!(log.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_two_controllers() {
+        let mut bus = test_bus();
+        bus.joypad1
+            .set_button_pressed_status(JoyPadButton::BUTTON_A, true);
+        bus.joypad1
+            .set_button_pressed_status(JoyPadButton::UP, true);
+        bus.joypad2
+            .set_button_pressed_status(JoyPadButton::START, true);
+        bus.joypad2
+            .set_button_pressed_status(JoyPadButton::RIGHT, true);
+
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        // A, B, Select, Start, Up, Down, Left, Right
+        let pad1: Vec<u8> = (0..8).map(|_| bus.mem_read(0x4016)).collect();
+        let pad2: Vec<u8> = (0..8).map(|_| bus.mem_read(0x4017)).collect();
+        assert_eq!(pad1, vec![1, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(pad2, vec![0, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_apu_frame_irq() {
+        let mut bus = test_bus();
+        bus.mem_write(0x4017, 0x00);
+        for _ in 0..30000 {
+            bus.tick(1);
+        }
+        assert!(bus.poll_irq_status());
+
+        // inhibitを立てるとフラグも消える
+        bus.mem_write(0x4017, 0b0100_0000);
+        assert!(!bus.poll_irq_status());
+    }
+
+    #[test]
+    fn test_read_4015_clears_frame_irq() {
+        let mut bus = test_bus();
+        bus.mem_write(0x4017, 0x00);
+        for _ in 0..30000 {
+            bus.tick(1);
+        }
+        // peekでは消えない
+        assert_eq!(bus.peek(0x4015) & 0b0100_0000, 0b0100_0000);
+        assert!(bus.poll_irq_status());
+
+        assert_eq!(bus.mem_read(0x4015) & 0b0100_0000, 0b0100_0000);
+        assert!(!bus.poll_irq_status());
+        assert_eq!(bus.mem_read(0x4015) & 0b0100_0000, 0);
+    }
+
+    #[test]
+    fn test_dmc_sample_fetch() {
+        // $C000に1バイトのサンプル (bitが全部1なので毎回+2)
+        let mut rom = test_nrom();
+        rom.prg_rom[0x4000] = 0xFF;
+        let mut bus = Bus::new_headless(rom).unwrap();
+        bus.mem_write(0x4010, 0x0F);
+        bus.mem_write(0x4011, 120);
+        bus.mem_write(0x4012, 0x00);
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0b0001_0000);
+
+        let mut levels = vec![bus.apu_mut().dmc.output()];
+        for _ in 0..1000 {
+            bus.tick(1);
+            let level = bus.apu_mut().dmc.output();
+            if level != *levels.last().unwrap() {
+                levels.push(level);
+            }
+        }
+        // 127を超える分は無視される
+        assert_eq!(levels, vec![120, 122, 124, 126]);
+        // 1バイト読むのにCPUが4サイクル止まる ($4015への書き込みと重なったので3)
+        assert_eq!(bus.cycles(), 1000 + 3);
+        assert_eq!(bus.mem_read(0x4015) & 0b0001_0000, 0);
+    }
+
+    #[test]
+    fn test_dmc_controller_read_conflict() {
+        // Bだけ押している. 2回目の読み込み(B)の結果
+        for (conflict, expected) in [(false, 1), (true, 0)] {
+            let mut bus = test_bus();
+            bus.set_dmc_read_conflict(conflict);
+            bus.joypad_mut(1)
+                .set_button_pressed_status(JoyPadButton::BUTTON_B, true);
+            bus.mem_write(0x4016, 1);
+            bus.mem_write(0x4016, 0);
+            bus.mem_write(0x4015, 0b0001_0000);
+
+            // Aを読んだ命令の途中でDMCのDMAが入る
+            assert_eq!(bus.mem_read(0x4016), 0);
+            bus.tick(4);
+            assert_eq!(bus.mem_read(0x4016), expected);
+        }
+    }
+
+    #[test]
+    fn test_pal_ppu_dots_per_cpu_cycle() {
+        let mut rom = test_nrom();
+        rom.region = Region::PAL;
+        let mut bus = Bus::new_headless(rom).unwrap();
+        // CPU 5サイクルでPPUは16ドット
+        for _ in 0..5 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.ppu().dot(), 16);
+        bus.tick(5);
+        assert_eq!(bus.ppu().dot(), 32);
+    }
+}
@@ -20,39 +20,124 @@
 
 // Control Byte2 (binary)
 // | ROM Mapper Type(RMT) | RMT | RMT | RMT | iNES format | iNES format | 0 | 0 |
-// RMTの下位2bitが10ならiNES2.0, 00ならiNES1.0
+// iNES formatの2bitが10ならNES 2.0, それ以外はiNES1.0として読む
+
+// NES 2.0だけ (iNES1.0では8~15は0か意味がない)
+// byte 8:  | submapper x4 | mapper bit 8~11 x4 |
+// byte 9:  | CHR ROM sizeの上位4bit | PRG ROM sizeの上位4bit |
+//          上位4bitが0xFならサイズのbyteは指数表記 EEEEEEMM (2^E * (MM * 2 + 1) bytes)
+// byte 12: 下位2bitがタイミング (0: NTSC, 1: PAL, 2: 両対応, 3: Dendy)
+
+use crate::hash;
+use std::io::{Cursor, Read};
+use std::path::Path;
 
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16384; // 0x4000
 const CHR_ROM_PAGE_SIZE: usize = 8192; // 0x2000
 
-#[derive(Debug, PartialEq)]
+// zipのlocal file header (PK\x03\x04)
+const ZIP_TAG: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOUR_SCREEN,
+    // 4枚とも同じ1KB (マッパーが切り替える. ヘッダーでは指定できない)
+    SINGLE_SCREEN_LOWER,
+    SINGLE_SCREEN_UPPER,
+}
+
+// テレビ方式 (iNESヘッダのbyte 9 bit0)
+// PALは1フレームが312ラインと長く, CPUも遅いので50Hzで動く
+// APUのフレームカウンタとノイズ/DMCの周期もPAL用になる
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Region {
+    NTSC,
+    PAL,
+}
+
+impl Region {
+    // 1フレームのスキャンライン数. 最後の1本がpre-render line
+    pub fn scanlines(&self) -> u16 {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL => 312,
+        }
+    }
+
+    // vblank(とNMI)が始まるスキャンライン. どちらも描画240ライン + post-render 1ライン
+    pub fn vblank_scanline(&self) -> u16 {
+        241
+    }
+
+    pub fn pre_render_scanline(&self) -> u16 {
+        self.scanlines() - 1
+    }
+
+    // 描画中の奇数フレームはpre-render lineが1ドット短くなる (NTSCだけ)
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        match self {
+            Region::NTSC => true,
+            Region::PAL => false,
+        }
+    }
+
+    // CPU 1サイクルあたりのPPUドット数 (分子, 分母). NTSCは3, PALは3.2
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (u16, u16) {
+        match self {
+            Region::NTSC => (3, 1),
+            Region::PAL => (16, 5),
+        }
+    }
+
+    // CPUクロック (Hz)
+    pub fn cpu_clock_rate(&self) -> f64 {
+        match self {
+            Region::NTSC => 1_789_773.0,
+            Region::PAL => 1_662_607.0,
+        }
+    }
+
+    // フレームレート (Hz)
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::NTSC => 60.0988,
+            Region::PAL => 50.007,
+        }
+    }
 }
 
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    pub mapper: u16,
+    // NES 2.0のみ. iNES1.0なら0
+    pub submapper: u8,
     pub screen_mirroring: Mirroring, // PPU
+    pub battery: bool,               // 0x6000~0x7FFFのPRG RAMをバッテリーで保持する
+    pub region: Region,
+    // PRG ROM + CHR ROMのハッシュ (ヘッダーとトレーナーは含まない). Rom::newで1回だけ計算する
+    pub(crate) crc32: u32,
+    pub(crate) sha1: [u8; 20],
 }
 
 impl Rom {
     // .nes file identification
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
+        if raw.len() < 16 || &raw[0..4] != NES_TAG {
             return Err("File is not in iNES file format".to_string());
         }
 
         // control byte2
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
-        let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
+        let nes2 = (raw[7] >> 2) & 0b11 == 0b10;
+        let mut mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
+        let mut submapper = 0;
+        if nes2 {
+            mapper |= ((raw[8] & 0b1111) as u16) << 8;
+            submapper = raw[8] >> 4;
         }
 
         // PPU
@@ -64,21 +149,125 @@ impl Rom {
             (false, false) => Mirroring::HORIZONTAL,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let (prg_rom_size, chr_rom_size) = if nes2 {
+            (
+                nes2_rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE)?,
+                nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE)?,
+            )
+        } else {
+            (
+                raw[4] as usize * PRG_ROM_PAGE_SIZE,
+                raw[5] as usize * CHR_ROM_PAGE_SIZE,
+            )
+        };
+
+        // マッパーはPRG ROMのバンク数で割るので, 空のPRG ROMは読めない
+        if prg_rom_size == 0 {
+            return Err("ROM has no PRG ROM".to_string());
+        }
 
         let skip_trainer = raw[6] & 0b100 != 0;
+        let battery = raw[6] & 0b10 != 0;
+        let pal = if nes2 {
+            // Dendyも50HzなのでPALで近似する
+            matches!(raw[12] & 0b11, 1 | 3)
+        } else {
+            raw[9] & 1 != 0
+        };
+        let region = if pal { Region::PAL } else { Region::NTSC };
 
         let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err(format!(
+                "ROM file is truncated: {} bytes (expected {})",
+                raw.len(),
+                chr_rom_start + chr_rom_size
+            ));
+        }
+
+        let data = &raw[prg_rom_start..(chr_rom_start + chr_rom_size)];
 
         Ok(Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
             chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
-            mapper: mapper,
+            mapper,
+            submapper,
             screen_mirroring: screen_mirroring,
+            battery,
+            region,
+            crc32: hash::crc32(data),
+            sha1: hash::sha1(data),
         })
     }
+
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    pub fn sha1(&self) -> [u8; 20] {
+        self.sha1
+    }
+}
+
+// ROMファイルを読む. zipなら中の.nesを取り出す (Rom::newに渡す前の生のバイト列)
+pub fn load_rom_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, String> {
+    let data = std::fs::read(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.as_ref().display(), e))?;
+    if data.starts_with(&ZIP_TAG) {
+        extract_nes_from_zip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+// zipの中の.nesファイル. 複数あれば一番大きいもの (同じ大きさのものがあればエラー)
+pub fn extract_nes_from_zip(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(data)).map_err(|e| format!("Invalid zip file: {}", e))?;
+
+    // (index, サイズ, 名前)
+    let mut entries = vec![];
+    for i in 0..archive.len() {
+        let file = archive
+            .by_index(i)
+            .map_err(|e| format!("Invalid zip file: {}", e))?;
+        if file.is_file() && file.name().to_ascii_lowercase().ends_with(".nes") {
+            entries.push((i, file.size(), file.name().to_string()));
+        }
+    }
+    entries.sort_by_key(|e| std::cmp::Reverse(e.1));
+    let index = match entries.as_slice() {
+        [] => return Err("No .nes file in the zip".to_string()),
+        [(_, size, first), (_, second_size, second), ..] if size == second_size => {
+            return Err(format!(
+                "The zip has several .nes files ({}, {}, ...). Please extract the one to play",
+                first, second
+            ))
+        }
+        [(index, _, _), ..] => *index,
+    };
+
+    let mut file = archive
+        .by_index(index)
+        .map_err(|e| format!("Invalid zip file: {}", e))?;
+    let mut rom = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut rom)
+        .map_err(|e| format!("Failed to extract {}: {}", file.name(), e))?;
+    Ok(rom)
+}
+
+// NES 2.0のPRG/CHR ROMのサイズ (lsbはbyte 4/5, msbはbyte 9の4bit)
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> Result<usize, String> {
+    if msb == 0b1111 {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        return 1usize
+            .checked_shl(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .ok_or_else(|| format!("ROM size is too large: 2^{} * {}", exponent, multiplier));
+    }
+    Ok((((msb as usize) << 8) | lsb as usize) * page_size)
 }
 
 #[cfg(test)]
@@ -123,6 +312,13 @@ pub mod test {
         Rom::new(&test_rom).unwrap()
     }
 
+    // test_romと同じ中身のNROM (mapper 0). Busを作るテスト用
+    pub fn test_nrom() -> Rom {
+        let mut rom = test_rom();
+        rom.mapper = 0;
+        rom
+    }
+
     #[test]
     fn test() {
         let test_rom = create_rom(TestRom {
@@ -140,6 +336,97 @@ pub mod test {
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
         assert_eq!(rom.mapper, 3);
         assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert!(!rom.battery);
+    }
+
+    #[test]
+    fn test_hash() {
+        // PRG ROMが0x01 * 32KB, CHR ROMが0x02 * 8KB (ヘッダーは含まない)
+        let rom = test_rom();
+        assert_eq!(rom.crc32(), 0x9012_89B3);
+        assert_eq!(
+            rom.sha1(),
+            [
+                0x6e, 0x82, 0xd6, 0xbd, 0x77, 0x88, 0xe0, 0x6d, 0x64, 0x9f, 0xac, 0x76, 0xdd, 0x89,
+                0xb9, 0x23, 0xdb, 0xd5, 0x93, 0xd6,
+            ]
+        );
+
+        // ヘッダーが違っても中身が同じなら同じ
+        let mut raw = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x03, 0, 0, 1, 0, 0, 0, 0, 0, 0,
+        ];
+        raw.extend(vec![1; 2 * PRG_ROM_PAGE_SIZE]);
+        raw.extend(vec![2; CHR_ROM_PAGE_SIZE]);
+        assert_eq!(Rom::new(&raw).unwrap().crc32(), rom.crc32());
+    }
+
+    #[test]
+    fn test_battery() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x33, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+        assert!(rom.battery);
+        assert_eq!(rom.region, Region::NTSC);
+    }
+
+    #[test]
+    fn test_extract_nes_from_zip() {
+        use std::io::Write;
+        use zip::write::{FileOptions, ZipWriter};
+
+        let raw = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+        let zip = |files: &[(&str, &[u8])]| {
+            let mut writer = ZipWriter::new(Cursor::new(vec![]));
+            for (name, data) in files {
+                writer.start_file(*name, FileOptions::default()).unwrap();
+                writer.write_all(data).unwrap();
+            }
+            writer.finish().unwrap().into_inner()
+        };
+
+        // .nes以外と小さい.nesは無視する
+        let data = zip(&[
+            ("readme.txt", b"hello"),
+            ("small.nes", &raw[..16]),
+            ("Game.NES", &raw),
+        ]);
+        assert!(data.starts_with(&ZIP_TAG));
+        let extracted = extract_nes_from_zip(&data).unwrap();
+        assert_eq!(extracted, raw);
+        assert_eq!(Rom::new(&extracted).unwrap().mapper, 3);
+
+        assert!(extract_nes_from_zip(&zip(&[("readme.txt", b"hello")])).is_err());
+        assert!(extract_nes_from_zip(&zip(&[("a.nes", &raw), ("b.nes", &raw)])).is_err());
+    }
+
+    #[test]
+    fn test_pal() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 0x01, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.region, Region::PAL);
     }
 
     #[test]
@@ -177,19 +464,99 @@ pub mod test {
     }
 
     #[test]
-    fn test_nes2_is_not_supported() {
+    fn test_nes2() {
+        // mapper 0x243 (byte 8の下位4bit + byte 7 + byte 6), submapper 5
+        // PRG ROM: 0x100 * 16KB (byte 9の下位4bitが上位), CHR ROM: 1 * 8KB, PAL
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x00, 0x01, 0x31, 0x48, 0x52, 0x01, 00, 00, 0x01, 00, 00,
+                00,
             ],
             trainer: None,
-            prg_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
+            prg_rom: vec![1; 0x100 * PRG_ROM_PAGE_SIZE],
             chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
         });
-        let rom = Rom::new(&test_rom);
-        match rom {
-            Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "NES2.0 format is not supported"),
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.mapper, 0x243);
+        assert_eq!(rom.submapper, 5);
+        assert_eq!(rom.prg_rom.len(), 0x100 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom, vec![2; 1 * CHR_ROM_PAGE_SIZE]);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert_eq!(rom.region, Region::PAL);
+    }
+
+    #[test]
+    fn test_nes2_exponent_size() {
+        // PRG ROM: 2^14 * 3 = 48KB, CHR ROM: 2^13 * 1 = 8KB
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E,
+                0x45,
+                0x53,
+                0x1A,
+                (14 << 2) | 1,
+                13 << 2,
+                0x00,
+                0x08,
+                00,
+                0xFF,
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 3 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.submapper, 0);
+        assert_eq!(rom.prg_rom.len(), 3 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+        assert_eq!(rom.region, Region::NTSC);
+
+        // ファイルが足りなければエラー (パニックしない)
+        assert!(Rom::new(&test_rom[..test_rom.len() - 1].to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_empty_prg_rom() {
+        // mapper 4, PRG ROM 0バンク
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x00, 0x01, 0x40, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+        match Rom::new(&test_rom) {
+            Ok(_) => panic!("should not load rom"),
+            Err(e) => assert_eq!(e, "ROM has no PRG ROM"),
         }
     }
+
+    #[test]
+    fn test_ines_ignores_nes2_bytes() {
+        // iNES1.0ではbyte 8/9の上位bitは読まない
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x40, 0x52, 0xF0, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.mapper, 0x43);
+        assert_eq!(rom.submapper, 0);
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.region, Region::NTSC);
+    }
 }
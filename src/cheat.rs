@@ -0,0 +1,101 @@
+// Game Genie のコード
+// CPUがPRG ROM(0x8000~0xFFFF)を読んだときに値を差し替える
+//
+// 文字は A P Z L G I T Y E O X U K S V N がそれぞれ0~15
+// 6文字: 1678 H234 -IJK LABC DMNO 5EFG
+// 8文字: 1678 H234 -IJK LABC DMNO %EFG !^&* 5@#$
+//   値 = 12345678, アドレス = 0x8000 + ABCDEFGHIJKLMNO, 比較値 = !@#$%^&*
+//   (8文字はROMの値が比較値と同じときだけ差し替える. バンク切り替えで別の場所を壊さないため)
+
+const LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Cheat {
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl Cheat {
+    pub fn decode(code: &str) -> Result<Cheat, String> {
+        let n = code
+            .to_ascii_uppercase()
+            .bytes()
+            .map(|letter| LETTERS.iter().position(|l| *l == letter).map(|i| i as u16))
+            .collect::<Option<Vec<u16>>>()
+            .ok_or_else(|| format!("Invalid Game Genie code: {}", code))?;
+        if n.len() != 6 && n.len() != 8 {
+            return Err(format!("Game Genie code must be 6 or 8 letters: {}", code));
+        }
+
+        let addr = 0x8000
+            | ((n[3] & 7) << 12)
+            | ((n[4] & 8) << 8)
+            | ((n[5] & 7) << 8)
+            | ((n[1] & 8) << 4)
+            | ((n[2] & 7) << 4)
+            | (n[3] & 8)
+            | (n[4] & 7);
+        // 6文字と8文字で値のbit 3の場所が違う
+        let value_bit3 = if n.len() == 6 { n[5] } else { n[7] };
+        let value = ((n[0] & 8) << 4) | ((n[1] & 7) << 4) | (value_bit3 & 8) | (n[0] & 7);
+        let compare = if n.len() == 8 {
+            Some((((n[6] & 8) << 4) | ((n[7] & 7) << 4) | (n[5] & 8) | (n[6] & 7)) as u8)
+        } else {
+            None
+        };
+
+        Ok(Cheat {
+            addr,
+            value: value as u8,
+            compare,
+        })
+    }
+
+    // ROMから読んだ値dataを差し替えるならその値
+    pub fn apply(&self, addr: u16, data: u8) -> Option<u8> {
+        if addr != self.addr {
+            return None;
+        }
+        match self.compare {
+            Some(compare) if compare != data => None,
+            _ => Some(self.value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // Super Mario Bros. 無限残機
+        assert_eq!(
+            Cheat::decode("SXIOPO"),
+            Ok(Cheat {
+                addr: 0x91D9,
+                value: 0xAD,
+                compare: None
+            })
+        );
+        assert_eq!(
+            Cheat::decode("zexpygla"),
+            Ok(Cheat {
+                addr: 0x94A7,
+                value: 0x02,
+                compare: Some(0x03)
+            })
+        );
+        assert!(Cheat::decode("SXIOP").is_err());
+        assert!(Cheat::decode("SXIOPB").is_err());
+    }
+
+    #[test]
+    fn test_apply_with_compare() {
+        let cheat = Cheat::decode("ZEXPYGLA").unwrap();
+        assert_eq!(cheat.apply(0x94A7, 0x03), Some(0x02));
+        assert_eq!(cheat.apply(0x94A7, 0x04), None);
+        assert_eq!(cheat.apply(0x94A8, 0x03), None);
+    }
+}
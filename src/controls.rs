@@ -0,0 +1,244 @@
+use crate::joypad::JoyPadButton;
+use crate::render::palette::BuiltinPalette;
+use sdl2::keyboard::Keycode;
+use std::collections::HashMap;
+use std::path::Path;
+
+// キー設定 (controls.json)
+// キーはSDLのKeycode名 (Keycode::name()と同じ表記), 値はJoyPadButtonの名前
+//
+// {
+//     "player1": { "Up": "UP", "Down": "DOWN", "A": "BUTTON_A", ... },
+//     "player2": { "I": "UP", "K": "DOWN", "N": "BUTTON_A", ... }
+// }
+//
+// player2は省略できる(省略したらデフォルト). 書いたプレイヤーは全ボタンに1つ以上キーが必要
+//
+// "settings": { "palette": "grayscale" } で内蔵パレットを選べる (F4で切り替えると書き込まれる)
+
+const BUTTONS: [(&str, JoyPadButton); 8] = [
+    ("RIGHT", JoyPadButton::RIGHT),
+    ("LEFT", JoyPadButton::LEFT),
+    ("DOWN", JoyPadButton::DOWN),
+    ("UP", JoyPadButton::UP),
+    ("START", JoyPadButton::START),
+    ("SELECT", JoyPadButton::SELECT),
+    ("BUTTON_B", JoyPadButton::BUTTON_B),
+    ("BUTTON_A", JoyPadButton::BUTTON_A),
+];
+
+pub struct Controls {
+    pub player1: HashMap<String, JoyPadButton>,
+    pub player2: HashMap<String, JoyPadButton>,
+    pub palette: BuiltinPalette,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Controls {
+            player1: bindings(&[
+                ("Down", JoyPadButton::DOWN),
+                ("Up", JoyPadButton::UP),
+                ("Right", JoyPadButton::RIGHT),
+                ("Left", JoyPadButton::LEFT),
+                ("Space", JoyPadButton::SELECT),
+                ("Return", JoyPadButton::START),
+                ("A", JoyPadButton::BUTTON_A),
+                ("S", JoyPadButton::BUTTON_B),
+            ]),
+            player2: bindings(&[
+                ("K", JoyPadButton::DOWN),
+                ("I", JoyPadButton::UP),
+                ("L", JoyPadButton::RIGHT),
+                ("J", JoyPadButton::LEFT),
+                ("Right Shift", JoyPadButton::SELECT),
+                ("Backspace", JoyPadButton::START),
+                ("N", JoyPadButton::BUTTON_A),
+                ("M", JoyPadButton::BUTTON_B),
+            ]),
+            palette: BuiltinPalette::Standard,
+        }
+    }
+}
+
+impl Controls {
+    // ファイルがなければデフォルトの設定を使う
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Controls, String> {
+        if !path.as_ref().exists() {
+            return Ok(Controls::default());
+        }
+        let text = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+        Controls::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Controls, String> {
+        let mut config: HashMap<String, HashMap<String, String>> =
+            serde_json::from_str(text).map_err(|e| format!("Invalid controls config: {}", e))?;
+
+        let player1 = match config.remove("player1") {
+            Some(keys) => parse_player("player1", keys)?,
+            None => return Err("Controls config has no \"player1\" section".to_string()),
+        };
+        let player2 = match config.remove("player2") {
+            Some(keys) => parse_player("player2", keys)?,
+            None => Controls::default().player2,
+        };
+        let palette = match config.remove("settings") {
+            Some(settings) => parse_settings(settings)?,
+            None => BuiltinPalette::Standard,
+        };
+        if let Some(section) = config.keys().next() {
+            return Err(format!(
+                "Unknown section \"{}\" in controls config",
+                section
+            ));
+        }
+
+        Ok(Controls {
+            player1,
+            player2,
+            palette,
+        })
+    }
+
+    // 選んだパレットを設定ファイルに書き込む. キー設定はそのまま (ファイルがなければsettingsだけ)
+    pub fn save_palette<P: AsRef<Path>>(path: P, palette: BuiltinPalette) -> Result<(), String> {
+        let mut config = if path.as_ref().exists() {
+            let text = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+            serde_json::from_str(&text).map_err(|e| format!("Invalid controls config: {}", e))?
+        } else {
+            serde_json::Map::new()
+        };
+        let mut settings = serde_json::Map::new();
+        settings.insert("palette".to_string(), palette.name().into());
+        config.insert("settings".to_string(), settings.into());
+        let text = serde_json::to_string_pretty(&config).map_err(|e| e.to_string())?;
+        std::fs::write(&path, text).map_err(|e| e.to_string())
+    }
+}
+
+fn parse_settings(settings: HashMap<String, String>) -> Result<BuiltinPalette, String> {
+    let mut palette = BuiltinPalette::Standard;
+    for (key, value) in settings {
+        match key.as_str() {
+            "palette" => palette = BuiltinPalette::parse(&value)?,
+            _ => return Err(format!("Unknown setting \"{}\" in controls config", key)),
+        }
+    }
+    Ok(palette)
+}
+
+fn bindings(keys: &[(&str, JoyPadButton)]) -> HashMap<String, JoyPadButton> {
+    keys.iter()
+        .map(|(key, button)| (key.to_string(), *button))
+        .collect()
+}
+
+fn parse_player(
+    player: &str,
+    keys: HashMap<String, String>,
+) -> Result<HashMap<String, JoyPadButton>, String> {
+    let mut result = HashMap::new();
+    for (key, name) in keys {
+        if Keycode::from_name(&key).is_none() {
+            return Err(format!("{}: unknown key name \"{}\"", player, key));
+        }
+        let button = match BUTTONS.iter().find(|(button_name, _)| *button_name == name) {
+            Some((_, button)) => *button,
+            None => return Err(format!("Unknown button \"{}\" for key \"{}\"", name, key)),
+        };
+        result.insert(key, button);
+    }
+
+    for (name, button) in BUTTONS.iter() {
+        if !result.values().any(|bound| bound == button) {
+            return Err(format!("{}: button {} has no key binding", player, name));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE: &str = r#"{
+        "player1": {
+            "W": "UP",
+            "S": "DOWN",
+            "A": "LEFT",
+            "D": "RIGHT",
+            "Up": "UP",
+            "Right Shift": "SELECT",
+            "Return": "START",
+            "J": "BUTTON_A",
+            "K": "BUTTON_B"
+        }
+    }"#;
+
+    #[test]
+    fn test_parse_controls() {
+        let controls = Controls::parse(SAMPLE).unwrap();
+
+        let expected = bindings(&[
+            ("W", JoyPadButton::UP),
+            ("S", JoyPadButton::DOWN),
+            ("A", JoyPadButton::LEFT),
+            ("D", JoyPadButton::RIGHT),
+            ("Up", JoyPadButton::UP),
+            ("Right Shift", JoyPadButton::SELECT),
+            ("Return", JoyPadButton::START),
+            ("J", JoyPadButton::BUTTON_A),
+            ("K", JoyPadButton::BUTTON_B),
+        ]);
+        assert_eq!(controls.player1, expected);
+        assert_eq!(controls.player2, Controls::default().player2);
+    }
+
+    #[test]
+    fn test_missing_binding() {
+        let config = r#"{ "player1": { "Up": "UP", "Down": "DOWN" } }"#;
+        let err = Controls::parse(config).err().unwrap();
+        assert_eq!(err, "player1: button RIGHT has no key binding");
+    }
+
+    #[test]
+    fn test_palette_setting() {
+        assert_eq!(
+            Controls::parse(SAMPLE).unwrap().palette,
+            BuiltinPalette::Standard
+        );
+        let config = SAMPLE.replacen('{', r#"{ "settings": { "palette": "deuteranopia" },"#, 1);
+        let controls = Controls::parse(&config).unwrap();
+        assert_eq!(controls.palette, BuiltinPalette::Deuteranopia);
+        assert_eq!(controls.player1, Controls::parse(SAMPLE).unwrap().player1);
+
+        let config = SAMPLE.replacen('{', r#"{ "settings": { "palette": "sepia" },"#, 1);
+        assert!(Controls::parse(&config).is_err());
+    }
+
+    #[test]
+    fn test_save_palette_keeps_bindings() {
+        let path = std::env::temp_dir().join("hobby_nes_emulator_test_controls.json");
+        std::fs::write(&path, SAMPLE).unwrap();
+        Controls::save_palette(&path, BuiltinPalette::Grayscale).unwrap();
+        let controls = Controls::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(controls.palette, BuiltinPalette::Grayscale);
+        assert_eq!(controls.player1, Controls::parse(SAMPLE).unwrap().player1);
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let config = SAMPLE.replace("\"W\"", "\"Whoops\"");
+        let err = Controls::parse(&config).err().unwrap();
+        assert_eq!(err, "player1: unknown key name \"Whoops\"");
+    }
+
+    #[test]
+    fn test_unknown_button() {
+        let config = r#"{ "player1": { "Up": "JUMP" } }"#;
+        assert!(Controls::parse(config).is_err());
+    }
+}
@@ -1,11 +1,16 @@
-use crate::bus::Bus;
 use crate::opcodes;
-use std::collections::HashMap;
+use crate::profiler::Profiler;
+use crate::save_state::{SaveState, StateReader, StateWriter};
+use std::collections::HashSet;
+use std::fmt;
 
 // stack
 const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xFD;
 
+// XAA(ANE)のマジック定数. 実機では個体や温度で変わる (0xEE, 0xFFなど)
+const XAA_MAGIC: u8 = 0xEE;
+
 bitflags! {
     pub struct CpuFlags: u8 {
         const CLEAR_STATUS      = 0b0000_0000;
@@ -21,16 +26,64 @@ bitflags! {
     }
 }
 
-pub struct CPU<'a> {
+pub struct CPU<M> {
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
     pub status: CpuFlags,
     pub program_counter: u16,
     pub stack_pointer: u8,
-    pub bus: Bus<'a>,
+    pub bus: M,
+    // BRKで実行を止める (snake gameなどのテスト用プログラム向け)
+    pub halt_on_brk: bool,
+    // KIL(JAM)で止まった. リセットするまで命令も割り込みも進まない
+    halted: bool,
+    // デバッガ用
+    breakpoints: HashSet<u16>,
+    read_watchpoints: HashSet<u16>,
+    write_watchpoints: HashSet<u16>,
+    watch_hit: Option<StopReason>,
+    // 有効にしたときだけSome (無効なら数えない)
+    profiler: Option<Profiler>,
+}
+
+// run_with_callbackが止まった理由
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StopReason {
+    // halt_on_brkでBRKに当たった
+    Brk,
+    // PCがブレークポイントに来た (その命令はまだ実行していない)
+    Breakpoint(u16),
+    // ウォッチしているアドレスを読んだ/書いた (その命令は実行済み)
+    ReadWatchpoint(u16),
+    WriteWatchpoint(u16),
+}
+
+// エミュレーションを続けられないエラー. ライブラリとして使うときにホストごと落ちないようにpanicしない
+// どちらも(オペコード, そのアドレス). PCはそのオペコードを指したまま
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EmuError {
+    // KIL(JAM): 実機ではCPUが止まる
+    IllegalHalt(u8, u16),
+    // 実行できないオペコード
+    UnknownOpcode(u8, u16),
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::IllegalHalt(code, pc) => {
+                write!(f, "CPU jammed by opcode {:02X} at {:04X}", code, pc)
+            }
+            EmuError::UnknownOpcode(code, pc) => {
+                write!(f, "unknown opcode {:02X} at {:04X}", code, pc)
+            }
+        }
+    }
 }
 
+impl std::error::Error for EmuError {}
+
 #[derive(Debug)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
@@ -64,26 +117,68 @@ pub trait Mem {
         self.mem_write(pos, lo);
         self.mem_write(pos + 1, hi);
     }
+
+    // ここから下はCPU以外(PPU/APU/マッパー)とのやりとり
+    // ただのメモリ(テスト用のFlatMemなど)なら何もしなくていい
+
+    // CPUが進めたサイクル数
+    fn tick(&mut self, _cycles: u8) {}
+
+    // 起動してからのCPUサイクル数 (OAM/DMC DMAでバスがCPUを止めた分も含む)
+    fn cycles(&self) -> usize {
+        0
+    }
+
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn poll_irq_status(&self) -> bool {
+        false
+    }
+
+    // リセットボタン
+    fn soft_reset(&mut self) {}
 }
 
-impl Mem for CPU<'_> {
+impl<M: Mem> Mem for CPU<M> {
     fn mem_read(&mut self, addr: u16) -> u8 {
+        self.watch_read(addr);
         self.bus.mem_read(addr)
     }
 
     fn mem_read_u16(&mut self, pos: u16) -> u16 {
+        self.watch_read(pos);
+        self.watch_read(pos.wrapping_add(1));
         self.bus.mem_read_u16(pos)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
-        self.bus.mem_write(addr, data)
+        self.watch_write(addr);
+        self.bus.mem_write(addr, data);
     }
 
     fn mem_write_u16(&mut self, pos: u16, data: u16) {
+        self.watch_write(pos);
+        self.watch_write(pos.wrapping_add(1));
         self.bus.mem_write_u16(pos, data)
     }
 }
 
+// BCDの引き算 (各桁ごとに借りる)
+fn subtract_decimal(a: u8, value: u8, borrow: u8) -> u8 {
+    let mut lo = (a & 0x0F) as i16 - (value & 0x0F) as i16 - borrow as i16;
+    let mut hi = (a >> 4) as i16 - (value >> 4) as i16;
+    if lo < 0 {
+        lo += 10;
+        hi -= 1;
+    }
+    if hi < 0 {
+        hi += 10;
+    }
+    ((hi as u8 & 0x0F) << 4) | (lo as u8 & 0x0F)
+}
+
 // メモリページサイズは256byte,
 // [0x0000 .. 0x00FF], [0x0100 .. 0x01FF]
 fn page_cross(base: u16, addr: u16) -> bool {
@@ -92,8 +187,10 @@ fn page_cross(base: u16, addr: u16) -> bool {
 
 mod interrupt {
     #[derive(PartialEq, Eq)]
+    #[allow(clippy::upper_case_acronyms)]
     pub enum InterruptType {
         NMI,
+        IRQ,
         BRK,
     }
 
@@ -109,19 +206,27 @@ mod interrupt {
         itype: InterruptType::NMI,
         vector_addr: 0xFFFA,
         b_flag_mask: 0b0010_0000,
-        cpu_cycles: 2,
+        cpu_cycles: 7,
     };
 
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xFFFE,
+        b_flag_mask: 0b0010_0000,
+        cpu_cycles: 7,
+    };
+
+    // 7 cycles are already charged by the BRK opcode itself
     pub(super) const BRK: Interrupt = Interrupt {
         itype: InterruptType::BRK,
         vector_addr: 0xFFFE,
         b_flag_mask: 0b0011_0000,
-        cpu_cycles: 1,
+        cpu_cycles: 0,
     };
 }
 
-impl<'a> CPU<'a> {
-    pub fn new<'b>(bus: Bus<'b>) -> CPU<'b> {
+impl<M: Mem> CPU<M> {
+    pub fn new(bus: M) -> CPU<M> {
         CPU {
             register_a: 0,
             register_x: 0,
@@ -131,9 +236,88 @@ impl<'a> CPU<'a> {
             stack_pointer: STACK_RESET,
             // memory: [0; 0xFFFF]
             bus: bus,
+            halt_on_brk: false,
+            halted: false,
+            breakpoints: HashSet::new(),
+            read_watchpoints: HashSet::new(),
+            write_watchpoints: HashSet::new(),
+            watch_hit: None,
+            profiler: None,
+        }
+    }
+
+    // 有効にし直すとそれまでの結果は消える
+    pub fn enable_profiling(&mut self, enabled: bool) {
+        self.profiler = if enabled { Some(Profiler::new()) } else { None };
+    }
+
+    // (PC, 実行回数) を回数の多い順に. 無効なら空
+    pub fn profile_report(&self) -> Vec<(u16, u64)> {
+        self.profiler
+            .as_ref()
+            .map(|profiler| profiler.pc_report())
+            .unwrap_or_default()
+    }
+
+    // (ニーモニック, 実行回数, サイクル数) をサイクル数の多い順に. 無効なら空
+    pub fn profile_mnemonics(&self) -> Vec<(&'static str, u64, u64)> {
+        self.profiler
+            .as_ref()
+            .map(|profiler| profiler.mnemonic_report())
+            .unwrap_or_default()
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn add_read_watchpoint(&mut self, addr: u16) {
+        self.read_watchpoints.insert(addr);
+    }
+
+    pub fn add_write_watchpoint(&mut self, addr: u16) {
+        self.write_watchpoints.insert(addr);
+    }
+
+    // 読み込み/書き込みの両方から外す
+    pub fn remove_watchpoint(&mut self, addr: u16) {
+        self.read_watchpoints.remove(&addr);
+        self.write_watchpoints.remove(&addr);
+    }
+
+    // 最初に当たったものだけ覚えておく
+    fn watch_read(&mut self, addr: u16) {
+        if self.watch_hit.is_none()
+            && !self.read_watchpoints.is_empty()
+            && self.read_watchpoints.contains(&addr)
+        {
+            self.watch_hit = Some(StopReason::ReadWatchpoint(addr));
         }
     }
 
+    fn watch_write(&mut self, addr: u16) {
+        if self.watch_hit.is_none()
+            && !self.write_watchpoints.is_empty()
+            && self.write_watchpoints.contains(&addr)
+        {
+            self.watch_hit = Some(StopReason::WriteWatchpoint(addr));
+        }
+    }
+
+    // CPUのサイクルはすべてここを通してPPUに伝える
+    fn tick(&mut self, cycles: u8) {
+        self.bus.tick(cycles);
+    }
+
+    // 起動してからのCPUサイクル数. 数えるのはバス (OAM/DMC DMAで止まった分も含む)
+    pub fn total_cycles(&self) -> usize {
+        self.bus.cycles()
+    }
+
     pub fn get_absolute_address(&mut self, mode: &AddressingMode, addr: u16) -> (u16, bool) {
         match mode {
             // ページ跨りがあるのはAbsolute_X, Absolute_Y, Indirect_Y
@@ -243,11 +427,71 @@ impl<'a> CPU<'a> {
         self.set_register_a(tmp);
     }
 
+    // ADC/RRA
+    fn add_with_carry(&mut self, value: u8) {
+        if self.decimal_enabled() {
+            self.add_decimal(value);
+        } else {
+            self.add_to_register_a(value);
+        }
+    }
+
+    // SBC/ISB/*SBC. A - M - (1 - C) = A + !M + C
+    // ADCと同じ加算にするので, キャリーとオーバーフローはADCと必ず同じ規則になる
+    fn subtract_with_borrow(&mut self, value: u8) {
+        let a = self.register_a;
+        let borrow = !self.status.contains(CpuFlags::CARRY_FLAG) as u8;
+        self.add_to_register_a(value ^ 0xFF);
+        if self.decimal_enabled() {
+            // フラグはバイナリの結果のまま (NMOS 6502), Aだけ10進で補正する
+            self.register_a = subtract_decimal(a, value, borrow);
+        }
+    }
+
+    // NESの2A03はDECIMAL_MODE_FLAGを無視する
+    fn decimal_enabled(&self) -> bool {
+        cfg!(feature = "decimal_mode") && self.status.contains(CpuFlags::DECIMAL_MODE_FLAG)
+    }
+
+    // NMOS 6502のBCD加算. Zはバイナリの結果, NとVは上位桁を補正する前の値から決まる
+    fn add_decimal(&mut self, value: u8) {
+        let a = self.register_a as u16;
+        let v = value as u16;
+        let carry = self.status.contains(CpuFlags::CARRY_FLAG) as u16;
+
+        let mut lo = (a & 0x0F) + (v & 0x0F) + carry;
+        if lo > 0x09 {
+            lo += 0x06;
+        }
+        let mut result = (a & 0xF0) + (v & 0xF0) + if lo > 0x0F { 0x10 } else { 0 } + (lo & 0x0F);
+
+        self.status
+            .set(CpuFlags::ZERO_FLAG, (a + v + carry) & 0xFF == 0);
+        self.status.set(CpuFlags::NEGATIVE_FLAG, result & 0x80 != 0);
+        self.status
+            .set(CpuFlags::OVERFLOW_FLAG, !(a ^ v) & (a ^ result) & 0x80 != 0);
+        if result > 0x9F {
+            result += 0x60;
+        }
+        self.status.set(CpuFlags::CARRY_FLAG, result > 0xFF);
+        self.register_a = result as u8;
+    }
+
     fn set_register_a(&mut self, value: u8) {
         self.register_a = value;
         self.update_zero_and_negative_flags(self.register_a);
     }
 
+    fn set_register_x(&mut self, value: u8) {
+        self.register_x = value;
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn set_register_y(&mut self, value: u8) {
+        self.register_y = value;
+        self.update_zero_and_negative_flags(self.register_y);
+    }
+
     fn update_zero_and_negative_flags(&mut self, result: u8) {
         if result == 0 {
             self.status.insert(CpuFlags::ZERO_FLAG);
@@ -262,14 +506,6 @@ impl<'a> CPU<'a> {
         }
     }
 
-    fn update_negative_flags(&mut self, result: u8) {
-        if result >> 7 == 1 {
-            self.status.insert(CpuFlags::NEGATIVE_FLAG);
-        } else {
-            self.status.remove(CpuFlags::NEGATIVE_FLAG);
-        }
-    }
-
     fn stack_pop(&mut self) -> u8 {
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
         self.mem_read((STACK as u16) + self.stack_pointer as u16)
@@ -294,21 +530,13 @@ impl<'a> CPU<'a> {
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
-        self.add_to_register_a(value);
-        if page_cross {
-            self.bus.tick(1);
-        }
+        let value = self.load_operand(mode);
+        self.add_with_carry(value);
     }
 
     fn and(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
+        let value = self.load_operand(mode);
         self.set_register_a(value & self.register_a);
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn asl_accumulator(&mut self) {
@@ -324,9 +552,11 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_a);
     }
 
+    // キャリーはシフトで押し出されたbit7, Z/Nは書き込んだ値から
+    // SLO/SREは返り値を使ってAを更新するので, 最後のフラグはAのもの(Cだけこっちのまま)
     fn asl(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, _) = self.get_operand_address(&mode);
-        let mut data = self.mem_read(addr);
+        let mut data = self.read_for_modify(addr);
         // 7bitが設定されている場合
         if data >> 7 == 1 {
             self.status.insert(CpuFlags::CARRY_FLAG); // set carry flag
@@ -340,17 +570,17 @@ impl<'a> CPU<'a> {
     }
 
     // Branch opecode (bcc, bcs, ...)
+    // 分岐しない: 2cycle (opcodeの表の分), 分岐する: +1, 分岐先が別のページ: さらに+1
+    // ページ跨ぎは分岐命令の次の命令のアドレス(オペランドの次)と分岐先で比べる
     fn branch(&mut self) {
         // 分岐するならクロック時間消費
-        self.bus.tick(1);
+        self.tick(1);
         let branch: i8 = self.mem_read(self.program_counter) as i8;
-        let branch_addr = self
-            .program_counter
-            .wrapping_add(1)
-            .wrapping_add(branch as u16);
+        let next_instruction = self.program_counter.wrapping_add(1);
+        let branch_addr = next_instruction.wrapping_add(branch as u16);
 
-        if self.program_counter.wrapping_add(1) & 0xFF00 != branch_addr & 0xFF00 {
-            self.bus.tick(1);
+        if page_cross(next_instruction, branch_addr) {
+            self.tick(1);
         }
         self.program_counter = branch_addr;
     }
@@ -417,7 +647,12 @@ impl<'a> CPU<'a> {
         }
     }
 
-    fn brk(&mut self) {}
+    // INTERRUPT_DISABLEに関係なく割り込む
+    fn brk(&mut self) {
+        // BRKの次の1byteはパディングなので, 戻り先はPC+2
+        self.program_counter += 1;
+        self.interrupt(interrupt::BRK);
+    }
 
     fn bvc(&mut self) {
         if !self.status.contains(CpuFlags::OVERFLOW_FLAG) {
@@ -459,12 +694,8 @@ impl<'a> CPU<'a> {
     }
 
     fn cmp(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
+        let value = self.load_operand(mode);
         self.compare(self.register_a, value);
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn cpx(&mut self, mode: &AddressingMode) {
@@ -481,7 +712,7 @@ impl<'a> CPU<'a> {
 
     fn dec(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, _) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
+        let value = self.read_for_modify(addr);
         let res = value.wrapping_sub(1);
         self.mem_write(addr, res);
         self.update_zero_and_negative_flags(res);
@@ -499,18 +730,14 @@ impl<'a> CPU<'a> {
     }
 
     fn eor(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
+        let value = self.load_operand(mode);
         self.register_a ^= value;
         self.update_zero_and_negative_flags(self.register_a);
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn inc(&mut self, mode: &AddressingMode) {
         let (addr, _) = self.get_operand_address(&mode);
-        let mut value = self.mem_read(addr);
+        let mut value = self.read_for_modify(addr);
         value = value.wrapping_add(1);
         self.mem_write(addr, value);
         self.update_zero_and_negative_flags(value);
@@ -537,34 +764,41 @@ impl<'a> CPU<'a> {
         self.program_counter = addr;
     }
 
-    fn lda(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
+    // 読み込み命令共通: オペランドを読んで, ページを跨いだら1cycle追加
+    // 跨いだときは上位バイトを直す前のアドレス(1ページ手前)を一度読んでしまう. その1cycle
+    fn load_operand(&mut self, mode: &AddressingMode) -> u8 {
+        let (addr, page_cross) = self.get_operand_address(mode);
+        if page_cross {
+            self.mem_read(addr.wrapping_sub(0x100));
+        }
         let value = self.mem_read(addr);
-        self.register_a = value;
-        self.set_register_a(value);
         if page_cross {
-            self.bus.tick(1);
+            self.tick(1);
         }
+        value
     }
 
-    fn ldx(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
+    // RMW命令 (ASL/LSR/ROL/ROR/INC/DEC, 非公式のSLO/SRE/RLA/RRA/DCP/ISB)
+    // 6502は読んだ値を一度そのまま書き戻してから, 変更した値を書く. バスを見ているマッパーには両方見える
+    fn read_for_modify(&mut self, addr: u16) -> u8 {
         let value = self.mem_read(addr);
-        self.register_x = value;
-        self.update_zero_and_negative_flags(self.register_x);
-        if page_cross {
-            self.bus.tick(1);
-        }
+        self.mem_write(addr, value);
+        value
+    }
+
+    fn lda(&mut self, mode: &AddressingMode) {
+        let value = self.load_operand(mode);
+        self.set_register_a(value);
+    }
+
+    fn ldx(&mut self, mode: &AddressingMode) {
+        let value = self.load_operand(mode);
+        self.set_register_x(value);
     }
 
     fn ldy(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
-        self.register_y = value;
-        self.update_zero_and_negative_flags(self.register_y);
-        if page_cross {
-            self.bus.tick(1);
-        }
+        let value = self.load_operand(mode);
+        self.set_register_y(value);
     }
 
     fn lsr_accumulator(&mut self) {
@@ -574,13 +808,14 @@ impl<'a> CPU<'a> {
         } else {
             self.status.remove(CpuFlags::CARRY_FLAG);
         }
-        data = data >> 1; // data * 2
+        data >>= 1; // data / 2
         self.set_register_a(data)
     }
 
+    // キャリーはシフトで押し出されたbit0. 結果のbit7は必ず0なのでNは常にクリアされる
     fn lsr(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, _) = self.get_operand_address(mode);
-        let mut value = self.mem_read(addr);
+        let mut value = self.read_for_modify(addr);
         if value & 1 == 1 {
             self.status.insert(CpuFlags::CARRY_FLAG);
         } else {
@@ -597,12 +832,8 @@ impl<'a> CPU<'a> {
     }
 
     fn ora(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+        let value = self.load_operand(mode);
         self.set_register_a(value | self.register_a);
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn pha(&mut self) {
@@ -645,7 +876,7 @@ impl<'a> CPU<'a> {
 
     fn rol(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, _) = self.get_operand_address(mode);
-        let mut value = self.mem_read(addr);
+        let mut value = self.read_for_modify(addr);
         let tmp = self.status.contains(CpuFlags::CARRY_FLAG);
 
         if value >> 7 == 1 {
@@ -658,7 +889,7 @@ impl<'a> CPU<'a> {
             value |= 1;
         }
         self.mem_write(addr, value);
-        self.update_negative_flags(value);
+        self.update_zero_and_negative_flags(value);
         value
     }
 
@@ -680,7 +911,7 @@ impl<'a> CPU<'a> {
 
     fn ror(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, _) = self.get_operand_address(mode);
-        let mut value = self.mem_read(addr);
+        let mut value = self.read_for_modify(addr);
         let tmp = self.status.contains(CpuFlags::CARRY_FLAG);
 
         if value & 1 == 1 {
@@ -695,7 +926,7 @@ impl<'a> CPU<'a> {
             value &= !0b1000_0000;
         }
         self.mem_write(addr, value);
-        self.update_negative_flags(value);
+        self.update_zero_and_negative_flags(value);
         value
     }
 
@@ -711,12 +942,8 @@ impl<'a> CPU<'a> {
     }
 
     fn sbc(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
-        self.add_to_register_a(((value as i8).wrapping_neg().wrapping_sub(1)) as u8);
-        if page_cross {
-            self.bus.tick(1);
-        }
+        let value = self.load_operand(mode);
+        self.subtract_with_borrow(value);
     }
 
     fn sec(&mut self) {
@@ -797,31 +1024,22 @@ impl<'a> CPU<'a> {
         self.mem_write(addr, res);
     }
 
-    // not confirmed
+    // ARR: AND #imm してから ROR A (入力のキャリーがbit7に入る)
+    // フラグはRORと違う: C = 結果のbit6, V = bit6 ^ bit5, N/Zは結果から
     fn arr(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(&mode);
+        let (addr, _) = self.get_operand_address(mode);
         let value = self.mem_read(addr);
-
-        self.set_register_a(self.register_a & value);
-
-        self.ror_accumulator();
-        let res_bit_5 = (self.register_a >> 5) & 1;
-        let res_bit_6 = (self.register_a >> 6) & 1;
-
-        if (res_bit_5 == 1) & (res_bit_6 == 1) {
-            self.status.insert(CpuFlags::CARRY_FLAG);
-            self.status.remove(CpuFlags::OVERFLOW_FLAG);
-        } else if (res_bit_5 == 0) & (res_bit_6 == 0) {
-            self.status.remove(CpuFlags::CARRY_FLAG);
-            self.status.remove(CpuFlags::OVERFLOW_FLAG);
-        } else if (res_bit_5 == 1) & (res_bit_6 == 0) {
-            self.status.remove(CpuFlags::CARRY_FLAG);
-            self.status.insert(CpuFlags::OVERFLOW_FLAG);
-        } else if (res_bit_5 == 0) & (res_bit_6 == 1) {
-            self.status.insert(CpuFlags::CARRY_FLAG);
-            self.status.remove(CpuFlags::OVERFLOW_FLAG);
-        }
-        self.update_zero_and_negative_flags(self.register_a);
+        let carry_in = if self.status.contains(CpuFlags::CARRY_FLAG) {
+            0b1000_0000
+        } else {
+            0
+        };
+        let result = ((self.register_a & value) >> 1) | carry_in;
+        self.set_register_a(result);
+        let bit_6 = result & 0b0100_0000 != 0;
+        let bit_5 = result & 0b0010_0000 != 0;
+        self.status.set(CpuFlags::CARRY_FLAG, bit_6);
+        self.status.set(CpuFlags::OVERFLOW_FLAG, bit_6 ^ bit_5);
     }
 
     // not confirmed
@@ -834,32 +1052,45 @@ impl<'a> CPU<'a> {
 
     // not confirmed
     fn lxa(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
+        let value = self.load_operand(mode);
         self.set_register_a(self.register_a & value);
         self.register_x = self.register_a;
         self.update_zero_and_negative_flags(self.register_a);
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
-    // not confirmed
     fn ahx(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
-        let res = self.register_x & self.register_a & (addr >> 8) as u8;
-        self.mem_write(addr, res);
+        self.store_and_high(mode, self.register_a & self.register_x);
     }
 
-    // not confirmed
+    // AHX/SHX/SHY/TAS共通: reg & (インデックスを足す前のアドレスの上位バイト + 1) を書き込む
+    // ページを跨ぐと, 書き込み先アドレスの上位バイトが書き込む値に化ける
+    fn store_and_high(&mut self, mode: &AddressingMode, reg: u8) {
+        let (addr, page_cross) = self.get_operand_address(mode);
+        let high = (addr >> 8) as u8;
+        let base_high = if page_cross {
+            high.wrapping_sub(1)
+        } else {
+            high
+        };
+        let value = reg & base_high.wrapping_add(1);
+        let addr = if page_cross {
+            (value as u16) << 8 | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.mem_write(addr, value);
+    }
+
+    // X = (A & X) - operand (borrowなしの減算, carryはCMPと同じ)
     fn axs(&mut self, mode: &AddressingMode) {
         let (addr, _) = self.get_operand_address(&mode);
         let value = self.mem_read(addr);
-        let tmp = self.register_x & self.register_a;
-        let res = tmp.wrapping_sub(tmp);
+        let tmp = self.register_a & self.register_x;
+        let res = tmp.wrapping_sub(value);
         if value <= tmp {
             self.status.insert(CpuFlags::CARRY_FLAG);
+        } else {
+            self.status.remove(CpuFlags::CARRY_FLAG);
         }
         self.update_zero_and_negative_flags(res);
         self.register_x = res;
@@ -867,13 +1098,11 @@ impl<'a> CPU<'a> {
 
     fn dcp(&mut self, mode: &AddressingMode) {
         let (addr, _) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
+        let value = self.read_for_modify(addr);
         let res = value.wrapping_sub(1);
         self.mem_write(addr, res);
-        if res <= self.register_a {
-            self.status.insert(CpuFlags::CARRY_FLAG);
-        }
-        self.update_zero_and_negative_flags(self.register_a.wrapping_sub(res));
+        // DEC + CMP
+        self.compare(self.register_a, res);
     }
 
     fn nop_dop(&mut self) {
@@ -882,18 +1111,13 @@ impl<'a> CPU<'a> {
 
     fn isb(&mut self, mode: &AddressingMode) {
         let (addr, _) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
+        let value = self.read_for_modify(addr);
         let res = value.wrapping_add(1);
         self.update_zero_and_negative_flags(res);
-        self.add_to_register_a(res.wrapping_neg().wrapping_sub(1));
+        self.subtract_with_borrow(res);
         self.mem_write(addr, res);
     }
 
-    // not confirmed
-    fn kil(&mut self) {
-        return;
-    }
-
     // not confirmed
     fn las(&mut self, mode: &AddressingMode) {
         let (addr, _) = self.get_operand_address(&mode);
@@ -906,8 +1130,7 @@ impl<'a> CPU<'a> {
     }
 
     fn lax(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
+        let value = self.load_operand(mode);
         self.set_register_a(value);
         self.register_x = value;
     }
@@ -919,7 +1142,7 @@ impl<'a> CPU<'a> {
 
     fn rra(&mut self, mode: &AddressingMode) {
         let value = self.ror(mode);
-        self.add_to_register_a(value);
+        self.add_with_carry(value);
     }
 
     fn sbc_ex(&mut self, mode: &AddressingMode) {
@@ -938,50 +1161,38 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_a);
     }
 
-    // not confirmed
     fn shx(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(&mode);
-        let tmp = (addr >> 8 as u8).wrapping_add(1) as u8;
-        let res = self.register_x & tmp;
-        self.mem_write(addr, res);
+        self.store_and_high(mode, self.register_x);
     }
 
-    // not confirmed
     fn shy(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(&mode);
-        let tmp = (addr >> 8 as u8).wrapping_add(1) as u8;
-        let res = self.register_y & tmp;
-        self.mem_write(addr, res);
+        self.store_and_high(mode, self.register_y);
     }
 
-    fn nop_top(&mut self) {
-        return;
+    // オペランドは読むだけ. Absolute_Xでページを跨いだら1cycle追加
+    fn nop_top(&mut self, mode: &AddressingMode) {
+        self.load_operand(mode);
     }
 
-    // not confirmed
+    // 不安定な命令: A = (A | MAGIC) & X & operand
+    // MAGICは実機によって違うので, これに頼るプログラムは実機でも動かないことがある
     fn xaa(&mut self, mode: &AddressingMode) {
-        // Exact operation unknown. <- ???
-        self.register_a = self.register_x;
-        self.update_zero_and_negative_flags(self.register_a);
         let (addr, _) = self.get_operand_address(mode);
         let data = self.mem_read(addr);
-        self.set_register_a(data & self.register_a);
+        self.set_register_a((self.register_a | XAA_MAGIC) & self.register_x & data);
     }
 
-    // not confirmed
+    // SP = A & X, それからAHXと同じように書き込む
     fn tas(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(&mode);
-        let res = self.register_x & self.register_a;
-        self.stack_pointer = res;
-        let res1 = self.stack_pointer & (addr >> 8 as u8).wrapping_add(1) as u8;
-        self.mem_write(addr, res1);
+        self.stack_pointer = self.register_a & self.register_x;
+        self.store_and_high(mode, self.stack_pointer);
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<StopReason, EmuError> {
         self.load(program);
         self.reset();
         self.program_counter = 0x0600;
-        self.run();
+        self.run()
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
@@ -999,323 +1210,1352 @@ impl<'a> CPU<'a> {
         self.status = CpuFlags::from_bits_truncate(0b100100);
         // 0xFFFC, 0xFFFDにはloadの時点で0x00,0x80つまり0x8000が入っているはず
         self.program_counter = self.mem_read_u16(0xFFFC);
+        // リセットシーケンスに7cycleかかる (電源投入直後ならnestestのログと同じCYC:7)
+        self.halted = false;
+        self.tick(7);
     }
 
-    fn crash(&mut self) {
-        let pc = self.program_counter.wrapping_sub(1);
-        panic!("unexpected opecode was executed {:?} ", self.mem_read(pc));
+    // リセットボタン (電源投入のresetとは別)
+    // A/X/YとRAMは残したまま, Iフラグを立てて$FFFCのベクタから再開する
+    // SPはスタックに書き込まずに3つ下がる
+    pub fn soft_reset(&mut self) {
+        self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+        self.bus.soft_reset();
+        self.halted = false;
+        self.program_counter = self.mem_read_u16(0xFFFC);
+        self.tick(7);
     }
 
+    // 6502と同じく PCH, PCL, P の順にpushする (RTIは逆順にpop)
     fn interrupt(&mut self, interrupt: interrupt::Interrupt) {
         self.stack_push_u16(self.program_counter);
         let mut flag = self.status.clone();
         flag.set(
             CpuFlags::BREAK_COMMAND,
-            interrupt.b_flag_mask & 0b010000 == 1,
+            interrupt.b_flag_mask & 0b010000 != 0,
         );
         flag.set(
             CpuFlags::BREAK2_COMMAND,
-            interrupt.b_flag_mask & 0b100000 == 1,
+            interrupt.b_flag_mask & 0b100000 != 0,
         );
 
         self.stack_push(flag.bits);
         self.status.insert(CpuFlags::INTERRUPT_DISABLE);
 
-        self.bus.tick(interrupt.cpu_cycles);
+        self.tick(interrupt.cpu_cycles);
         self.program_counter = self.mem_read_u16(interrupt.vector_addr);
     }
 
-    pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+    pub fn run(&mut self) -> Result<StopReason, EmuError> {
+        self.run_with_callback(|_| {})
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    // ブレークポイントで止まった後にもう一度呼べば, その命令から再開する
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<StopReason, EmuError>
     where
-        F: FnMut(&mut CPU),
+        F: FnMut(&mut CPU<M>),
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+        let mut resume_from = Some(self.program_counter);
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt(interrupt::NMI);
+            self.handle_interrupts();
+            callback(self);
+            let pc = self.program_counter;
+            if resume_from != Some(pc) && self.breakpoints.contains(&pc) {
+                return Ok(StopReason::Breakpoint(pc));
+            }
+            resume_from = None;
+            if let Some(reason) = self.execute_instruction()? {
+                return Ok(reason);
             }
+        }
+    }
 
-            callback(self);
-            // 0x8000の値(命令)を読み込む
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
-            // println!("{:x}", self.program_counter);
-            let opcode = opcodes
-                .get(&code)
-                .expect(&format!("OpCode {:x} is not recognized", code));
-            // println!("{:x}", code);
-
-            match code {
-                // ADC (Add with Carry)
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
-                // AND (Logical AND)
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
-                // ASL (Arithmetic Shift Left Accumulator) Accumulatorはopecodeのみなので分ける
-                0x0A => self.asl_accumulator(),
-                // ASL (Arithmetic Shift Left other)
-                0x06 | 0x16 | 0x0E | 0x1E => {
-                    self.asl(&opcode.mode);
-                }
-                // BCC (Branch if Carry Clear)
-                0x90 => self.bcc(),
-                // BCS (Branch if Carry Set)
-                0xB0 => self.bcs(),
-                // BEQ (Branch if Equal)
-                0xF0 => self.beq(),
-                // BIT (Bit Test)
-                0x24 | 0x2C => {
-                    self.bit(&opcode.mode);
-                }
-                // BMI (Branch if Minus)
-                0x30 => self.bmi(),
-                // BNE (Branch if Not Equal)
-                0xD0 => self.bne(),
-                // BPL (Branch if Positive)
-                0x10 => self.bpl(),
-                // BRK (Force Interrupt)
-                // 0x00 => {
-                //     self.brk();
-                //     return
-                // }
-                0x00 => {
-                    self.program_counter += 1;
-                    if !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
-                        self.interrupt(interrupt::BRK);
-                    }
-                }
-                // BVC (Branch if Overflow Clear)
-                0x50 => self.bvc(),
-                // BVS (Branch if Overflow Set)
-                0x70 => self.bvs(),
-                // CLC (Clear Carry Flag)
-                0x18 => self.clc(),
-                // CLD (Clear Decimal Mode)
-                0xd8 => self.cld(),
-                // CLI (Clear Interrupt Disable)
-                0x58 => self.cli(),
-                // CLV (Clear Oveflow Flag)
-                0xb8 => self.clv(),
-                // CMP (Compare)
-                0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
-                    self.cmp(&opcode.mode);
-                }
-                // CPX (Compare X Register)
-                0xE0 | 0xE4 | 0xEC => {
-                    self.cpx(&opcode.mode);
-                }
-                // CPY (Compare Y Register)
-                0xC0 | 0xC4 | 0xCC => {
-                    self.cpy(&opcode.mode);
-                }
-                // DEC (Decrement Memory)
-                0xC6 | 0xD6 | 0xCE | 0xDE => {
-                    self.dec(&opcode.mode);
-                }
-                // DEX (Decrement X Register)
-                0xCA => self.dex(),
-                // DEY (Decrement Y Register)
-                0x88 => self.dey(),
-                // EOR (Exclusive OR)
-                0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
-                // INC (Increment Memory)
-                0xE6 | 0xF6 | 0xEE | 0xFE => {
-                    self.inc(&opcode.mode);
-                }
-                // INX (Incremetn X Register)
-                0xE8 => self.inx(),
-                // INY (Increment Y Register)
-                0xC8 => self.iny(),
-                // JMP (Jump)
-                0x4C | 0x6C => {
-                    self.jmp(&opcode.mode);
-                }
-                // JSR (Jump to Subroutine)
-                0x20 => self.jsr(&opcode.mode),
-                // LDA (Load Accumulator)
-                0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
-                    self.lda(&opcode.mode);
-                }
-                // LDX (Load X Register)
-                0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
-                    self.ldx(&opcode.mode);
-                }
-                // LDY (Load Y Register)
-                0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
-                    self.ldy(&opcode.mode);
-                }
-                // LSR (Logic Shift Right Accumulator)
-                0x4A => self.lsr_accumulator(),
-                // LSR (Logic Shift Right)
-                0x46 | 0x56 | 0x4E | 0x5E => {
-                    self.lsr(&opcode.mode);
-                }
-                // NOP (No Operation)
-                0xEA => self.nop(),
-                // ORA (Logical Inclusive OR)
-                0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
-                // PHA (Push Accumulator)
-                0x48 => self.pha(),
-                // PHP (Push Processor Status)
-                0x08 => self.php(),
-                // PLA (Pull Accumulator)
-                0x68 => self.pla(),
-                // PLP (Pull Processor Status)
-                0x28 => self.plp(),
-                // ROL (Rotate Left Accumulator)
-                0x2A => self.rol_accumulator(),
-                // ROL (Rotate Left)
-                0x26 | 0x36 | 0x2E | 0x3E => {
-                    self.rol(&opcode.mode);
-                }
-                // ROR (Rotate Right Accumulator)
-                0x6A => self.ror_accumulator(),
-                // ROR (Rotate Right)
-                0x66 | 0x76 | 0x6E | 0x7E => {
-                    self.ror(&opcode.mode);
-                }
-                // RTI (Return from Interrupt)
-                0x40 => self.rti(),
-                // RTS (Return from Subeourine)
-                0x60 => self.rts(),
-                // SBC (Sbstract with Carry)
-                0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
-                    self.sbc(&opcode.mode);
-                }
-                // SEC (Set Carry Flag)
-                0x38 => self.sec(),
-                // SED (Set Decimal Flag)
-                0xF8 => self.sed(),
-                // SEI (SetInterrupt Disable)
-                0x78 => self.sei(),
-                // STA (Store Accumulator)
-                0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
-                // STX (Store X Register)
-                0x86 | 0x96 | 0x8E => {
-                    self.stx(&opcode.mode);
-                }
-                // STY (Store Y Register)
-                0x84 | 0x94 | 0x8C => {
-                    self.sty(&opcode.mode);
-                }
-                // TAX (Transfer Accumulator to X)
-                0xAA => self.tax(),
-                // TAY (Transfer Accumulator to Y)
-                0xA8 => self.tay(),
-                // TSX (Transfer Stack Pointer to X)
-                0xBA => self.tsx(),
-                // TXA (Transfer X to Accumulator)
-                0x8A => self.txa(),
-                // TXS (Transfer X to Stack Pointer)
-                0x9A => self.txs(),
-                // TYA (Transfer Y to Accumulator)
-                0x98 => self.tya(),
-
-                // illegal opcodes
-                // *ANC(AAC)
-                0x0B | 0x2B => {
-                    self.anc(&opcode.mode);
-                }
-                // *SAX(AAX)
-                0x87 | 0x97 | 0x8F | 0x83 => {
-                    self.sax(&opcode.mode);
-                }
-                // *ARR
-                0x6B => self.arr(&opcode.mode),
-                // *ALR
-                0x4B => self.alr(&opcode.mode),
-                // *LXA(ATX)
-                0xAB => self.lxa(&opcode.mode),
-                // *AHX(AXA)
-                0x9F | 0x93 => self.ahx(&opcode.mode),
-                // *AXS
-                0xCB => self.axs(&opcode.mode),
-                // *DCP
-                0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xD3 | 0xC3 => self.dcp(&opcode.mode),
-                // *NOP(DOP) (No Operation)
-                0x04 | 0x14 | 0x34 | 0x44 | 0x54 | 0x64 | 0x74 | 0x80 | 0x82 | 0x89 | 0xC2
-                | 0xD4 | 0xE2 | 0xF4 => {
-                    // TODO: CHECK
-                    let (addr, page_cross) = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read(addr);
-                    if page_cross {
-                        self.bus.tick(1);
-                    }
-                    // do nothing
-                    self.nop_dop();
-                }
-                // *ISB(ISC)
-                0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => {
-                    self.isb(&opcode.mode);
-                }
-                // *NOP(KIL)
-                0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2
-                | 0xF2 => {
-                    self.kil();
-                }
-                // *LAS(LAR)
-                0xBB => self.las(&opcode.mode),
-                // *LAX
-                0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 => {
-                    self.lax(&opcode.mode);
-                }
-                // *NOP(NOP)
-                0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => {
-                    self.nop();
-                }
-                // *RLA
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => {
-                    self.rla(&opcode.mode);
-                }
-                // *RRA
-                0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => {
-                    self.rra(&opcode.mode);
-                }
-                // *SBC
-                0xEB => self.sbc_ex(&opcode.mode),
-                // *SLO
-                0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => {
-                    self.slo(&opcode.mode);
-                }
-                // *SRE
-                0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => {
-                    self.sre(&opcode.mode);
-                }
-                // *SHX(SXA)
-                0x9E => self.shx(&opcode.mode),
-                // *SHY(SYA)
-                0x9C => self.shy(&opcode.mode),
-                // *NOP(TOP)
-                0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {
-                    self.nop_top();
+    // callbackをN CPUサイクルごとに呼ぶ (トレースを命令ごとではなく間引いて取る用)
+    // 命令の途中では呼べないので, Nサイクルの区切りを過ぎたあとの最初の命令の前に1回呼ぶ
+    pub fn run_with_callback_every<F>(
+        &mut self,
+        n: usize,
+        mut callback: F,
+    ) -> Result<StopReason, EmuError>
+    where
+        F: FnMut(&mut CPU<M>),
+    {
+        assert!(n > 0, "callback interval must be at least 1 cycle");
+        let mut next = self.total_cycles() + n;
+        self.run_with_callback(move |cpu| {
+            let cycles = cpu.total_cycles();
+            if cycles >= next {
+                // OAM DMAなどで区切りを何個もまたいでも呼ぶのは1回
+                next += n * ((cycles - next) / n + 1);
+                callback(cpu);
+            }
+        })
+    }
+
+    // 割り込みがあればそれを受け付け, なければ1命令だけ実行する
+    // ブレークポイントは見ない (デバッガから1命令ずつ進める用)
+    pub fn step(&mut self) -> Result<Option<StopReason>, EmuError> {
+        if !self.handle_interrupts() {
+            return self.execute_instruction();
+        }
+        Ok(None)
+    }
+
+    // 1命令実行して, 止まるべきならその理由を返す
+    // (callbackのtraceなどが読んだ分はウォッチポイントに数えない)
+    fn execute_instruction(&mut self) -> Result<Option<StopReason>, EmuError> {
+        self.watch_hit = None;
+        let cycles_before = self.total_cycles();
+        let running = self.execute()?;
+        let cycles = self.total_cycles() - cycles_before;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.add_cycles(cycles);
+        }
+        if !running {
+            return Ok(Some(StopReason::Brk));
+        }
+        Ok(self.watch_hit.take())
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    fn handle_interrupts(&mut self) -> bool {
+        // KILで止まっている間はNMI/IRQも受け付けない (PCはKILを指したままなので, 実行するたびに同じエラー)
+        if self.halted {
+            return false;
+        }
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt(interrupt::NMI);
+            true
+        } else if self.bus.poll_irq_status() {
+            self.interrupt_irq()
+        } else {
+            false
+        }
+    }
+
+    // IRQはINTERRUPT_DISABLEが立っている間は受け付けない
+    fn interrupt_irq(&mut self) -> bool {
+        if self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
+            return false;
+        }
+        self.interrupt(interrupt::IRQ);
+        true
+    }
+
+    // halt_on_brkでBRKに当たった場合はfalseを返す
+    fn execute(&mut self) -> Result<bool, EmuError> {
+        let opcodes = &*opcodes::OPCODES_MAP;
+        // 0x8000の値(命令)を読み込む
+        let code = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
+        // println!("{:x}", self.program_counter);
+        let opcode = match opcodes.get(&code) {
+            Some(opcode) => opcode,
+            None => {
+                self.program_counter -= 1;
+                return Err(EmuError::UnknownOpcode(code, self.program_counter));
+            }
+        };
+        // println!("{:x}", code);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.count_instruction(program_counter_state - 1, opcode.mnemonic);
+        }
+
+        match code {
+            // ADC (Add with Carry)
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            }
+            // AND (Logical AND)
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            }
+            // ASL (Arithmetic Shift Left Accumulator) Accumulatorはopecodeのみなので分ける
+            0x0A => self.asl_accumulator(),
+            // ASL (Arithmetic Shift Left other)
+            0x06 | 0x16 | 0x0E | 0x1E => {
+                self.asl(&opcode.mode);
+            }
+            // BCC (Branch if Carry Clear)
+            0x90 => self.bcc(),
+            // BCS (Branch if Carry Set)
+            0xB0 => self.bcs(),
+            // BEQ (Branch if Equal)
+            0xF0 => self.beq(),
+            // BIT (Bit Test)
+            0x24 | 0x2C => {
+                self.bit(&opcode.mode);
+            }
+            // BMI (Branch if Minus)
+            0x30 => self.bmi(),
+            // BNE (Branch if Not Equal)
+            0xD0 => self.bne(),
+            // BPL (Branch if Positive)
+            0x10 => self.bpl(),
+            // BRK (Force Interrupt)
+            0x00 => {
+                if self.halt_on_brk {
+                    return Ok(false);
                 }
-                // *XAA
-                0x8B => self.xaa(&opcode.mode),
-                // *TAS(XAS)
-                0x9B => self.tas(&opcode.mode),
-                // other opecode (crash)
-                _ => self.crash(),
+                self.brk();
+            }
+            // BVC (Branch if Overflow Clear)
+            0x50 => self.bvc(),
+            // BVS (Branch if Overflow Set)
+            0x70 => self.bvs(),
+            // CLC (Clear Carry Flag)
+            0x18 => self.clc(),
+            // CLD (Clear Decimal Mode)
+            0xd8 => self.cld(),
+            // CLI (Clear Interrupt Disable)
+            0x58 => self.cli(),
+            // CLV (Clear Oveflow Flag)
+            0xb8 => self.clv(),
+            // CMP (Compare)
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
+                self.cmp(&opcode.mode);
+            }
+            // CPX (Compare X Register)
+            0xE0 | 0xE4 | 0xEC => {
+                self.cpx(&opcode.mode);
             }
+            // CPY (Compare Y Register)
+            0xC0 | 0xC4 | 0xCC => {
+                self.cpy(&opcode.mode);
+            }
+            // DEC (Decrement Memory)
+            0xC6 | 0xD6 | 0xCE | 0xDE => {
+                self.dec(&opcode.mode);
+            }
+            // DEX (Decrement X Register)
+            0xCA => self.dex(),
+            // DEY (Decrement Y Register)
+            0x88 => self.dey(),
+            // EOR (Exclusive OR)
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            }
+            // INC (Increment Memory)
+            0xE6 | 0xF6 | 0xEE | 0xFE => {
+                self.inc(&opcode.mode);
+            }
+            // INX (Incremetn X Register)
+            0xE8 => self.inx(),
+            // INY (Increment Y Register)
+            0xC8 => self.iny(),
+            // JMP (Jump)
+            0x4C | 0x6C => {
+                self.jmp(&opcode.mode);
+            }
+            // JSR (Jump to Subroutine)
+            0x20 => self.jsr(&opcode.mode),
+            // LDA (Load Accumulator)
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
+                self.lda(&opcode.mode);
+            }
+            // LDX (Load X Register)
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
+                self.ldx(&opcode.mode);
+            }
+            // LDY (Load Y Register)
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
+                self.ldy(&opcode.mode);
+            }
+            // LSR (Logic Shift Right Accumulator)
+            0x4A => self.lsr_accumulator(),
+            // LSR (Logic Shift Right)
+            0x46 | 0x56 | 0x4E | 0x5E => {
+                self.lsr(&opcode.mode);
+            }
+            // NOP (No Operation)
+            0xEA => self.nop(),
+            // ORA (Logical Inclusive OR)
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            }
+            // PHA (Push Accumulator)
+            0x48 => self.pha(),
+            // PHP (Push Processor Status)
+            0x08 => self.php(),
+            // PLA (Pull Accumulator)
+            0x68 => self.pla(),
+            // PLP (Pull Processor Status)
+            0x28 => self.plp(),
+            // ROL (Rotate Left Accumulator)
+            0x2A => self.rol_accumulator(),
+            // ROL (Rotate Left)
+            0x26 | 0x36 | 0x2E | 0x3E => {
+                self.rol(&opcode.mode);
+            }
+            // ROR (Rotate Right Accumulator)
+            0x6A => self.ror_accumulator(),
+            // ROR (Rotate Right)
+            0x66 | 0x76 | 0x6E | 0x7E => {
+                self.ror(&opcode.mode);
+            }
+            // RTI (Return from Interrupt)
+            0x40 => self.rti(),
+            // RTS (Return from Subeourine)
+            0x60 => self.rts(),
+            // SBC (Sbstract with Carry)
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
+                self.sbc(&opcode.mode);
+            }
+            // SEC (Set Carry Flag)
+            0x38 => self.sec(),
+            // SED (Set Decimal Flag)
+            0xF8 => self.sed(),
+            // SEI (SetInterrupt Disable)
+            0x78 => self.sei(),
+            // STA (Store Accumulator)
+            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            }
+            // STX (Store X Register)
+            0x86 | 0x96 | 0x8E => {
+                self.stx(&opcode.mode);
+            }
+            // STY (Store Y Register)
+            0x84 | 0x94 | 0x8C => {
+                self.sty(&opcode.mode);
+            }
+            // TAX (Transfer Accumulator to X)
+            0xAA => self.tax(),
+            // TAY (Transfer Accumulator to Y)
+            0xA8 => self.tay(),
+            // TSX (Transfer Stack Pointer to X)
+            0xBA => self.tsx(),
+            // TXA (Transfer X to Accumulator)
+            0x8A => self.txa(),
+            // TXS (Transfer X to Stack Pointer)
+            0x9A => self.txs(),
+            // TYA (Transfer Y to Accumulator)
+            0x98 => self.tya(),
+
+            // illegal opcodes
+            // *ANC(AAC)
+            0x0B | 0x2B => {
+                self.anc(&opcode.mode);
+            }
+            // *SAX(AAX)
+            0x87 | 0x97 | 0x8F | 0x83 => {
+                self.sax(&opcode.mode);
+            }
+            // *ARR
+            0x6B => self.arr(&opcode.mode),
+            // *ALR
+            0x4B => self.alr(&opcode.mode),
+            // *LXA(ATX)
+            0xAB => self.lxa(&opcode.mode),
+            // *AHX(AXA)
+            0x9F | 0x93 => self.ahx(&opcode.mode),
+            // *AXS
+            0xCB => self.axs(&opcode.mode),
+            // *DCP
+            0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xD3 | 0xC3 => self.dcp(&opcode.mode),
+            // *NOP(DOP) (No Operation)
+            0x04 | 0x14 | 0x34 | 0x44 | 0x54 | 0x64 | 0x74 | 0x80 | 0x82 | 0x89 | 0xC2 | 0xD4
+            | 0xE2 | 0xF4 => {
+                // TODO: CHECK
+                self.load_operand(&opcode.mode);
+                // do nothing
+                self.nop_dop();
+            }
+            // *ISB(ISC)
+            0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => {
+                self.isb(&opcode.mode);
+            }
+            // *KIL(JAM)
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => {
+                self.halted = true;
+                self.program_counter -= 1;
+                return Err(EmuError::IllegalHalt(code, self.program_counter));
+            }
+            // *LAS(LAR)
+            0xBB => self.las(&opcode.mode),
+            // *LAX
+            0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 => {
+                self.lax(&opcode.mode);
+            }
+            // *NOP(NOP)
+            0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => {
+                self.nop();
+            }
+            // *RLA
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => {
+                self.rla(&opcode.mode);
+            }
+            // *RRA
+            0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => {
+                self.rra(&opcode.mode);
+            }
+            // *SBC
+            0xEB => self.sbc_ex(&opcode.mode),
+            // *SLO
+            0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => {
+                self.slo(&opcode.mode);
+            }
+            // *SRE
+            0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => {
+                self.sre(&opcode.mode);
+            }
+            // *SHX(SXA)
+            0x9E => self.shx(&opcode.mode),
+            // *SHY(SYA)
+            0x9C => self.shy(&opcode.mode),
+            // *NOP(TOP)
+            0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {
+                self.nop_top(&opcode.mode);
+            }
+            // *XAA
+            0x8B => self.xaa(&opcode.mode),
+            // *TAS(XAS)
+            0x9B => self.tas(&opcode.mode),
+        }
+
+        self.tick(opcode.cycles);
 
-            self.bus.tick(opcode.cycles);
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
+        }
+        Ok(true)
+    }
+}
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+impl<M: Mem + SaveState> CPU<M> {
+    // マシン全体の状態 (ROMは含まない)
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.write_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    // 同じROMで保存したものしか読めない. 失敗したら状態は中途半端になる
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(data)?;
+        self.read_state(&mut reader)?;
+        reader.finish()
+    }
+}
+
+impl<M: Mem + SaveState> SaveState for CPU<M> {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.register_a);
+        writer.write_u8(self.register_x);
+        writer.write_u8(self.register_y);
+        writer.write_u8(self.status.bits());
+        writer.write_u16(self.program_counter);
+        writer.write_u8(self.stack_pointer);
+        writer.write_bool(self.halted);
+        self.bus.write_state(writer);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.register_a = reader.read_u8()?;
+        self.register_x = reader.read_u8()?;
+        self.register_y = reader.read_u8()?;
+        self.status = CpuFlags::from_bits_truncate(reader.read_u8()?);
+        self.program_counter = reader.read_u16()?;
+        self.stack_pointer = reader.read_u8()?;
+        self.halted = reader.read_bool()?;
+        self.bus.read_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{Mirroring, Region, Rom};
+
+    const IRQ_VECTOR: u16 = 0x9000;
+    const NMI_VECTOR: u16 = 0x9100;
+
+    // MMC3 (mapper 4) 32K PRG, IRQ/BRKベクタは0x9000, NMIベクタは0x9100 (RTIだけ)
+    fn test_bus() -> Bus<'static> {
+        let mut prg_rom = vec![0xEA; 0x8000];
+        prg_rom[(NMI_VECTOR - 0x8000) as usize] = 0x40;
+        prg_rom[0x7FFA] = (NMI_VECTOR & 0xFF) as u8;
+        prg_rom[0x7FFB] = (NMI_VECTOR >> 8) as u8;
+        prg_rom[0x7FFE] = (IRQ_VECTOR & 0xFF) as u8;
+        prg_rom[0x7FFF] = (IRQ_VECTOR >> 8) as u8;
+        let rom = Rom {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            mapper: 4,
+            submapper: 0,
+            screen_mirroring: Mirroring::VERTICAL,
+            battery: false,
+            region: Region::NTSC,
+            crc32: 0,
+            sha1: [0; 20],
+        };
+        Bus::new_headless(rom).unwrap()
+    }
+
+    fn test_cpu(program: Vec<u8>) -> CPU<Bus<'static>> {
+        let mut cpu = CPU::new(test_bus());
+        cpu.load(program);
+        cpu.program_counter = 0x0600;
+        cpu
+    }
+
+    fn run_steps(cpu: &mut CPU<Bus>, steps: usize) {
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+    }
+
+    // MMC3のIRQ latchを0にしてレンダリングを有効にし, 1スキャンライン進めるとIRQが立つ
+    fn raise_irq(cpu: &mut CPU<Bus>) {
+        cpu.mem_write(0xC000, 0);
+        cpu.mem_write(0xC001, 0);
+        cpu.mem_write(0xE001, 0);
+        cpu.mem_write(0x2001, 0b0001_1000);
+        for _ in 0..3 {
+            cpu.bus.tick(38);
+        }
+        assert!(cpu.bus.poll_irq_status());
+    }
+
+    // PPUもマッパーもない64KBのただのメモリ. 命令だけをテストしたいとき用
+    struct FlatMem {
+        memory: Vec<u8>,
+        // tickで渡されたサイクル数の合計
+        cycles: usize,
+        // バスアクセスの記録 (書き込みならtrue, アドレス, 値)
+        accesses: Vec<(bool, u16, u8)>,
+    }
+
+    impl FlatMem {
+        fn new() -> Self {
+            FlatMem {
+                memory: vec![0; 0x10000],
+                cycles: 0,
+                accesses: vec![],
+            }
+        }
+    }
+
+    impl Mem for FlatMem {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            let data = self.memory[addr as usize];
+            self.accesses.push((false, addr, data));
+            data
+        }
+
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.accesses.push((true, addr, data));
+            self.memory[addr as usize] = data;
+        }
+
+        fn tick(&mut self, cycles: u8) {
+            self.cycles += cycles as usize;
+        }
+
+        fn cycles(&self) -> usize {
+            self.cycles
+        }
+    }
+
+    fn flat_cpu(program: Vec<u8>) -> CPU<FlatMem> {
+        let mut cpu = CPU::new(FlatMem::new());
+        cpu.load(program);
+        cpu.program_counter = 0x0600;
+        cpu.halt_on_brk = true;
+        cpu
+    }
+
+    // NMIを有効にしてvblankまでPPUを進める
+    fn raise_nmi(cpu: &mut CPU<Bus>) {
+        cpu.mem_write(0x2000, 0b1000_0000);
+        while cpu.bus.ppu().nmi_interrupt.is_none() {
+            cpu.bus.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_on_flat_mem() {
+        // LDA #$50; ADC #$50; BRK
+        let mut cpu = flat_cpu(vec![0xA9, 0x50, 0x69, 0x50, 0x00]);
+        assert_eq!(cpu.run(), Ok(StopReason::Brk));
+        assert_eq!(cpu.register_a, 0xA0);
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW_FLAG));
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE_FLAG));
+        assert!(!cpu.status.contains(CpuFlags::CARRY_FLAG));
+
+        // SEC; LDA #$50; SBC #$F0; STA $10; INC $10; BRK
+        let mut cpu = flat_cpu(vec![
+            0x38, 0xA9, 0x50, 0xE9, 0xF0, 0x85, 0x10, 0xE6, 0x10, 0x00,
+        ]);
+        assert_eq!(cpu.run(), Ok(StopReason::Brk));
+        assert_eq!(cpu.register_a, 0x60);
+        assert!(!cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert!(!cpu.status.contains(CpuFlags::OVERFLOW_FLAG));
+        assert_eq!(cpu.bus.memory[0x10], 0x61);
+        // 2 + 2 + 2 + 3 + 5. サイクルはすべてMem::tickを通る
+        assert_eq!(cpu.bus.cycles, 14);
+        assert_eq!(cpu.total_cycles(), 14);
+    }
+
+    #[test]
+    fn test_rmw_dummy_write() {
+        // INC $10
+        let mut cpu = flat_cpu(vec![0xE6, 0x10]);
+        cpu.bus.memory[0x10] = 0x41;
+        cpu.bus.accesses.clear();
+        cpu.step().unwrap();
+        // 読んだ値をそのまま書いてから, 増やした値を書く
+        let data_accesses: Vec<_> = cpu
+            .bus
+            .accesses
+            .iter()
+            .filter(|(_, addr, _)| *addr == 0x10)
+            .collect();
+        assert_eq!(
+            data_accesses,
+            vec![
+                &(false, 0x10, 0x41),
+                &(true, 0x10, 0x41),
+                &(true, 0x10, 0x42)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_page_cross_dummy_read() {
+        // LDX #$FF; LDA $02F0,X (-> $03EF)
+        let mut cpu = flat_cpu(vec![0xA2, 0xFF, 0xBD, 0xF0, 0x02]);
+        cpu.bus.memory[0x03EF] = 0x55;
+        cpu.step().unwrap();
+        cpu.bus.accesses.clear();
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_a, 0x55);
+        // 上位バイトを直す前の$02EFを先に読む
+        let reads: Vec<u16> = cpu.bus.accesses.iter().map(|(_, addr, _)| *addr).collect();
+        assert_eq!(&reads[reads.len() - 2..], &[0x02EF, 0x03EF]);
+    }
+
+    #[test]
+    fn test_nmi_and_rti_restore_state() {
+        // SEC; LDA #$80; NOP
+        let mut cpu = test_cpu(vec![0x38, 0xA9, 0x80, 0xEA]);
+        run_steps(&mut cpu, 2);
+        raise_nmi(&mut cpu);
+        let status = cpu.status.bits;
+        let cycles = cpu.total_cycles();
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, NMI_VECTOR);
+        assert_eq!(cpu.total_cycles(), cycles + 7);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert_eq!(cpu.mem_read(0x01FD), 0x06);
+        assert_eq!(cpu.mem_read(0x01FC), 0x03);
+
+        // RTI
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.status.bits, status);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+    }
+
+    #[test]
+    fn test_irq_jumps_to_vector() {
+        // CLI; NOP
+        let mut cpu = test_cpu(vec![0x58, 0xEA]);
+        cpu.step().unwrap();
+        raise_irq(&mut cpu);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, IRQ_VECTOR);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        // return address, then status with BREAK clear and BREAK2 set
+        assert_eq!(cpu.mem_read(0x01FD), 0x06);
+        assert_eq!(cpu.mem_read(0x01FC), 0x01);
+        assert_eq!(cpu.mem_read(0x01FB), 0b0010_0000);
+        assert_eq!(cpu.stack_pointer, STACK_RESET.wrapping_sub(3));
+    }
+
+    #[test]
+    fn test_brk() {
+        // CLI; BRK; (padding)
+        let mut cpu = test_cpu(vec![0x58, 0x00, 0xFF]);
+        cpu.step().unwrap();
+        assert!(!cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        cpu.step().unwrap();
+
+        assert_eq!(cpu.program_counter, IRQ_VECTOR);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert!(!cpu.status.contains(CpuFlags::BREAK_COMMAND));
+        // return address skips the padding byte
+        assert_eq!(cpu.mem_read(0x01FD), 0x06);
+        assert_eq!(cpu.mem_read(0x01FC), 0x03);
+        // pushed before setting I
+        assert_eq!(cpu.mem_read(0x01FB), 0b0011_0000);
+        assert_eq!(cpu.stack_pointer, STACK_RESET.wrapping_sub(3));
+    }
+
+    #[test]
+    fn test_halt_on_brk() {
+        // INX; BRK
+        let mut cpu = test_cpu(vec![0xE8, 0x00]);
+        cpu.halt_on_brk = true;
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.program_counter, 0x0602);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+    }
+
+    #[test]
+    fn test_run_with_callback_every() {
+        // NOP x 200 (400サイクル); BRK
+        let mut program = vec![0xEA; 200];
+        program.push(0x00);
+        let mut cpu = test_cpu(program);
+        cpu.halt_on_brk = true;
+        let start = cpu.total_cycles();
+        let mut calls = vec![];
+        cpu.run_with_callback_every(10, |cpu| calls.push(cpu.total_cycles() - start))
+            .unwrap();
+
+        assert_eq!(calls.len(), 40);
+        assert!(calls.iter().enumerate().all(|(i, c)| *c == (i + 1) * 10));
+    }
+
+    #[test]
+    fn test_kil_returns_error() {
+        // INX; KIL; INX
+        let mut cpu = test_cpu(vec![0xE8, 0x02, 0xE8]);
+        assert_eq!(cpu.run(), Err(EmuError::IllegalHalt(0x02, 0x0601)));
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.program_counter, 0x0601);
+        assert_eq!(
+            cpu.step().unwrap_err().to_string(),
+            "CPU jammed by opcode 02 at 0601"
+        );
+    }
+
+    #[test]
+    fn test_kil_halts_until_reset() {
+        // INX; JAM
+        let mut cpu = test_cpu(vec![0xE8, 0x12]);
+        assert!(cpu.run().is_err());
+        assert!(cpu.is_halted());
+
+        // 止まっている間はPCもサイクルも進まず, IRQも受け付けない
+        cpu.status.remove(CpuFlags::INTERRUPT_DISABLE);
+        raise_irq(&mut cpu);
+        let cycles = cpu.total_cycles();
+        for _ in 0..3 {
+            assert_eq!(cpu.step(), Err(EmuError::IllegalHalt(0x12, 0x0601)));
+        }
+        assert_eq!(cpu.program_counter, 0x0601);
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.total_cycles(), cycles);
+
+        cpu.soft_reset();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.program_counter, 0xEAEA);
+        assert_eq!(cpu.step(), Ok(None));
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        // INX; INX; INX; BRK
+        let mut cpu = test_cpu(vec![0xE8, 0xE8, 0xE8, 0x00]);
+        cpu.halt_on_brk = true;
+        cpu.add_breakpoint(0x0602);
+
+        assert_eq!(cpu.run(), Ok(StopReason::Breakpoint(0x0602)));
+        assert_eq!(cpu.program_counter, 0x0602);
+        assert_eq!(cpu.register_x, 2);
+
+        // 同じ場所から再開できる
+        assert_eq!(cpu.run(), Ok(StopReason::Brk));
+        assert_eq!(cpu.register_x, 3);
+
+        cpu.remove_breakpoint(0x0602);
+        cpu.program_counter = 0x0600;
+        assert_eq!(cpu.run(), Ok(StopReason::Brk));
+        assert_eq!(cpu.register_x, 6);
+    }
+
+    #[test]
+    fn test_step_ignores_breakpoint() {
+        // INX; INX
+        let mut cpu = test_cpu(vec![0xE8, 0xE8]);
+        cpu.add_breakpoint(0x0600);
+        assert_eq!(cpu.step(), Ok(None));
+        assert_eq!(cpu.program_counter, 0x0601);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // LDA #$05; STA $10; LDX $10; BRK
+        let mut cpu = test_cpu(vec![0xA9, 0x05, 0x85, 0x10, 0xA6, 0x10, 0x00]);
+        cpu.halt_on_brk = true;
+        cpu.add_write_watchpoint(0x10);
+        cpu.add_read_watchpoint(0x10);
+
+        // 書き込んだ命令の直後で止まる
+        assert_eq!(cpu.run(), Ok(StopReason::WriteWatchpoint(0x10)));
+        assert_eq!(cpu.program_counter, 0x0604);
+        assert_eq!(cpu.mem_read(0x10), 0x05);
+
+        assert_eq!(cpu.run(), Ok(StopReason::ReadWatchpoint(0x10)));
+        assert_eq!(cpu.program_counter, 0x0606);
+        assert_eq!(cpu.register_x, 0x05);
+
+        cpu.remove_watchpoint(0x10);
+        assert_eq!(cpu.run(), Ok(StopReason::Brk));
+    }
+
+    fn assert_zero_negative<M>(cpu: &CPU<M>, zero: bool, negative: bool) {
+        assert_eq!(cpu.status.contains(CpuFlags::ZERO_FLAG), zero);
+        assert_eq!(cpu.status.contains(CpuFlags::NEGATIVE_FLAG), negative);
+    }
+
+    #[test]
+    fn test_load_immediate_flags() {
+        // LDA #$00; LDX #$80; LDY #$00; LDA #$80
+        let mut cpu = test_cpu(vec![0xA9, 0x00, 0xA2, 0x80, 0xA0, 0x00, 0xA9, 0x80]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_a, 0x00);
+        assert_zero_negative(&cpu, true, false);
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_x, 0x80);
+        assert_zero_negative(&cpu, false, true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_y, 0x00);
+        assert_zero_negative(&cpu, true, false);
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert_zero_negative(&cpu, false, true);
+    }
+
+    #[test]
+    fn test_load_absolute_x_page_cross_flags() {
+        // LDX #$01; LDA $06FF,X; LDY $06FF,X; LDA $0700,X
+        let mut cpu = test_cpu(vec![
+            0xA2, 0x01, 0xBD, 0xFF, 0x06, 0xBC, 0xFF, 0x06, 0xBD, 0x00, 0x07,
+        ]);
+        cpu.mem_write(0x0700, 0x80);
+        cpu.mem_write(0x0701, 0x00);
+
+        run_steps(&mut cpu, 2);
+        assert_eq!(cpu.register_a, 0x80);
+        assert_zero_negative(&cpu, false, true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_y, 0x80);
+        assert_zero_negative(&cpu, false, true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_a, 0x00);
+        assert_zero_negative(&cpu, true, false);
+    }
+
+    #[test]
+    fn test_rotate_memory_to_zero_sets_zero_flag() {
+        // LDA #$80; STA $10; CLC; ROL $10
+        let mut cpu = test_cpu(vec![0xA9, 0x80, 0x85, 0x10, 0x18, 0x26, 0x10]);
+        run_steps(&mut cpu, 4);
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert_zero_negative(&cpu, true, false);
+
+        // LDA #$01; STA $10; CLC; ROR $10
+        let mut cpu = test_cpu(vec![0xA9, 0x01, 0x85, 0x10, 0x18, 0x66, 0x10]);
+        run_steps(&mut cpu, 4);
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert_zero_negative(&cpu, true, false);
+
+        // 0でなくなれば消える: SEC; ROR $10 -> 0x80
+        let mut cpu = test_cpu(vec![0xA9, 0x00, 0x85, 0x10, 0x38, 0x66, 0x10]);
+        run_steps(&mut cpu, 4);
+        assert_eq!(cpu.mem_read(0x10), 0x80);
+        assert_zero_negative(&cpu, false, true);
+    }
+
+    #[test]
+    fn test_rla_rra_flags_come_from_accumulator() {
+        // LDA #$80; STA $10; LDA #$FF; CLC; RLA $10 -> $10 = 0x00, A = 0x00
+        let mut cpu = test_cpu(vec![0xA9, 0x80, 0x85, 0x10, 0xA9, 0xFF, 0x18, 0x27, 0x10]);
+        run_steps(&mut cpu, 5);
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert_eq!(cpu.register_a, 0x00);
+        assert_zero_negative(&cpu, true, false);
+
+        // LDA #$01; STA $10; LDA #$FF; CLC; RRA $10 -> $10 = 0x00, A = 0xFF + 0x00 + 1
+        let mut cpu = test_cpu(vec![0xA9, 0x01, 0x85, 0x10, 0xA9, 0xFF, 0x18, 0x67, 0x10]);
+        run_steps(&mut cpu, 5);
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert_zero_negative(&cpu, true, false);
+    }
+
+    #[test]
+    fn test_lsr_to_zero() {
+        // LDA #$FF (Nを立てておく); LDX #$01; STX $10; LSR $10
+        let mut cpu = test_cpu(vec![0xA9, 0xFF, 0xA2, 0x01, 0x86, 0x10, 0x46, 0x10]);
+        run_steps(&mut cpu, 4);
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert_zero_negative(&cpu, true, false);
+
+        // LDA #$FF; LSR A -> 0x7F
+        let mut cpu = test_cpu(vec![0xA9, 0xFF, 0x4A]);
+        run_steps(&mut cpu, 2);
+        assert_eq!(cpu.register_a, 0x7F);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert_zero_negative(&cpu, false, false);
+    }
+
+    #[test]
+    fn test_asl_of_0x80() {
+        // LDA #$80; STA $10; CLC; ASL $10
+        let mut cpu = test_cpu(vec![0xA9, 0x80, 0x85, 0x10, 0x18, 0x06, 0x10]);
+        run_steps(&mut cpu, 4);
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert_zero_negative(&cpu, true, false);
+
+        // LDA #$80; ASL A
+        let mut cpu = test_cpu(vec![0xA9, 0x80, 0x0A]);
+        run_steps(&mut cpu, 2);
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert_zero_negative(&cpu, true, false);
+    }
+
+    #[test]
+    fn test_slo_sre_flags() {
+        // LDA #$80; STA $10; LDA #$81; SLO $10 -> $10 = 0x00, A = 0x81 | 0x00
+        let mut cpu = test_cpu(vec![0xA9, 0x80, 0x85, 0x10, 0xA9, 0x81, 0x07, 0x10]);
+        run_steps(&mut cpu, 4);
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert_eq!(cpu.register_a, 0x81);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert_zero_negative(&cpu, false, true);
+
+        // LDA #$03; STA $10; LDA #$01; SRE $10 -> $10 = 0x01, A = 0x01 ^ 0x01
+        let mut cpu = test_cpu(vec![0xA9, 0x03, 0x85, 0x10, 0xA9, 0x01, 0x47, 0x10]);
+        run_steps(&mut cpu, 4);
+        assert_eq!(cpu.mem_read(0x10), 0x01);
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert_zero_negative(&cpu, true, false);
+    }
+
+    #[test]
+    fn test_dcp_matches_cmp() {
+        // (A, メモリ, 結果のメモリ, C, Z, N)
+        let cases = [
+            // A < M-1: 以前はキャリーが立ったまま残っていた
+            (0x10, 0x12, 0x11, false, false, true),
+            (0x10, 0x11, 0x10, true, true, false),
+            (0x20, 0x11, 0x10, true, false, false),
+            (0x00, 0x02, 0x01, false, false, true),
+            // 0x00 - 1 = 0xFF
+            (0x80, 0x00, 0xFF, false, false, true),
+        ];
+        for (a, m, res, carry, zero, negative) in cases {
+            // SEC; LDA #m; STA $10; LDA #a; DCP $10
+            let mut cpu = test_cpu(vec![0x38, 0xA9, m, 0x85, 0x10, 0xA9, a, 0xC7, 0x10]);
+            run_steps(&mut cpu, 5);
+            assert_eq!(cpu.mem_read(0x10), res);
+            assert_eq!(cpu.register_a, a);
+            assert_eq!(cpu.status.contains(CpuFlags::CARRY_FLAG), carry);
+            assert_zero_negative(&cpu, zero, negative);
+        }
+    }
+
+    #[test]
+    fn test_xaa() {
+        // LDA #$01; LDX #$FF; XAA #$FF -> (0x01 | 0xEE) & 0xFF & 0xFF
+        let mut cpu = test_cpu(vec![0xA9, 0x01, 0xA2, 0xFF, 0x8B, 0xFF]);
+        run_steps(&mut cpu, 3);
+        assert_eq!(cpu.register_a, 0xEF);
+        assert_eq!(cpu.register_x, 0xFF);
+        assert_zero_negative(&cpu, false, true);
+
+        // LDA #$00; LDX #$13; XAA #$0F -> 0xEE & 0x13 & 0x0F
+        let mut cpu = test_cpu(vec![0xA9, 0x00, 0xA2, 0x13, 0x8B, 0x0F]);
+        run_steps(&mut cpu, 3);
+        assert_eq!(cpu.register_a, 0x02);
+        assert_zero_negative(&cpu, false, false);
+
+        // LDA #$00; LDX #$11; XAA #$FF -> 0xEE & 0x11 = 0
+        let mut cpu = test_cpu(vec![0xA9, 0x00, 0xA2, 0x11, 0x8B, 0xFF]);
+        run_steps(&mut cpu, 3);
+        assert_eq!(cpu.register_a, 0x00);
+        assert_zero_negative(&cpu, true, false);
+    }
+
+    #[test]
+    fn test_shx_shy() {
+        // LDX #$05; LDY #$01; SHX $0200,Y -> $0201 = 0x05 & (0x02 + 1)
+        let mut cpu = test_cpu(vec![0xA2, 0x05, 0xA0, 0x01, 0x9E, 0x00, 0x02]);
+        run_steps(&mut cpu, 3);
+        assert_eq!(cpu.mem_read(0x0201), 0x01);
+
+        // LDX #$05; LDY #$10; SHX $02F8,Y -> 0x0308だが上位バイトが0x01に化ける
+        let mut cpu = test_cpu(vec![0xA2, 0x05, 0xA0, 0x10, 0x9E, 0xF8, 0x02]);
+        run_steps(&mut cpu, 3);
+        assert_eq!(cpu.mem_read(0x0108), 0x01);
+        assert_eq!(cpu.mem_read(0x0308), 0x00);
+
+        // LDY #$07; LDX #$01; SHY $0400,X -> $0401 = 0x07 & 0x05
+        let mut cpu = test_cpu(vec![0xA0, 0x07, 0xA2, 0x01, 0x9C, 0x00, 0x04]);
+        run_steps(&mut cpu, 3);
+        assert_eq!(cpu.mem_read(0x0401), 0x05);
+
+        // LDY #$03; LDX #$20; SHY $04F0,X -> 0x0510, 値は 0x03 & 0x05 = 0x01 なので $0110 に書かれる
+        let mut cpu = test_cpu(vec![0xA0, 0x03, 0xA2, 0x20, 0x9C, 0xF0, 0x04]);
+        run_steps(&mut cpu, 3);
+        assert_eq!(cpu.mem_read(0x0110), 0x01);
+        assert_eq!(cpu.mem_read(0x0510), 0x00);
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        fn step_cycles(cpu: &mut CPU<Bus>) -> usize {
+            let before = cpu.total_cycles();
+            cpu.step().unwrap();
+            cpu.total_cycles() - before
+        }
+
+        // LDA #$00; BNE +2 (分岐しない)
+        let mut cpu = test_cpu(vec![0xA9, 0x00, 0xD0, 0x02]);
+        cpu.step().unwrap();
+        assert_eq!(step_cycles(&mut cpu), 2);
+        assert_eq!(cpu.program_counter, 0x0604);
+
+        // LDA #$01; BNE +2 (同じページ)
+        let mut cpu = test_cpu(vec![0xA9, 0x01, 0xD0, 0x02]);
+        cpu.step().unwrap();
+        assert_eq!(step_cycles(&mut cpu), 3);
+        assert_eq!(cpu.program_counter, 0x0606);
+
+        // $06FD: BNE +$10 -> 次の命令は$06FF, 分岐先は$070F
+        let mut cpu = test_cpu(vec![0xA9, 0x01]);
+        cpu.step().unwrap();
+        cpu.mem_write(0x06FD, 0xD0);
+        cpu.mem_write(0x06FE, 0x10);
+        cpu.program_counter = 0x06FD;
+        assert_eq!(step_cycles(&mut cpu), 4);
+        assert_eq!(cpu.program_counter, 0x070F);
+
+        // $06FE: BNE +$00 -> 次の命令は$0700. 分岐命令自体とはページが違うが, 分岐先とは同じ
+        let mut cpu = test_cpu(vec![0xA9, 0x01]);
+        cpu.step().unwrap();
+        cpu.mem_write(0x06FE, 0xD0);
+        cpu.mem_write(0x06FF, 0x00);
+        cpu.program_counter = 0x06FE;
+        assert_eq!(step_cycles(&mut cpu), 3);
+        assert_eq!(cpu.program_counter, 0x0700);
+
+        // LDA #$01; BNE -8 -> $0604 - 8 = $05FC (後ろのページ)
+        let mut cpu = test_cpu(vec![0xA9, 0x01, 0xD0, 0xF8]);
+        cpu.step().unwrap();
+        assert_eq!(step_cycles(&mut cpu), 4);
+        assert_eq!(cpu.program_counter, 0x05FC);
+    }
+
+    #[test]
+    fn test_profiler() {
+        // LDX #$00; loop: INX; BNE loop; BRK
+        let mut cpu = test_cpu(vec![0xA2, 0x00, 0xE8, 0xD0, 0xFD, 0x00]);
+        cpu.halt_on_brk = true;
+        assert!(cpu.profile_report().is_empty());
+        cpu.enable_profiling(true);
+        cpu.run().unwrap();
+
+        let report = cpu.profile_report();
+        assert_eq!(report[0], (0x0602, 256));
+        assert_eq!(report[1], (0x0603, 256));
+        assert_eq!(report[2].1, 1);
+
+        let mnemonics = cpu.profile_mnemonics();
+        // BNE: 255回分岐(3cycle) + 最後は分岐しない(2cycle)
+        assert_eq!(mnemonics[0], ("BNE", 256, 255 * 3 + 2));
+        assert_eq!(mnemonics[1], ("INX", 256, 256 * 2));
+
+        cpu.enable_profiling(false);
+        assert!(cpu.profile_report().is_empty());
+    }
+
+    #[test]
+    fn test_total_cycles() {
+        // LDA #$01; STA $10; LDX $10; INX; LDA $06FF,X (page cross)
+        let mut cpu = test_cpu(vec![
+            0xA9, 0x01, 0x85, 0x10, 0xA6, 0x10, 0xE8, 0xBD, 0xFF, 0x06,
+        ]);
+        run_steps(&mut cpu, 5);
+        assert_eq!(cpu.total_cycles(), 2 + 3 + 3 + 2 + 5);
+    }
+
+    #[test]
+    fn test_total_cycles_after_reset() {
+        let mut cpu = CPU::new(test_bus());
+        cpu.reset();
+        assert_eq!(cpu.total_cycles(), 7);
+    }
+
+    #[test]
+    fn test_soft_reset_keeps_ram() {
+        // LDA #$42; STA $10; LDX #$07; CLI
+        let mut cpu = test_cpu(vec![0xA9, 0x42, 0x85, 0x10, 0xA2, 0x07, 0x58]);
+        run_steps(&mut cpu, 4);
+        // $2006の上位バイトだけ書いた状態
+        cpu.mem_write(0x2006, 0x3F);
+        let sp = cpu.stack_pointer;
+
+        cpu.soft_reset();
+        // リセットベクタは0xEAEA (test_busのPRGはNOPで埋めてある)
+        assert_eq!(cpu.program_counter, 0xEAEA);
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.register_x, 0x07);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert_eq!(cpu.stack_pointer, sp.wrapping_sub(3));
+
+        // アドレスラッチが戻っているので上位バイトから書ける
+        cpu.mem_write(0x2006, 0x23);
+        cpu.mem_write(0x2006, 0x45);
+        assert_eq!(cpu.bus.ppu().loopy.v, 0x2345);
+    }
+
+    #[test]
+    fn test_total_cycles_counts_oam_dma_stall() {
+        // STA $4014
+        let mut cpu = test_cpu(vec![0x8D, 0x14, 0x40]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.total_cycles(), 513 + 4);
+    }
+
+    #[test]
+    fn test_dmc_dma_stall_cycles() {
+        // LDA #$10; STA $4015 (DMCを有効にするとすぐサンプルを読みに行く)
+        // STAの書き込みと重なるので3サイクル
+        let mut cpu = test_cpu(vec![0xA9, 0x10, 0x8D, 0x15, 0x40]);
+        run_steps(&mut cpu, 2);
+        assert_eq!(cpu.total_cycles(), 2 + 4 + 3);
+
+        // 読み込み(NOP)と重なると4サイクル
+        let mut cpu = test_cpu(vec![0xEA]);
+        cpu.bus.mem_write(0x4015, 0x10);
+        cpu.step().unwrap();
+        assert_eq!(cpu.total_cycles(), 2 + 4);
+
+        // OAM DMA中なら2サイクル
+        let mut cpu = test_cpu(vec![0x8D, 0x14, 0x40]);
+        cpu.bus.mem_write(0x4015, 0x10);
+        cpu.step().unwrap();
+        assert_eq!(cpu.total_cycles(), 4 + 513 + 2);
+    }
+
+    #[test]
+    fn test_axs() {
+        // LDA #$F0; LDX #$3C; AXS #$10
+        let mut cpu = test_cpu(vec![0xA9, 0xF0, 0xA2, 0x3C, 0xCB, 0x10]);
+        run_steps(&mut cpu, 3);
+        assert_eq!(cpu.register_x, 0x20);
+        assert_eq!(cpu.register_a, 0xF0);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert!(!cpu.status.contains(CpuFlags::ZERO_FLAG));
+        assert!(!cpu.status.contains(CpuFlags::NEGATIVE_FLAG));
+
+        // SEC; LDA #$F0; LDX #$3C; AXS #$40 (0x30 - 0x40 borrows)
+        let mut cpu = test_cpu(vec![0x38, 0xA9, 0xF0, 0xA2, 0x3C, 0xCB, 0x40]);
+        run_steps(&mut cpu, 4);
+        assert_eq!(cpu.register_x, 0xF0);
+        assert!(!cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE_FLAG));
+
+        // LDA #$FF; LDX #$30; AXS #$30
+        let mut cpu = test_cpu(vec![0xA9, 0xFF, 0xA2, 0x30, 0xCB, 0x30]);
+        run_steps(&mut cpu, 3);
+        assert_eq!(cpu.register_x, 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert!(cpu.status.contains(CpuFlags::ZERO_FLAG));
+    }
+
+    // CLC/SEC; LDA #a; SBC #value (0xEBなら*SBC)
+    fn run_sbc(carry: bool, a: u8, opcode: u8, value: u8) -> CPU<Bus<'static>> {
+        let set_carry = if carry { 0x38 } else { 0x18 };
+        let mut cpu = test_cpu(vec![set_carry, 0xA9, a, opcode, value]);
+        run_steps(&mut cpu, 3);
+        cpu
+    }
+
+    #[test]
+    fn test_sbc_boundaries() {
+        // (キャリー, A, M) -> (A, キャリー, オーバーフロー)
+        for (carry, a, value, result, carry_out, overflow) in [
+            // 0x00 - 0x01: 借りが出てキャリーが消える
+            (true, 0x00, 0x01, 0xFF, false, false),
+            (false, 0x00, 0x01, 0xFE, false, false),
+            (true, 0x00, 0x00, 0x00, true, false),
+            (false, 0x00, 0x00, 0xFF, false, false),
+            // -128 - 1 = +127 (符号付きで溢れる)
+            (true, 0x80, 0x01, 0x7F, true, true),
+            // 127 - (-1) = -128
+            (true, 0x7F, 0xFF, 0x80, false, true),
+            (false, 0xFF, 0xFF, 0xFF, false, false),
+        ] {
+            for opcode in [0xE9, 0xEB] {
+                let cpu = run_sbc(carry, a, opcode, value);
+                let case = (carry, a, value, opcode);
+                assert_eq!(cpu.register_a, result, "{:?}", case);
+                assert_eq!(
+                    cpu.status.contains(CpuFlags::CARRY_FLAG),
+                    carry_out,
+                    "{:?}",
+                    case
+                );
+                assert_eq!(
+                    cpu.status.contains(CpuFlags::OVERFLOW_FLAG),
+                    overflow,
+                    "{:?}",
+                    case
+                );
+                assert_eq!(cpu.status.contains(CpuFlags::ZERO_FLAG), result == 0);
+                assert_eq!(cpu.status.contains(CpuFlags::NEGATIVE_FLAG), result >= 0x80);
             }
         }
     }
+
+    #[test]
+    fn test_arr() {
+        // (キャリー, A, M) -> (A, キャリー, オーバーフロー)
+        for (carry, a, value, result, carry_out, overflow) in [
+            // bit6とbit5が両方1: Cだけ
+            (false, 0xFF, 0xFF, 0x7F, true, false),
+            (true, 0xFF, 0xFF, 0xFF, true, false),
+            // bit5だけ: Vだけ
+            (false, 0xFF, 0x40, 0x20, false, true),
+            // bit6だけ: CとV
+            (false, 0xFF, 0x80, 0x40, true, true),
+            // ANDの結果のbit0はキャリーに入らない
+            (false, 0x03, 0x01, 0x00, false, false),
+            (true, 0x00, 0x00, 0x80, false, false),
+        ] {
+            // CLC/SEC; LDA #a; ARR #value
+            let set_carry = if carry { 0x38 } else { 0x18 };
+            let mut cpu = test_cpu(vec![set_carry, 0xA9, a, 0x6B, value]);
+            run_steps(&mut cpu, 3);
+            let case = (carry, a, value);
+            assert_eq!(cpu.register_a, result, "{:?}", case);
+            assert_eq!(
+                cpu.status.contains(CpuFlags::CARRY_FLAG),
+                carry_out,
+                "{:?}",
+                case
+            );
+            assert_eq!(
+                cpu.status.contains(CpuFlags::OVERFLOW_FLAG),
+                overflow,
+                "{:?}",
+                case
+            );
+            assert_eq!(cpu.status.contains(CpuFlags::ZERO_FLAG), result == 0);
+            assert_eq!(cpu.status.contains(CpuFlags::NEGATIVE_FLAG), result >= 0x80);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "decimal_mode"))]
+    fn test_nes_ignores_decimal_flag() {
+        // SED; CLC; LDA #$09; ADC #$01
+        let mut cpu = test_cpu(vec![0xF8, 0x18, 0xA9, 0x09, 0x69, 0x01]);
+        run_steps(&mut cpu, 4);
+        assert_eq!(cpu.register_a, 0x0A);
+    }
+
+    #[cfg(feature = "decimal_mode")]
+    fn run_decimal(carry: bool, a: u8, opcode: u8, value: u8) -> CPU<Bus<'static>> {
+        // SED; CLC/SEC; LDA #a; ADC/SBC #value
+        let set_carry = if carry { 0x38 } else { 0x18 };
+        let mut cpu = test_cpu(vec![0xF8, set_carry, 0xA9, a, opcode, value]);
+        run_steps(&mut cpu, 4);
+        cpu
+    }
+
+    #[test]
+    #[cfg(feature = "decimal_mode")]
+    fn test_adc_decimal() {
+        let cpu = run_decimal(false, 0x12, 0x69, 0x34);
+        assert_eq!(cpu.register_a, 0x46);
+        assert!(!cpu.status.contains(CpuFlags::CARRY_FLAG));
+
+        // 58 + 46 + 1 = 105
+        let cpu = run_decimal(true, 0x58, 0x69, 0x46);
+        assert_eq!(cpu.register_a, 0x05);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+
+        // 09 + 01 = 10 (下の桁の繰り上がり)
+        let cpu = run_decimal(false, 0x09, 0x69, 0x01);
+        assert_eq!(cpu.register_a, 0x10);
+        assert!(!cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert!(!cpu.status.contains(CpuFlags::ZERO_FLAG));
+
+        // 50 + 50 = 100: Nは補正前の0xA0から, Vは符号付きのオーバーフロー
+        let cpu = run_decimal(false, 0x50, 0x69, 0x50);
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE_FLAG));
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW_FLAG));
+        // Zはバイナリの結果(0xA0)
+        assert!(!cpu.status.contains(CpuFlags::ZERO_FLAG));
+    }
+
+    #[test]
+    #[cfg(feature = "decimal_mode")]
+    fn test_sbc_decimal() {
+        // 46 - 12 = 34
+        let cpu = run_decimal(true, 0x46, 0xE9, 0x12);
+        assert_eq!(cpu.register_a, 0x34);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+
+        // 40 - 13 - 1 = 26
+        let cpu = run_decimal(false, 0x40, 0xE9, 0x13);
+        assert_eq!(cpu.register_a, 0x26);
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+
+        // 12 - 21 = -9 -> 91, 借りたのでCはクリア
+        let cpu = run_decimal(true, 0x12, 0xE9, 0x21);
+        assert_eq!(cpu.register_a, 0x91);
+        assert!(!cpu.status.contains(CpuFlags::CARRY_FLAG));
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE_FLAG));
+
+        // 21 - 21 = 0
+        let cpu = run_decimal(true, 0x21, 0xE9, 0x21);
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::ZERO_FLAG));
+        assert!(cpu.status.contains(CpuFlags::CARRY_FLAG));
+    }
+
+    #[test]
+    fn test_irq_is_masked_by_interrupt_disable() {
+        // SEI; NOP; CLI
+        let mut cpu = test_cpu(vec![0x78, 0xEA, 0x58]);
+        raise_irq(&mut cpu);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0601);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0602);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0603);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, IRQ_VECTOR);
+    }
 }
@@ -0,0 +1,122 @@
+use crate::bus::Bus;
+use crate::opcodes;
+use crate::symbols::SymbolTable;
+use crate::trace::format_instruction;
+
+// start..=end を命令ごとに逆アセンブルする (実行はしない)
+// Bus::peekで読むので, $2002や$4016などを読んでもレジスタの状態は変わらない
+// symbolsがあればオペランドのアドレスをラベル名にする
+// 知らないオペコードは ".byte $XX" にして1バイト進める (今は非公式命令も含め256個全部OPCODES_MAPにある)
+pub fn disassemble(
+    bus: &Bus,
+    start: u16,
+    end: u16,
+    symbols: Option<&SymbolTable>,
+) -> Vec<(u16, String)> {
+    let mut result = vec![];
+    // endが0xFFFFのときに溢れないようにu32で回す
+    let mut addr = start as u32;
+    while addr <= end as u32 {
+        let pc = addr as u16;
+        let code = bus.peek(pc);
+        match opcodes::OPCODES_MAP.get(&code) {
+            Some(ops) => {
+                let operand: Vec<u8> = (1..ops.len as u16)
+                    .map(|i| bus.peek(pc.wrapping_add(i)))
+                    .collect();
+                let text = format_instruction(ops, pc, &operand, None, symbols);
+                result.push((pc, text.trim().to_string()));
+                addr += ops.len as u32;
+            }
+            None => {
+                result.push((pc, format!(".byte ${:02X}", code)));
+                addr += 1;
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_nrom;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_disassemble() {
+        let mut bus = Bus::new_headless(test_nrom()).unwrap();
+        let program = [
+            0xA9, 0x01, // LDA #$01
+            0x9D, 0x00, 0x02, // STA $0200,X
+            0xB1, 0x10, // LDA ($10),Y
+            0xD0, 0xF9, // BNE $0002
+            0x0A, // ASL A
+            0x02, // KIL (非公式. 実行するとCPUが止まる)
+            0x6C, 0x34, 0x12, // JMP ($1234)
+        ];
+        for (i, data) in program.iter().enumerate() {
+            bus.mem_write(i as u16, *data);
+        }
+
+        let result = disassemble(&bus, 0x0000, program.len() as u16 - 1, None);
+        assert_eq!(
+            result,
+            vec![
+                (0x0000, String::from("LDA #$01")),
+                (0x0002, String::from("STA $0200,X")),
+                (0x0005, String::from("LDA ($10),Y")),
+                (0x0007, String::from("BNE $0002")),
+                (0x0009, String::from("ASL A")),
+                (0x000A, String::from("*KIL")),
+                (0x000B, String::from("JMP ($1234)")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_with_symbols() {
+        let mut bus = Bus::new_headless(test_nrom()).unwrap();
+        let program = [
+            0x20, 0xF5, 0xC5, // JSR $C5F5
+            0x8D, 0x00, 0x03, // STA $0300
+            0xA5, 0x10, // LDA $10
+        ];
+        for (i, data) in program.iter().enumerate() {
+            bus.mem_write(i as u16, *data);
+        }
+        let symbols = SymbolTable::parse("C5F5=ResetHandler\nR:0010:pointer\n").unwrap();
+
+        // ラベルのない$0300はそのまま
+        let result = disassemble(&bus, 0x0000, program.len() as u16 - 1, Some(&symbols));
+        assert_eq!(
+            result,
+            vec![
+                (0x0000, String::from("JSR ResetHandler")),
+                (0x0003, String::from("STA $0300")),
+                (0x0006, String::from("LDA pointer")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_without_side_effects() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut bus = Bus::new_headless(test_nrom()).unwrap();
+        let accesses = Rc::new(Cell::new(0));
+        let counter = accesses.clone();
+        bus.on_access(move |_, _, _| counter.set(counter.get() + 1));
+        // VBlankのフラグを立てる
+        while bus.ppu().peek_status() & 0b1000_0000 == 0 {
+            bus.tick(1);
+        }
+
+        // PPUとAPU/joypadのレジスタを含めて読む
+        disassemble(&bus, 0x2000, 0x2007, None);
+        disassemble(&bus, 0x4015, 0x4017, None);
+        assert_eq!(accesses.get(), 0);
+        assert_ne!(bus.ppu().peek_status() & 0b1000_0000, 0);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::render::frame::Frame;
+use sdl2::render::{Canvas, TextureCreator};
+use sdl2::video::{FullscreenType, Window, WindowContext};
+use sdl2::VideoSubsystem;
+
+// F7で切り替えるウィンドウの倍率
+pub const SCALES: [u32; 3] = [2, 3, 4];
+
+// 2x -> 3x -> 4x -> 2x. --scaleで候補にない倍率にしていたら, その次に大きい候補へ
+pub fn next_scale(scale: u32) -> u32 {
+    SCALES
+        .iter()
+        .copied()
+        .find(|s| *s > scale)
+        .unwrap_or(SCALES[0])
+}
+
+// ウィンドウと描画先
+// 描画は常にFrameの大きさ(256x240)の座標でやり, 倍率とフルスクリーンは見せ方だけを変える
+// フルスクリーンでは画面に入る最大の整数倍で真ん中に出す
+pub struct Display {
+    canvas: Canvas<Window>,
+    scale: u32,
+    fullscreen: bool,
+}
+
+impl Display {
+    pub fn new(
+        video: &VideoSubsystem,
+        title: &str,
+        scale: u32,
+        vsync: bool,
+    ) -> Result<Display, String> {
+        let window = video
+            .window(
+                title,
+                Frame::WIDTH as u32 * scale,
+                Frame::HEIGHT as u32 * scale,
+            )
+            .position_centered()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let builder = window.into_canvas();
+        let builder = if vsync {
+            builder.present_vsync()
+        } else {
+            builder
+        };
+        let mut canvas = builder.build().map_err(|e| e.to_string())?;
+        canvas
+            .set_logical_size(Frame::WIDTH as u32, Frame::HEIGHT as u32)
+            .map_err(|e| e.to_string())?;
+        canvas.set_integer_scale(true)?;
+        Ok(Display {
+            canvas,
+            scale,
+            fullscreen: false,
+        })
+    }
+
+    pub fn canvas(&mut self) -> &mut Canvas<Window> {
+        &mut self.canvas
+    }
+
+    pub fn texture_creator(&self) -> TextureCreator<WindowContext> {
+        self.canvas.texture_creator()
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    // フルスクリーン中は倍率だけ覚えておき, ウィンドウに戻したときに使う
+    pub fn cycle_scale(&mut self) -> Result<(), String> {
+        self.scale = next_scale(self.scale);
+        if !self.fullscreen {
+            self.resize_window()?;
+        }
+        Ok(())
+    }
+
+    // ボーダーレス (デスクトップの解像度のまま) のフルスクリーン
+    pub fn toggle_fullscreen(&mut self) -> Result<(), String> {
+        let fullscreen = !self.fullscreen;
+        let mode = if fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
+        };
+        self.canvas.window_mut().set_fullscreen(mode)?;
+        self.fullscreen = fullscreen;
+        if !fullscreen {
+            self.resize_window()?;
+        }
+        Ok(())
+    }
+
+    fn resize_window(&mut self) -> Result<(), String> {
+        self.canvas
+            .window_mut()
+            .set_size(
+                Frame::WIDTH as u32 * self.scale,
+                Frame::HEIGHT as u32 * self.scale,
+            )
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_next_scale() {
+        assert_eq!(next_scale(2), 3);
+        assert_eq!(next_scale(3), 4);
+        assert_eq!(next_scale(4), 2);
+        // --scaleで候補にない倍率
+        assert_eq!(next_scale(1), 2);
+        assert_eq!(next_scale(5), 2);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::bus::{Bus, RamInit};
+use crate::cartridge::Rom;
+use crate::cpu::{EmuError, CPU};
+use crate::joypad::{JoyPad, JoyPadButton};
+use crate::ppu::NesPPU;
+use crate::render;
+use crate::render::frame::Frame;
+use crate::render::palette::Palette;
+use crate::render::RenderOptions;
+use std::cell::Cell;
+use std::rc::Rc;
+
+// ライブラリとして組み込むとき(GUI/WASM/テスト)用. SDLはいらない
+// Rom -> Bus -> CPU の組み立てと寿命の面倒を全部ここで見る
+pub struct Emulator {
+    cpu: CPU<Bus<'static>>,
+    frame: Frame,
+    palette: Palette,
+    render_options: RenderOptions,
+    // Busのcallbackが1フレーム終わるたびに立てる
+    frame_done: Rc<Cell<bool>>,
+}
+
+impl Emulator {
+    // .nesファイルの中身から起動する (リセット済み)
+    pub fn from_bytes(data: &[u8]) -> Result<Emulator, String> {
+        let rom = Rom::new(&data.to_vec())?;
+        Emulator::new(rom, RamInit::Zero)
+    }
+
+    // 対応していないマッパーのROMはErr
+    pub fn new(rom: Rom, ram_init: RamInit) -> Result<Emulator, String> {
+        let frame_done = Rc::new(Cell::new(false));
+        let frame_end = frame_done.clone();
+        let bus = Bus::new_with_ram_init(
+            rom,
+            ram_init,
+            move |_: &NesPPU, _: &mut JoyPad, _: &mut JoyPad| {
+                frame_end.set(true);
+            },
+        )?;
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        Ok(Emulator {
+            cpu,
+            frame: Frame::new(),
+            palette: Palette::default(),
+            render_options: RenderOptions::default(),
+            frame_done,
+        })
+    }
+
+    // PPUが1フレーム描き終わるまでCPUを進めて, 画面をframe_bufferに描く
+    // CPUが止まったら(KILなど)エラー. 画面はそのまま
+    pub fn step_frame(&mut self) -> Result<(), EmuError> {
+        self.step_frame_with_callback(|_| {})
+    }
+
+    // step_frameと同じ. 命令を実行する前に毎回callbackを呼ぶ (トレース用)
+    pub fn step_frame_with_callback<F>(&mut self, mut callback: F) -> Result<(), EmuError>
+    where
+        F: FnMut(&mut CPU<Bus<'static>>),
+    {
+        self.frame_done.set(false);
+        while !self.frame_done.get() {
+            callback(&mut self.cpu);
+            self.cpu.step()?;
+        }
+        render::render_with_options(
+            self.cpu.bus.ppu(),
+            &mut self.frame,
+            &self.palette,
+            &self.render_options,
+        );
+        Ok(())
+    }
+
+    // PPUがvblankフラグを立てる(scanline 241に入る)まで進めて, 使ったCPUサイクル数を返す
+    // NMIはまだ処理していない (フラグが立った命令の直後で止まる)
+    // レンダリングが無効でもvblankは毎フレーム来る
+    pub fn run_until_vblank(&mut self) -> Result<usize, EmuError> {
+        let start = self.cpu.total_cycles();
+        // 今vblank中なら, 一度抜けてから次に入るまで
+        let mut in_vblank = self.in_vblank();
+        loop {
+            self.cpu.step()?;
+            let now = self.in_vblank();
+            if now && !in_vblank {
+                break;
+            }
+            in_vblank = now;
+        }
+        Ok(self.cpu.total_cycles() - start)
+    }
+
+    // 画面に出すRGB. 内蔵のものは BuiltinPalette::palette() で作れる
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    // falseなら1ラインに9個以上のスプライトも全部描く (ちらつかない). デフォルトはtrue (実機どおり)
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.render_options.sprite_limit = enabled;
+    }
+
+    fn in_vblank(&self) -> bool {
+        self.cpu.bus.ppu().peek_status() & 0b1000_0000 != 0
+    }
+
+    // 最後に描いた画面 (オーバーレイは含まない)
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    // RGB24, 1行は Frame::WIDTH * 3 バイト
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.frame.data
+    }
+
+    // WASM用: JSはこのポインタと長さでwasmのメモリを直接読む (step_frameの後も同じ場所)
+    pub fn frame_buffer_ptr(&self) -> *const u8 {
+        self.frame.data.as_ptr()
+    }
+
+    pub fn frame_buffer_len(&self) -> usize {
+        self.frame.data.len()
+    }
+
+    // player: 1 or 2
+    pub fn set_button(&mut self, player: u8, button: JoyPadButton, pressed: bool) {
+        self.cpu
+            .bus
+            .joypad_mut(player)
+            .set_button_pressed_status(button, pressed);
+    }
+
+    // リセットボタン
+    pub fn reset(&mut self) {
+        self.cpu.soft_reset();
+    }
+
+    pub fn cpu(&self) -> &CPU<Bus<'static>> {
+        &self.cpu
+    }
+
+    // セーブステート/巻き戻し/APUの設定など, フロントエンドが直接触る用
+    pub fn cpu_mut(&mut self) -> &mut CPU<Bus<'static>> {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_step_frame_advances_one_frame() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let data = std::fs::read(format!("{}/nestest.nes", dir)).unwrap();
+        let mut emulator = Emulator::from_bytes(&data).unwrap();
+
+        // 毎回pre-render lineが終わった直後 (scanline 0) で止まる
+        emulator.step_frame().unwrap();
+        for _ in 0..3 {
+            let ppu = emulator.cpu().bus.ppu();
+            let (start_dot, start_cycles) = (ppu.dot(), emulator.cpu().total_cycles());
+            assert_eq!(ppu.scanline(), 0);
+
+            emulator.step_frame().unwrap();
+            let ppu = emulator.cpu().bus.ppu();
+            assert_eq!(ppu.scanline(), 0);
+            // 進んだドット数 - 止まった位置のずれ = 262ライン分 (奇数フレームは1ドット短い)
+            let dots = (emulator.cpu().total_cycles() - start_cycles) as i64 * 3;
+            let frame_dots = dots - (ppu.dot() as i64 - start_dot as i64);
+            assert!(
+                (262 * 341 - 1..=262 * 341).contains(&frame_dots),
+                "{}",
+                frame_dots
+            );
+        }
+    }
+}
+
+// WASMのエントリポイント (wasm-bindgenなしでJSから呼ぶ)
+// cargo rustc --lib --release --no-default-features --target wasm32-unknown-unknown --crate-type cdylib
+//
+// JS側の流れ:
+//   ptr = rom_alloc(len) -> ROMをptrに書き込む -> emu = emulator_new(ptr, len) (ROMのメモリはここで解放)
+//   毎フレーム emulator_step_frame(emu) して emulator_frame_buffer_ptr/len の範囲を読む
+#[cfg(target_arch = "wasm32")]
+pub mod wasm {
+    use super::Emulator;
+    use crate::joypad::JoyPadButton;
+
+    #[no_mangle]
+    pub extern "C" fn rom_alloc(len: usize) -> *mut u8 {
+        let mut rom = vec![0u8; len];
+        let ptr = rom.as_mut_ptr();
+        std::mem::forget(rom);
+        ptr
+    }
+
+    // 読めないROMならnull
+    #[no_mangle]
+    pub unsafe extern "C" fn emulator_new(rom: *mut u8, len: usize) -> *mut Emulator {
+        let data = Vec::from_raw_parts(rom, len, len);
+        match Emulator::from_bytes(&data) {
+            Ok(emulator) => Box::into_raw(Box::new(emulator)),
+            Err(_) => std::ptr::null_mut(),
+        }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn emulator_free(emulator: *mut Emulator) {
+        drop(Box::from_raw(emulator));
+    }
+
+    // CPUが止まったらfalse
+    #[no_mangle]
+    pub unsafe extern "C" fn emulator_step_frame(emulator: *mut Emulator) -> bool {
+        (*emulator).step_frame().is_ok()
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn emulator_frame_buffer_ptr(emulator: *const Emulator) -> *const u8 {
+        (*emulator).frame_buffer_ptr()
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn emulator_frame_buffer_len(emulator: *const Emulator) -> usize {
+        (*emulator).frame_buffer_len()
+    }
+
+    // button: JoyPadButtonのビット
+    #[no_mangle]
+    pub unsafe extern "C" fn emulator_set_button(
+        emulator: *mut Emulator,
+        player: u8,
+        button: u8,
+        pressed: bool,
+    ) {
+        (*emulator).set_button(player, JoyPadButton::from_bits_truncate(button), pressed);
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn emulator_reset(emulator: *mut Emulator) {
+        (*emulator).reset();
+    }
+}
@@ -0,0 +1,132 @@
+use crate::cartridge::Region;
+use std::time::{Duration, Instant};
+
+// 早送り. Turboの間は倍率分だけ速く回す
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EmulatorSpeed {
+    Normal,
+    Turbo(u32),
+}
+
+impl EmulatorSpeed {
+    pub fn factor(&self) -> u32 {
+        match self {
+            EmulatorSpeed::Normal => 1,
+            EmulatorSpeed::Turbo(factor) => *factor,
+        }
+    }
+}
+
+// vsyncを使わないときのフレームレート調整
+// モニタのリフレッシュレートに関係なく, NTSCなら60.0988Hz, PALなら50.007Hzで回す
+pub struct FrameLimiter {
+    // 等速での1フレームの時間
+    frame_duration: Duration,
+    speed: EmulatorSpeed,
+    // デバッグ用のクロック倍率 (0.1なら10倍遅く回す). CPU/PPU/APUの比率は変わらない
+    clock_scale: f64,
+    next_frame: Instant,
+
+    // 1秒ごとにFPSを計算する
+    frames: u32,
+    fps_start: Instant,
+    fps: f64,
+}
+
+impl FrameLimiter {
+    pub fn new(region: Region) -> Self {
+        let now = Instant::now();
+        FrameLimiter {
+            frame_duration: Duration::from_nanos((1_000_000_000.0 / region.frame_rate()) as u64),
+            speed: EmulatorSpeed::Normal,
+            clock_scale: 1.0,
+            next_frame: now,
+            frames: 0,
+            fps_start: now,
+            fps: 0.0,
+        }
+    }
+
+    // 早送り中は倍率で割った時間
+    pub fn frame_duration(&self) -> Duration {
+        self.frame_duration.div_f64(self.clock_scale) / self.speed.factor()
+    }
+
+    pub fn set_clock_scale(&mut self, scale: f64) {
+        self.clock_scale = scale;
+    }
+
+    pub fn set_speed(&mut self, speed: EmulatorSpeed) {
+        self.speed = speed;
+    }
+
+    // 1フレームごとに呼ぶ. 次のフレームの時刻まで寝る
+    pub fn wait(&mut self) {
+        self.next_frame += self.frame_duration();
+        let now = Instant::now();
+        if self.next_frame > now {
+            std::thread::sleep(self.next_frame - now);
+        } else {
+            // 大きく遅れたら(ウィンドウのドラッグなど)追いつこうとせずにやり直す
+            self.next_frame = now;
+        }
+
+        self.frames += 1;
+        let elapsed = self.fps_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = self.frames as f64 / elapsed.as_secs_f64();
+            self.frames = 0;
+            self.fps_start = Instant::now();
+        }
+    }
+
+    // 直近1秒間の実測FPS
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_duration() {
+        // 1秒 / 60.0988 = 16.639267ms
+        let limiter = FrameLimiter::new(Region::NTSC);
+        assert_eq!(limiter.frame_duration(), Duration::from_nanos(16_639_267));
+        // 1秒 / 50.007 = 19.997200ms
+        let limiter = FrameLimiter::new(Region::PAL);
+        assert_eq!(limiter.frame_duration(), Duration::from_nanos(19_997_200));
+    }
+
+    #[test]
+    fn test_turbo_frame_duration() {
+        let mut limiter = FrameLimiter::new(Region::NTSC);
+        limiter.set_speed(EmulatorSpeed::Turbo(4));
+        assert_eq!(
+            limiter.frame_duration(),
+            Duration::from_nanos(16_639_267 / 4)
+        );
+
+        limiter.set_speed(EmulatorSpeed::Normal);
+        assert_eq!(limiter.frame_duration(), Duration::from_nanos(16_639_267));
+    }
+
+    #[test]
+    fn test_clock_scale() {
+        let mut limiter = FrameLimiter::new(Region::NTSC);
+        limiter.set_clock_scale(0.1);
+        assert_eq!(limiter.frame_duration(), Duration::from_nanos(166_392_670));
+    }
+
+    #[test]
+    fn test_wait_paces_frames() {
+        let mut limiter = FrameLimiter::new(Region::NTSC);
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.wait();
+        }
+        assert!(start.elapsed() >= limiter.frame_duration() * 3);
+    }
+}
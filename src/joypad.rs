@@ -1,3 +1,5 @@
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
 bitflags! {
     pub struct JoyPadButton: u8 {
 	const RIGHT    = 0b1000_0000;
@@ -44,7 +46,80 @@ impl JoyPad {
         response
     }
 
+    // デバッガ用: 次に読んだら返る値. ボタンの位置は進めない
+    pub fn peek(&self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        (self.button_status.bits & (1 << self.button_index)) >> self.button_index
+    }
+
     pub fn set_button_pressed_status(&mut self, button: JoyPadButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
+
+    // 今押されているボタン全部 (ムービーの記録/再生用)
+    pub fn buttons(&self) -> JoyPadButton {
+        self.button_status
+    }
+
+    pub fn set_buttons(&mut self, buttons: JoyPadButton) {
+        self.button_status = buttons;
+    }
+}
+
+impl SaveState for JoyPad {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.strobe);
+        writer.write_u8(self.button_index);
+        writer.write_u8(self.button_status.bits);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.strobe = reader.read_bool()?;
+        self.button_index = reader.read_u8()?;
+        self.button_status = JoyPadButton::from_bits_truncate(reader.read_u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strobe_and_shift() {
+        let mut joypad = JoyPad::new();
+        joypad.set_button_pressed_status(JoyPadButton::BUTTON_A, true);
+        joypad.set_button_pressed_status(JoyPadButton::START, true);
+        joypad.write(1);
+        joypad.write(0);
+
+        // A, B, Select, Start, Up, Down, Left, Right
+        let bits: Vec<u8> = (0..8).map(|_| joypad.read()).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 0]);
+        // 8回より多く読むと標準コントローラは1を返す
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+
+        // strobeし直すと先頭から
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 0);
+    }
+
+    #[test]
+    fn test_strobe_high_keeps_reloading() {
+        let mut joypad = JoyPad::new();
+        joypad.write(1);
+        assert_eq!(joypad.read(), 0);
+        joypad.set_button_pressed_status(JoyPadButton::BUTTON_A, true);
+        // strobeが立っている間はずっとAボタンの状態を返す
+        for _ in 0..10 {
+            assert_eq!(joypad.read(), 1);
+        }
+        joypad.set_button_pressed_status(JoyPadButton::BUTTON_A, false);
+        assert_eq!(joypad.read(), 0);
+    }
 }
@@ -0,0 +1,35 @@
+pub mod apu;
+pub mod bus;
+pub mod cartridge;
+pub mod cheat;
+pub mod cpu;
+pub mod disassembler;
+pub mod emulator;
+pub mod hash;
+pub mod joypad;
+pub mod mapper;
+pub mod movie;
+pub mod opcodes;
+pub mod ppu;
+pub mod profiler;
+pub mod render;
+pub mod rewind;
+pub mod save_state;
+pub mod symbols;
+pub mod trace;
+
+// デスクトップ版のフロントエンド用 (ファイル/時計を使うのでWASMでは使えない)
+#[cfg(feature = "sdl")]
+pub mod controls;
+#[cfg(feature = "sdl")]
+pub mod display;
+#[cfg(feature = "sdl")]
+pub mod frame_limiter;
+#[cfg(feature = "sdl")]
+pub mod options;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
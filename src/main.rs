@@ -1,33 +1,36 @@
-pub mod bus;
-pub mod cartridge;
-pub mod cpu;
-pub mod joypad;
-pub mod opcodes;
-pub mod ppu;
-pub mod render;
-pub mod trace;
-
-use bus::Bus;
-use cartridge::Rom;
+use hobby_nes_emulator::{
+    apu, cartridge, controls, cpu, display, emulator, frame_limiter, joypad, options, render,
+    rewind, trace,
+};
+
+use cartridge::{load_rom_bytes, Rom};
 use cpu::Mem;
 use cpu::CPU;
-use ppu::NesPPU;
+use display::Display;
+use emulator::Emulator;
+use frame_limiter::{EmulatorSpeed, FrameLimiter};
+use options::{Options, USAGE};
 use render::frame::Frame;
-// use trace::trace;
+use render::overlay::{self, Overlays};
+use render::palette::Palette;
+use render::viewer::DebugView;
+use rewind::Rewind;
+use trace::trace;
 
+use apu::resampler::Resampler;
+use apu::Channel;
+use sdl2::audio::{AudioCallback, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
 use sdl2::EventPump;
 
-use std::collections::HashMap;
-
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate bitflags;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 fn color(byte: u8) -> Color {
     match byte {
@@ -43,7 +46,7 @@ fn color(byte: u8) -> Color {
     }
 }
 
-fn read_screen_state(cpu: &mut CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
+fn read_screen_state<M: Mem>(cpu: &mut CPU<M>, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     let mut frame_idx = 0;
     let mut update = false;
     for i in 0x0200..0x0600 {
@@ -65,7 +68,7 @@ fn read_screen_state(cpu: &mut CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
 // A ←
 // S ↓
 // D →
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+fn handle_user_input<M: Mem>(cpu: &mut CPU<M>, event_pump: &mut EventPump) {
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. }
@@ -102,77 +105,555 @@ fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
     }
 }
 
+// Tabを押している間の早送りの倍率
+const TURBO_SPEED: u32 = 4;
+
+// Rを押している間の巻き戻し. 10秒分を2フレームごとに保存する
+const REWIND_SECONDS: u32 = 10;
+const REWIND_STRIDE: u32 = 2;
+
+const AUDIO_SAMPLE_RATE: i32 = 44100;
+// vsyncのときはこれ以上溜まっていたらエミュレーションを待たせる (約46ms)
+// --no-vsyncのときはFrameLimiterが速度を決めるので, 溢れた分を捨てる
+const AUDIO_BUFFER_LIMIT: usize = 2048;
+
+// SDLのオーディオスレッドから呼ばれる. 足りない分は無音で埋める
+struct AudioOutput {
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+}
+
+impl AudioCallback for AudioOutput {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        for sample in out.iter_mut() {
+            *sample = buffer.pop_front().unwrap_or(0.0);
+        }
+    }
+}
+
+// キーの名前はControls::loadで確認してある
+fn keycode_map(
+    bindings: &HashMap<String, joypad::JoyPadButton>,
+) -> HashMap<Keycode, joypad::JoyPadButton> {
+    bindings
+        .iter()
+        .filter_map(|(name, button)| Some((Keycode::from_name(name)?, *button)))
+        .collect()
+}
+
+// --headless: 画面も音もなしで回す (--traceでログを取る用). CPUが止まるまで終わらない
+fn run_headless(rom: Rom, options: &Options) {
+    let mut emulator = match Emulator::new(rom, options.ram_init) {
+        Ok(emulator) => emulator,
+        Err(e) => {
+            eprintln!("{}: {}", options.rom_path, e);
+            std::process::exit(1);
+        }
+    };
+    emulator
+        .cpu_mut()
+        .bus
+        .set_dmc_read_conflict(options.dmc_read_conflict);
+    let trace_enabled = options.trace;
+    loop {
+        let result = emulator.step_frame_with_callback(|cpu| {
+            if trace_enabled {
+                println!("{}", trace(cpu));
+            }
+        });
+        if let Err(e) = result {
+            println!("Emulation stopped: {}", e);
+            return;
+        }
+        emulator.cpu_mut().bus.apu_mut().take_samples();
+    }
+}
+
 fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // cartridge
+    // .zipなら中の.nesを読む
+    let rom_path = options.rom_path.as_str();
+    let bytes: Vec<u8> = match load_rom_bytes(rom_path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut rom = match Rom::new(&bytes) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("{}: {}", rom_path, e);
+            std::process::exit(1);
+        }
+    };
+    // --region: ヘッダーのregionが間違っているROM用
+    if let Some(region) = options.region {
+        rom.region = region;
+    }
+    if options.headless {
+        run_headless(rom, &options);
+        return;
+    }
+    let region = rom.region;
+
     // initialize SDL2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-        .window("NES EMULATOR", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
-        .position_centered()
-        .build()
-        .unwrap();
-
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    // --no-vsync: モニタのリフレッシュレートに関係なくFrameLimiterでNESの速度に合わせる
+    let no_vsync = options.no_vsync;
+    // 描画の座標はFrameの大きさ. 倍率とフルスクリーンはDisplayが持つ
+    let mut display =
+        Display::new(&video_subsystem, "NES EMULATOR", options.scale, !no_vsync).unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
 
-    let creator = canvas.texture_creator();
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let audio_buffer = Arc::new(Mutex::new(VecDeque::new()));
+    let desired_spec = AudioSpecDesired {
+        freq: Some(AUDIO_SAMPLE_RATE),
+        channels: Some(1),
+        samples: Some(1024),
+    };
+    let audio_device = audio_subsystem
+        .open_playback(None, &desired_spec, |_| AudioOutput {
+            buffer: audio_buffer.clone(),
+        })
+        .unwrap();
+    audio_device.resume();
+
+    let creator = display.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
+        .create_texture_target(
+            PixelFormatEnum::RGB24,
+            Frame::WIDTH as u32,
+            Frame::HEIGHT as u32,
+        )
+        .unwrap();
+    // F2でネームテーブル4枚を表示する (デバッグ用)
+    let mut name_tables_frame = Frame::with_dimensions(
+        render::viewer::NAME_TABLES_WIDTH,
+        render::viewer::NAME_TABLES_HEIGHT,
+    );
+    let mut name_tables_texture = creator
+        .create_texture_target(
+            PixelFormatEnum::RGB24,
+            name_tables_frame.width as u32,
+            name_tables_frame.height as u32,
+        )
         .unwrap();
+    // F3でCHRのパターンテーブル(2バンク)を表示する. パレットはBGの0番
+    let mut pattern_tables_frame = Frame::with_dimensions(
+        render::viewer::PATTERN_TABLES_WIDTH,
+        render::viewer::PATTERN_TABLES_HEIGHT,
+    );
+    let mut pattern_tables_texture = creator
+        .create_texture_target(
+            PixelFormatEnum::RGB24,
+            pattern_tables_frame.width as u32,
+            pattern_tables_frame.height as u32,
+        )
+        .unwrap();
+    let mut debug_view = DebugView::Off;
+    // F6でスプライトの枠を重ねる. 重ねた画面は別のFrameに作る (元の画面はそのまま)
+    let mut overlays = Overlays::default();
+    let mut overlay_frame = Frame::new();
+    // F12のスクリーンショットにオーバーレイを含めるか (F10で切り替え). デフォルトは元の画面
+    let mut screenshot_overlays = false;
+    let mut screenshots = 0;
 
-    // cartridge
-    let bytes: Vec<u8> = std::fs::read("../games/Alter_Ego.nes").unwrap();
-    // let bytes: Vec<u8> = std::fs::read("../games/mojon-twins--multicart.nes").unwrap();
-    // let bytes: Vec<u8> = std::fs::read("../games/cyo.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
-    // bus
-    let mut frame = Frame::new();
-
-    let mut key_map = HashMap::new();
-    key_map.insert(Keycode::Down, joypad::JoyPadButton::DOWN);
-    key_map.insert(Keycode::Up, joypad::JoyPadButton::UP);
-    key_map.insert(Keycode::Right, joypad::JoyPadButton::RIGHT);
-    key_map.insert(Keycode::Left, joypad::JoyPadButton::LEFT);
-    key_map.insert(Keycode::Space, joypad::JoyPadButton::SELECT);
-    key_map.insert(Keycode::Return, joypad::JoyPadButton::START);
-    key_map.insert(Keycode::A, joypad::JoyPadButton::BUTTON_A);
-    key_map.insert(Keycode::S, joypad::JoyPadButton::BUTTON_B);
-
-    let bus = Bus::new(rom, move |ppu: &NesPPU, joypad: &mut joypad::JoyPad| {
-        render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 2 * 3).unwrap();
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
+    // APUは1 CPUサイクルに1サンプル作るので, 入力のサンプルレートはCPUクロック
+    // 音の消費で速度を合わせるので PALならこれで50Hzになる
+    let mut resampler = Resampler::new(region.cpu_clock_rate(), AUDIO_SAMPLE_RATE as f64);
+    // --clock-scale <倍率>: マシン全体をゆっくり(速く)回す. タイミングのバグを追う用
+    let clock_scale = options.clock_scale.filter(|scale| *scale != 1.0);
+    let mut frame_limiter = if no_vsync || clock_scale.is_some() {
+        let mut limiter = FrameLimiter::new(region);
+        limiter.set_clock_scale(clock_scale.unwrap_or(1.0));
+        Some(limiter)
+    } else {
+        None
+    };
+    // キー設定 (なければデフォルト. 読めなければエラーを出してデフォルト)
+    let controls = controls::Controls::load("controls.json").unwrap_or_else(|e| {
+        println!("{}, using the default controls", e);
+        controls::Controls::default()
+    });
+    let key_map = keycode_map(&controls.player1);
+    let key_map2 = keycode_map(&controls.player2);
+
+    // --palette <file.pal>: 64色 x RGBの.palファイル. 読めなければ内蔵のパレットを使う
+    // F4で内蔵のパレットを順番に切り替える (選んだものはcontrols.jsonに保存)
+    let mut builtin_palette = controls.palette;
+    let mut system_palette = options
+        .palette
+        .as_deref()
+        .map(Palette::from_pal_file)
+        .unwrap_or_else(|| Ok(builtin_palette.palette()))
+        .unwrap_or_else(|e| {
+            println!("{}, using the built-in palette", e);
+            builtin_palette.palette()
+        });
+
+    // --ram-init zero|ff|random:<seed>: 電源投入時のRAMの中身 (デフォルトは0)
+    let mut emulator = match Emulator::new(rom, options.ram_init) {
+        Ok(emulator) => emulator,
+        Err(e) => {
+            eprintln!("{}: {}", rom_path, e);
+            std::process::exit(1);
+        }
+    };
+    emulator.set_palette(system_palette.clone());
+    // --no-sprite-limit: 1ラインに9個以上のスプライトも描く
+    emulator.set_sprite_limit(!options.no_sprite_limit);
+    // --dmc-read-conflict: DMCのDMAと$4016/$4017の読み込みが重なったときのbit飛びを再現する
+    emulator
+        .cpu_mut()
+        .bus
+        .set_dmc_read_conflict(options.dmc_read_conflict);
+    let sav_path = Path::new(rom_path).with_extension("sav");
+    let state_path = Path::new(rom_path).with_extension("state");
+    // 読めない/大きさが違う.savは無視して, 空のPRG RAMで始める
+    if sav_path.exists() {
+        let result = std::fs::read(&sav_path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| emulator.cpu_mut().bus.load_sram(&data));
+        if let Err(e) = result {
+            println!("Failed to load {}: {}", sav_path.display(), e);
+        }
+    }
+
+    let trace_enabled = options.trace;
+    // Tabを押している間は早送り (音は消す)
+    let mut speed = EmulatorSpeed::Normal;
+    // Rを押している間は巻き戻し
+    let mut rewind = Rewind::new(REWIND_SECONDS, region.frame_rate(), REWIND_STRIDE);
+    let mut rewinding = false;
+    // Pで一時停止. 止まっている間は . を押すたびに1フレーム進める (音は出さない)
+    let mut paused = false;
+    let mut frame_advance = false;
+    // F8でVRAM/パレット/OAMのダンプを書く. 押すたびに別のファイル (game.vram1.txt, game.vram2.txt, ...)
+    let mut vram_dumps = 0;
+
+    'running: loop {
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => break 'running,
+                // F5でクイックセーブ, F9でクイックロード
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => {
+                    if let Err(e) = std::fs::write(&state_path, emulator.cpu().save_state()) {
+                        println!("Failed to save state: {}", e);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    ..
+                } => match std::fs::read(&state_path) {
+                    Ok(data) => {
+                        if let Err(e) = emulator.cpu_mut().load_state(&data) {
+                            println!("Failed to load state: {}", e);
+                        }
+                    }
+                    Err(e) => println!("Failed to read state: {}", e),
+                },
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => {
+                    vram_dumps += 1;
+                    let path =
+                        Path::new(rom_path).with_extension(format!("vram{}.txt", vram_dumps));
+                    match emulator.cpu().bus.ppu().dump_vram(&path) {
+                        Ok(()) => println!("VRAM dumped to {}", path.display()),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    ..
+                } => overlays.sprite_boxes = !overlays.sprite_boxes,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    ..
+                } => {
+                    screenshot_overlays = !screenshot_overlays;
+                    println!(
+                        "screenshots {} overlays",
+                        if screenshot_overlays {
+                            "include"
+                        } else {
+                            "exclude"
+                        }
+                    );
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => {
+                    screenshots += 1;
+                    let path = Path::new(rom_path)
+                        .with_extension(format!("screenshot{}.png", screenshots));
+                    let frame = overlay::screenshot_frame(
+                        emulator.frame(),
+                        &overlay_frame,
+                        screenshot_overlays,
+                    );
+                    match render::png::save(frame, &path) {
+                        Ok(()) => println!("Screenshot saved to {}", path.display()),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                // F1でリセットボタン
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    ..
+                } => emulator.reset(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    ..
+                } => debug_view = debug_view.toggle(DebugView::NameTables),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    ..
+                } => debug_view = debug_view.toggle(DebugView::PatternTables),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    ..
+                } => {
+                    builtin_palette = builtin_palette.next();
+                    system_palette = builtin_palette.palette();
+                    emulator.set_palette(system_palette.clone());
+                    println!("palette: {}", builtin_palette.name());
+                    if let Err(e) =
+                        controls::Controls::save_palette("controls.json", builtin_palette)
+                    {
+                        println!("Failed to save the palette setting: {}", e);
+                    }
+                }
+                // F7で倍率を2x/3x/4xと切り替え, F11でフルスクリーン. 描画の中身は変わらない
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    ..
+                } => match display.cycle_scale() {
+                    Ok(()) => println!("scale: {}x", display.scale()),
+                    Err(e) => println!("Failed to change the scale: {}", e),
+                },
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    ..
+                } => {
+                    if let Err(e) = display.toggle_fullscreen() {
+                        println!("Failed to toggle fullscreen: {}", e);
+                    }
+                }
+                // 1~5でチャンネル(矩形波1, 2, 三角波, ノイズ, DMC)のミュートを切り替える
+                Event::KeyDown {
+                    keycode:
+                        Some(
+                            keycode @ (Keycode::Num1
+                            | Keycode::Num2
+                            | Keycode::Num3
+                            | Keycode::Num4
+                            | Keycode::Num5),
+                        ),
+                    ..
+                } => {
+                    let channel = match keycode {
+                        Keycode::Num1 => Channel::Pulse1,
+                        Keycode::Num2 => Channel::Pulse2,
+                        Keycode::Num3 => Channel::Triangle,
+                        Keycode::Num4 => Channel::Noise,
+                        _ => Channel::Dmc,
+                    };
+                    let apu = emulator.cpu_mut().bus.apu_mut();
+                    let muted = !apu.is_channel_muted(channel);
+                    apu.set_channel_muted(channel, muted);
+                    println!("{:?}: {}", channel, if muted { "muted" } else { "on" });
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => speed = EmulatorSpeed::Turbo(TURBO_SPEED),
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => speed = EmulatorSpeed::Normal,
+                Event::KeyDown {
+                    keycode: Some(Keycode::R),
+                    ..
+                } => rewinding = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::R),
+                    ..
+                } => rewinding = false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat: false,
+                    ..
+                } => {
+                    paused = !paused;
+                    println!("{}", if paused { "paused" } else { "resumed" });
+                    audio_buffer.lock().unwrap().clear();
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Period),
+                    ..
+                } if paused => frame_advance = true,
 
                 Event::KeyDown { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, true);
+                    let keycode = keycode.unwrap_or(Keycode::Ampersand);
+                    if let Some(key) = key_map.get(&keycode) {
+                        emulator.set_button(1, *key, true);
+                    }
+                    if let Some(key) = key_map2.get(&keycode) {
+                        emulator.set_button(2, *key, true);
                     }
                 }
                 Event::KeyUp { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, false);
+                    let keycode = keycode.unwrap_or(Keycode::Ampersand);
+                    if let Some(key) = key_map.get(&keycode) {
+                        emulator.set_button(1, *key, false);
+                    }
+                    if let Some(key) = key_map2.get(&keycode) {
+                        emulator.set_button(2, *key, false);
                     }
                 }
                 _ => { /* do nothing */ }
             }
         }
-    });
-    // cpu
-    let mut cpu = CPU::new(bus);
 
-    cpu.reset();
-    // cpu.run();
+        // vsyncのときは早送りの倍率分のフレームを回して最後だけ描く
+        // FrameLimiterがあるときはlimiterが速度を決めるので1フレームずつ
+        let frames = if paused {
+            frame_advance as u32
+        } else if frame_limiter.is_some() {
+            1
+        } else {
+            speed.factor()
+        };
+        frame_advance = false;
+        for _ in 0..frames {
+            let result = emulator.step_frame_with_callback(|cpu| {
+                if trace_enabled {
+                    println!("{}", trace(cpu));
+                }
+            });
+            // KILなどでCPUが止まった
+            if let Err(e) = result {
+                println!("Emulation stopped: {}", e);
+                break 'running;
+            }
+
+            let samples = emulator.cpu_mut().bus.apu_mut().take_samples();
+            if rewinding {
+                if let Err(e) = rewind.rewind(emulator.cpu_mut()) {
+                    println!("Failed to rewind: {}", e);
+                }
+                // 巻き戻し中の音は鳴らさない
+                audio_buffer.lock().unwrap().clear();
+                continue;
+            }
+            rewind.record(emulator.cpu());
+            // 早送り中と一時停止中は音を捨てる
+            if speed != EmulatorSpeed::Normal || paused {
+                continue;
+            }
+            let samples = resampler.process(&samples);
+            let mut buffer = audio_buffer.lock().unwrap();
+            buffer.extend(samples);
+            if no_vsync {
+                if buffer.len() > AUDIO_BUFFER_LIMIT {
+                    let excess = buffer.len() - AUDIO_BUFFER_LIMIT;
+                    buffer.drain(..excess);
+                }
+            } else {
+                drop(buffer);
+                while audio_buffer.lock().unwrap().len() > AUDIO_BUFFER_LIMIT {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+
+        // 一時停止中も描き続ける (デバッグ表示の切り替えとウィンドウの再描画のため)
+        let ppu = emulator.cpu().bus.ppu();
+        overlay::composite(ppu, emulator.frame(), &overlays, &mut overlay_frame);
+        let canvas = display.canvas();
+        if debug_view == DebugView::NameTables {
+            render::viewer::render_name_tables(ppu, &mut name_tables_frame, &system_palette);
+            debug_assert_eq!(name_tables_frame.pitch(), name_tables_frame.width * 3);
+            debug_assert_eq!(
+                name_tables_texture.query().width as usize,
+                name_tables_frame.width
+            );
+            name_tables_texture
+                .update(None, &name_tables_frame.data, name_tables_frame.pitch())
+                .unwrap();
+            canvas.copy(&name_tables_texture, None, None).unwrap();
+        } else if debug_view == DebugView::PatternTables {
+            render::viewer::render_pattern_tables(
+                ppu,
+                &mut pattern_tables_frame,
+                0,
+                &system_palette,
+            );
+            debug_assert_eq!(pattern_tables_frame.pitch(), pattern_tables_frame.width * 3);
+            debug_assert_eq!(
+                pattern_tables_texture.query().width as usize,
+                pattern_tables_frame.width
+            );
+            pattern_tables_texture
+                .update(
+                    None,
+                    &pattern_tables_frame.data,
+                    pattern_tables_frame.pitch(),
+                )
+                .unwrap();
+            // 縦横比を変えずに上に寄せる
+            let dst = Rect::new(
+                0,
+                0,
+                pattern_tables_frame.width as u32,
+                pattern_tables_frame.height as u32,
+            );
+            canvas.set_draw_color(Color::BLACK);
+            canvas.clear();
+            canvas.copy(&pattern_tables_texture, None, dst).unwrap();
+        } else {
+            debug_assert_eq!(overlay_frame.pitch(), overlay_frame.width * 3);
+            debug_assert_eq!(texture.query().width as usize, overlay_frame.width);
+            texture
+                .update(None, &overlay_frame.data, overlay_frame.pitch())
+                .unwrap();
+            canvas.copy(&texture, None, None).unwrap();
+        }
+        canvas.present();
+
+        if let Some(limiter) = frame_limiter.as_mut() {
+            limiter.set_speed(speed);
+            limiter.wait();
+            let title = format!("NES EMULATOR ({:.1} fps)", limiter.fps());
+            canvas.window_mut().set_title(&title).unwrap();
+        }
+    }
 
-    cpu.run_with_callback(move |cpu| {
-        // println!("{}", trace(cpu));
-    })
+    // 終了時にPRG RAMを保存する
+    if let Some(sram) = emulator.cpu().bus.sram() {
+        std::fs::write(&sav_path, sram).unwrap();
+    }
 }
@@ -0,0 +1,138 @@
+use super::{read_unbanked_chr, write_unbanked_chr, Mapper};
+use crate::cartridge::Mirroring;
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
+pub(super) const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// Mapper 7
+// 0x8000~0xFFFFへの書き込みで
+//   bit 0~2: 0x8000~0xFFFF全体の32Kバンク
+//   bit 4:   1画面ミラーリングのどちらのネームテーブルを使うか (0: lower, 1: upper)
+// CHRはRAM(8K)
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    bank_select: u8,
+}
+
+impl Axrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Axrom {
+            prg_rom,
+            chr: if chr_is_ram {
+                vec![0; CHR_RAM_SIZE]
+            } else {
+                chr_rom
+            },
+            chr_is_ram,
+            bank_select: 0,
+        }
+    }
+
+    fn bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+}
+
+impl Mapper for Axrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank = (self.bank_select & 0b111) as usize % self.bank_count();
+        self.prg_rom[bank * PRG_BANK_SIZE + (addr - 0x8000) as usize]
+    }
+
+    fn write_prg(&mut self, _addr: u16, data: u8) {
+        self.bank_select = data;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        read_unbanked_chr(&self.chr, addr)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        write_unbanked_chr(&mut self.chr, self.chr_is_ram, addr, data);
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        if self.bank_select & 0b1_0000 != 0 {
+            Some(Mirroring::SINGLE_SCREEN_UPPER)
+        } else {
+            Some(Mirroring::SINGLE_SCREEN_LOWER)
+        }
+    }
+}
+
+impl SaveState for Axrom {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.bank_select);
+        if self.chr_is_ram {
+            writer.write_bytes(&self.chr);
+        }
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.bank_select = reader.read_u8()?;
+        if self.chr_is_ram {
+            reader.read_bytes(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Region;
+    use crate::ppu::NesPPU;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn banked_prg(banks: usize) -> Vec<u8> {
+        let mut prg = vec![0; banks * PRG_BANK_SIZE];
+        for bank in 0..banks {
+            prg[bank * PRG_BANK_SIZE] = bank as u8;
+            prg[bank * PRG_BANK_SIZE + 0x7FFF] = 0x80 | bank as u8;
+        }
+        prg
+    }
+
+    #[test]
+    fn test_axrom_bank_switching() {
+        // 256K PRG = 8 banks
+        let mut axrom = Axrom::new(banked_prg(8), vec![]);
+        assert_eq!(axrom.read_prg(0x8000), 0);
+
+        for bank in 0..8 {
+            axrom.write_prg(0x8000, bank);
+            assert_eq!(axrom.read_prg(0x8000), bank);
+            assert_eq!(axrom.read_prg(0xFFFF), 0x80 | bank);
+        }
+        // bit 4はバンクに関係ない
+        axrom.write_prg(0xFFFF, 0b1_0011);
+        assert_eq!(axrom.read_prg(0x8000), 3);
+
+        axrom.write_chr(0x1234, 0x66);
+        assert_eq!(axrom.read_chr(0x1234), 0x66);
+    }
+
+    #[test]
+    fn test_axrom_single_screen_mirroring() {
+        let axrom = Rc::new(RefCell::new(Axrom::new(banked_prg(2), vec![])));
+        let ppu = NesPPU::new_with_mapper(axrom.clone(), Mirroring::HORIZONTAL, Region::NTSC);
+
+        // 4つのネームテーブルが全部同じ1K
+        assert_eq!(ppu.current_mirroring(), Mirroring::SINGLE_SCREEN_LOWER);
+        for name_table in [0x2000, 0x2400, 0x2800, 0x2C00] {
+            assert_eq!(ppu.mirror_vram_addr(name_table + 0x05), 0x005);
+        }
+
+        axrom.borrow_mut().write_prg(0x8000, 0b1_0001);
+        assert_eq!(ppu.current_mirroring(), Mirroring::SINGLE_SCREEN_UPPER);
+        for name_table in [0x2000, 0x2400, 0x2800, 0x2C00] {
+            assert_eq!(ppu.mirror_vram_addr(name_table + 0x05), 0x405);
+        }
+        assert_eq!(axrom.borrow().read_prg(0x8000), 1);
+    }
+}
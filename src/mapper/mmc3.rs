@@ -0,0 +1,312 @@
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
+pub(super) const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// Mapper 4
+// PRG: 8Kバンク x 4 (0x8000, 0xA000, 0xC000, 0xE000)
+// CHR: 2Kバンク x 2 + 1Kバンク x 4
+//
+// 0x8000 (even) bank select   CPMx xRRR
+//                             |||   +++- 次の0x8001で書き込むバンクレジスタ(R0~R7)
+//                             ||+------- (unused)
+//                             |+-------- PRG ROM bank mode
+//                             +--------- CHR A12 inversion
+// 0x8001 (odd)  bank data
+// 0xA000 (even) mirroring (0: vertical, 1: horizontal)
+// 0xA001 (odd)  PRG RAM protect
+// 0xC000 (even) IRQ latch
+// 0xC001 (odd)  IRQ reload
+// 0xE000 (even) IRQ disable (pending IRQを取り消す)
+// 0xE001 (odd)  IRQ enable
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+
+    bank_select: u8,
+    registers: [u8; 8],
+    // 0xA000に書き込まれるまではヘッダーの設定
+    mirroring: Option<Mirroring>,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    // PPU A12の立ち上がりでスキャンラインカウンタを進める
+    last_a12: bool,
+}
+
+impl Mmc3 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Mmc3 {
+            prg_rom,
+            chr: if chr_is_ram {
+                vec![0; CHR_RAM_SIZE]
+            } else {
+                chr_rom
+            },
+            chr_is_ram,
+            bank_select: 0,
+            registers: [0; 8],
+            mirroring: None,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            last_a12: false,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        // PRGが16Kより小さい(NES 2.0の8K)ときは最後のバンクと同じ
+        let second_last = self.prg_bank_count().saturating_sub(2);
+        let prg_mode = self.bank_select & 0b0100_0000 != 0;
+        let bank = match (addr, prg_mode) {
+            (0x8000..=0x9FFF, false) => self.registers[6] as usize,
+            (0x8000..=0x9FFF, true) => second_last,
+            (0xA000..=0xBFFF, _) => self.registers[7] as usize,
+            (0xC000..=0xDFFF, false) => second_last,
+            (0xC000..=0xDFFF, true) => self.registers[6] as usize,
+            (_, _) => self.prg_bank_count() - 1,
+        };
+        bank % self.prg_bank_count()
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        // A12 inversionが立っていると0x0000~0x0FFFと0x1000~0x1FFFの割り当てが入れ替わる
+        let addr = if self.bank_select & 0b1000_0000 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        let bank = match addr {
+            0x0000..=0x07FF => (self.registers[0] & 0xFE) as usize * CHR_BANK_SIZE + addr as usize,
+            0x0800..=0x0FFF => {
+                (self.registers[1] & 0xFE) as usize * CHR_BANK_SIZE + (addr - 0x0800) as usize
+            }
+            _ => {
+                let slot = (addr - 0x1000) as usize / CHR_BANK_SIZE;
+                self.registers[2 + slot] as usize * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE
+            }
+        };
+        bank % self.chr.len()
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank = self.prg_bank(addr);
+        self.prg_rom[bank * PRG_BANK_SIZE + (addr as usize % PRG_BANK_SIZE)]
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+        match (addr, even) {
+            (0x8000..=0x9FFF, true) => self.bank_select = data,
+            (0x8000..=0x9FFF, false) => {
+                self.registers[(self.bank_select & 0b111) as usize] = data;
+            }
+            (0xA000..=0xBFFF, true) => {
+                self.mirroring = Some(if data & 1 == 0 {
+                    Mirroring::VERTICAL
+                } else {
+                    Mirroring::HORIZONTAL
+                });
+            }
+            // PRG RAM protect
+            (0xA000..=0xBFFF, false) => {}
+            (0xC000..=0xDFFF, true) => self.irq_latch = data,
+            (0xC000..=0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000..=0xFFFF, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (_, _) => self.irq_enabled = true,
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let addr = self.chr_addr(addr);
+            self.chr[addr] = data;
+        }
+    }
+
+    fn ppu_a12(&mut self, high: bool) {
+        if high && !self.last_a12 {
+            self.clock_irq_counter();
+        }
+        self.last_a12 = high;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        self.mirroring
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+}
+
+impl SaveState for Mmc3 {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.bank_select);
+        writer.write_bytes(&self.registers);
+        writer.write_u8(match self.mirroring {
+            None => 0,
+            Some(Mirroring::VERTICAL) => 1,
+            _ => 2,
+        });
+        writer.write_u8(self.irq_latch);
+        writer.write_u8(self.irq_counter);
+        writer.write_bool(self.irq_reload);
+        writer.write_bool(self.irq_enabled);
+        writer.write_bool(self.irq_pending);
+        writer.write_bool(self.last_a12);
+        if self.chr_is_ram {
+            writer.write_bytes(&self.chr);
+        }
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.bank_select = reader.read_u8()?;
+        reader.read_bytes(&mut self.registers)?;
+        self.mirroring = match reader.read_u8()? {
+            0 => None,
+            1 => Some(Mirroring::VERTICAL),
+            _ => Some(Mirroring::HORIZONTAL),
+        };
+        self.irq_latch = reader.read_u8()?;
+        self.irq_counter = reader.read_u8()?;
+        self.irq_reload = reader.read_bool()?;
+        self.irq_enabled = reader.read_bool()?;
+        self.irq_pending = reader.read_bool()?;
+        self.last_a12 = reader.read_bool()?;
+        if self.chr_is_ram {
+            reader.read_bytes(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_mmc3() -> Mmc3 {
+        // 64K PRG = 8 banks, 各バンクの先頭にバンク番号
+        let mut prg = vec![0; 8 * PRG_BANK_SIZE];
+        for bank in 0..8 {
+            prg[bank * PRG_BANK_SIZE] = bank as u8;
+        }
+        Mmc3::new(prg, vec![])
+    }
+
+    fn clock_a12(mmc3: &mut Mmc3) {
+        mmc3.ppu_a12(false);
+        mmc3.ppu_a12(true);
+    }
+
+    #[test]
+    fn test_mmc3_prg_banks() {
+        let mut mmc3 = test_mmc3();
+        mmc3.write_prg(0x8000, 6);
+        mmc3.write_prg(0x8001, 2);
+        mmc3.write_prg(0x8000, 7);
+        mmc3.write_prg(0x8001, 3);
+
+        assert_eq!(mmc3.read_prg(0x8000), 2);
+        assert_eq!(mmc3.read_prg(0xA000), 3);
+        assert_eq!(mmc3.read_prg(0xC000), 6);
+        assert_eq!(mmc3.read_prg(0xE000), 7);
+
+        // PRG bank mode 1: 0x8000と0xC000が入れ替わる
+        mmc3.write_prg(0x8000, 0b0100_0000);
+        assert_eq!(mmc3.read_prg(0x8000), 6);
+        assert_eq!(mmc3.read_prg(0xC000), 2);
+    }
+
+    #[test]
+    fn test_mmc3_single_prg_bank() {
+        let mut mmc3 = Mmc3::new(vec![0x42; PRG_BANK_SIZE], vec![]);
+        for addr in [0x8000, 0xA000, 0xC000, 0xE000] {
+            assert_eq!(mmc3.read_prg(addr), 0x42);
+        }
+        mmc3.write_prg(0x8000, 0b0100_0000);
+        assert_eq!(mmc3.read_prg(0x8000), 0x42);
+    }
+
+    #[test]
+    fn test_mmc3_scanline_irq() {
+        let mut mmc3 = test_mmc3();
+        mmc3.write_prg(0xC000, 3); // latch
+        mmc3.write_prg(0xC001, 0); // reload
+        mmc3.write_prg(0xE001, 0); // enable
+
+        // 1回目の立ち上がりでlatchをロード, その後3回で0になる
+        let mut fired = 0;
+        for _ in 0..4 {
+            assert!(!mmc3.irq_pending());
+            clock_a12(&mut mmc3);
+            if mmc3.irq_pending() {
+                fired += 1;
+            }
+        }
+        assert_eq!(fired, 1);
+
+        // A12がhighのままなら数えない
+        mmc3.ppu_a12(true);
+        assert!(mmc3.irq_pending());
+
+        // acknowledge
+        mmc3.write_prg(0xE000, 0);
+        assert!(!mmc3.irq_pending());
+        for _ in 0..8 {
+            clock_a12(&mut mmc3);
+        }
+        assert!(!mmc3.irq_pending());
+    }
+
+    #[test]
+    fn test_mmc3_mirroring() {
+        let mut mmc3 = test_mmc3();
+        assert_eq!(mmc3.mirroring(), None);
+        mmc3.write_prg(0xA000, 1);
+        assert_eq!(mmc3.mirroring(), Some(Mirroring::HORIZONTAL));
+        mmc3.write_prg(0xBFFE, 0);
+        assert_eq!(mmc3.mirroring(), Some(Mirroring::VERTICAL));
+        // 奇数アドレスはPRG RAM protect
+        mmc3.write_prg(0xA001, 1);
+        assert_eq!(mmc3.mirroring(), Some(Mirroring::VERTICAL));
+    }
+}
@@ -0,0 +1,89 @@
+use crate::cartridge::{Mirroring, Rom};
+use crate::save_state::SaveState;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub mod axrom;
+pub mod mmc3;
+pub mod nrom;
+pub mod uxrom;
+
+use axrom::Axrom;
+use mmc3::Mmc3;
+use nrom::Nrom;
+use uxrom::Uxrom;
+
+// カートリッジ上のバンク切り替え回路
+// CPUからは0x8000~0xFFFF(PRG), PPUからは0x0000~0x1FFF(CHR)として見える
+// バンクの状態はセーブステートに含める
+pub trait Mapper: SaveState {
+    fn read_prg(&self, addr: u16) -> u8;
+    fn write_prg(&mut self, addr: u16, data: u8);
+    fn read_chr(&self, addr: u16) -> u8;
+    // CHR ROMへの書き込みは無視する (CHR RAMのときだけ書ける)
+    fn write_chr(&mut self, addr: u16, data: u8);
+
+    // PPUアドレスバスのA12 (MMC3はこの立ち上がりでスキャンラインを数える)
+    fn ppu_a12(&mut self, _high: bool) {}
+
+    // 実行中にミラーリングを切り替えるマッパーはその値を返す. Noneならヘッダーの設定のまま
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    // IRQ line (CPUが割り込みを受け付けても下がらない, マッパー側でacknowledgeする)
+    fn irq_pending(&self) -> bool {
+        false
+    }
+}
+
+// Bus(PRG)とPPU(CHR)の両方から参照される
+pub type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+// バンク切り替えの無い8KBのCHR (NROM, UxROM, AxROM)
+// CHRが8KBより小さいとき(壊れたROMなど)は, その大きさでミラーする
+fn read_unbanked_chr(chr: &[u8], addr: u16) -> u8 {
+    chr[addr as usize % chr.len()]
+}
+
+fn write_unbanked_chr(chr: &mut [u8], chr_is_ram: bool, addr: u16, data: u8) {
+    if chr_is_ram {
+        let len = chr.len();
+        chr[addr as usize % len] = data;
+    }
+}
+
+// NES 2.0のヘッダーはバンクの倍数でないPRG ROMの大きさも書けるが, マッパーはバンク単位でしか読めない
+fn check_prg_size(rom: &Rom, bank_size: usize) -> Result<(), String> {
+    if !rom.prg_rom.len().is_multiple_of(bank_size) {
+        return Err(format!(
+            "Mapper {} needs PRG ROM in {}KB banks, got {} bytes",
+            rom.mapper,
+            bank_size / 1024,
+            rom.prg_rom.len()
+        ));
+    }
+    Ok(())
+}
+
+pub fn from_rom(rom: Rom) -> Result<SharedMapper, String> {
+    match rom.mapper {
+        0 => {
+            check_prg_size(&rom, nrom::PRG_BANK_SIZE)?;
+            Ok(Rc::new(RefCell::new(Nrom::new(rom.prg_rom, rom.chr_rom))))
+        }
+        2 => {
+            check_prg_size(&rom, uxrom::PRG_BANK_SIZE)?;
+            Ok(Rc::new(RefCell::new(Uxrom::new(rom.prg_rom, rom.chr_rom))))
+        }
+        4 => {
+            check_prg_size(&rom, mmc3::PRG_BANK_SIZE)?;
+            Ok(Rc::new(RefCell::new(Mmc3::new(rom.prg_rom, rom.chr_rom))))
+        }
+        7 => {
+            check_prg_size(&rom, axrom::PRG_BANK_SIZE)?;
+            Ok(Rc::new(RefCell::new(Axrom::new(rom.prg_rom, rom.chr_rom))))
+        }
+        _ => Err(format!("Mapper {} is not supported", rom.mapper)),
+    }
+}
@@ -0,0 +1,66 @@
+use super::{read_unbanked_chr, write_unbanked_chr, Mapper};
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
+pub(super) const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// Mapper 0
+// PRG ROM 16K or 32K (16Kの場合は0xC000~0xFFFFにミラー), CHR ROM 8K
+// CHR ROMが0バンクのカートリッジ(homebrewなど)は8KのCHR RAMを持つ
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Nrom {
+            prg_rom,
+            chr: if chr_is_ram {
+                vec![0; CHR_RAM_SIZE]
+            } else {
+                chr_rom
+            },
+            chr_is_ram,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let mut addr = addr - 0x8000;
+        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
+            addr %= 0x4000;
+        }
+        self.prg_rom[addr as usize]
+    }
+
+    // ROMなので書き込みは無視する (実機のカートリッジも何もしない)
+    fn write_prg(&mut self, _addr: u16, _data: u8) {}
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        read_unbanked_chr(&self.chr, addr)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        write_unbanked_chr(&mut self.chr, self.chr_is_ram, addr, data);
+    }
+}
+
+// バンク切り替えが無いので, 保存するのはCHR RAMだけ
+impl SaveState for Nrom {
+    fn write_state(&self, writer: &mut StateWriter) {
+        if self.chr_is_ram {
+            writer.write_bytes(&self.chr);
+        }
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        if self.chr_is_ram {
+            reader.read_bytes(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use super::{read_unbanked_chr, write_unbanked_chr, Mapper};
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
+pub(super) const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// Mapper 2
+// 0x8000~0xFFFFへの書き込みで0x8000~0xBFFFの16Kバンクを切り替える
+// 0xC000~0xFFFFは常に最後のバンクに固定
+// CHRはほとんどのタイトルでRAM(8K)
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    bank_select: u8,
+}
+
+impl Uxrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Uxrom {
+            prg_rom,
+            chr: if chr_is_ram {
+                vec![0; CHR_RAM_SIZE]
+            } else {
+                chr_rom
+            },
+            chr_is_ram,
+            bank_select: 0,
+        }
+    }
+
+    fn bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+}
+
+impl Mapper for Uxrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xBFFF => {
+                let bank = self.bank_select as usize % self.bank_count();
+                self.prg_rom[bank * PRG_BANK_SIZE + (addr - 0x8000) as usize]
+            }
+            _ => {
+                let bank = self.bank_count() - 1;
+                self.prg_rom[bank * PRG_BANK_SIZE + (addr - 0xC000) as usize]
+            }
+        }
+    }
+
+    fn write_prg(&mut self, _addr: u16, data: u8) {
+        self.bank_select = data;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        read_unbanked_chr(&self.chr, addr)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        write_unbanked_chr(&mut self.chr, self.chr_is_ram, addr, data);
+    }
+}
+
+impl SaveState for Uxrom {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.bank_select);
+        if self.chr_is_ram {
+            writer.write_bytes(&self.chr);
+        }
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.bank_select = reader.read_u8()?;
+        if self.chr_is_ram {
+            reader.read_bytes(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn banked_prg(banks: usize) -> Vec<u8> {
+        let mut prg = vec![0; banks * PRG_BANK_SIZE];
+        for bank in 0..banks {
+            prg[bank * PRG_BANK_SIZE] = bank as u8;
+            prg[bank * PRG_BANK_SIZE + 0x3FFF] = 0x80 | bank as u8;
+        }
+        prg
+    }
+
+    #[test]
+    fn test_uxrom_bank_switching() {
+        // 128K PRG = 8 banks
+        let mut uxrom = Uxrom::new(banked_prg(8), vec![]);
+
+        assert_eq!(uxrom.read_prg(0x8000), 0);
+        assert_eq!(uxrom.read_prg(0xC000), 7);
+        assert_eq!(uxrom.read_prg(0xFFFF), 0x87);
+
+        for bank in 0..8 {
+            uxrom.write_prg(0x8000 + bank as u16, bank);
+            assert_eq!(uxrom.read_prg(0x8000), bank);
+            assert_eq!(uxrom.read_prg(0xBFFF), 0x80 | bank);
+            // fixed to the last bank
+            assert_eq!(uxrom.read_prg(0xC000), 7);
+        }
+    }
+
+    #[test]
+    fn test_uxrom_chr_ram() {
+        let mut uxrom = Uxrom::new(banked_prg(2), vec![]);
+        uxrom.write_chr(0x1234, 0x66);
+        assert_eq!(uxrom.read_chr(0x1234), 0x66);
+
+        let mut uxrom = Uxrom::new(banked_prg(2), vec![2; 0x2000]);
+        uxrom.write_chr(0x1234, 0x66);
+        assert_eq!(uxrom.read_chr(0x1234), 2);
+    }
+}
@@ -0,0 +1,324 @@
+// ムービー (入力の記録と再生). TASや回帰テスト用
+// 同じROMを同じRamInitで起動して, リセット直後から再生すれば同じ結果になる
+//
+// [MAGIC 4byte][VERSION 1byte][ROMのハッシュ u32][RamInit 1byte + seed u64]
+// [エントリ数 u32][(フレーム番号 u32, player1 1byte, player2 1byte) ...]
+//
+// 入力が変わったフレームだけ記録する (次のエントリまでは同じボタンを押し続ける)
+
+use crate::bus::RamInit;
+use crate::cartridge::Rom;
+use crate::joypad::{JoyPad, JoyPadButton};
+
+pub const MAGIC: &[u8; 4] = b"NESM";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MovieHeader {
+    // ROMのCRC32. 違うROMで再生しないように確かめる用
+    pub rom_hash: u32,
+    pub ram_init: RamInit,
+}
+
+impl MovieHeader {
+    pub fn new(rom: &Rom, ram_init: RamInit) -> Self {
+        MovieHeader {
+            rom_hash: rom.crc32(),
+            ram_init,
+        }
+    }
+
+    pub fn check_rom(&self, rom: &Rom) -> Result<(), String> {
+        if rom.crc32() != self.rom_hash {
+            return Err(format!(
+                "Movie was recorded with another ROM (CRC32 {:08X}, this ROM is {:08X})",
+                self.rom_hash,
+                rom.crc32()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct MovieFrame {
+    frame: u32,
+    player1: u8,
+    player2: u8,
+}
+
+// Busのフレームごとのcallbackで, 入力を反映した後にrecordを呼ぶ
+pub struct MovieRecorder {
+    header: MovieHeader,
+    frames: Vec<MovieFrame>,
+    frame_count: u32,
+}
+
+impl MovieRecorder {
+    pub fn new(header: MovieHeader) -> Self {
+        MovieRecorder {
+            header,
+            frames: vec![],
+            frame_count: 0,
+        }
+    }
+
+    pub fn record(&mut self, joypad1: &JoyPad, joypad2: &JoyPad) {
+        let entry = MovieFrame {
+            frame: self.frame_count,
+            player1: joypad1.buttons().bits(),
+            player2: joypad2.buttons().bits(),
+        };
+        let changed = match self.frames.last() {
+            Some(last) => last.player1 != entry.player1 || last.player2 != entry.player2,
+            None => true,
+        };
+        if changed {
+            self.frames.push(entry);
+        }
+        self.frame_count += 1;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        data.extend_from_slice(&self.header.rom_hash.to_le_bytes());
+        let (kind, seed) = match self.header.ram_init {
+            RamInit::Zero => (0, 0),
+            RamInit::Ones => (1, 0),
+            RamInit::Random(seed) => (2, seed),
+        };
+        data.push(kind);
+        data.extend_from_slice(&seed.to_le_bytes());
+        data.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        for entry in self.frames.iter() {
+            data.extend_from_slice(&entry.frame.to_le_bytes());
+            data.push(entry.player1);
+            data.push(entry.player2);
+        }
+        data
+    }
+}
+
+// Busのフレームごとのcallbackでplayを呼ぶと, 記録したときと同じ入力になる
+pub struct MoviePlayer {
+    header: MovieHeader,
+    frames: Vec<MovieFrame>,
+    position: usize,
+    frame_count: u32,
+}
+
+impl MoviePlayer {
+    pub fn from_bytes(data: &[u8]) -> Result<MoviePlayer, String> {
+        if data.len() < 5 || &data[0..4] != MAGIC {
+            return Err("Not a movie".to_string());
+        }
+        if data[4] != VERSION {
+            return Err(format!(
+                "Unsupported movie version: {} (expected {})",
+                data[4], VERSION
+            ));
+        }
+        let mut reader = MovieReader { data, pos: 5 };
+        let rom_hash = reader.read_u32()?;
+        let kind = reader.read_u8()?;
+        let seed = reader.read_u64()?;
+        let ram_init = match kind {
+            0 => RamInit::Zero,
+            1 => RamInit::Ones,
+            2 => RamInit::Random(seed),
+            _ => return Err(format!("Unknown RAM init in movie: {}", kind)),
+        };
+        let len = reader.read_u32()?;
+        let mut frames = vec![];
+        for _ in 0..len {
+            frames.push(MovieFrame {
+                frame: reader.read_u32()?,
+                player1: reader.read_u8()?,
+                player2: reader.read_u8()?,
+            });
+        }
+        if reader.pos != data.len() {
+            return Err("Movie has trailing data".to_string());
+        }
+        Ok(MoviePlayer {
+            header: MovieHeader { rom_hash, ram_init },
+            frames,
+            position: 0,
+            frame_count: 0,
+        })
+    }
+
+    // Busを作る前にROMとRamInitを合わせるのに使う
+    pub fn header(&self) -> MovieHeader {
+        self.header
+    }
+
+    pub fn play(&mut self, joypad1: &mut JoyPad, joypad2: &mut JoyPad) {
+        if let Some((player1, player2)) = self.next_frame() {
+            joypad1.set_buttons(player1);
+            joypad2.set_buttons(player2);
+        }
+    }
+
+    // playと同じだが, JoyPadに入れずに返す. 入力が変わらないフレームはNone
+    // (Emulatorなど, 2つのJoyPadを同時に借りられないとき用)
+    pub fn next_frame(&mut self) -> Option<(JoyPadButton, JoyPadButton)> {
+        let mut buttons = None;
+        if let Some(entry) = self.frames.get(self.position) {
+            if entry.frame == self.frame_count {
+                buttons = Some((
+                    JoyPadButton::from_bits_truncate(entry.player1),
+                    JoyPadButton::from_bits_truncate(entry.player2),
+                ));
+                self.position += 1;
+            }
+        }
+        self.frame_count += 1;
+        buttons
+    }
+
+    // 最後の入力の変化まで再生した
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.frames.len()
+    }
+}
+
+struct MovieReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> MovieReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.pos + len > self.data.len() {
+            return Err("Movie is truncated".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, String> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::CPU;
+    use crate::ppu::NesPPU;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    const FRAMES: u32 = 100;
+
+    fn nestest() -> Rom {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let bytes = std::fs::read(format!("{}/nestest.nes", dir)).unwrap();
+        Rom::new(&bytes).unwrap()
+    }
+
+    // frames回callbackが呼ばれるまで動かして, 最後の状態を返す
+    fn run_frames<F>(ram_init: RamInit, mut on_frame: F) -> Vec<u8>
+    where
+        F: FnMut(&mut JoyPad, &mut JoyPad),
+    {
+        let frames = Rc::new(Cell::new(0));
+        let counter = frames.clone();
+        let bus = Bus::new_with_ram_init(
+            nestest(),
+            ram_init,
+            move |_: &NesPPU, joypad1: &mut JoyPad, joypad2: &mut JoyPad| {
+                on_frame(joypad1, joypad2);
+                counter.set(counter.get() + 1);
+            },
+        )
+        .unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        while frames.get() < FRAMES {
+            cpu.step().unwrap();
+        }
+        cpu.save_state()
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let header = MovieHeader::new(&nestest(), RamInit::Random(7));
+        let recorder = RefCell::new(MovieRecorder::new(header));
+        let mut frame = 0u32;
+        let recorded = run_frames(header.ram_init, |joypad1, joypad2| {
+            // 数フレームごとに変わる適当な入力
+            let buttons = (frame / 3).wrapping_mul(37) as u8;
+            joypad1.set_buttons(JoyPadButton::from_bits_truncate(buttons));
+            joypad2.set_buttons(JoyPadButton::from_bits_truncate(!buttons));
+            recorder.borrow_mut().record(joypad1, joypad2);
+            frame += 1;
+        });
+        let movie = recorder.borrow().to_bytes();
+
+        let mut player = MoviePlayer::from_bytes(&movie).unwrap();
+        assert_eq!(player.header(), header);
+        assert!(player.header().check_rom(&nestest()).is_ok());
+        assert!(player.header().check_rom(&test_rom()).is_err());
+        let replayed = run_frames(player.header().ram_init, |joypad1, joypad2| {
+            player.play(joypad1, joypad2);
+        });
+        assert!(player.is_finished());
+        assert_eq!(replayed, recorded);
+
+        // 入力なしで動かすと違う状態になる
+        let idle = run_frames(header.ram_init, |_, _| {});
+        assert_ne!(idle, recorded);
+    }
+
+    #[test]
+    fn test_record_only_changes() {
+        let mut recorder = MovieRecorder::new(MovieHeader {
+            rom_hash: 0,
+            ram_init: RamInit::Zero,
+        });
+        let mut joypad1 = JoyPad::new();
+        let joypad2 = JoyPad::new();
+        recorder.record(&joypad1, &joypad2);
+        recorder.record(&joypad1, &joypad2);
+        joypad1.set_button_pressed_status(JoyPadButton::START, true);
+        recorder.record(&joypad1, &joypad2);
+        recorder.record(&joypad1, &joypad2);
+        assert_eq!(
+            recorder.frames,
+            vec![
+                MovieFrame {
+                    frame: 0,
+                    player1: 0,
+                    player2: 0
+                },
+                MovieFrame {
+                    frame: 2,
+                    player1: JoyPadButton::START.bits(),
+                    player2: 0
+                },
+            ]
+        );
+
+        let movie = recorder.to_bytes();
+        assert!(MoviePlayer::from_bytes(&movie[..movie.len() - 1]).is_err());
+        assert!(MoviePlayer::from_bytes(b"NESS\x01").is_err());
+    }
+}
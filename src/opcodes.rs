@@ -265,8 +265,8 @@ lazy_static! {
     OpCode::new(0x44, "*NOP", 2, 3, AddressingMode::ZeroPage),
     OpCode::new(0x54, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
     OpCode::new(0x64, "*NOP", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0x74, "*NOP", 2, 3, AddressingMode::ZeroPage_X),
-    OpCode::new(0x80, "*NOP", 2, 3, AddressingMode::Immediate),
+    OpCode::new(0x74, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x80, "*NOP", 2, 2, AddressingMode::Immediate),
     OpCode::new(0x82, "*NOP", 2, 2, AddressingMode::Immediate),
     OpCode::new(0x89, "*NOP", 2, 2, AddressingMode::Immediate),
     OpCode::new(0xC2, "*NOP", 2, 2, AddressingMode::Immediate),
@@ -282,18 +282,18 @@ lazy_static! {
     OpCode::new(0xE3, "*ISB", 2, 8, AddressingMode::Indirect_X),
     OpCode::new(0xF3, "*ISB", 2, 8, AddressingMode::Indirect_Y),
     //     KIL (JAM) [HLT] - (nop?)
-    OpCode::new(0x02, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x12, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x22, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x32, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x42, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x52, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x62, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x72, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x92, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0xB2, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0xD2, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0xF2, "*NOP", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x02, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x12, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x22, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x32, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x42, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x52, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x62, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x72, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x92, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xB2, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xD2, "*KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xF2, "*KIL", 1, 2, AddressingMode::NoneAddressing),
     //     LAR (LAE) [LAS] - (las)
     OpCode::new(0xBB, "*LAS", 3, 4, AddressingMode::Absolute_Y),
     //     LAX (LAX) [LAX] -
@@ -0,0 +1,168 @@
+use crate::bus::RamInit;
+use crate::cartridge::Region;
+
+pub const USAGE: &str = "\
+usage: hobby_nes_emulator <rom.nes|rom.zip> [options]
+
+options:
+  --scale N                   window scale (default 3)
+  --region ntsc|pal           override the region in the ROM header
+  --palette FILE              64-color .pal file
+  --no-vsync                  keep the NES speed with a frame limiter instead of vsync
+  --clock-scale FACTOR        run the whole machine slower/faster (e.g. 0.1)
+  --ram-init zero|ff|random:SEED
+                              CPU RAM at power-on (default zero)
+  --dmc-read-conflict         reproduce the DMC DMA / controller read glitch
+  --no-sprite-limit           draw more than 8 sprites per scanline (no flicker)
+  --trace                     print a nestest-style trace line per instruction
+  --headless                  run without a window or sound";
+
+// デスクトップ版のコマンドライン引数
+// 値を取るものは "--scale 2" と "--scale=2" のどちらでもいい
+#[derive(Debug, PartialEq, Clone)]
+pub struct Options {
+    pub rom_path: String,
+    pub scale: u32,
+    // ROMのヘッダーより優先する
+    pub region: Option<Region>,
+    pub palette: Option<String>,
+    pub no_vsync: bool,
+    pub clock_scale: Option<f64>,
+    pub ram_init: RamInit,
+    pub dmc_read_conflict: bool,
+    pub no_sprite_limit: bool,
+    pub trace: bool,
+    pub headless: bool,
+}
+
+impl Options {
+    // argsはプログラム名を除いたもの (std::env::args().skip(1))
+    pub fn parse<I>(args: I) -> Result<Options, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        let mut rom_path = None;
+        let mut options = Options {
+            rom_path: String::new(),
+            scale: 3,
+            region: None,
+            palette: None,
+            no_vsync: false,
+            clock_scale: None,
+            ram_init: RamInit::Zero,
+            dmc_read_conflict: false,
+            no_sprite_limit: false,
+            trace: false,
+            headless: false,
+        };
+
+        while let Some(arg) = args.next() {
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) if arg.starts_with("--") => (name, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} needs a value", name))
+            };
+            match name {
+                "--scale" => {
+                    let scale = value()?;
+                    options.scale = match scale.parse::<u32>() {
+                        Ok(scale) if scale > 0 => scale,
+                        _ => return Err(format!("Invalid scale: {}", scale)),
+                    };
+                }
+                "--region" => {
+                    options.region = match value()?.as_str() {
+                        "ntsc" => Some(Region::NTSC),
+                        "pal" => Some(Region::PAL),
+                        region => return Err(format!("Unknown region: {}", region)),
+                    };
+                }
+                "--palette" => options.palette = Some(value()?),
+                "--clock-scale" => {
+                    let scale = value()?;
+                    options.clock_scale = match scale.parse::<f64>() {
+                        Ok(scale) if scale > 0.0 => Some(scale),
+                        _ => return Err(format!("Invalid clock scale: {}", scale)),
+                    };
+                }
+                "--ram-init" => options.ram_init = RamInit::parse(&value()?)?,
+                "--no-vsync" => options.no_vsync = true,
+                "--dmc-read-conflict" => options.dmc_read_conflict = true,
+                "--no-sprite-limit" => options.no_sprite_limit = true,
+                "--trace" => options.trace = true,
+                "--headless" => options.headless = true,
+                _ if name.starts_with('-') => return Err(format!("Unknown option: {}", name)),
+                _ if rom_path.is_some() => return Err(format!("Unexpected argument: {}", arg)),
+                _ => rom_path = Some(arg.clone()),
+            }
+        }
+
+        options.rom_path = rom_path.ok_or_else(|| "No ROM file given".to_string())?;
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let options = Options::parse(args(&[
+            "--scale",
+            "2",
+            "games/smb.zip",
+            "--region=pal",
+            "--palette",
+            "fceux.pal",
+            "--no-vsync",
+            "--no-sprite-limit",
+            "--ram-init=random:7",
+            "--trace",
+            "--headless",
+        ]))
+        .unwrap();
+        assert_eq!(
+            options,
+            Options {
+                rom_path: "games/smb.zip".to_string(),
+                scale: 2,
+                region: Some(Region::PAL),
+                palette: Some("fceux.pal".to_string()),
+                no_vsync: true,
+                clock_scale: None,
+                ram_init: RamInit::Random(7),
+                dmc_read_conflict: false,
+                no_sprite_limit: true,
+                trace: true,
+                headless: true,
+            }
+        );
+
+        let options = Options::parse(args(&["game.nes"])).unwrap();
+        assert_eq!(options.scale, 3);
+        assert_eq!(options.region, None);
+        assert!(!options.trace);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Options::parse(args(&[])).is_err());
+        assert!(Options::parse(args(&["--trace"])).is_err());
+        assert!(Options::parse(args(&["game.nes", "--scale"])).is_err());
+        assert!(Options::parse(args(&["game.nes", "--scale", "0"])).is_err());
+        assert!(Options::parse(args(&["game.nes", "--region", "dendy"])).is_err());
+        assert!(Options::parse(args(&["game.nes", "--fullscreen"])).is_err());
+        assert!(Options::parse(args(&["a.nes", "b.nes"])).is_err());
+    }
+}
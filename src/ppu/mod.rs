@@ -1,20 +1,28 @@
-use crate::cartridge::Mirroring;
-use registers::addr::AddrRegister;
+use crate::cartridge::{Mirroring, Region};
+use crate::mapper::nrom::Nrom;
+use crate::mapper::SharedMapper;
+use crate::save_state::{SaveState, StateReader, StateWriter};
 use registers::control::ControlRegister;
+use registers::loopy::LoopyRegister;
 use registers::mask::MaskRegister;
 use registers::oam::OamRegisters;
-use registers::scroll::ScrollRegister;
 use registers::status::StatusRegister;
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
 
 pub mod registers;
 
 pub struct NesPPU {
-    // カートリッジに保存されている画像に関するデータ
-    pub chr_rom: Vec<u8>,
+    // カートリッジに保存されている画像に関するデータ(CHR)はマッパー経由で読む
+    pub mapper: SharedMapper,
     // PPUミラーリング
     pub mirroring: Mirroring,
+    // スキャンライン数などのタイミング
+    pub region: Region,
     // 背景情報を保持する内部メモリ
-    pub vram: [u8; 2048],
+    // 本体のVRAMは2KBで, 後ろの2KBは4画面ミラーリングのカートリッジが積んでいる分
+    pub vram: [u8; 4096],
     // スプライト情報を保持する内部メモリ
     // スプライト：背景画像の上にコマ送りでキャラクターを描画する技術らしい
     pub oam_addr: u8,
@@ -24,20 +32,59 @@ pub struct NesPPU {
     pub palette_table: [u8; 32],
     // 内部バッファ(addr)
     internal_data_buf: u8,
-    // ./registers/addr.rs
-    pub addr: AddrRegister,
+    // ./registers/loopy.rs ($2005/$2006/$2007で共有するv, t, x, w)
+    pub loopy: LoopyRegister,
     // ./registers/control.rs
     pub ctrl: ControlRegister,
     // ./registers/mask.rs
     pub mask: MaskRegister,
     // ./registers/sutatus.rs
     pub status: StatusRegister,
-    // ./registers/scroll.rs
-    pub scroll: ScrollRegister,
     // NMI interrupt
     scanline: u16,
     cycles: usize,
     pub nmi_interrupt: Option<u8>,
+    // VBlankが立つ1ドット前に$2002を読んだので, このフレームはVBlankフラグもNMIも立てない
+    suppress_vblank: bool,
+    // 奇数フレームか (pre-render lineの長さが変わる)
+    odd_frame: bool,
+    // 描画中の各スキャンラインの始めのスクロール位置など (renderが使う)
+    scanline_latches: [ScanlineLatch; 240],
+}
+
+// スキャンラインを描き始めたときのv, fine X, BGのパターンテーブル
+// renderはフレームの最後にまとめて描くので, 画面の途中でスクロールを変える(ステータスバーなど)
+// ゲームのために行ごとに覚えておく
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScanlineLatch {
+    pub v: u16,
+    pub fine_x: u8,
+    pub bg_pattern_addr: u16,
+}
+
+impl ScanlineLatch {
+    // スクロール0で$2000から描いたときの各行 (まだ1フレームも描いていないとき用)
+    fn unscrolled() -> [ScanlineLatch; 240] {
+        let mut latches = [ScanlineLatch::default(); 240];
+        for (y, latch) in latches.iter_mut().enumerate() {
+            latch.v = ((y % 8) << 12 | (y / 8) << 5) as u16;
+        }
+        latches
+    }
+
+    pub fn nametable_addr(&self) -> u16 {
+        0x2000 | (self.v & 0x0C00)
+    }
+
+    // ネームテーブル内での左端のx (0~255)
+    pub fn scroll_x(&self) -> usize {
+        (self.v & 0x1F) as usize * 8 + self.fine_x as usize
+    }
+
+    // ネームテーブル内でのy (0~255. 240以上は属性テーブルをタイルとして読む)
+    pub fn y(&self) -> usize {
+        ((self.v >> 5) & 0x1F) as usize * 8 + ((self.v >> 12) & 0b111) as usize
+    }
 }
 
 pub trait PPU {
@@ -55,35 +102,115 @@ pub trait PPU {
     fn write_oam_dma(&mut self, value: &[u8; 256]);
 }
 
+// パレットのアドレス($3F00~$3FFF)をpalette_tableの添字にする
+// - $3F20~$3FFFは$3F00~$3F1Fのミラー
+// - スプライトの$3F10/$3F14/$3F18/$3F1Cは背景の$3F00/$3F04/$3F08/$3F0Cと同じ場所
+// - $3F04/$3F08/$3F0Cは$3F00のミラーではなく別の値を持てる
+//   (描画では透明なピクセルは常に$3F00の色になるので, 画面には出てこない)
+fn mirror_palette_addr(addr: u16) -> usize {
+    let index = (addr & 0x1F) as usize;
+    match index {
+        0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+        _ => index,
+    }
+}
+
+// 16進ダンプ. 1行16バイトで, 行の先頭はaddrからのアドレス
+fn dump_bytes(dump: &mut String, addr: usize, data: &[u8]) {
+    for (i, line) in data.chunks(16).enumerate() {
+        let bytes: Vec<String> = line.iter().map(|b| format!("{:02X}", b)).collect();
+        dump.push_str(&format!("{:04X}: {}\n", addr + i * 16, bytes.join(" ")));
+    }
+}
+
 impl NesPPU {
     pub fn new_empty_rom() -> Self {
         NesPPU::new(vec![0; 2048], Mirroring::HORIZONTAL)
     }
 
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        NesPPU::new_with_mapper(
+            Rc::new(RefCell::new(Nrom::new(vec![], chr_rom))),
+            mirroring,
+            Region::NTSC,
+        )
+    }
+
+    pub fn new_with_mapper(mapper: SharedMapper, mirroring: Mirroring, region: Region) -> Self {
         NesPPU {
-            chr_rom: chr_rom,
+            mapper,
             mirroring: mirroring,
-            vram: [0; 2048],
+            region,
+            vram: [0; 4096],
             oam: OamRegisters::new(),
             oam_addr: 0,
             oam_data: [0; 64 * 4],
             palette_table: [0; 32],
             internal_data_buf: 0,
-            addr: AddrRegister::new(),
+            loopy: LoopyRegister::new(),
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
-            scroll: ScrollRegister::new(),
             scanline: 0,
             cycles: 0,
             nmi_interrupt: None,
+            suppress_vblank: false,
+            odd_frame: false,
+            scanline_latches: ScanlineLatch::unscrolled(),
         }
     }
 
+    // リセットボタン: VRAM/OAM/パレットはそのまま, レジスタの書き込みトグルだけ戻す
+    pub fn soft_reset(&mut self) {
+        self.loopy.reset_latch();
+        self.ctrl.update(0);
+        self.mask.update(0);
+        self.internal_data_buf = 0;
+    }
+
+    // render用: 描画中のスキャンライン(0~239)の始めに覚えた値
+    pub fn scanline_latch(&self, line: usize) -> ScanlineLatch {
+        self.scanline_latches[line]
+    }
+
+    // 今のスキャンライン (0~261, PALは0~311). 240~はvblank, 最後がpre-render line
+    // trace/デバッガ用 (読むだけ)
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    // スキャンラインの中のドット (0~340). cyclesはPPUのクロック = ドット
+    pub fn dot(&self) -> usize {
+        self.cycles
+    }
+
+    // デバッガ用: 読んでも状態が変わらない版
+    // $2002 vblankとラッチをクリアしない
+    pub fn peek_status(&self) -> u8 {
+        self.status.get_status()
+    }
+
+    // $2007 次に読んだら返る値. アドレスは進めない
+    // パレット以外はバッファ越しなので, 1回前に読んだ値になる
+    pub fn peek_data(&self) -> u8 {
+        match self.loopy.vram_addr() {
+            addr @ 0x3F00..=0x3FFF => self.palette_table[mirror_palette_addr(addr)],
+            _ => self.internal_data_buf,
+        }
+    }
+
+    // $2004
+    pub fn peek_oam_data(&self) -> u8 {
+        self.oam.get_data()
+    }
+
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        self.mapper.borrow().read_chr(addr)
+    }
+
     // addr register
     fn increment_vram_addr(&mut self) {
-        self.addr.increment(self.ctrl.vram_addr_increment());
+        self.loopy.increment(self.ctrl.vram_addr_increment());
     }
 
     // Horizontal:
@@ -92,52 +219,284 @@ impl NesPPU {
     // Vertical:
     //   [ A ] [ B ]
     //   [ a ] [ b ]
+    // Four screen:
+    //   [ A ] [ B ]
+    //   [ C ] [ D ]
+    // Single screen (lower / upper):
+    //   [ A ] [ A ]     [ a ] [ a ]
+    //   [ A ] [ A ]     [ a ] [ a ]
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram = addr & 0b1011_1111_1111_11;
         let vram_index = mirrored_vram - 0x2000;
         let name_table = vram_index / 0x400;
-        match (&self.mirroring, name_table) {
+        match (self.current_mirroring(), name_table) {
             (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
             (Mirroring::HORIZONTAL, 2) | (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
             (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
+            // 0x000~0xFFFの12bitをそのまま使う
+            (Mirroring::FOUR_SCREEN, _) => vram_index,
+            (Mirroring::SINGLE_SCREEN_LOWER, _) => vram_index & 0x3FF,
+            (Mirroring::SINGLE_SCREEN_UPPER, _) => 0x400 | (vram_index & 0x3FF),
             _ => vram_index,
         }
     }
 
-    // NMI Interrupt
+    // マッパーが切り替えていればそちらを使う
+    // 4画面はカートリッジの配線で決まっているので変わらない
+    pub fn current_mirroring(&self) -> Mirroring {
+        if self.mirroring == Mirroring::FOUR_SCREEN {
+            return Mirroring::FOUR_SCREEN;
+        }
+        self.mapper.borrow().mirroring().unwrap_or(self.mirroring)
+    }
+
+    // デバッグ用: VRAM, パレット, OAMを16進ダンプでファイルに書く (フレームごとに書いてdiffを取る)
+    pub fn dump_vram<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        std::fs::write(&path, self.vram_dump())
+            .map_err(|e| format!("Failed to write {}: {}", path.as_ref().display(), e))
+    }
+
+    // ネームテーブルごとにタイル(960バイト)と属性テーブル(64バイト)に分け, $2000~のどこに見えているかも書く
+    // VRAMは4画面ミラーリングなら4KB, それ以外は本体の2KBだけ. 行のアドレスはVRAMの中のオフセット
+    pub fn vram_dump(&self) -> String {
+        let mut dump = String::new();
+        let tables = if self.current_mirroring() == Mirroring::FOUR_SCREEN {
+            4
+        } else {
+            2
+        };
+        for table in 0..tables {
+            let start = table * 0x400;
+            let mapped: Vec<String> = (0..4)
+                .map(|n| 0x2000 + n * 0x400)
+                .filter(|addr| self.mirror_vram_addr(*addr) as usize == start)
+                .map(|addr| format!("${:04X}", addr))
+                .collect();
+            let mapped = if mapped.is_empty() {
+                "not mapped".to_string()
+            } else {
+                mapped.join(" ")
+            };
+            dump.push_str(&format!(
+                "[nametable {}] vram ${:03X} ({})\n",
+                table, start, mapped
+            ));
+            dump.push_str("tiles:\n");
+            dump_bytes(&mut dump, start, &self.vram[start..start + 0x3C0]);
+            dump.push_str("attributes:\n");
+            dump_bytes(
+                &mut dump,
+                start + 0x3C0,
+                &self.vram[start + 0x3C0..start + 0x400],
+            );
+            dump.push('\n');
+        }
+        dump.push_str("[palette]\n");
+        dump_bytes(&mut dump, 0x3F00, &self.palette_table);
+        dump.push_str("\n[oam]\n");
+        dump_bytes(&mut dump, 0, &self.oam_data);
+        dump
+    }
+
+    // cyclesドット進める. フレームが終わったらtrue
+    // 各イベントは実機と同じドットで起こす
+    // - (241, 1): VBlank開始, NMI
+    // - (261, 1): VBlank, スプライト0ヒット, スプライトオーバーフローのクリア
+    // - (261, 339): 描画中の奇数フレームは(261, 340)を飛ばして(0, 0)に進む (pre-render lineが340ドット)
+    // - 描画中のライン: スプライト0ヒットはその画素のドット, オーバーフローはdot 257, A12はdot 260
     pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles >= 341 {
-            if self.is_sprite_zero_hit(self.cycles) {
-                self.status.set_sprite_zero_hit(true);
+        let mut frame_complete = false;
+        let mut sprite_zero_x = self.sprite_zero_hit_x_on_line();
+        for _ in 0..cycles {
+            let scanline = self.scanline;
+            if self.step_dot() {
+                frame_complete = true;
             }
+            if self.scanline != scanline {
+                sprite_zero_x = self.sprite_zero_hit_x_on_line();
+            } else if let Some(x) = sprite_zero_x {
+                // dot x+1でx番目の画素を描く
+                if x < self.cycles {
+                    self.status.set_sprite_zero_hit(true);
+                    sprite_zero_x = None;
+                }
+            }
+        }
+        frame_complete
+    }
 
-            self.cycles -= 341;
-            self.scanline += 1;
+    // 1ドット進める. フレームが終わったらtrue
+    fn step_dot(&mut self) -> bool {
+        self.cycles += 1;
+        let visible = self.scanline < 240;
+        let pre_render = self.scanline == self.region.pre_render_scanline();
+
+        // 描画中はvがスクロール位置として進む (横方向のタイルごとのインクリメントは省略)
+        if (visible || pre_render) && self.rendering_enabled() {
+            match self.cycles {
+                256 => self.loopy.increment_y(),
+                257 => self.loopy.copy_horizontal(),
+                280..=304 if pre_render => self.loopy.copy_vertical(),
+                _ => {}
+            }
+        }
 
-            if self.scanline == 241 {
-                self.status.set_vblank_started(true);
-                self.status.set_sprite_zero_hit(false);
-                if self.ctrl.generate_nmi() {
-                    self.nmi_interrupt = Some(1);
+        match self.cycles {
+            1 if self.scanline == self.region.vblank_scanline() => {
+                if self.suppress_vblank {
+                    self.suppress_vblank = false;
+                } else {
+                    self.status.set_vblank_started(true);
+                    if self.ctrl.generate_nmi() {
+                        self.nmi_interrupt = Some(1);
+                    }
                 }
             }
+            1 if pre_render => {
+                self.status.reset_vblank_started();
+                self.status.set_sprite_zero_hit(false);
+                self.status.set_sprite_overflow(false);
+                self.nmi_interrupt = None;
+            }
+            // スプライト評価(dot 65~256)の終わり
+            257 if visible && self.rendering_enabled() && self.sprite_overflow() => {
+                self.status.set_sprite_overflow(true);
+            }
+            // MMC3のスキャンラインカウンタ用
+            // BGを0x0000, スプライトを0x1000から読む一般的な構成では, スプライトのパターンを
+            // 読み始めるdot 260でA12が立ち上がる
+            260 if (visible || pre_render) && self.rendering_enabled() => {
+                let mut mapper = self.mapper.borrow_mut();
+                mapper.ppu_a12(false);
+                mapper.ppu_a12(true);
+            }
+            _ => {}
+        }
 
-            if self.scanline >= 262 {
+        let skip_dot = pre_render
+            && self.odd_frame
+            && self.rendering_enabled()
+            && self.region.skips_odd_frame_dot();
+        if self.cycles >= 341 || (skip_dot && self.cycles >= 340) {
+            self.cycles = 0;
+            self.scanline += 1;
+            let mut frame_complete = false;
+            if self.scanline >= self.region.scanlines() {
                 self.scanline = 0;
-                self.nmi_interrupt = None;
-                self.status.set_sprite_zero_hit(false);
-                self.status.reset_vblank_started();
-                return true;
+                self.odd_frame = !self.odd_frame;
+                frame_complete = true;
+            }
+            if self.scanline < 240 {
+                self.scanline_latches[self.scanline as usize] = ScanlineLatch {
+                    v: self.loopy.v,
+                    fine_x: self.loopy.x,
+                    bg_pattern_addr: self.ctrl.backround_pattern_addr(),
+                };
             }
+            return frame_complete;
         }
-        return false;
+        false
+    }
+
+    // 今のスキャンラインでまだスプライト0ヒットが起きていなければ, 起きる画素のx
+    fn sprite_zero_hit_x_on_line(&self) -> Option<usize> {
+        if self.scanline >= 240 || self.status.check_sprite_zero_hit() {
+            return None;
+        }
+        self.sprite_zero_hit_x()
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask.check_show_background() || self.mask.check_show_sprites()
     }
 
-    fn is_sprite_zero_hit(&self, cycles: usize) -> bool {
-        let y = self.oam_data[0] as usize;
-        let x = self.oam_data[3] as usize;
-        (y == self.scanline as usize) && x <= cycles && self.mask.check_show_sprites()
+    // 現在のスキャンラインに9個以上のスプライトがあるか
+    // 実機のスプライト評価のバグ(誤検出/検出漏れ)は再現せず, 正しく数える
+    fn sprite_overflow(&self) -> bool {
+        let size = self.ctrl.sprite_size() as usize;
+        let line = self.scanline as usize;
+        let count = self
+            .oam_data
+            .chunks(4)
+            .filter(|sprite| {
+                let y = sprite[0] as usize;
+                line >= y && line < y + size
+            })
+            .count();
+        count > 8
+    }
+
+    // スプライト0ヒット (近似)
+    // 現在のスキャンラインでスプライト0の不透明ピクセルと背景の不透明ピクセルが重なる最初のxを返す.
+    // tickのたびに呼ばれ, cyclesがそのxを過ぎた時点でフラグを立てる.
+    // - 背景は現在のスクロールとベースネームテーブルから引く(ライン途中のスクロール変更は反映しない)
+    // - スプライトのY座標はrenderと同じくOAMの値をそのまま使う(実機は1ライン下に描画される)
+    fn sprite_zero_hit_x(&self) -> Option<usize> {
+        if !self.mask.check_show_background() || !self.mask.check_show_sprites() {
+            return None;
+        }
+        let size = self.ctrl.sprite_size() as usize;
+        let sprite_y = self.oam_data[0] as usize;
+        let line = self.scanline as usize;
+        if line < sprite_y || line >= sprite_y + size {
+            return None;
+        }
+
+        let attr = self.oam_data[2];
+        let flip_vertical = attr >> 7 & 1 == 1;
+        let flip_horizontal = attr >> 6 & 1 == 1;
+        let mut row = line - sprite_y;
+        if flip_vertical {
+            row = size - 1 - row;
+        }
+        let tile_idx = self.oam_data[1] as u16;
+        let tile_addr = if size == 16 {
+            // 8x16: bit0がバンク, 下半分は次のタイル
+            let bank = (tile_idx & 1) * 0x1000;
+            bank + ((tile_idx & 0xFE) + (row / 8) as u16) * 16
+        } else {
+            self.ctrl.sprite_pattern_addr() + tile_idx * 16
+        };
+        let lower = self.read_chr(tile_addr + (row % 8) as u16);
+        let upper = self.read_chr(tile_addr + (row % 8) as u16 + 8);
+
+        let left_clip =
+            !self.mask.check_show_background_left() || !self.mask.check_show_sprite_left();
+        for column in 0..8 {
+            let x = self.oam_data[3] as usize + column;
+            // x=255では発生しない
+            if x >= 255 {
+                break;
+            }
+            if x < 8 && left_clip {
+                continue;
+            }
+            let bit = if flip_horizontal { column } else { 7 - column };
+            let opaque = (lower >> bit | upper >> bit) & 1 == 1;
+            if opaque && self.background_opaque(x, line) {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    // 画面座標(x, y)の背景ピクセルがカラー0以外か
+    // スクロール位置はそのスキャンラインの始めに覚えた値を使う
+    fn background_opaque(&self, x: usize, y: usize) -> bool {
+        let latch = self.scanline_latches[y];
+        let mut name_table = latch.nametable_addr();
+        let mut px = x + latch.scroll_x();
+        let py = latch.y();
+        if px >= 256 {
+            px -= 256;
+            name_table ^= 0x400;
+        }
+
+        let tile_addr = name_table + (py / 8 * 32 + px / 8) as u16;
+        let tile_idx = self.vram[self.mirror_vram_addr(tile_addr) as usize] as u16;
+        let chr_addr = latch.bg_pattern_addr + tile_idx * 16 + (py % 8) as u16;
+        let bit = 7 - px % 8;
+        (self.read_chr(chr_addr) >> bit | self.read_chr(chr_addr + 8) >> bit) & 1 == 1
     }
 
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
@@ -145,17 +504,86 @@ impl NesPPU {
     }
 }
 
+// マッパーはBusの方で保存する
+impl SaveState for NesPPU {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(match self.mirroring {
+            Mirroring::VERTICAL => 0,
+            Mirroring::HORIZONTAL => 1,
+            Mirroring::FOUR_SCREEN => 2,
+            Mirroring::SINGLE_SCREEN_LOWER => 3,
+            Mirroring::SINGLE_SCREEN_UPPER => 4,
+        });
+        writer.write_bytes(&self.vram);
+        writer.write_u8(self.oam_addr);
+        writer.write_bytes(&self.oam_data);
+        self.oam.write_state(writer);
+        writer.write_bytes(&self.palette_table);
+        writer.write_u8(self.internal_data_buf);
+        self.loopy.write_state(writer);
+        writer.write_u8(self.ctrl.bits());
+        writer.write_u8(self.mask.bits());
+        writer.write_u8(self.status.bits());
+        writer.write_u16(self.scanline);
+        writer.write_usize(self.cycles);
+        writer.write_bool(self.nmi_interrupt.is_some());
+        writer.write_u8(self.nmi_interrupt.unwrap_or(0));
+        writer.write_bool(self.suppress_vblank);
+        writer.write_bool(self.odd_frame);
+        for latch in self.scanline_latches.iter() {
+            writer.write_u16(latch.v);
+            writer.write_u8(latch.fine_x);
+            writer.write_u16(latch.bg_pattern_addr);
+        }
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.mirroring = match reader.read_u8()? {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::FOUR_SCREEN,
+            3 => Mirroring::SINGLE_SCREEN_LOWER,
+            4 => Mirroring::SINGLE_SCREEN_UPPER,
+            mirroring => return Err(format!("Invalid mirroring in save state: {}", mirroring)),
+        };
+        reader.read_bytes(&mut self.vram)?;
+        self.oam_addr = reader.read_u8()?;
+        reader.read_bytes(&mut self.oam_data)?;
+        self.oam.read_state(reader)?;
+        reader.read_bytes(&mut self.palette_table)?;
+        self.internal_data_buf = reader.read_u8()?;
+        self.loopy.read_state(reader)?;
+        self.ctrl.update(reader.read_u8()?);
+        self.mask.update(reader.read_u8()?);
+        self.status.update(reader.read_u8()?);
+        self.scanline = reader.read_u16()?;
+        self.cycles = reader.read_usize()?;
+        let nmi = reader.read_bool()?;
+        let nmi_value = reader.read_u8()?;
+        self.nmi_interrupt = if nmi { Some(nmi_value) } else { None };
+        self.suppress_vblank = reader.read_bool()?;
+        self.odd_frame = reader.read_bool()?;
+        for latch in self.scanline_latches.iter_mut() {
+            latch.v = reader.read_u16()?;
+            latch.fine_x = reader.read_u8()?;
+            latch.bg_pattern_addr = reader.read_u16()?;
+        }
+        Ok(())
+    }
+}
+
 impl PPU for NesPPU {
     // addr register
     // updateで書き込み(CPUからのアドレス指定(2回))でバッファを2回返す
     fn write_to_ppu_addr(&mut self, value: u8) {
-        self.addr.update(value);
+        self.loopy.write_addr(value);
     }
 
     // control
     fn write_to_ctrl(&mut self, value: u8) {
         let before_nmi_status = self.ctrl.generate_nmi();
         self.ctrl.update(value);
+        self.loopy.write_ctrl(value);
         if !before_nmi_status && self.ctrl.generate_nmi() && self.status.check_vblank_started() {
             self.nmi_interrupt = Some(1);
         }
@@ -163,7 +591,7 @@ impl PPU for NesPPU {
 
     // addr register
     fn read_data(&mut self) -> u8 {
-        let addr = self.addr.get();
+        let addr = self.loopy.vram_addr();
         self.increment_vram_addr();
 
         match addr {
@@ -171,7 +599,7 @@ impl PPU for NesPPU {
             // インターナルバッファを返す（2回返すと欲しい値が得られる）
             0..=0x1FFF => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.read_chr(addr);
                 result
             },
             // PPU addr register
@@ -181,15 +609,18 @@ impl PPU for NesPPU {
                 self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
                 result
             },
-            0x3000..=0x3EFF => panic!(
-                "addr space 0x3000..0x3EFF is not expected to be used, requested = {}",
-                addr
-            ),
-            0x3F10 | 0x3F14 | 0x3F18 | 0x3F1C => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3F00) as usize]
+            // $2000~$2EFFのミラー
+            0x3000..=0x3EFF => {
+                let result = self.internal_data_buf;
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr - 0x1000) as usize];
+                result
+            },
+            // パレットはバッファを通さずにそのまま返す
+            // ただしバッファには"下"にあるネームテーブル($2Fxx)の値が入る
+            0x3F00..=0x3FFF => {
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.palette_table[mirror_palette_addr(addr)]
             },
-            0x3F00..=0x3FFF => self.palette_table[(addr - 0x3F00) as usize],
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
     }
@@ -201,12 +632,22 @@ impl PPU for NesPPU {
 
     // status
     // ステータスを読み込むと、VBlankとScroll、PPU_Addrのラッチがクリアされる
+    // VBlankが立つ(241, 1)付近で読むと実機と同じように競合する
+    // - (241, 0): フラグは0で読め, このフレームはフラグもNMIも立たない
+    // - (241, 1)~(241, 2): フラグは1で読めるが, NMIは起きない
+    // CPUは命令単位でPPUを進めるので, ここでのドットは読み込み時点のおおよその位置になる
     fn read_status(&mut self) -> u8 {
+        if self.scanline == self.region.vblank_scanline() {
+            match self.cycles {
+                0 => self.suppress_vblank = true,
+                1 | 2 => self.nmi_interrupt = None,
+                _ => {}
+            }
+        }
         let data = self.status.get_status();
         // reset
         self.status.reset_vblank_started();
-        self.addr.reset_latch();
-        self.scroll.reset_latch();
+        self.loopy.reset_latch();
         data
     }
 
@@ -216,7 +657,7 @@ impl PPU for NesPPU {
 
     // scroll
     fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.write(value);
+        self.loopy.write_scroll(value);
     }
 
     // oam addr
@@ -242,24 +683,19 @@ impl PPU for NesPPU {
     }
 
     fn write_to_data(&mut self, value: u8) {
-        let addr = self.addr.get();
+        let addr = self.loopy.vram_addr();
         println!("ADDR : {:x}", addr);
         match addr {
-            0..=0x1FFF => println!("attempt to write to CHR ROM space {}", addr),
+            0..=0x1FFF => self.mapper.borrow_mut().write_chr(addr, value),
             0x2000..=0x2FFF => {
                 self.vram[self.mirror_vram_addr(addr) as usize] = value;
             }
-            // 0x3000..=0x3EFF => unimplemented!("addr {:x} shouldnt be used in reality", addr),
+            // $2000~$2EFFのミラー
             0x3000..=0x3EFF => {
-                // self.vram[self.mirror_vram_addr(addr) as usize] = value;
-            }
-            0x3F10 | 0x3F14 | 0x3F18 | 0x3F1C => {
-                let addr_mirror = addr - 0x10;
-                self.palette_table[(addr_mirror - 0x3F00) as usize] = value;
+                self.vram[self.mirror_vram_addr(addr - 0x1000) as usize] = value;
             }
             0x3F00..=0x3FFF => {
-                println!("palette table access : {:?}", addr - 0x3F00);
-                self.palette_table[(addr - 0x3F00) as usize] = value;
+                self.palette_table[mirror_palette_addr(addr)] = value;
             }
             _ => panic!("unexpected access to mirrored space {:x}", addr),
         }
@@ -270,6 +706,7 @@ impl PPU for NesPPU {
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::mapper::Mapper;
 
     #[test]
     fn test_ppu_vram_writes() {
@@ -291,10 +728,33 @@ pub mod test {
         ppu.write_to_ppu_addr(0x05);
 
         ppu.read_data(); //load_into_buffer
-        assert_eq!(ppu.addr.get(), 0x2306);
+        assert_eq!(ppu.loopy.v, 0x2306);
         assert_eq!(ppu.read_data(), 0x66);
     }
 
+    #[test]
+    fn test_scroll_and_addr_share_write_toggle() {
+        let mut ppu = NesPPU::new_empty_rom();
+        // $2005を1回だけ書いたので, 次の$2006は下位バイトになる
+        ppu.write_to_scroll(0x10);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.loopy.v, 0x0005);
+
+        // $2002を読めば上位バイトから
+        ppu.write_to_scroll(0x10);
+        ppu.read_status();
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.loopy.v, 0x2305);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.loopy.v, 0x2306);
+        assert_eq!(ppu.vram[0x0305], 0x66);
+
+        // $2000のネームテーブルはtに入る
+        ppu.write_to_ctrl(0b10);
+        assert_eq!(ppu.loopy.nametable_addr(), 0x2800);
+    }
+
     #[test]
     fn test_ppu_vram_reads_cross_page() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -327,6 +787,88 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x88);
     }
 
+    #[test]
+    fn test_palette_read_fills_buffer_with_name_table() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        ppu.palette_table[0x05] = 0x12;
+        // $3F05の下にあるのは$2F05
+        let name_table_addr = ppu.mirror_vram_addr(0x2F05) as usize;
+        ppu.vram[name_table_addr] = 0x66;
+
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.read_data(), 0x12);
+
+        // 次の読み出しは(どのアドレスでも)バッファに入ったネームテーブルの値を返す
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_vram_3000_mirror() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        ppu.write_to_ppu_addr(0x30);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load_into_buffer
+        assert_eq!(ppu.read_data(), 0x66);
+
+        // 読み出しも$2000側と同じ場所を見る
+        ppu.vram[ppu.mirror_vram_addr(0x2EFF) as usize] = 0x77;
+        ppu.write_to_ppu_addr(0x3E);
+        ppu.write_to_ppu_addr(0xFF);
+        ppu.read_data(); //load_into_buffer
+
+        // 次は$3F00(パレット)なので, バッファを返すアドレスに戻してから読む
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x77);
+    }
+
+    fn write_palette(ppu: &mut NesPPU, addr: u16, value: u8) {
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr((addr & 0xFF) as u8);
+        ppu.write_to_data(value);
+    }
+
+    fn read_palette(ppu: &mut NesPPU, addr: u16) -> u8 {
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr((addr & 0xFF) as u8);
+        ppu.read_data()
+    }
+
+    #[test]
+    fn test_palette_mirroring() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+
+        write_palette(&mut ppu, 0x3F10, 0x21);
+        assert_eq!(read_palette(&mut ppu, 0x3F00), 0x21);
+        write_palette(&mut ppu, 0x3F00, 0x0F);
+        assert_eq!(read_palette(&mut ppu, 0x3F10), 0x0F);
+        write_palette(&mut ppu, 0x3F1C, 0x12);
+        assert_eq!(read_palette(&mut ppu, 0x3F0C), 0x12);
+
+        // $3F04は$3F14とは共有するが, $3F00とは別の値
+        write_palette(&mut ppu, 0x3F04, 0x16);
+        assert_eq!(read_palette(&mut ppu, 0x3F14), 0x16);
+        assert_eq!(read_palette(&mut ppu, 0x3F00), 0x0F);
+        assert_eq!(ppu.palette_table[0x04], 0x16);
+
+        // $3F20以降は$3F00~$3F1Fのミラー
+        assert_eq!(read_palette(&mut ppu, 0x3F24), 0x16);
+        assert_eq!(read_palette(&mut ppu, 0x3FF0), 0x0F);
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0x3C);
+        assert_eq!(ppu.peek_data(), 0x12);
+    }
+
     #[test]
     fn test_vram_horizontal_mirror() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -380,6 +922,86 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_vram_four_screen() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::FOUR_SCREEN);
+
+        for (i, hi) in [0x20, 0x24, 0x28, 0x2C].iter().enumerate() {
+            ppu.write_to_ppu_addr(*hi);
+            ppu.write_to_ppu_addr(0x05);
+            ppu.write_to_data(0x10 + i as u8);
+        }
+
+        for (i, hi) in [0x20, 0x24, 0x28, 0x2C].iter().enumerate() {
+            ppu.write_to_ppu_addr(*hi);
+            ppu.write_to_ppu_addr(0x05);
+            ppu.read_data(); //load into buffer
+            assert_eq!(ppu.read_data(), 0x10 + i as u8);
+        }
+        assert_eq!(ppu.vram[0xC05], 0x13);
+        // 0x3000~はミラー
+        assert_eq!(ppu.mirror_vram_addr(0x3C05), 0xC05);
+    }
+
+    // ミラーリングを外から切り替えられるマッパー
+    struct SwitchingMapper {
+        mirroring: Option<Mirroring>,
+    }
+
+    impl SaveState for SwitchingMapper {
+        fn write_state(&self, _writer: &mut StateWriter) {}
+
+        fn read_state(&mut self, _reader: &mut StateReader) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    impl Mapper for SwitchingMapper {
+        fn read_prg(&self, _addr: u16) -> u8 {
+            0
+        }
+        fn write_prg(&mut self, _addr: u16, _data: u8) {}
+        fn read_chr(&self, _addr: u16) -> u8 {
+            0
+        }
+        fn write_chr(&mut self, _addr: u16, _data: u8) {}
+
+        fn mirroring(&self) -> Option<Mirroring> {
+            self.mirroring
+        }
+    }
+
+    #[test]
+    fn test_mapper_switches_to_single_screen() {
+        let mapper = Rc::new(RefCell::new(SwitchingMapper { mirroring: None }));
+        let mut ppu = NesPPU::new_with_mapper(mapper.clone(), Mirroring::VERTICAL, Region::NTSC);
+
+        // vertical: $2000と$2400は別
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x77);
+        assert_eq!(ppu.vram[0x005], 0x66);
+        assert_eq!(ppu.vram[0x405], 0x77);
+
+        mapper.borrow_mut().mirroring = Some(Mirroring::SINGLE_SCREEN_LOWER);
+        assert_eq!(ppu.current_mirroring(), Mirroring::SINGLE_SCREEN_LOWER);
+        for hi in [0x20, 0x24, 0x28, 0x2C] {
+            ppu.write_to_ppu_addr(hi);
+            ppu.write_to_ppu_addr(0x05);
+            ppu.read_data(); //load into buffer
+            assert_eq!(ppu.read_data(), 0x66);
+        }
+
+        mapper.borrow_mut().mirroring = Some(Mirroring::SINGLE_SCREEN_UPPER);
+        ppu.write_to_ppu_addr(0x28);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x77);
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -439,6 +1061,134 @@ pub mod test {
         assert_eq!(ppu.read_oam_data(), 0x77);
     }
 
+    // tile 1: 全ピクセルが不透明
+    fn test_ppu() -> NesPPU {
+        let mut chr_rom = vec![0; 0x2000];
+        for i in 0..8 {
+            chr_rom[16 + i] = 0xFF;
+        }
+        NesPPU::new(chr_rom, Mirroring::HORIZONTAL)
+    }
+
+    fn tick_scanline(ppu: &mut NesPPU) {
+        ppu.tick(200);
+        ppu.tick(141);
+    }
+
+    #[test]
+    fn test_dump_vram() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::VERTICAL);
+        ppu.vram[0x005] = 0xAB;
+        ppu.vram[0x3C1] = 0xCD;
+        ppu.vram[0x7FF] = 0xEF;
+        ppu.palette_table[0x11] = 0x2A;
+        ppu.oam_data[4] = 0x55;
+        let path = std::env::temp_dir().join("hobby_nes_emulator_test_vram.txt");
+        ppu.dump_vram(&path).unwrap();
+        let dump = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let pos = |text: &str| dump.find(text).unwrap();
+        assert!(dump.contains("[nametable 0] vram $000 ($2000 $2800)\n"));
+        assert!(dump.contains("[nametable 1] vram $400 ($2400 $2C00)\n"));
+        assert!(!dump.contains("[nametable 2]"));
+        // 1枚目のタイル, 1枚目の属性テーブル, 2枚目の属性テーブルの最後
+        let tiles = pos("0000: 00 00 00 00 00 AB 00");
+        assert!(pos("tiles:") < tiles && tiles < pos("attributes:"));
+        let attributes = pos("03C0: 00 CD 00");
+        assert!(pos("attributes:") < attributes && attributes < pos("[nametable 1]"));
+        let last = pos(&format!("07F0: {}EF\n", "00 ".repeat(15)));
+        assert!(pos("[nametable 1]") < last && last < pos("[palette]"));
+        assert!(pos("[palette]") < pos("3F10: 00 2A 00"));
+        assert!(pos("[oam]") < pos("0000: 00 00 00 00 55 00"));
+
+        let ppu = NesPPU::new(vec![0; 2048], Mirroring::FOUR_SCREEN);
+        assert!(ppu
+            .vram_dump()
+            .contains("[nametable 3] vram $C00 ($2C00)\n"));
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let mut ppu = test_ppu();
+        // 背景タイル(列2, 行5) = 画面(16..24, 40..48)にスプライト0を重ねる
+        ppu.vram[5 * 32 + 2] = 1;
+        ppu.oam_data[0] = 40;
+        ppu.oam_data[1] = 1;
+        ppu.oam_data[3] = 16;
+        ppu.write_to_mask(0b0001_1110);
+
+        for _ in 0..40 {
+            tick_scanline(&mut ppu);
+        }
+        assert!(!ppu.status.check_sprite_zero_hit());
+
+        ppu.tick(16);
+        assert!(!ppu.status.check_sprite_zero_hit());
+        ppu.tick(1);
+        assert!(ppu.status.check_sprite_zero_hit());
+
+        // pre-renderラインでクリアされる
+        ppu.tick(200);
+        for _ in 40..261 {
+            tick_scanline(&mut ppu);
+        }
+        assert_eq!((ppu.scanline(), ppu.dot()), (261, 217));
+        assert!(!ppu.status.check_sprite_zero_hit());
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_needs_opaque_background() {
+        let mut ppu = test_ppu();
+        ppu.oam_data[0] = 40;
+        ppu.oam_data[1] = 1;
+        ppu.oam_data[3] = 16;
+        ppu.write_to_mask(0b0001_1110);
+
+        for _ in 0..48 {
+            tick_scanline(&mut ppu);
+        }
+        assert!(!ppu.status.check_sprite_zero_hit());
+    }
+
+    fn place_sprites(ppu: &mut NesPPU, count: usize, y: u8) {
+        for i in 0..64 {
+            ppu.oam_data[i * 4] = if i < count { y } else { 0xFF };
+        }
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = test_ppu();
+        place_sprites(&mut ppu, 9, 50);
+        ppu.write_to_mask(0b0001_1000);
+
+        for _ in 0..50 {
+            tick_scanline(&mut ppu);
+        }
+        assert!(!ppu.status.check_sprite_overflow());
+        tick_scanline(&mut ppu);
+        assert!(ppu.status.check_sprite_overflow());
+
+        // pre-renderラインでクリアされる
+        for _ in 51..262 {
+            tick_scanline(&mut ppu);
+        }
+        assert!(!ppu.status.check_sprite_overflow());
+    }
+
+    #[test]
+    fn test_sprite_overflow_eight_sprites() {
+        let mut ppu = test_ppu();
+        place_sprites(&mut ppu, 8, 50);
+        ppu.write_to_mask(0b0001_1000);
+
+        for _ in 0..60 {
+            tick_scanline(&mut ppu);
+        }
+        assert!(!ppu.status.check_sprite_overflow());
+    }
+
     #[test]
     fn test_oam_dma() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -459,4 +1209,175 @@ pub mod test {
         ppu.write_to_oam_addr(0x11);
         assert_eq!(ppu.read_oam_data(), 0x66);
     }
+
+    #[test]
+    fn test_pal_frame_timing() {
+        let mut ppu = test_ppu();
+        ppu.region = Region::PAL;
+        ppu.write_to_ctrl(0b1000_0000);
+
+        for _ in 0..240 {
+            tick_scanline(&mut ppu);
+        }
+        assert!(!ppu.status.check_vblank_started());
+        tick_scanline(&mut ppu);
+        assert_eq!(ppu.scanline(), 241);
+        // VBlankはdot 1で立つ
+        assert!(!ppu.status.check_vblank_started());
+        ppu.tick(1);
+        assert!(ppu.status.check_vblank_started());
+        assert!(ppu.nmi_interrupt.is_some());
+
+        // NTSCならここでフレームが終わる
+        let mut lines = 241;
+        loop {
+            ppu.tick(200);
+            lines += 1;
+            if ppu.tick(141) {
+                break;
+            }
+            // VBlankはpre-renderライン(311)のdot 1で消える
+            assert_eq!(
+                ppu.status.check_vblank_started(),
+                ppu.scanline() != ppu.region.pre_render_scanline()
+            );
+        }
+        assert_eq!(lines, 312);
+        assert_eq!(ppu.scanline(), 0);
+        assert!(!ppu.status.check_vblank_started());
+    }
+
+    #[test]
+    fn test_scanline_and_dot() {
+        let mut ppu = test_ppu();
+        assert_eq!((ppu.scanline(), ppu.dot()), (0, 0));
+        ppu.tick(100);
+        assert_eq!((ppu.scanline(), ppu.dot()), (0, 100));
+        // 341ドットで次のライン
+        ppu.tick(241);
+        assert_eq!((ppu.scanline(), ppu.dot()), (1, 0));
+        for _ in 1..261 {
+            tick_scanline(&mut ppu);
+        }
+        ppu.tick(5);
+        assert_eq!((ppu.scanline(), ppu.dot()), (261, 5));
+        // pre-render lineが終わるとフレームの先頭に戻る (描画していないのでドットは飛ばさない)
+        ppu.tick(200);
+        assert!(ppu.tick(136));
+        assert_eq!((ppu.scanline(), ppu.dot()), (0, 0));
+    }
+
+    // フレームが終わるまでのドット数
+    fn frame_dots(ppu: &mut NesPPU) -> usize {
+        let mut dots = 1;
+        while !ppu.tick(1) {
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn test_odd_frame_skips_a_dot() {
+        let mut ppu = test_ppu();
+        ppu.write_to_mask(0b0000_1000);
+        // 1フレーム目(偶数)は341 * 262ドット, 2フレーム目(奇数)はpre-render lineが340ドット
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262 - 1);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+
+        // 描画していなければ飛ばさない
+        ppu.write_to_mask(0);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+
+        // PALは飛ばさない
+        let mut ppu = test_ppu();
+        ppu.region = Region::PAL;
+        ppu.write_to_mask(0b0000_1000);
+        assert_eq!(frame_dots(&mut ppu), 341 * 312);
+        assert_eq!(frame_dots(&mut ppu), 341 * 312);
+    }
+
+    // スキャンライン241のdot 0まで進める
+    fn tick_to_vblank_line(ppu: &mut NesPPU) {
+        for _ in 0..241 {
+            tick_scanline(ppu);
+        }
+        assert_eq!((ppu.scanline(), ppu.dot()), (241, 0));
+    }
+
+    #[test]
+    fn test_vblank_flags_at_exact_dots() {
+        let mut ppu = test_ppu();
+        ppu.write_to_ctrl(0b1000_0000);
+        tick_to_vblank_line(&mut ppu);
+        assert!(!ppu.status.check_vblank_started());
+        assert!(ppu.nmi_interrupt.is_none());
+        ppu.tick(1);
+        assert_eq!((ppu.scanline(), ppu.dot()), (241, 1));
+        assert!(ppu.status.check_vblank_started());
+        assert!(ppu.nmi_interrupt.is_some());
+
+        // 1回のtickが(241, 1)をまたいでも立つ
+        let mut ppu = test_ppu();
+        for _ in 0..240 {
+            tick_scanline(&mut ppu);
+        }
+        ppu.tick(200);
+        ppu.tick(140);
+        assert!(!ppu.status.check_vblank_started());
+        ppu.tick(3);
+        assert_eq!((ppu.scanline(), ppu.dot()), (241, 2));
+        assert!(ppu.status.check_vblank_started());
+
+        // pre-renderラインのdot 1で消える
+        for _ in 241..261 {
+            tick_scanline(&mut ppu);
+        }
+        assert_eq!((ppu.scanline(), ppu.dot()), (261, 2));
+        assert!(!ppu.status.check_vblank_started());
+    }
+
+    #[test]
+    fn test_vblank_nmi_race() {
+        // 2ドット以上前に読めば普通にVBlankとNMIが来る
+        let mut ppu = test_ppu();
+        ppu.write_to_ctrl(0b1000_0000);
+        for _ in 0..240 {
+            tick_scanline(&mut ppu);
+        }
+        ppu.tick(200);
+        ppu.tick(140);
+        assert_eq!(ppu.read_status() & 0x80, 0);
+        ppu.tick(2);
+        assert!(ppu.status.check_vblank_started());
+        assert!(ppu.poll_nmi_interrupt().is_some());
+
+        // 1ドット前: フラグは0で, このフレームはフラグもNMIも立たない
+        let mut ppu = test_ppu();
+        ppu.write_to_ctrl(0b1000_0000);
+        tick_to_vblank_line(&mut ppu);
+        assert_eq!(ppu.read_status() & 0x80, 0);
+        ppu.tick(10);
+        assert!(!ppu.status.check_vblank_started());
+        assert!(ppu.poll_nmi_interrupt().is_none());
+
+        // 同じドット/1ドット後: フラグは1で読めるがNMIは起きない
+        for late in [1, 2] {
+            let mut ppu = test_ppu();
+            ppu.write_to_ctrl(0b1000_0000);
+            tick_to_vblank_line(&mut ppu);
+            ppu.tick(late);
+            assert_eq!(ppu.read_status() & 0x80, 0x80);
+            assert!(ppu.poll_nmi_interrupt().is_none());
+        }
+
+        // 2ドット後ならNMIはそのまま
+        let mut ppu = test_ppu();
+        ppu.write_to_ctrl(0b1000_0000);
+        tick_to_vblank_line(&mut ppu);
+        ppu.tick(3);
+        assert_eq!(ppu.read_status() & 0x80, 0x80);
+        assert!(ppu.poll_nmi_interrupt().is_some());
+    }
 }
@@ -58,7 +58,7 @@ impl ControlRegister {
         } else {
             0x0000
         }
-        // TODO ignore 8*16 mode
+        // 8x16モードでは使わない (タイル番号のbit0で決まる)
     }
 
     pub fn backround_pattern_addr(&self) -> u16 {
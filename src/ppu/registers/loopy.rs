@@ -0,0 +1,253 @@
+// PPU内部のVRAMアドレスレジスタ (通称loopyレジスタ)
+// $2005(スクロール)と$2006(アドレス)は別々のレジスタではなく, 同じt/wを共有している
+// - v: 今のVRAMアドレス ($2007の読み書きはここ. 描画中はスクロール位置)
+// - t: 一時アドレス (画面左上のスクロール位置). $2006の2回目の書き込みでvにコピーされる
+// - x: fine X scroll (3bit)
+// - w: $2005/$2006共通の書き込みトグル. $2002を読むと0に戻る
+//
+// v, tは15bit:
+// yyy NN YYYYY XXXXX
+// ||| || ||||| +++++-- coarse X scroll
+// ||| || +++++-------- coarse Y scroll
+// ||| ++-------------- nametable select
+// +++----------------- fine Y scroll
+
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
+pub struct LoopyRegister {
+    pub v: u16,
+    pub t: u16,
+    pub x: u8,
+    pub w: bool,
+}
+
+impl LoopyRegister {
+    pub fn new() -> Self {
+        LoopyRegister {
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+        }
+    }
+
+    // $2000: t: ...GH.. ........ <- d: ......GH
+    pub fn write_ctrl(&mut self, data: u8) {
+        self.t = (self.t & !0x0C00) | ((data as u16 & 0b11) << 10);
+    }
+
+    // $2005
+    // 1回目: t: ....... ...ABCDE <- d: ABCDE...
+    //        x:              FGH <- d: .....FGH
+    // 2回目: t: FGH..AB CDE..... <- d: ABCDEFGH
+    pub fn write_scroll(&mut self, data: u8) {
+        if !self.w {
+            self.t = (self.t & !0x001F) | (data as u16 >> 3);
+            self.x = data & 0b111;
+        } else {
+            self.t = (self.t & !0x73E0) | ((data as u16 & 0b111) << 12) | ((data as u16 >> 3) << 5);
+        }
+        self.w = !self.w;
+    }
+
+    // $2006
+    // 1回目: t: .CDEFGH ........ <- d: ..CDEFGH (bit14は0になる)
+    // 2回目: t: ....... ABCDEFGH <- d: ABCDEFGH, v = t
+    pub fn write_addr(&mut self, data: u8) {
+        if !self.w {
+            self.t = (self.t & 0x00FF) | ((data as u16 & 0x3F) << 8);
+        } else {
+            self.t = (self.t & 0xFF00) | data as u16;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+
+    // $2007を読み書きしたあと
+    pub fn increment(&mut self, inc: u8) {
+        self.v = self.v.wrapping_add(inc as u16) & 0x7FFF;
+    }
+
+    pub fn reset_latch(&mut self) {
+        self.w = false;
+    }
+
+    // 描画中のdot 256: fine Yを1つ進める. 29行目の次は下のネームテーブルへ
+    // (31行目の次は属性テーブルを通って同じネームテーブルの0行目に戻る)
+    pub fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v >> 5) & 0x1F;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // 描画中のdot 257: v: ....A.. ...BCDEF <- t: ....A.. ...BCDEF
+    pub fn copy_horizontal(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    // pre-render lineのdot 280~304: v: GHIA.BC DEF..... <- t: GHIA.BC DEF.....
+    pub fn copy_vertical(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    // $2007でアクセスするアドレス (PPUのアドレス空間は14bit)
+    pub fn vram_addr(&self) -> u16 {
+        self.v & 0x3FFF
+    }
+
+    // tから見たスクロール位置 (ピクセル単位)
+    pub fn scroll_x(&self) -> u8 {
+        ((self.t & 0x1F) << 3) as u8 | self.x
+    }
+
+    pub fn scroll_y(&self) -> u8 {
+        (((self.t >> 5) & 0x1F) << 3) as u8 | ((self.t >> 12) & 0b111) as u8
+    }
+
+    // tのネームテーブル ($2000, $2400, $2800, $2C00)
+    pub fn nametable_addr(&self) -> u16 {
+        0x2000 | (self.t & 0x0C00)
+    }
+}
+
+impl Default for LoopyRegister {
+    fn default() -> Self {
+        LoopyRegister::new()
+    }
+}
+
+impl SaveState for LoopyRegister {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.v);
+        writer.write_u16(self.t);
+        writer.write_u8(self.x);
+        writer.write_bool(self.w);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.v = reader.read_u16()?;
+        self.t = reader.read_u16()?;
+        self.x = reader.read_u8()?;
+        self.w = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(fine_y: u16, nametable: u16, coarse_y: u16, coarse_x: u16) -> u16 {
+        fine_y << 12 | nametable << 10 | coarse_y << 5 | coarse_x
+    }
+
+    #[test]
+    fn test_scroll_and_addr_writes() {
+        // nesdevのPPU scrollingにある書き込み例
+        let mut loopy = LoopyRegister::new();
+        loopy.write_ctrl(0b00);
+        assert_eq!(loopy.t & 0x0C00, 0);
+
+        // $2002を読んでから$2005に2回
+        loopy.reset_latch();
+        loopy.write_scroll(0b0111_1101);
+        assert_eq!(loopy.t, addr(0b000, 0b00, 0b00000, 0b01111));
+        assert_eq!(loopy.x, 0b101);
+        assert!(loopy.w);
+        loopy.write_scroll(0b0101_1110);
+        assert_eq!(loopy.t, addr(0b110, 0b00, 0b01011, 0b01111));
+        assert!(!loopy.w);
+        assert_eq!(loopy.scroll_x(), 0b0111_1101);
+        assert_eq!(loopy.scroll_y(), 0b0101_1110);
+
+        // $2006に2回. 2回目でvに入る
+        loopy.write_addr(0b0011_1101);
+        assert_eq!(loopy.t, addr(0b011, 0b11, 0b01011, 0b01111));
+        assert_eq!(loopy.v, 0);
+        loopy.write_addr(0b1111_0000);
+        assert_eq!(loopy.t, addr(0b011, 0b11, 0b01111, 0b10000));
+        assert_eq!(loopy.v, loopy.t);
+    }
+
+    #[test]
+    fn test_shared_write_toggle() {
+        // $2005の1回目のあとの$2006は2回目の書き込みになる
+        let mut loopy = LoopyRegister::new();
+        loopy.write_scroll(0x08);
+        loopy.write_addr(0x45);
+        assert!(!loopy.w);
+        assert_eq!(loopy.v, 0x0045);
+
+        // 途中でラッチを戻すと上位バイトからやり直し
+        loopy.write_addr(0x23);
+        loopy.reset_latch();
+        loopy.write_addr(0x21);
+        loopy.write_addr(0x00);
+        assert_eq!(loopy.v, 0x2100);
+    }
+
+    #[test]
+    fn test_split_scroll_sequence() {
+        // 画面途中でスクロールを変える定番の書き込み順: $2006, $2005, $2005, $2006
+        // ネームテーブル1, coarse Y 3, fine Y 5, coarse X 4, fine X 2
+        let mut loopy = LoopyRegister::new();
+        loopy.write_addr(0b0000_0100);
+        loopy.write_scroll(0b0001_1101);
+        loopy.write_scroll(0b0010_0010);
+        loopy.write_addr(0b0110_0100);
+        assert_eq!(loopy.v, addr(0b101, 0b01, 0b00011, 0b00100));
+        assert_eq!(loopy.x, 0b010);
+    }
+
+    #[test]
+    fn test_increment_y() {
+        let mut loopy = LoopyRegister::new();
+        loopy.v = addr(0b110, 0b00, 28, 3);
+        loopy.increment_y();
+        assert_eq!(loopy.v, addr(0b111, 0b00, 28, 3));
+        loopy.increment_y();
+        assert_eq!(loopy.v, addr(0, 0b00, 29, 3));
+        // 29行目の次は下のネームテーブル
+        loopy.v = addr(0b111, 0b01, 29, 3);
+        loopy.increment_y();
+        assert_eq!(loopy.v, addr(0, 0b11, 0, 3));
+        // 31行目(属性テーブル)の次は同じネームテーブル
+        loopy.v = addr(0b111, 0b01, 31, 3);
+        loopy.increment_y();
+        assert_eq!(loopy.v, addr(0, 0b01, 0, 3));
+    }
+
+    #[test]
+    fn test_copy_from_t() {
+        let mut loopy = LoopyRegister::new();
+        loopy.t = addr(0b101, 0b11, 12, 7);
+        loopy.copy_horizontal();
+        assert_eq!(loopy.v, addr(0, 0b01, 0, 7));
+        loopy.copy_vertical();
+        assert_eq!(loopy.v, loopy.t);
+    }
+
+    #[test]
+    fn test_increment_wraps_15_bits() {
+        let mut loopy = LoopyRegister::new();
+        loopy.write_addr(0x3F);
+        loopy.write_addr(0xFF);
+        loopy.increment(1);
+        assert_eq!(loopy.vram_addr(), 0x0000);
+        loopy.v = 0x7FFF;
+        loopy.increment(32);
+        assert_eq!(loopy.v, 0x001F);
+    }
+}
@@ -23,6 +23,7 @@ bitflags! {
     }
 }
 
+#[derive(Debug, PartialEq)]
 pub enum Color {
     Red,
     Green,
@@ -1,6 +1,5 @@
-pub mod addr;
 pub mod control;
+pub mod loopy;
 pub mod mask;
 pub mod oam;
-pub mod scroll;
 pub mod status;
@@ -1,3 +1,5 @@
+use crate::save_state::{SaveState, StateReader, StateWriter};
+
 pub struct OamRegisters {
     pub oam_addr: u8,
     pub oam_data: [u8; 256],
@@ -27,7 +29,19 @@ impl OamRegisters {
         }
     }
 
-    pub fn get_data(&mut self) -> u8 {
+    pub fn get_data(&self) -> u8 {
         self.oam_data[self.oam_addr as usize]
     }
 }
+
+impl SaveState for OamRegisters {
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.oam_addr);
+        writer.write_bytes(&self.oam_data);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.oam_addr = reader.read_u8()?;
+        reader.read_bytes(&mut self.oam_data)
+    }
+}
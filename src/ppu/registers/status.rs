@@ -49,6 +49,14 @@ impl StatusRegister {
         self.set(StatusRegister::VBLANK_STARTED, flag);
     }
 
+    pub fn check_sprite_overflow(&self) -> bool {
+        self.contains(StatusRegister::SPRITE_OVERFLOW)
+    }
+
+    pub fn check_sprite_zero_hit(&self) -> bool {
+        self.contains(StatusRegister::SPRITE_ZERO_HIT)
+    }
+
     pub fn check_vblank_started(&self) -> bool {
         self.contains(StatusRegister::VBLANK_STARTED)
     }
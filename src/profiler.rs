@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+// 命令ごとの実行回数 (PCごと, ニーモニックごと) とニーモニックごとのサイクル数
+// CPU::enable_profiling(true) のときだけ作られる
+pub struct Profiler {
+    pc_counts: HashMap<u16, u64>,
+    // (実行回数, サイクル数)
+    mnemonics: HashMap<&'static str, (u64, u64)>,
+    // 直前に数えた命令 (サイクル数は実行し終わってから分かる)
+    current: Option<&'static str>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            pc_counts: HashMap::new(),
+            mnemonics: HashMap::new(),
+            current: None,
+        }
+    }
+
+    // オペコードを読んだ直後に呼ぶ
+    pub fn count_instruction(&mut self, pc: u16, mnemonic: &'static str) {
+        *self.pc_counts.entry(pc).or_insert(0) += 1;
+        self.mnemonics.entry(mnemonic).or_insert((0, 0)).0 += 1;
+        self.current = Some(mnemonic);
+    }
+
+    // 命令を実行し終わったら, かかったサイクル数を足す
+    pub fn add_cycles(&mut self, cycles: usize) {
+        if let Some(mnemonic) = self.current.take() {
+            self.mnemonics.entry(mnemonic).or_insert((0, 0)).1 += cycles as u64;
+        }
+    }
+
+    // (PC, 実行回数) を回数の多い順に
+    pub fn pc_report(&self) -> Vec<(u16, u64)> {
+        let mut report: Vec<(u16, u64)> = self.pc_counts.iter().map(|(k, v)| (*k, *v)).collect();
+        report.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        report
+    }
+
+    // (ニーモニック, 実行回数, サイクル数) をサイクル数の多い順に
+    pub fn mnemonic_report(&self) -> Vec<(&'static str, u64, u64)> {
+        let mut report: Vec<(&'static str, u64, u64)> = self
+            .mnemonics
+            .iter()
+            .map(|(k, (count, cycles))| (*k, *count, *cycles))
+            .collect();
+        report.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)));
+        report
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reports_are_sorted() {
+        let mut profiler = Profiler::new();
+        for (pc, mnemonic, cycles) in [
+            (0x8000, "LDA", 2),
+            (0x8002, "INX", 2),
+            (0x8003, "BNE", 3),
+            (0x8002, "INX", 2),
+            (0x8003, "BNE", 3),
+        ] {
+            profiler.count_instruction(pc, mnemonic);
+            profiler.add_cycles(cycles);
+        }
+
+        assert_eq!(
+            profiler.pc_report(),
+            vec![(0x8002, 2), (0x8003, 2), (0x8000, 1)]
+        );
+        assert_eq!(
+            profiler.mnemonic_report(),
+            vec![("BNE", 2, 6), ("INX", 2, 4), ("LDA", 1, 2)]
+        );
+    }
+}
@@ -1,24 +1,102 @@
 // ゲーム画面レンダリング用
+// dataはRGB24で, 1行の長さ(pitch)は width * 3
 pub struct Frame {
     pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
 }
 
+// NTSCの画素は横長 (8:7)
+const NTSC_PIXEL_ASPECT: f64 = 8.0 / 7.0;
+
 impl Frame {
-    const WIDTH: usize = 256 * 2;
-    const HIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = 240;
 
     pub fn new() -> Self {
+        Frame::with_dimensions(Frame::WIDTH, Frame::HEIGHT)
+    }
+
+    pub fn with_dimensions(width: usize, height: usize) -> Self {
         Frame {
-            data: vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3],
+            data: vec![0; width * height * 3],
+            width,
+            height,
         }
     }
 
+    // SDLのtexture.updateに渡す1行のバイト数
+    pub fn pitch(&self) -> usize {
+        self.width * 3
+    }
+
+    // 範囲外は無視する (スクロール中のタイルが画面外にはみ出すことがある)
     pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
-        let base = y * 3 * Frame::WIDTH + x * 3;
-        if base + 2 < self.data.len() {
-            self.data[base] = rgb.0;
-            self.data[base + 1] = rgb.1;
-            self.data[base + 2] = rgb.2;
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let base = y * self.pitch() + x * 3;
+        self.data[base] = rgb.0;
+        self.data[base + 1] = rgb.1;
+        self.data[base + 2] = rgb.2;
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = y * self.pitch() + x * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    // 最近傍で任意のサイズに引き伸ばす
+    pub fn stretch(&self, width: usize, height: usize) -> Frame {
+        let mut frame = Frame::with_dimensions(width, height);
+        for y in 0..height {
+            let src_y = y * self.height / height;
+            for x in 0..width {
+                let src_x = x * self.width / width;
+                frame.set_pixel(x, y, self.get_pixel(src_x, src_y));
+            }
         }
+        frame
+    }
+
+    // scale倍して, 横をNTSCの画素比に合わせて伸ばす (256x240, scale 1 -> 293x240)
+    pub fn to_ntsc_aspect(&self, scale: usize) -> Frame {
+        let width = (self.width as f64 * scale as f64 * NTSC_PIXEL_ASPECT).round() as usize;
+        self.stretch(width, self.height * scale)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pitch_matches_width() {
+        let mut frame = Frame::with_dimensions(300, 10);
+        assert_eq!(frame.pitch(), 300 * 3);
+        assert_eq!(frame.data.len(), frame.pitch() * 10);
+
+        frame.set_pixel(299, 0, (1, 2, 3));
+        frame.set_pixel(0, 1, (4, 5, 6));
+        assert_eq!(&frame.data[299 * 3..300 * 3], &[1, 2, 3]);
+        assert_eq!(&frame.data[frame.pitch()..frame.pitch() + 3], &[4, 5, 6]);
+
+        // 範囲外は次の行に回り込まない
+        frame.set_pixel(300, 0, (7, 8, 9));
+        frame.set_pixel(0, 10, (7, 8, 9));
+        assert_eq!(frame.get_pixel(0, 1), (4, 5, 6));
+        assert!(!frame.data.contains(&7));
+    }
+
+    #[test]
+    fn test_ntsc_aspect() {
+        let mut frame = Frame::new();
+        frame.set_pixel(255, 239, (1, 2, 3));
+        let stretched = frame.to_ntsc_aspect(2);
+        assert_eq!(stretched.width, 585);
+        assert_eq!(stretched.height, 480);
+        assert_eq!(stretched.data.len(), stretched.pitch() * 480);
+        assert_eq!(stretched.get_pixel(584, 479), (1, 2, 3));
+        assert_eq!(stretched.get_pixel(0, 0), (0, 0, 0));
     }
 }
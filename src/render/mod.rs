@@ -1,45 +1,31 @@
 pub mod frame;
+pub mod overlay;
 pub mod palette;
+pub mod png;
+pub mod viewer;
 
-use crate::cartridge::Mirroring;
+use crate::cartridge::Region;
+use crate::ppu::registers::mask::Color;
 use crate::ppu::NesPPU;
 use frame::Frame;
+use palette::Palette;
 
-struct Rect {
-    x1: usize,
-    y1: usize,
-    x2: usize,
-    y2: usize,
-}
-
-impl Rect {
-    fn new(x1: usize, y1: usize, x2: usize, y2: usize) -> Self {
-        Rect {
-            x1: x1,
-            y1: y1,
-            x2: x2,
-            y2: y2,
-        }
-    }
-}
-
+// ネームテーブル1枚(32x30タイル)をframeの(origin_x, origin_y)から描く (ネームテーブルビューア用)
 fn render_name_table(
     ppu: &NesPPU,
+    system_palette: &Palette,
     frame: &mut Frame,
     name_table: &[u8],
-    view_port: Rect,
-    shift_x: isize,
-    shift_y: isize,
+    origin_x: usize,
+    origin_y: usize,
 ) {
     let bank = ppu.ctrl.backround_pattern_addr();
     let attribute_table = &name_table[0x3c0..0x400];
 
-    for i in 0..0x3c0 {
+    for (i, &tile_idx) in name_table[..0x3c0].iter().enumerate() {
         let tile_column = i % 32;
         let tile_row = i / 32;
-        let tile_idx = name_table[i] as u16;
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = fetch_tile(ppu, bank, tile_idx as u16);
         let palette = bg_palette(ppu, attribute_table, tile_column, tile_row);
 
         for y in 0..=7 {
@@ -48,35 +34,50 @@ fn render_name_table(
 
             for x in (0..=7).rev() {
                 let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
+                upper >>= 1;
+                lower >>= 1;
                 let rgb = match value {
-                    0 => palette::SYSTEM_PALETTE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALETTE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALETTE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALETTE[palette[3] as usize],
+                    0 => color(ppu, system_palette, ppu.palette_table[0]),
+                    1 => color(ppu, system_palette, palette[1]),
+                    2 => color(ppu, system_palette, palette[2]),
+                    3 => color(ppu, system_palette, palette[3]),
                     _ => panic!("cant be"),
                 };
-                let pixel_x = tile_column * 8 + x;
-                let pixel_y = tile_row * 8 + y;
-
-                if pixel_x >= view_port.x1
-                    && pixel_x < view_port.x2
-                    && pixel_y >= view_port.y1
-                    && pixel_y < view_port.y2
-                {
-                    frame.set_pixel(
-                        (shift_x + pixel_x as isize) as usize,
-                        (shift_y + pixel_y as isize) as usize,
-                        rgb,
-                    );
-                }
+                frame.set_pixel(
+                    origin_x + tile_column * 8 + x,
+                    origin_y + tile_row * 8 + y,
+                    rgb,
+                );
             }
         }
     }
 }
 
-pub fn render(ppu: &NesPPU, frame: &mut Frame) {
+// 実機どおりにするか, 見やすくするかの設定
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RenderOptions {
+    // 1ラインに9個目以降のスプライトを描かない (実機ではこれでちらつく)
+    // falseでも$2002のスプライトオーバーフローは変わらない (PPU側で数える)
+    pub sprite_limit: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions { sprite_limit: true }
+    }
+}
+
+// system_paletteは画面に出すRGB (.palで差し替えられる)
+pub fn render(ppu: &NesPPU, frame: &mut Frame, system_palette: &Palette) {
+    render_with_options(ppu, frame, system_palette, &RenderOptions::default());
+}
+
+pub fn render_with_options(
+    ppu: &NesPPU,
+    frame: &mut Frame,
+    system_palette: &Palette,
+    options: &RenderOptions,
+) {
     // let bank = ppu.ctrl.backround_pattern_addr();
 
     // // background rendering
@@ -106,40 +107,117 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
     // 	    }
     // 	}
     // }
-    let scroll_x = (ppu.scroll.h_scroll) as usize;
-    let scroll_y = (ppu.scroll.v_scroll) as usize;
+    let backdrop = color(ppu, system_palette, ppu.palette_table[0]);
+    for y in 0..240 {
+        for x in 0..256 {
+            frame.set_pixel(x, y, backdrop);
+        }
+    }
 
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.base_nametable_addr())
-    {
-        (Mirroring::VERTICAL, 0x2000) | (Mirroring::VERTICAL, 0x2800) => {
-            (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800])
+    // 背景が不透明(カラー0以外)なピクセル. 背面スプライトの判定に使う
+    let mut bg_opaque = vec![false; 256 * 240];
+
+    if ppu.mask.check_show_background() {
+        render_background(ppu, system_palette, frame, &mut bg_opaque);
+        // 左端8ピクセルを隠す
+        if !ppu.mask.check_show_background_left() {
+            for y in 0..240 {
+                for x in 0..8 {
+                    frame.set_pixel(x, y, backdrop);
+                    bg_opaque[y * 256 + x] = false;
+                }
+            }
         }
-        (Mirroring::VERTICAL, 0x2400) | (Mirroring::VERTICAL, 0x2C00) => {
-            (&ppu.vram[0x400..0x800], &ppu.vram[0..0x400])
+    }
+
+    if ppu.mask.check_show_sprites() {
+        let hidden = if options.sprite_limit {
+            sprites_over_limit(ppu)
+        } else {
+            vec![false; 64 * 240]
+        };
+        render_sprites(ppu, system_palette, frame, &bg_opaque, &hidden);
+    }
+}
+
+// 1ラインに描けるスプライトの数
+const SPRITES_PER_LINE: usize = 8;
+
+// [スプライト番号 * 240 + y] が, そのラインで9個目以降になって描かれないか
+// OAMの番号が小さい方から8個まで (PPUのスプライト評価と同じ順)
+fn sprites_over_limit(ppu: &NesPPU) -> Vec<bool> {
+    let size = ppu.ctrl.sprite_size() as usize;
+    let mut count = [0; 240];
+    let mut hidden = vec![false; 64 * 240];
+    for (i, sprite) in ppu.oam_data.chunks(4).enumerate() {
+        let top = sprite[0] as usize;
+        for y in top..(top + size).min(240) {
+            count[y] += 1;
+            hidden[i * 240 + y] = count[y] > SPRITES_PER_LINE;
         }
-        (_, _) => (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800]),
-    };
+    }
+    hidden
+}
 
-    render_name_table(
-        ppu,
-        frame,
-        main_nametable,
-        Rect::new(scroll_x, scroll_y, 256, 240),
-        -(scroll_x as isize),
-        -(scroll_y as isize),
-    );
-
-    render_name_table(
-        ppu,
-        frame,
-        second_nametable,
-        Rect::new(0, 0, scroll_x, 240),
-        (256 - scroll_x) as isize,
-        0,
-    );
+// スキャンラインごとに, その行の始めに覚えたスクロール位置とパターンテーブルで描く
+// (画面の途中でスクロールを変えるステータスバーなどはこれで分かれて見える)
+fn render_background(
+    ppu: &NesPPU,
+    system_palette: &Palette,
+    frame: &mut Frame,
+    bg_opaque: &mut [bool],
+) {
+    let backdrop = color(ppu, system_palette, ppu.palette_table[0]);
+    for y in 0..240 {
+        let latch = ppu.scanline_latch(y);
+        let scroll_x = latch.scroll_x();
+        let (tile_row, fine_y) = (latch.y() / 8, latch.y() % 8);
 
-    // sprite rendering
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
+        // 1行は最大33タイルにまたがる. 右端を越えたら隣のネームテーブル
+        for i in 0..=32 {
+            let left = scroll_x / 8 * 8 + i * 8;
+            let (addr, tile_column) = if left >= 256 {
+                (latch.nametable_addr() ^ 0x400, (left - 256) / 8)
+            } else {
+                (latch.nametable_addr(), left / 8)
+            };
+            let name_table = name_table(ppu, addr);
+            let tile_idx = name_table[tile_row * 32 + tile_column] as u16;
+            let palette = bg_palette(ppu, &name_table[0x3c0..0x400], tile_column, tile_row);
+            let chr_addr = latch.bg_pattern_addr + tile_idx * 16 + fine_y as u16;
+            let plane0 = ppu.read_chr(chr_addr);
+            let plane1 = ppu.read_chr(chr_addr + 8);
+
+            for bit in 0..8 {
+                if left + bit < scroll_x || left + bit - scroll_x >= 256 {
+                    continue;
+                }
+                let x = left + bit - scroll_x;
+                let value = (plane1 >> (7 - bit) & 1) << 1 | (plane0 >> (7 - bit) & 1);
+                let rgb = match value {
+                    0 => backdrop,
+                    _ => color(ppu, system_palette, palette[value as usize]),
+                };
+                frame.set_pixel(x, y, rgb);
+                bg_opaque[y * 256 + x] = value != 0;
+            }
+        }
+    }
+}
+
+fn render_sprites(
+    ppu: &NesPPU,
+    system_palette: &Palette,
+    frame: &mut Frame,
+    bg_opaque: &[bool],
+    hidden: &[bool],
+) {
+    // 優先順位はピクセルごとに決める
+    // 1. スプライト同士はOAMの番号が小さい方 (スプライト0が一番上). 背面スプライトでも同じ
+    // 2. 勝ったスプライトが背面(属性bit5)で背景が不透明なら背景, それ以外はスプライト
+    // なので背面スプライトは, 後ろの番号の前面スプライトも一緒に隠す (SMB3のキノコ)
+    let mut sprite_opaque = vec![false; 256 * 240];
+    for i in (0..ppu.oam_data.len()).step_by(4) {
         let tile_idx = ppu.oam_data[i + 1] as u16;
         let tile_x = ppu.oam_data[i + 3] as usize;
         let tile_y = ppu.oam_data[i] as usize;
@@ -154,39 +232,117 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
         } else {
             false
         };
+        // 属性bit5: 1なら背景の後ろに描画
+        let behind_background = ppu.oam_data[i + 2] >> 5 & 1 == 1;
         let palette_idx = ppu.oam_data[i + 2] & 0b11;
         let sprite_palette = sprite_palette(ppu, palette_idx);
 
-        let bank: u16 = ppu.ctrl.sprite_pattern_addr();
-
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        // 8x16ではタイル番号のbit0でパターンテーブルを選び, 偶数番号のタイルを上, 次のタイルを下に並べる
+        let height = ppu.ctrl.sprite_size() as usize;
+        let tiles = if height == 16 {
+            let bank = (tile_idx & 1) * 0x1000;
+            [
+                fetch_tile(ppu, bank, tile_idx & 0xFE),
+                fetch_tile(ppu, bank, (tile_idx & 0xFE) + 1),
+            ]
+        } else {
+            let tile = fetch_tile(ppu, ppu.ctrl.sprite_pattern_addr(), tile_idx);
+            [tile, tile]
+        };
 
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
+        for y in 0..height {
+            let tile = &tiles[y / 8];
+            let mut upper = tile[y % 8];
+            let mut lower = tile[y % 8 + 8];
             'ololo: for x in (0..=7).rev() {
                 let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
+                upper >>= 1;
+                lower >>= 1;
                 let rgb = match value {
                     0 => continue 'ololo,
-                    1 => palette::SYSTEM_PALETTE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALETTE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALETTE[sprite_palette[3] as usize],
+                    1 => color(ppu, system_palette, sprite_palette[1]),
+                    2 => color(ppu, system_palette, sprite_palette[2]),
+                    3 => color(ppu, system_palette, sprite_palette[3]),
                     _ => panic!("cant be"),
                 };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
+                let (pixel_x, pixel_y) = match (flip_horizontal, flip_vertical) {
+                    (false, false) => (tile_x + x, tile_y + y),
+                    (true, false) => (tile_x + 7 - x, tile_y + y),
+                    // 上下反転は2つのタイルも入れ替わる
+                    (false, true) => (tile_x + x, tile_y + height - 1 - y),
+                    (true, true) => (tile_x + 7 - x, tile_y + height - 1 - y),
+                };
+                if pixel_x >= 256 || pixel_y >= 240 {
+                    continue 'ololo;
+                }
+                if pixel_x < 8 && !ppu.mask.check_show_sprite_left() {
+                    continue 'ololo;
+                }
+                if hidden[i / 4 * 240 + pixel_y] {
+                    continue 'ololo;
                 }
+                // 前の番号のスプライトがもう描いている
+                if sprite_opaque[pixel_y * 256 + pixel_x] {
+                    continue 'ololo;
+                }
+                sprite_opaque[pixel_y * 256 + pixel_x] = true;
+                if behind_background && bg_opaque[pixel_y * 256 + pixel_x] {
+                    continue 'ololo;
+                }
+                frame.set_pixel(pixel_x, pixel_y, rgb);
             }
         }
     }
 }
 
+// パレットのインデックスをRGBにする. PPUMASKのグレースケールと色強調をここでまとめて反映する
+fn color(ppu: &NesPPU, system_palette: &Palette, palette_idx: u8) -> (u8, u8, u8) {
+    let idx = if ppu.mask.check_gray_scale() {
+        palette_idx & 0x30
+    } else {
+        palette_idx & 0x3F
+    };
+    let rgb = system_palette.rgb(idx);
+    if ppu.mask.bits() & 0b1110_0000 == 0 {
+        return rgb;
+    }
+
+    // 強調されていない色を約25%暗くする
+    // PALは赤と緑の強調bitが入れ替わっている
+    let emphasis = ppu.mask.emphasize_red();
+    let (red, green) = match ppu.region {
+        Region::NTSC => (Color::Red, Color::Green),
+        Region::PAL => (Color::Green, Color::Red),
+    };
+    let dim = |value: u8, keep: bool| {
+        if keep {
+            value
+        } else {
+            (value as u16 * 3 / 4) as u8
+        }
+    };
+    (
+        dim(rgb.0, emphasis.contains(&red)),
+        dim(rgb.1, emphasis.contains(&green)),
+        dim(rgb.2, emphasis.contains(&Color::Blue)),
+    )
+}
+
+// ネームテーブルのアドレス($2000, $2400, $2800, $2C00)から物理VRAMの1KBを返す
+fn name_table(ppu: &NesPPU, addr: u16) -> &[u8] {
+    let start = (ppu.mirror_vram_addr(addr) & 0xC00) as usize;
+    &ppu.vram[start..start + 0x400]
+}
+
+// 1タイル = 16byte (下位8byte + 上位8byte)
+fn fetch_tile(ppu: &NesPPU, bank: u16, tile_idx: u16) -> [u8; 16] {
+    let mut tile = [0; 16];
+    for (i, byte) in tile.iter_mut().enumerate() {
+        *byte = ppu.read_chr(bank + tile_idx * 16 + i as u16);
+    }
+    tile
+}
+
 fn bg_palette(
     ppu: &NesPPU,
     attribute_table: &[u8],
@@ -194,7 +350,7 @@ fn bg_palette(
     tile_row: usize,
 ) -> [u8; 4] {
     let attr_table_idx = tile_row / 4 * 8 + tile_column / 4;
-    let attr_byte = ppu.vram[0x3C0 + attr_table_idx];
+    let attr_byte = attribute_table[attr_table_idx];
 
     let palette_idx = match (tile_column % 4 / 2, tile_row % 4 / 2) {
         (0, 0) => attr_byte & 0b11,
@@ -222,3 +378,467 @@ fn sprite_palette(ppu: &NesPPU, palette_idx: u8) -> [u8; 4] {
         ppu.palette_table[start + 2],
     ]
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+
+    // tile 1: 全ピクセルがカラー1, tile 2: 全ピクセルがカラー2
+    fn test_ppu() -> NesPPU {
+        let mut chr_rom = vec![0; 0x2000];
+        for i in 0..8 {
+            chr_rom[16 + i] = 0xFF;
+            chr_rom[32 + 8 + i] = 0xFF;
+        }
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.mask.update(0b0001_1110);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[2] = 0x16;
+        ppu.palette_table[0x11] = 0x21;
+        ppu
+    }
+
+    // スクロール位置は描画中に行ごとに覚えるので, 2フレーム進める
+    // (1フレーム目のpre-renderでtがvに入り, 2フレーム目の各行で記録される)
+    fn run_frames(ppu: &mut NesPPU) {
+        for _ in 0..2 {
+            while !ppu.tick(255) {}
+        }
+    }
+
+    fn set_sprite(ppu: &mut NesPPU, idx: usize, x: u8, y: u8, tile: u8, attr: u8) {
+        ppu.oam_data[idx * 4] = y;
+        ppu.oam_data[idx * 4 + 1] = tile;
+        ppu.oam_data[idx * 4 + 2] = attr;
+        ppu.oam_data[idx * 4 + 3] = x;
+    }
+
+    #[test]
+    fn test_render_undersized_chr() {
+        // CHRが4KBしかないのに, BGもスプライトも0x1000のパターンテーブルを使う
+        let mut chr_rom = vec![0; 0x1000];
+        for i in 0..8 {
+            chr_rom[16 + i] = 0xFF;
+        }
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.mask.update(0b0001_1110);
+        ppu.ctrl.update(0b0001_1000);
+        ppu.palette_table[1] = 0x2A;
+        ppu.vram[0] = 1;
+        set_sprite(&mut ppu, 0, 100, 100, 0xFF, 0);
+        run_frames(&mut ppu);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        // 0x1010は0x0010と同じ
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALETTE[0x2A]);
+    }
+
+    #[test]
+    fn test_render_8x16_sprite() {
+        // $1000側のtile 2が全部カラー1, tile 3が全部カラー2
+        let mut chr_rom = vec![0; 0x2000];
+        for i in 0..8 {
+            chr_rom[0x1000 + 2 * 16 + i] = 0xFF;
+            chr_rom[0x1000 + 3 * 16 + 8 + i] = 0xFF;
+        }
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.mask.update(0b0001_1110);
+        // 8x16. スプライトのパターンテーブル(bit3)は$0000のまま
+        ppu.ctrl.update(0b0010_0000);
+        ppu.palette_table[0x11] = 0x21;
+        ppu.palette_table[0x12] = 0x16;
+        // タイル番号3 -> $1000のtile 2と3
+        set_sprite(&mut ppu, 0, 100, 100, 3, 0);
+        set_sprite(&mut ppu, 1, 140, 100, 3, 0b1000_0000);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        let (upper, lower) = (palette::SYSTEM_PALETTE[0x21], palette::SYSTEM_PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(100, 100), upper);
+        assert_eq!(frame.get_pixel(107, 107), upper);
+        assert_eq!(frame.get_pixel(100, 108), lower);
+        assert_eq!(frame.get_pixel(107, 115), lower);
+        assert_eq!(frame.get_pixel(100, 116), palette::SYSTEM_PALETTE[0]);
+
+        // 上下反転すると下のタイルが上に来る
+        assert_eq!(frame.get_pixel(140, 100), lower);
+        assert_eq!(frame.get_pixel(147, 107), lower);
+        assert_eq!(frame.get_pixel(140, 108), upper);
+        assert_eq!(frame.get_pixel(147, 115), upper);
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        // 1つのラインに10個並べる
+        let mut ppu = test_ppu();
+        for i in 0..10 {
+            set_sprite(&mut ppu, i, i as u8 * 16, 50, 1, 0);
+        }
+        let sprite = palette::SYSTEM_PALETTE[0x21];
+        let backdrop = palette::SYSTEM_PALETTE[0x0F];
+        let mut frame = Frame::new();
+
+        // OAMの先頭から8個だけ
+        render(&ppu, &mut frame, &Palette::default());
+        for i in 0..10 {
+            let expected = if i < 8 { sprite } else { backdrop };
+            assert_eq!(frame.get_pixel(i * 16 + 4, 53), expected, "sprite {}", i);
+        }
+
+        let options = RenderOptions {
+            sprite_limit: false,
+        };
+        render_with_options(&ppu, &mut frame, &Palette::default(), &options);
+        for i in 0..10 {
+            assert_eq!(frame.get_pixel(i * 16 + 4, 53), sprite, "sprite {}", i);
+        }
+    }
+
+    #[test]
+    fn test_render_horizontal_scroll() {
+        let mut ppu = test_ppu();
+        ppu.mirroring = Mirroring::VERTICAL;
+        // 1列目のタイルと, 右隣のネームテーブルの0列目のタイル
+        ppu.vram[1] = 2;
+        ppu.vram[0x400] = 1;
+        ppu.palette_table[1] = 0x2A;
+        ppu.loopy.write_scroll(8);
+        ppu.loopy.write_scroll(0);
+        run_frames(&mut ppu);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        let backdrop = palette::SYSTEM_PALETTE[0x0F];
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(7, 7), palette::SYSTEM_PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(8, 0), backdrop);
+        assert_eq!(frame.get_pixel(247, 0), backdrop);
+        assert_eq!(frame.get_pixel(248, 0), palette::SYSTEM_PALETTE[0x2A]);
+        assert_eq!(frame.get_pixel(255, 7), palette::SYSTEM_PALETTE[0x2A]);
+    }
+
+    #[test]
+    fn test_render_vertical_scroll() {
+        let mut ppu = test_ppu();
+        // 2行目のタイルと, 下のネームテーブルの0行目のタイル
+        ppu.vram[64] = 2;
+        ppu.vram[0x400] = 1;
+        ppu.palette_table[1] = 0x2A;
+        ppu.loopy.write_scroll(0);
+        ppu.loopy.write_scroll(16);
+        run_frames(&mut ppu);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        let backdrop = palette::SYSTEM_PALETTE[0x0F];
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(7, 7), palette::SYSTEM_PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(0, 8), backdrop);
+        assert_eq!(frame.get_pixel(0, 223), backdrop);
+        assert_eq!(frame.get_pixel(0, 224), palette::SYSTEM_PALETTE[0x2A]);
+        assert_eq!(frame.get_pixel(7, 231), palette::SYSTEM_PALETTE[0x2A]);
+    }
+
+    #[test]
+    fn test_render_diagonal_scroll() {
+        let mut ppu = test_ppu();
+        ppu.mirroring = Mirroring::VERTICAL;
+        ppu.vram[1] = 1;
+        ppu.vram[0x400] = 2;
+        ppu.palette_table[1] = 0x2A;
+        ppu.loopy.write_scroll(8);
+        ppu.loopy.write_scroll(16);
+        run_frames(&mut ppu);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        // 垂直ミラーリングでは下のネームテーブルは上と同じ物理VRAMになる
+        assert_eq!(frame.get_pixel(0, 224), palette::SYSTEM_PALETTE[0x2A]);
+        assert_eq!(frame.get_pixel(7, 231), palette::SYSTEM_PALETTE[0x2A]);
+        assert_eq!(frame.get_pixel(8, 224), palette::SYSTEM_PALETTE[0x0F]);
+        assert_eq!(frame.get_pixel(248, 224), palette::SYSTEM_PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(255, 231), palette::SYSTEM_PALETTE[0x16]);
+    }
+
+    #[test]
+    fn test_render_vertical_scroll_attribute_region() {
+        let mut ppu = test_ppu();
+        // 行31(属性テーブルの後半)のバイトがタイルとして表示される
+        ppu.vram[0x3E0] = 1;
+        ppu.vram[64] = 2;
+        ppu.palette_table[1] = 0x2A;
+        ppu.loopy.write_scroll(0);
+        ppu.loopy.write_scroll(248);
+        run_frames(&mut ppu);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALETTE[0x2A]);
+        // その後は同じネームテーブルの先頭に戻る
+        assert_eq!(frame.get_pixel(0, 24), palette::SYSTEM_PALETTE[0x16]);
+    }
+
+    #[test]
+    fn test_render_custom_palette() {
+        // 色番号iを(i, i * 2, i * 3)にした192バイトのパレット
+        let data: Vec<u8> = (0..64u8).flat_map(|i| [i, i * 2, i * 3]).collect();
+        let system_palette = Palette::from_bytes(&data).unwrap();
+
+        let mut ppu = test_ppu();
+        ppu.vram[0] = 2;
+        set_sprite(&mut ppu, 0, 100, 100, 1, 0);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &system_palette);
+
+        assert_eq!(frame.get_pixel(0, 0), (0x16, 0x2C, 0x42));
+        assert_eq!(frame.get_pixel(8, 0), (0x0F, 0x1E, 0x2D));
+        assert_eq!(frame.get_pixel(100, 101), (0x21, 0x42, 0x63));
+    }
+
+    #[test]
+    fn test_render_split_scroll() {
+        use crate::ppu::PPU;
+
+        let mut ppu = test_ppu();
+        ppu.mirroring = Mirroring::VERTICAL;
+        // 全部の行の1列目にtile 2
+        for row in 0..30 {
+            ppu.vram[row * 32 + 1] = 2;
+        }
+        run_frames(&mut ppu);
+
+        // スキャンライン119の途中でXスクロールを8にする (dot 257でvに入り, 120から効く)
+        while ppu.scanline() != 119 {
+            ppu.tick(1);
+        }
+        ppu.read_status();
+        ppu.write_to_scroll(8);
+        ppu.write_to_scroll(0);
+        // VBlank中にゲームがスクロールを0に戻す
+        while ppu.scanline() != 241 {
+            ppu.tick(1);
+        }
+        ppu.write_to_scroll(0);
+        ppu.write_to_scroll(0);
+        while !ppu.tick(1) {}
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+        let tile = palette::SYSTEM_PALETTE[0x16];
+        let backdrop = palette::SYSTEM_PALETTE[0x0F];
+        for y in [0, 60, 119] {
+            assert_eq!(frame.get_pixel(0, y), backdrop);
+            assert_eq!(frame.get_pixel(8, y), tile);
+        }
+        for y in [120, 180, 239] {
+            assert_eq!(frame.get_pixel(0, y), tile);
+            assert_eq!(frame.get_pixel(8, y), backdrop);
+        }
+    }
+
+    #[test]
+    fn test_render_chr_ram() {
+        use crate::bus::Bus;
+        use crate::cartridge::test::test_nrom;
+        use crate::cpu::Mem;
+
+        // CHR ROMが0バンクならCHR RAMになる
+        let mut rom = test_nrom();
+        rom.chr_rom = vec![];
+        let mut bus = Bus::new_headless(rom).unwrap();
+
+        // tile 1 の下位プレーンを全部1にする
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2006, 0x10);
+        for _ in 0..8 {
+            bus.mem_write(0x2007, 0xFF);
+        }
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2006, 0x10);
+        bus.mem_read(0x2007); // バッファの読み込み
+        assert_eq!(bus.mem_read(0x2007), 0xFF);
+        assert_eq!(bus.ppu().read_chr(0x0017), 0xFF);
+        assert_eq!(bus.ppu().read_chr(0x0018), 0x00);
+
+        // ネームテーブルの左上にtile 1, パレット
+        bus.mem_write(0x2006, 0x20);
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2007, 1);
+        bus.mem_write(0x2006, 0x3F);
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2007, 0x0F);
+        bus.mem_write(0x2007, 0x2A);
+        bus.mem_write(0x2001, 0b0000_1010);
+        // $2006で書き換わったスクロール位置を戻す
+        bus.mem_write(0x2000, 0);
+        bus.mem_write(0x2005, 0);
+        bus.mem_write(0x2005, 0);
+
+        let mut frame = Frame::new();
+        render(bus.ppu(), &mut frame, &Palette::default());
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALETTE[0x2A]);
+        assert_eq!(frame.get_pixel(7, 7), palette::SYSTEM_PALETTE[0x2A]);
+        assert_eq!(frame.get_pixel(8, 0), palette::SYSTEM_PALETTE[0x0F]);
+    }
+
+    #[test]
+    fn test_render_four_screen() {
+        let mut ppu = test_ppu();
+        ppu.mirroring = Mirroring::FOUR_SCREEN;
+        // $2C00 (4枚目) を表示する. 他のミラーリングなら0x400と同じになる
+        ppu.loopy.write_ctrl(0b11);
+        run_frames(&mut ppu);
+        ppu.vram[0xC00] = 2;
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(8, 0), palette::SYSTEM_PALETTE[0x0F]);
+    }
+
+    #[test]
+    fn test_render_background_disabled() {
+        let mut ppu = test_ppu();
+        for i in 0..0x3C0 {
+            ppu.vram[i] = 2;
+        }
+        ppu.mask.update(0b0000_0110);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        let backdrop = palette::SYSTEM_PALETTE[0x0F];
+        for y in 0..240 {
+            for x in 0..256 {
+                assert_eq!(frame.get_pixel(x, y), backdrop);
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_left_clipping() {
+        let mut ppu = test_ppu();
+        ppu.vram[0] = 2;
+        ppu.vram[1] = 2;
+        set_sprite(&mut ppu, 0, 4, 16, 1, 0);
+        ppu.mask.update(0b0001_1000);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        let backdrop = palette::SYSTEM_PALETTE[0x0F];
+        assert_eq!(frame.get_pixel(0, 0), backdrop);
+        assert_eq!(frame.get_pixel(7, 0), backdrop);
+        assert_eq!(frame.get_pixel(8, 0), palette::SYSTEM_PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(7, 16), backdrop);
+        assert_eq!(frame.get_pixel(8, 16), palette::SYSTEM_PALETTE[0x21]);
+    }
+
+    #[test]
+    fn test_render_gray_scale() {
+        let mut ppu = test_ppu();
+        ppu.vram[0] = 2;
+        ppu.mask.update(0b0001_1111);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        // 0x16 -> 0x10, 0x0F -> 0x00
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALETTE[0x10]);
+        assert_eq!(frame.get_pixel(8, 0), palette::SYSTEM_PALETTE[0x00]);
+    }
+
+    #[test]
+    fn test_render_emphasis() {
+        let mut ppu = test_ppu();
+        ppu.vram[0] = 2;
+        ppu.mask.update(0b0011_1110);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        let (r, g, b) = palette::SYSTEM_PALETTE[0x16];
+        assert_eq!(
+            frame.get_pixel(0, 0),
+            (r, (g as u16 * 3 / 4) as u8, (b as u16 * 3 / 4) as u8)
+        );
+
+        // PALではbit5が緑の強調
+        ppu.region = Region::PAL;
+        render(&ppu, &mut frame, &Palette::default());
+        assert_eq!(
+            frame.get_pixel(0, 0),
+            ((r as u16 * 3 / 4) as u8, g, (b as u16 * 3 / 4) as u8)
+        );
+    }
+
+    #[test]
+    fn test_render_sprite() {
+        let mut ppu = test_ppu();
+        set_sprite(&mut ppu, 0, 16, 24, 1, 0);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        let sprite = palette::SYSTEM_PALETTE[0x21];
+        let backdrop = palette::SYSTEM_PALETTE[0x0F];
+        for y in 24..32 {
+            for x in 16..24 {
+                assert_eq!(frame.get_pixel(x, y), sprite);
+            }
+        }
+        assert_eq!(frame.get_pixel(15, 24), backdrop);
+        assert_eq!(frame.get_pixel(24, 24), backdrop);
+        assert_eq!(frame.get_pixel(16, 32), backdrop);
+    }
+
+    #[test]
+    fn test_render_sprite_priority() {
+        let mut ppu = test_ppu();
+        // 背景の(0,0)タイルを不透明にして, 前面と背面のスプライトを重ねる
+        ppu.vram[0] = 2;
+        set_sprite(&mut ppu, 0, 0, 0, 1, 0b0010_0000);
+        set_sprite(&mut ppu, 1, 4, 8, 1, 0b0010_0000);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        // 背面スプライトは不透明な背景に隠れる
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALETTE[0x16]);
+        // 背景が透明なところでは背面スプライトも見える
+        assert_eq!(frame.get_pixel(4, 8), palette::SYSTEM_PALETTE[0x21]);
+
+        set_sprite(&mut ppu, 0, 0, 0, 1, 0);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALETTE[0x21]);
+    }
+
+    #[test]
+    fn test_render_sprite_priority_between_sprites() {
+        let mut ppu = test_ppu();
+        ppu.palette_table[0x15] = 0x2A;
+        // 背景は(0,0)のタイルだけ不透明 (x 0~7)
+        ppu.vram[0] = 2;
+        // スプライト0: 背面, x 4~11 (不透明な背景と透明な背景にまたがる)
+        // スプライト1: 前面, パレット1, x 8~15
+        set_sprite(&mut ppu, 0, 4, 0, 1, 0b0010_0000);
+        set_sprite(&mut ppu, 1, 8, 0, 1, 0b0000_0001);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame, &Palette::default());
+
+        let background = palette::SYSTEM_PALETTE[0x16];
+        let sprite0 = palette::SYSTEM_PALETTE[0x21];
+        let sprite1 = palette::SYSTEM_PALETTE[0x2A];
+        // 不透明な背景の上: 背面のスプライト0は隠れる
+        assert_eq!(frame.get_pixel(4, 0), background);
+        // 透明な背景の上: スプライト0が前面のスプライト1にも勝つ
+        assert_eq!(frame.get_pixel(8, 0), sprite0);
+        assert_eq!(frame.get_pixel(11, 0), sprite0);
+        assert_eq!(frame.get_pixel(12, 0), sprite1);
+
+        // スプライト1をx 0~7に動かす. スプライト0と重なるところは, 勝ったスプライト0が背面なので背景
+        set_sprite(&mut ppu, 1, 0, 0, 1, 0b0000_0001);
+        render(&ppu, &mut frame, &Palette::default());
+        assert_eq!(frame.get_pixel(4, 0), background);
+        assert_eq!(frame.get_pixel(7, 0), background);
+        // スプライト1だけのところは前面なので不透明な背景の上に出る
+        assert_eq!(frame.get_pixel(3, 0), sprite1);
+    }
+}
@@ -0,0 +1,117 @@
+// ゲーム画面の上に重ねるデバッグ表示
+// 元の画面(Frame)は書き換えずに別のFrameに重ねる. スクリーンショットはどちらを使うか選べる
+use super::frame::Frame;
+use crate::ppu::NesPPU;
+
+// スプライトの枠
+const SPRITE_BOX_COLOR: (u8, u8, u8) = (0x00, 0xFF, 0x00);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Overlays {
+    // OAMの64個のスプライトを枠で囲む (画面外(Y >= 0xEF)のものは描かない)
+    pub sprite_boxes: bool,
+}
+
+// cleanをoutにコピーして, 有効なオーバーレイを上に描く
+pub fn composite(ppu: &NesPPU, clean: &Frame, overlays: &Overlays, out: &mut Frame) {
+    out.data.copy_from_slice(&clean.data);
+    if overlays.sprite_boxes {
+        draw_sprite_boxes(ppu, out);
+    }
+}
+
+// F12で保存する画面. include_overlaysがfalseならオーバーレイのない元の画面
+pub fn screenshot_frame<'a>(
+    clean: &'a Frame,
+    composited: &'a Frame,
+    include_overlays: bool,
+) -> &'a Frame {
+    if include_overlays {
+        composited
+    } else {
+        clean
+    }
+}
+
+fn draw_sprite_boxes(ppu: &NesPPU, frame: &mut Frame) {
+    let height = ppu.ctrl.sprite_size() as usize;
+    for sprite in ppu.oam_data.chunks(4) {
+        if sprite[0] >= 0xEF {
+            continue;
+        }
+        let (x1, y1) = (sprite[3] as usize, sprite[0] as usize);
+        let (x2, y2) = (x1 + 7, y1 + height - 1);
+        for x in x1..=x2 {
+            frame.set_pixel(x, y1, SPRITE_BOX_COLOR);
+            frame.set_pixel(x, y2, SPRITE_BOX_COLOR);
+        }
+        for y in y1..=y2 {
+            frame.set_pixel(x1, y, SPRITE_BOX_COLOR);
+            frame.set_pixel(x2, y, SPRITE_BOX_COLOR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render::png;
+
+    fn ppu_with_sprite(x: u8, y: u8) -> NesPPU {
+        let mut ppu = NesPPU::new_empty_rom();
+        // 残りは画面外
+        ppu.oam_data = [0xFF; 256];
+        ppu.oam_data[0] = y;
+        ppu.oam_data[3] = x;
+        ppu
+    }
+
+    #[test]
+    fn test_composite_keeps_clean_frame() {
+        let ppu = ppu_with_sprite(16, 32);
+        let mut clean = Frame::new();
+        clean.set_pixel(100, 100, (1, 2, 3));
+        let mut displayed = Frame::new();
+        composite(
+            &ppu,
+            &clean,
+            &Overlays { sprite_boxes: true },
+            &mut displayed,
+        );
+
+        assert_eq!(displayed.get_pixel(16, 32), SPRITE_BOX_COLOR);
+        assert_eq!(displayed.get_pixel(23, 39), SPRITE_BOX_COLOR);
+        assert_eq!(displayed.get_pixel(20, 35), (0, 0, 0));
+        assert_eq!(displayed.get_pixel(100, 100), (1, 2, 3));
+        assert_eq!(clean.get_pixel(16, 32), (0, 0, 0));
+
+        // オーバーレイが無効なら同じ
+        composite(&ppu, &clean, &Overlays::default(), &mut displayed);
+        assert_eq!(displayed.data, clean.data);
+    }
+
+    #[test]
+    fn test_screenshot_without_overlays() {
+        let ppu = ppu_with_sprite(16, 32);
+        let clean = Frame::new();
+        let mut displayed = Frame::new();
+        composite(
+            &ppu,
+            &clean,
+            &Overlays { sprite_boxes: true },
+            &mut displayed,
+        );
+        assert_eq!(displayed.get_pixel(16, 32), SPRITE_BOX_COLOR);
+
+        let path = std::env::temp_dir().join("hobby_nes_screenshot_test.png");
+        png::save(screenshot_frame(&clean, &displayed, false), &path).unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        assert_eq!(saved, png::encode(&clean));
+        assert_ne!(saved, png::encode(&displayed));
+
+        png::save(screenshot_frame(&clean, &displayed, true), &path).unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, png::encode(&displayed));
+    }
+}
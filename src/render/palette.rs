@@ -14,4 +14,185 @@ pub static SYSTEM_PALETTE: [(u8,u8,u8); 64] = [
     (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0), 
     (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA), 
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
-];
\ No newline at end of file
+];
+
+// 実際に画面に出す64色. .palファイル(64色 x RGB = 192バイト)で差し替えられる
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    colors: [(u8, u8, u8); 64],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            colors: SYSTEM_PALETTE,
+        }
+    }
+}
+
+impl Palette {
+    pub fn from_bytes(data: &[u8]) -> Result<Palette, String> {
+        if data.len() != 64 * 3 {
+            return Err(format!(
+                "Palette must be 192 bytes (64 colors x RGB), got {} bytes",
+                data.len()
+            ));
+        }
+        let mut colors = [(0, 0, 0); 64];
+        for (color, rgb) in colors.iter_mut().zip(data.chunks(3)) {
+            *color = (rgb[0], rgb[1], rgb[2]);
+        }
+        Ok(Palette { colors })
+    }
+
+    pub fn from_pal_file(path: &str) -> Result<Palette, String> {
+        let data = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        Palette::from_bytes(&data)
+    }
+
+    // パレットRAMの値(0~63)のRGB. 上位2bitは無視する
+    pub fn rgb(&self, idx: u8) -> (u8, u8, u8) {
+        self.colors[(idx & 0x3F) as usize]
+    }
+
+    // 全部の色を同じ変換にかける
+    fn map(&self, f: impl Fn((u8, u8, u8)) -> (u8, u8, u8)) -> Palette {
+        let mut colors = self.colors;
+        for color in colors.iter_mut() {
+            *color = f(*color);
+        }
+        Palette { colors }
+    }
+}
+
+// 内蔵のパレット (アクセシビリティ用). 実行中に切り替えられる
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BuiltinPalette {
+    Standard,
+    Grayscale,
+    // 色覚の型ごとに, 見分けにくい色の差を見分けやすい方向に移す
+    Protanopia,
+    Deuteranopia,
+    Tritanopia,
+}
+
+const BUILTIN_PALETTES: [BuiltinPalette; 5] = [
+    BuiltinPalette::Standard,
+    BuiltinPalette::Grayscale,
+    BuiltinPalette::Protanopia,
+    BuiltinPalette::Deuteranopia,
+    BuiltinPalette::Tritanopia,
+];
+
+impl BuiltinPalette {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuiltinPalette::Standard => "standard",
+            BuiltinPalette::Grayscale => "grayscale",
+            BuiltinPalette::Protanopia => "protanopia",
+            BuiltinPalette::Deuteranopia => "deuteranopia",
+            BuiltinPalette::Tritanopia => "tritanopia",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<BuiltinPalette, String> {
+        BUILTIN_PALETTES
+            .iter()
+            .find(|palette| palette.name() == name)
+            .copied()
+            .ok_or_else(|| format!("Unknown palette: {}", name))
+    }
+
+    // ホットキーで順番に切り替える
+    pub fn next(&self) -> BuiltinPalette {
+        let i = BUILTIN_PALETTES.iter().position(|p| p == self).unwrap();
+        BUILTIN_PALETTES[(i + 1) % BUILTIN_PALETTES.len()]
+    }
+
+    pub fn palette(&self) -> Palette {
+        let standard = Palette::default();
+        match self {
+            BuiltinPalette::Standard => standard,
+            BuiltinPalette::Grayscale => standard.map(|(r, g, b)| {
+                // ITU-R BT.601の輝度
+                let y = ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8;
+                (y, y, y)
+            }),
+            BuiltinPalette::Protanopia => standard.map(|rgb| daltonize(rgb, PROTANOPIA)),
+            BuiltinPalette::Deuteranopia => standard.map(|rgb| daltonize(rgb, DEUTERANOPIA)),
+            BuiltinPalette::Tritanopia => standard.map(|rgb| daltonize(rgb, TRITANOPIA)),
+        }
+    }
+}
+
+// 各色覚での見え方の近似 (RGBにかける行列)
+const PROTANOPIA: [[f64; 3]; 3] = [
+    [0.567, 0.433, 0.0],
+    [0.558, 0.442, 0.0],
+    [0.0, 0.242, 0.758],
+];
+const DEUTERANOPIA: [[f64; 3]; 3] = [[0.625, 0.375, 0.0], [0.7, 0.3, 0.0], [0.0, 0.3, 0.7]];
+const TRITANOPIA: [[f64; 3]; 3] = [[0.95, 0.05, 0.0], [0.0, 0.433, 0.567], [0.0, 0.475, 0.525]];
+
+// simulationはその色覚での見え方の近似
+// 見え方との差(見分けられない成分)を, 見分けられる緑と青の方に足す
+fn daltonize(rgb: (u8, u8, u8), simulation: [[f64; 3]; 3]) -> (u8, u8, u8) {
+    let c = [rgb.0 as f64, rgb.1 as f64, rgb.2 as f64];
+    let seen: Vec<f64> = simulation
+        .iter()
+        .map(|row| row[0] * c[0] + row[1] * c[1] + row[2] * c[2])
+        .collect();
+    let err = [c[0] - seen[0], c[1] - seen[1], c[2] - seen[2]];
+    let clamp = |v: f64| v.round().clamp(0.0, 255.0) as u8;
+    (
+        clamp(c[0]),
+        clamp(c[1] + 0.7 * err[0] + err[1]),
+        clamp(c[2] + 0.7 * err[0] + err[2]),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_bytes() {
+        let data: Vec<u8> = (0..192).map(|i| i as u8).collect();
+        let palette = Palette::from_bytes(&data).unwrap();
+        assert_eq!(palette.rgb(0), (0, 1, 2));
+        assert_eq!(palette.rgb(63), (189, 190, 191));
+        assert_eq!(palette.rgb(0x40), (0, 1, 2));
+
+        assert!(Palette::from_bytes(&data[..191]).is_err());
+        assert!(Palette::from_bytes(&[0; 64 * 3 * 8]).is_err());
+        assert_eq!(Palette::default().rgb(0x16), SYSTEM_PALETTE[0x16]);
+    }
+
+    #[test]
+    fn test_grayscale() {
+        let palette = BuiltinPalette::Grayscale.palette();
+        for i in 0..64 {
+            let (r, g, b) = palette.rgb(i);
+            assert!(r == g && g == b, "color {:02X} is not grey", i);
+        }
+        assert_eq!(palette.rgb(0x30), (0xFF, 0xFF, 0xFF));
+        assert_eq!(palette.rgb(0x0F), (0x05, 0x05, 0x05));
+    }
+
+    #[test]
+    fn test_builtin_cycle() {
+        let mut palette = BuiltinPalette::Standard;
+        for _ in 0..BUILTIN_PALETTES.len() {
+            assert_eq!(BuiltinPalette::parse(palette.name()), Ok(palette));
+            assert_ne!(palette.next(), palette);
+            palette = palette.next();
+        }
+        assert_eq!(palette, BuiltinPalette::Standard);
+        assert!(BuiltinPalette::parse("sepia").is_err());
+
+        // 灰色はどの色覚向けでも変わらない
+        for builtin in BUILTIN_PALETTES {
+            assert_eq!(builtin.palette().rgb(0x00), SYSTEM_PALETTE[0x00]);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// スクリーンショット用のPNG (RGB 8bit, インターレースなし)
+// 圧縮はしない (deflateの無圧縮ブロックだけ). 256x240なら約180KB
+use super::frame::Frame;
+use crate::hash::crc32;
+use std::path::Path;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+// 無圧縮ブロック1つに入る最大のバイト数
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+pub fn encode(frame: &Frame) -> Vec<u8> {
+    let mut png = PNG_SIGNATURE.to_vec();
+
+    let mut header = vec![];
+    header.extend_from_slice(&(frame.width as u32).to_be_bytes());
+    header.extend_from_slice(&(frame.height as u32).to_be_bytes());
+    // bit depth 8, color type 2 (RGB), 圧縮/フィルタ/インターレースは0
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // 各行の先頭にフィルタの種類(0: なし)
+    let mut raw = Vec::with_capacity((frame.pitch() + 1) * frame.height);
+    for row in frame.data.chunks(frame.pitch()) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+pub fn save(frame: &Frame, path: &Path) -> Result<(), String> {
+    std::fs::write(path, encode(frame))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+// 長さ, 種類, データ, CRC (種類とデータから計算する)
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// zlibのヘッダー + 無圧縮ブロック + Adler-32
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = data.chunks(STORED_BLOCK_SIZE).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        // BFINAL, BTYPE = 00
+        out.push((i == blocks.len() - 1) as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut frame = Frame::with_dimensions(2, 1);
+        frame.set_pixel(1, 0, (0xFF, 0x00, 0x80));
+        let png = encode(&frame);
+
+        assert_eq!(&png[..8], &PNG_SIGNATURE);
+        // IHDR: 2x1, RGB 8bit
+        assert_eq!(&png[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+        // IDATの中身はフィルタ0 + 1行分がそのまま入っている
+        let idat = &png[33 + 8..];
+        assert_eq!(&idat[..7], &[0x78, 0x01, 1, 7, 0, !7, 0xFF]);
+        assert_eq!(&idat[7..14], &[0, 0, 0, 0, 0xFF, 0x00, 0x80]);
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]));
+    }
+}
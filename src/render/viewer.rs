@@ -0,0 +1,224 @@
+// デバッグ用の表示
+use super::frame::Frame;
+use super::palette::Palette;
+use super::{fetch_tile, name_table, render_name_table};
+use crate::ppu::NesPPU;
+
+pub const NAME_TABLES_WIDTH: usize = 256 * 2;
+pub const NAME_TABLES_HEIGHT: usize = 240 * 2;
+// CHRの1バンク = 16x16タイル
+pub const CHR_BANK_SIZE: usize = 128;
+pub const PATTERN_TABLES_WIDTH: usize = CHR_BANK_SIZE * 2;
+pub const PATTERN_TABLES_HEIGHT: usize = CHR_BANK_SIZE;
+
+// ゲーム画面の代わりに表示するもの
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DebugView {
+    Off,
+    NameTables,
+    PatternTables,
+}
+
+impl DebugView {
+    // 同じキーをもう一度押したら元の画面に戻る
+    pub fn toggle(self, view: DebugView) -> DebugView {
+        if self == view {
+            DebugView::Off
+        } else {
+            view
+        }
+    }
+}
+
+// スクロールの表示範囲の枠
+const VIEWPORT_COLOR: (u8, u8, u8) = (0xFF, 0x00, 0xFF);
+
+// 4枚のネームテーブル($2000, $2400, $2800, $2C00)を2x2に並べて描画する (512x480)
+// ミラーリングで同じ物理VRAMを指していれば同じ絵が並ぶ
+pub fn render_name_tables(ppu: &NesPPU, frame: &mut Frame, system_palette: &Palette) {
+    for i in 0..4 {
+        render_name_table(
+            ppu,
+            system_palette,
+            frame,
+            name_table(ppu, 0x2000 + i as u16 * 0x400),
+            i % 2 * 256,
+            i / 2 * 240,
+        );
+    }
+    draw_viewport(ppu, frame);
+}
+
+// 今のスクロール位置で画面に映っている256x240の範囲. 右端/下端は反対側に回り込む
+fn draw_viewport(ppu: &NesPPU, frame: &mut Frame) {
+    let base = ppu.loopy.nametable_addr();
+    let x1 = if base & 0x400 != 0 { 256 } else { 0 } + ppu.loopy.scroll_x() as usize;
+    let y1 = if base & 0x800 != 0 { 240 } else { 0 } + ppu.loopy.scroll_y() as usize;
+    for dx in 0..256 {
+        let x = (x1 + dx) % NAME_TABLES_WIDTH;
+        frame.set_pixel(x, y1 % NAME_TABLES_HEIGHT, VIEWPORT_COLOR);
+        frame.set_pixel(x, (y1 + 239) % NAME_TABLES_HEIGHT, VIEWPORT_COLOR);
+    }
+    for dy in 0..240 {
+        let y = (y1 + dy) % NAME_TABLES_HEIGHT;
+        frame.set_pixel(x1 % NAME_TABLES_WIDTH, y, VIEWPORT_COLOR);
+        frame.set_pixel((x1 + 255) % NAME_TABLES_WIDTH, y, VIEWPORT_COLOR);
+    }
+}
+
+// CHRのバンク(0: $0000, 1: $1000)の256タイルを128x128に並べる
+// paletteはパレットRAMの何番目を使うか (0~3: BG, 4~7: スプライト)
+// マッパー越しに読むので, バンク切り替えの結果がそのまま見える
+pub fn render_chr_bank(
+    ppu: &NesPPU,
+    frame: &mut Frame,
+    bank: u16,
+    palette: usize,
+    system_palette: &Palette,
+) {
+    draw_chr_bank(ppu, frame, bank, palette, 0, system_palette);
+}
+
+// 2つのバンクを左右に並べる (256x128)
+pub fn render_pattern_tables(
+    ppu: &NesPPU,
+    frame: &mut Frame,
+    palette: usize,
+    system_palette: &Palette,
+) {
+    draw_chr_bank(ppu, frame, 0, palette, 0, system_palette);
+    draw_chr_bank(ppu, frame, 1, palette, CHR_BANK_SIZE, system_palette);
+}
+
+fn draw_chr_bank(
+    ppu: &NesPPU,
+    frame: &mut Frame,
+    bank: u16,
+    palette: usize,
+    offset_x: usize,
+    system_palette: &Palette,
+) {
+    let colors = [
+        ppu.palette_table[0],
+        ppu.palette_table[palette * 4 + 1],
+        ppu.palette_table[palette * 4 + 2],
+        ppu.palette_table[palette * 4 + 3],
+    ];
+    for tile_idx in 0..256u16 {
+        let tile = fetch_tile(ppu, bank * 0x1000, tile_idx);
+        let tile_x = (tile_idx % 16) as usize * 8;
+        let tile_y = (tile_idx / 16) as usize * 8;
+        for y in 0..8 {
+            let upper = tile[y];
+            let lower = tile[y + 8];
+            for x in 0..8 {
+                let value = ((lower >> (7 - x)) & 1) << 1 | ((upper >> (7 - x)) & 1);
+                let rgb = system_palette.rgb(colors[value as usize]);
+                frame.set_pixel(offset_x + tile_x + x, tile_y + y, rgb);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+    use crate::render::palette;
+
+    // tile 1: カラー1, tile 2: カラー2, tile 3: カラー3
+    fn test_ppu(mirroring: Mirroring) -> NesPPU {
+        let mut chr_rom = vec![0; 0x2000];
+        for i in 0..8 {
+            chr_rom[16 + i] = 0xFF;
+            chr_rom[32 + 8 + i] = 0xFF;
+            chr_rom[48 + i] = 0xFF;
+            chr_rom[48 + 8 + i] = 0xFF;
+        }
+        let mut ppu = NesPPU::new(chr_rom, mirroring);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x16;
+        ppu.palette_table[2] = 0x2A;
+        ppu.palette_table[3] = 0x12;
+        ppu
+    }
+
+    #[test]
+    fn test_render_name_tables() {
+        let mut ppu = test_ppu(Mirroring::FOUR_SCREEN);
+        // 4枚それぞれの (1, 1) のタイルを変える
+        for (i, tile) in [0u8, 1, 2, 3].iter().enumerate() {
+            ppu.vram[i * 0x400 + 32 + 1] = *tile;
+        }
+        let mut frame = Frame::with_dimensions(NAME_TABLES_WIDTH, NAME_TABLES_HEIGHT);
+        render_name_tables(&ppu, &mut frame, &Palette::default());
+
+        let color = |idx: usize| palette::SYSTEM_PALETTE[idx];
+        assert_eq!(frame.get_pixel(8 + 4, 8 + 4), color(0x0F));
+        assert_eq!(frame.get_pixel(256 + 8 + 4, 8 + 4), color(0x16));
+        assert_eq!(frame.get_pixel(8 + 4, 240 + 8 + 4), color(0x2A));
+        assert_eq!(frame.get_pixel(256 + 8 + 4, 240 + 8 + 4), color(0x12));
+    }
+
+    #[test]
+    fn test_render_name_tables_mirroring() {
+        let mut ppu = test_ppu(Mirroring::VERTICAL);
+        ppu.vram[32 + 1] = 1;
+        let mut frame = Frame::with_dimensions(NAME_TABLES_WIDTH, NAME_TABLES_HEIGHT);
+        render_name_tables(&ppu, &mut frame, &Palette::default());
+
+        // 垂直ミラーリングでは上下が同じ
+        let color = palette::SYSTEM_PALETTE[0x16];
+        assert_eq!(frame.get_pixel(8 + 4, 8 + 4), color);
+        assert_eq!(frame.get_pixel(8 + 4, 240 + 8 + 4), color);
+        assert_eq!(
+            frame.get_pixel(256 + 8 + 4, 8 + 4),
+            palette::SYSTEM_PALETTE[0x0F]
+        );
+    }
+
+    #[test]
+    fn test_render_chr_bank() {
+        // bank 0 の tile 0x12 (1行目, 2列目): 下位プレーンと上位プレーンの1行目だけ
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0x120] = 0b1000_0001;
+        chr_rom[0x128] = 0b1100_0000;
+        // bank 1 は使わない
+        chr_rom[0x1120] = 0xFF;
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[5] = 0x21;
+        ppu.palette_table[6] = 0x22;
+        ppu.palette_table[7] = 0x23;
+
+        let mut frame = Frame::with_dimensions(CHR_BANK_SIZE, CHR_BANK_SIZE);
+        render_chr_bank(&ppu, &mut frame, 0, 1, &Palette::default());
+
+        let color = |idx: usize| palette::SYSTEM_PALETTE[idx];
+        let (x, y) = (16, 8);
+        // 下位プレーン1 + 上位プレーン1 = 3, 上位だけ = 2, 下位だけ = 1
+        assert_eq!(frame.get_pixel(x, y), color(0x23));
+        assert_eq!(frame.get_pixel(x + 1, y), color(0x22));
+        assert_eq!(frame.get_pixel(x + 2, y), color(0x0F));
+        assert_eq!(frame.get_pixel(x + 7, y), color(0x21));
+        assert_eq!(frame.get_pixel(x, y + 1), color(0x0F));
+    }
+
+    #[test]
+    fn test_viewport_wraps() {
+        let mut ppu = test_ppu(Mirroring::VERTICAL);
+        // $2400から右に16, 下に8スクロール
+        ppu.loopy.write_ctrl(0b01);
+        ppu.loopy.write_scroll(16);
+        ppu.loopy.write_scroll(8);
+        let mut frame = Frame::with_dimensions(NAME_TABLES_WIDTH, NAME_TABLES_HEIGHT);
+        render_name_tables(&ppu, &mut frame, &Palette::default());
+
+        // 左上の角
+        assert_eq!(frame.get_pixel(256 + 16, 8), VIEWPORT_COLOR);
+        // 右端は左側に回り込む: 272 + 255 = 527 -> 15
+        assert_eq!(frame.get_pixel(15, 100), VIEWPORT_COLOR);
+        assert_eq!(frame.get_pixel(15, 247), VIEWPORT_COLOR);
+        assert_ne!(frame.get_pixel(15, 248), VIEWPORT_COLOR);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::bus::Bus;
+use crate::cpu::CPU;
+use std::collections::VecDeque;
+
+// 巻き戻し
+// strideフレームごとにセーブステートを取り, 古いものから捨てるリングバッファに溜める
+pub struct Rewind {
+    snapshots: VecDeque<Vec<u8>>,
+    capacity: usize,
+    stride: u32,
+    frame: u32,
+}
+
+impl Rewind {
+    // seconds: 巻き戻せる秒数, stride: 何フレームに1回保存するか (毎フレームだと重い)
+    pub fn new(seconds: u32, frame_rate: f64, stride: u32) -> Self {
+        let stride = stride.max(1);
+        let capacity = ((seconds as f64 * frame_rate / stride as f64).ceil() as usize).max(1);
+        Rewind {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
+            stride,
+            frame: 0,
+        }
+    }
+
+    // 1フレームごとに呼ぶ
+    pub fn record(&mut self, cpu: &CPU<Bus>) {
+        self.frame += 1;
+        if self.frame < self.stride {
+            return;
+        }
+        self.frame = 0;
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(cpu.save_state());
+    }
+
+    // 1つ前のスナップショットに戻す. 最後の1つは消さずに何度でも戻れる
+    // 戻るものがなければfalse
+    // APUのサンプルバッファはload_stateで捨てられる
+    pub fn rewind(&mut self, cpu: &mut CPU<Bus>) -> Result<bool, String> {
+        if self.snapshots.len() > 1 {
+            self.snapshots.pop_back();
+        }
+        match self.snapshots.back() {
+            Some(state) => {
+                cpu.load_state(state)?;
+                self.frame = 0;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Rom;
+
+    fn test_cpu() -> CPU<Bus<'static>> {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let bytes = std::fs::read(format!("{}/nestest.nes", dir)).unwrap();
+        let mut cpu = CPU::new(Bus::new_headless(Rom::new(&bytes).unwrap()).unwrap());
+        cpu.reset();
+        cpu.program_counter = 0xC000;
+        cpu
+    }
+
+    fn run(cpu: &mut CPU<Bus>, steps: usize) {
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut cpu = test_cpu();
+        let mut rewind = Rewind::new(1, 10.0, 1);
+        let mut snapshots = vec![];
+        for _ in 0..10 {
+            run(&mut cpu, 100);
+            rewind.record(&cpu);
+            snapshots.push(cpu.save_state());
+        }
+        assert_eq!(rewind.len(), 10);
+
+        for _ in 0..5 {
+            assert!(rewind.rewind(&mut cpu).unwrap());
+        }
+        assert_eq!(cpu.save_state(), snapshots[4]);
+        assert_eq!(rewind.len(), 5);
+    }
+
+    #[test]
+    fn test_rewind_capacity_and_stride() {
+        let mut cpu = test_cpu();
+        // 1秒 x 10fps / 2フレームごと = 5個
+        let mut rewind = Rewind::new(1, 10.0, 2);
+        for _ in 0..20 {
+            run(&mut cpu, 10);
+            rewind.record(&cpu);
+        }
+        assert_eq!(rewind.len(), 5);
+
+        // 最後の1つは残る
+        for _ in 0..10 {
+            assert!(rewind.rewind(&mut cpu).unwrap());
+        }
+        assert_eq!(rewind.len(), 1);
+    }
+}
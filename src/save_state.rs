@@ -0,0 +1,181 @@
+// セーブステート
+// 各構造体の状態を決まった順番でそのまま書き出すだけのバイナリ
+//
+// [MAGIC 4byte][VERSION 1byte][CPU][Bus(RAM, PRG RAM, PPU, APU, mapper, joypad)]
+//
+// 書き出す順番やフィールドを変えたらVERSIONを上げること
+
+pub const MAGIC: &[u8; 4] = b"NESS";
+pub const VERSION: u8 = 14;
+
+pub trait SaveState {
+    fn write_state(&self, writer: &mut StateWriter);
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<(), String>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        StateWriter { data }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_usize(&mut self, value: usize) {
+        self.data.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+
+    // 長さ(u32)を先に書く
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data
+            .extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // ヘッダーを確認する
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        if data.len() < 5 || &data[0..4] != MAGIC {
+            return Err("Not a save state".to_string());
+        }
+        if data[4] != VERSION {
+            return Err(format!(
+                "Unsupported save state version: {} (expected {})",
+                data[4], VERSION
+            ));
+        }
+        Ok(StateReader { data, pos: 5 })
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.pos + len > self.data.len() {
+            return Err("Save state is truncated".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes) as usize)
+    }
+
+    // 長さが違ったらエラー (ROMが違うなど)
+    pub fn read_bytes(&mut self, dest: &mut [u8]) -> Result<(), String> {
+        let mut len = [0; 4];
+        len.copy_from_slice(self.take(4)?);
+        let len = u32::from_le_bytes(len) as usize;
+        if len != dest.len() {
+            return Err(format!(
+                "Save state size mismatch: {} (expected {})",
+                len,
+                dest.len()
+            ));
+        }
+        dest.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<(), String> {
+        if self.pos != self.data.len() {
+            return Err("Save state has trailing data".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::Rom;
+    use crate::cpu::CPU;
+
+    fn run(cpu: &mut CPU<Bus>, steps: usize) {
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let bytes = std::fs::read(format!("{}/nestest.nes", dir)).unwrap();
+        let mut cpu = CPU::new(Bus::new_headless(Rom::new(&bytes).unwrap()).unwrap());
+        cpu.reset();
+        cpu.program_counter = 0xC000;
+
+        run(&mut cpu, 1000);
+        let state = cpu.save_state();
+        let ram: Vec<u8> = (0..0x800).map(|addr| cpu.bus.peek(addr)).collect();
+        let pc = cpu.program_counter;
+        let cycles = cpu.total_cycles();
+
+        run(&mut cpu, 3000);
+        assert_ne!(cpu.program_counter, pc);
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.program_counter, pc);
+        assert_eq!(cpu.total_cycles(), cycles);
+        let loaded: Vec<u8> = (0..0x800).map(|addr| cpu.bus.peek(addr)).collect();
+        assert_eq!(loaded, ram);
+
+        // 読み込んだ状態を保存し直しても同じになる
+        assert_eq!(cpu.save_state(), state);
+    }
+
+    #[test]
+    fn test_reject_bad_header() {
+        let mut state = StateWriter::new().into_bytes();
+        state[4] = VERSION + 1;
+        assert!(StateReader::new(&state).is_err());
+        assert!(StateReader::new(b"NES\x1a\x01").is_err());
+        assert!(StateReader::new(b"NE").is_err());
+    }
+}
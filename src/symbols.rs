@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+// 逆アセンブル/トレースでアドレスの代わりに出すラベル (CPUアドレス -> 名前)
+// 読めるのは2種類:
+// - "C5F5=ResetHandler" ($は付けても付けなくてもいい)
+// - Mesenの.mlb "P:0C5F5:ResetHandler:コメント"
+//   P(PRG ROMのオフセット)は$8000からの32KBにそのまま置く (バンク切り替えは考えない)
+//   R(内部RAM)とG(レジスタ)はそのままのアドレス, S/W(PRG RAM)は$6000から
+// 空行と ; # で始まる行は無視する
+pub struct SymbolTable {
+    labels: HashMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable {
+            labels: HashMap::new(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SymbolTable, String> {
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.as_ref().display(), e))?;
+        SymbolTable::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<SymbolTable, String> {
+        let mut table = SymbolTable::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+            let symbol = if line.contains('=') {
+                parse_simple(line)
+            } else {
+                parse_mlb(line)
+            };
+            match symbol {
+                Some((addr, name)) => table.insert(addr, name),
+                // .mlbのコメントだけの行 (ラベルが空)
+                None if line.contains(':') => {}
+                None => return Err(format!("Invalid symbol at line {}: {}", i + 1, line)),
+            }
+        }
+        Ok(table)
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.labels.insert(addr, name.to_string());
+    }
+
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(|s| s.as_str())
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        SymbolTable::new()
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text.trim().trim_start_matches('$'), 16).ok()
+}
+
+// "C5F5=ResetHandler"
+fn parse_simple(line: &str) -> Option<(u16, &str)> {
+    let (addr, name) = line.split_once('=')?;
+    let addr = parse_hex(addr).filter(|a| *a <= 0xFFFF)?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((addr as u16, name))
+}
+
+// "P:0C5F5:ResetHandler:comment". 範囲 "R:0010-0011:Name" は先頭のアドレスにだけ付ける
+fn parse_mlb(line: &str) -> Option<(u16, &str)> {
+    let mut fields = line.splitn(4, ':');
+    let kind = fields.next()?;
+    let offset = parse_hex(fields.next()?.split('-').next()?)?;
+    let name = fields.next()?.trim();
+    if name.is_empty() {
+        return None;
+    }
+    let addr = match kind {
+        "P" => 0x8000 | (offset & 0x7FFF),
+        "R" => offset & 0x07FF,
+        "G" => offset,
+        "S" | "W" => 0x6000 | (offset & 0x1FFF),
+        _ => return None,
+    };
+    if addr > 0xFFFF {
+        return None;
+    }
+    Some((addr as u16, name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_formats() {
+        let text = "\
+; comment
+C5F5=ResetHandler
+$0010 = pointer
+P:0C5F5:ResetHandler2:comment
+R:0300-0301:buffer
+G:2002:PPUSTATUS
+P:00123::only comment
+";
+        let table = SymbolTable::parse(text).unwrap();
+        assert_eq!(table.label(0x0010), Some("pointer"));
+        // 後から読んだ方で上書き
+        assert_eq!(table.label(0xC5F5), Some("ResetHandler2"));
+        assert_eq!(table.label(0x0300), Some("buffer"));
+        assert_eq!(table.label(0x0301), None);
+        assert_eq!(table.label(0x2002), Some("PPUSTATUS"));
+        assert_eq!(table.label(0x8123), None);
+
+        assert!(SymbolTable::parse("C5F5 ResetHandler").is_err());
+    }
+}
@@ -1,67 +1,78 @@
+use crate::bus::Bus;
 use crate::cpu::AddressingMode;
 use crate::cpu::Mem;
 use crate::cpu::CPU;
 use crate::opcodes;
-use std::collections::HashMap;
+use crate::opcodes::OpCode;
+use crate::symbols::SymbolTable;
 
-pub fn trace(cpu: &mut CPU) -> String {
-    let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-
-    let code = cpu.mem_read(cpu.program_counter);
-    let ops = opscodes.get(&code).unwrap();
-
-    let begin = cpu.program_counter;
-    let mut hex_dump = vec![];
-    hex_dump.push(code);
+// 実行時にしか分からない情報 (traceのみ. disassembleでは使わない)
+pub struct Effective {
+    // 実効アドレスとその値
+    pub addr: u16,
+    pub value: u8,
+    // (zp,X)のzp+X, (zp),YのYを足す前のアドレス, JMP (ind)の飛び先
+    pub pointer: u16,
+}
 
-    let (mem_addr, stored_value) = match ops.mode {
-        AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
-        _ => {
-            let (addr, _) = cpu.get_absolute_address(&ops.mode, begin + 1);
-            (addr, cpu.mem_read(addr))
-        }
+// 1命令を "LDA $10 = 00" のような形にする. ニーモニックは右寄せ4文字 (非公式命令の*の分)
+// operandは命令に続くバイト (len - 1個)
+// effectiveがあれば実効アドレスと値を付け(trace), なければオペランドの表記だけ(disassemble)
+// symbolsがあれば, オペランドのアドレスにラベルがあるときはラベル名にする
+pub fn format_instruction(
+    ops: &OpCode,
+    pc: u16,
+    operand: &[u8],
+    effective: Option<&Effective>,
+    symbols: Option<&SymbolTable>,
+) -> String {
+    let zp = |addr: u8| match symbols.and_then(|s| s.label(addr as u16)) {
+        Some(label) => label.to_string(),
+        None => format!("${:02X}", addr),
+    };
+    let abs = |addr: u16| match symbols.and_then(|s| s.label(addr)) {
+        Some(label) => label.to_string(),
+        None => format!("${:04X}", addr),
     };
-
     let tmp = match ops.len {
         1 => match ops.code {
             0x0a | 0x4a | 0x2a | 0x6a => format!("A "),
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
-            // let value = cpu.mem_read(address));
-            hex_dump.push(address);
-
-            match ops.mode {
-                AddressingMode::Immediate => format!("#${:02x}", address),
-                AddressingMode::ZeroPage => format!("${:02x} = {:02x}", mem_addr, stored_value),
-                AddressingMode::ZeroPage_X => format!(
-                    "${:02x},X @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::ZeroPage_Y => format!(
-                    "${:02x},Y @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Indirect_X => format!(
-                    "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
-                    address,
-                    (address.wrapping_add(cpu.register_x)),
-                    mem_addr,
-                    stored_value
+            let address = operand[0];
+            match (&ops.mode, effective) {
+                (AddressingMode::Immediate, _) => format!("#${:02X}", address),
+                (AddressingMode::ZeroPage, Some(e)) => format!("{} = {:02X}", zp(address), e.value),
+                (AddressingMode::ZeroPage, None) => zp(address),
+                (AddressingMode::ZeroPage_X, Some(e)) => {
+                    format!("{},X @ {:02X} = {:02X}", zp(address), e.addr, e.value)
+                }
+                (AddressingMode::ZeroPage_X, None) => format!("{},X", zp(address)),
+                (AddressingMode::ZeroPage_Y, Some(e)) => {
+                    format!("{},Y @ {:02X} = {:02X}", zp(address), e.addr, e.value)
+                }
+                (AddressingMode::ZeroPage_Y, None) => format!("{},Y", zp(address)),
+                (AddressingMode::Indirect_X, Some(e)) => format!(
+                    "({},X) @ {:02X} = {:04X} = {:02X}",
+                    zp(address),
+                    e.pointer,
+                    e.addr,
+                    e.value
                 ),
-                AddressingMode::Indirect_Y => format!(
-                    "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
-                    address,
-                    (mem_addr.wrapping_sub(cpu.register_y as u16)),
-                    mem_addr,
-                    stored_value
+                (AddressingMode::Indirect_X, None) => format!("({},X)", zp(address)),
+                (AddressingMode::Indirect_Y, Some(e)) => format!(
+                    "({}),Y = {:04X} @ {:04X} = {:02X}",
+                    zp(address),
+                    e.pointer,
+                    e.addr,
+                    e.value
                 ),
-                AddressingMode::NoneAddressing => {
+                (AddressingMode::Indirect_Y, None) => format!("({}),Y", zp(address)),
+                (AddressingMode::NoneAddressing, _) => {
                     // assuming local jumps: BNE, BVS, etc....
-                    let address: usize =
-                        (begin as usize + 2).wrapping_add((address as i8) as usize);
-                    format!("${:04x}", address)
+                    let address = pc.wrapping_add(2).wrapping_add((address as i8) as u16);
+                    abs(address)
                 }
                 _ => panic!(
                     "unexpected addressing mode {:?} has ops-len 2. code {:02x}",
@@ -70,46 +81,33 @@ pub fn trace(cpu: &mut CPU) -> String {
             }
         }
         3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
-            hex_dump.push(address_lo);
-            hex_dump.push(address_hi);
-
-            let address = cpu.mem_read_u16(begin + 1);
-
-            match ops.mode {
-                AddressingMode::NoneAddressing => format!("${:04x}", address),
-                AddressingMode::Absolute => {
-                    // JMP(Absolute) JSR(Absolute)
-                    if ops.code == 0x4C || ops.code == 0x20 {
-                        format!("${:04x}", mem_addr)
-                    } else {
-                        format!("${:04x} = {:02x}", mem_addr, stored_value)
-                    }
+            let address = (operand[1] as u16) << 8 | (operand[0] as u16);
+            match (&ops.mode, effective) {
+                (AddressingMode::NoneAddressing, _) => abs(address),
+                // JMP(Absolute) JSR(Absolute)
+                (AddressingMode::Absolute, _) if ops.code == 0x4C || ops.code == 0x20 => {
+                    abs(address)
                 }
-                AddressingMode::Absolute_X => format!(
-                    "${:04x},X @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Absolute_Y => format!(
-                    "${:04x},Y @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Indirect_jmp => {
-                    if ops.code == 0x6C {
-                        //jmp indirect
-                        let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
-                            (hi as u16) << 8 | (lo as u16)
-                        } else {
-                            cpu.mem_read_u16(address)
-                        };
-                        format!("(${:04x}) = {:04x}", address, jmp_addr)
-                    } else {
-                        format!("${:04x}", address)
-                    }
+                (AddressingMode::Absolute, Some(e)) => {
+                    format!("{} = {:02X}", abs(address), e.value)
+                }
+                (AddressingMode::Absolute, None) => abs(address),
+                (AddressingMode::Absolute_X, Some(e)) => {
+                    format!("{},X @ {:04X} = {:02X}", abs(address), e.addr, e.value)
+                }
+                (AddressingMode::Absolute_X, None) => format!("{},X", abs(address)),
+                (AddressingMode::Absolute_Y, Some(e)) => {
+                    format!("{},Y @ {:04X} = {:02X}", abs(address), e.addr, e.value)
+                }
+                (AddressingMode::Absolute_Y, None) => format!("{},Y", abs(address)),
+                //jmp indirect
+                (AddressingMode::Indirect_jmp, Some(e)) if ops.code == 0x6C => {
+                    format!("({}) = {:04X}", abs(address), e.pointer)
                 }
+                (AddressingMode::Indirect_jmp, None) if ops.code == 0x6C => {
+                    format!("({})", abs(address))
+                }
+                (AddressingMode::Indirect_jmp, _) => abs(address),
                 _ => panic!(
                     "unexpected addressing mode {:?} has ops-len 3. code {:02x}",
                     ops.mode, ops.code
@@ -118,32 +116,101 @@ pub fn trace(cpu: &mut CPU) -> String {
         }
         _ => String::from(""),
     };
+    format!("{: >4} {}", ops.mnemonic, tmp)
+}
+
+pub fn trace(cpu: &mut CPU<Bus>) -> String {
+    trace_with_symbols(cpu, None)
+}
+
+// traceと同じ. symbolsのラベルをオペランドに使う
+pub fn trace_with_symbols(cpu: &mut CPU<Bus>, symbols: Option<&SymbolTable>) -> String {
+    let opcodes = &*opcodes::OPCODES_MAP;
+
+    let code = cpu.mem_read(cpu.program_counter);
+    let ops = opcodes.get(&code).unwrap();
+
+    let begin = cpu.program_counter;
+    let operand: Vec<u8> = (1..ops.len as u16)
+        .map(|i| cpu.mem_read(begin.wrapping_add(i)))
+        .collect();
+
+    let effective = match ops.mode {
+        AddressingMode::Immediate | AddressingMode::NoneAddressing => Effective {
+            addr: 0,
+            value: 0,
+            pointer: 0,
+        },
+        AddressingMode::Indirect_jmp => {
+            let address = (operand[1] as u16) << 8 | (operand[0] as u16);
+            // ページをまたがない6502のバグ
+            let jmp_addr = if address & 0x00FF == 0x00FF {
+                let lo = cpu.mem_read(address);
+                let hi = cpu.mem_read(address & 0xFF00);
+                (hi as u16) << 8 | (lo as u16)
+            } else {
+                cpu.mem_read_u16(address)
+            };
+            Effective {
+                addr: jmp_addr,
+                value: 0,
+                pointer: jmp_addr,
+            }
+        }
+        _ => {
+            let (addr, _) = cpu.get_absolute_address(&ops.mode, begin + 1);
+            // PPU/APU/joypadのレジスタは読むと状態が変わるので読まない (nestest.logもFF)
+            let value = match addr {
+                0x2000..=0x401F => 0xFF,
+                _ => cpu.mem_read(addr),
+            };
+            let pointer = match ops.mode {
+                AddressingMode::Indirect_X => operand[0].wrapping_add(cpu.register_x) as u16,
+                AddressingMode::Indirect_Y => addr.wrapping_sub(cpu.register_y as u16),
+                _ => 0,
+            };
+            Effective {
+                addr,
+                value,
+                pointer,
+            }
+        }
+    };
 
+    let mut hex_dump = vec![code];
+    hex_dump.extend(&operand);
     let hex_str = hex_dump
         .iter()
         .map(|z| format!("{:02x}", z))
         .collect::<Vec<String>>()
         .join(" ");
-    let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
+    let instruction = format_instruction(ops, begin, &operand, Some(&effective), symbols);
+    let asm_str = format!("{:04x}  {:8} {}", begin, hex_str, instruction)
         .trim()
         .to_string();
 
+    let ppu = cpu.bus.ppu();
     format!(
         "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
         asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
     )
     .to_ascii_uppercase()
+        + &format!(
+            " PPU:{:>3},{:>3} CYC:{}",
+            ppu.scanline(),
+            ppu.dot(),
+            cpu.total_cycles()
+        )
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::bus::Bus;
-    use crate::cartridge::test::test_rom;
+    use crate::cartridge::test::test_nrom;
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(test_rom());
+        let mut bus = Bus::new_headless(test_nrom()).unwrap();
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -151,6 +218,7 @@ mod test {
         bus.mem_write(104, 0x00);
 
         let mut cpu = CPU::new(bus);
+        cpu.halt_on_brk = true;
         cpu.program_counter = 0x64;
         cpu.register_a = 1;
         cpu.register_x = 2;
@@ -158,24 +226,25 @@ mod test {
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(|cpu| {
             result.push(trace(cpu));
-        });
+        })
+        .unwrap();
         assert_eq!(
-            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0,  0 CYC:0",
             result[0]
         );
         assert_eq!(
-            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0,  6 CYC:2",
             result[1]
         );
         assert_eq!(
-            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD PPU:  0, 12 CYC:4",
             result[2]
         );
     }
 
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom());
+        let mut bus = Bus::new_headless(test_nrom()).unwrap();
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);
@@ -188,15 +257,70 @@ mod test {
         bus.mem_write(0x400, 0xAA);
 
         let mut cpu = CPU::new(bus);
+        cpu.halt_on_brk = true;
         cpu.program_counter = 0x64;
         cpu.register_y = 0;
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(|cpu| {
             result.push(trace(cpu));
-        });
+        })
+        .unwrap();
         assert_eq!(
-            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
             result[0]
         );
     }
+
+    #[test]
+    fn test_format_ppu_and_cycles() {
+        let mut bus = Bus::new_headless(test_nrom()).unwrap();
+        // JMP $0064 (無限ループ)
+        bus.mem_write(100, 0x4c);
+        bus.mem_write(101, 0x64);
+        bus.mem_write(102, 0x00);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            result.push(trace(cpu));
+            if result.len() == 40 {
+                // 0x0200はBRKなので止まる
+                cpu.halt_on_brk = true;
+                cpu.program_counter = 0x0200;
+            }
+        })
+        .unwrap();
+        // JMPは3cycle = PPU 9dot. 38命令目でスキャンライン(341dot)を跨ぐ
+        assert_eq!(
+            "0064  4C 64 00  JMP $0064                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0,333 CYC:111",
+            result[37]
+        );
+        assert_eq!(
+            "0064  4C 64 00  JMP $0064                       A:00 X:00 Y:00 P:24 SP:FD PPU:  1,  1 CYC:114",
+            result[38]
+        );
+    }
+
+    // nestest.nesを$C000(自動テストモード)から実行して, nestest.logと1行ずつ比較する
+    // ログは2A03(BCDなし)のものなのでdecimal_modeでは比較しない
+    #[test]
+    #[cfg(not(feature = "decimal_mode"))]
+    fn test_nestest() {
+        use crate::cartridge::Rom;
+
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let bytes = std::fs::read(format!("{}/nestest.nes", dir)).unwrap();
+        let log = std::fs::read_to_string(format!("{}/nestest.log", dir)).unwrap();
+
+        let mut cpu = CPU::new(Bus::new_headless(Rom::new(&bytes).unwrap()).unwrap());
+        cpu.reset();
+        cpu.program_counter = 0xC000;
+
+        for (i, expected) in log.lines().take_while(|l| !l.is_empty()).enumerate() {
+            let actual = trace(&mut cpu);
+            assert_eq!(expected, actual, "nestest.log diverges at line {}", i + 1);
+            cpu.step().unwrap();
+        }
+    }
 }
@@ -0,0 +1,130 @@
+// blarggのテストROMの結果を読む
+// テスト中は$6000が0x80, 終わると結果のコード (0が成功) になり, $6004~にASCIIのメッセージ(0終端)が入る
+// $6001~$6003の"DE B0 61"が書かれるまでは$6000の値を信用しない
+use hobby_nes_emulator::emulator::Emulator;
+use std::path::Path;
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const MESSAGE: u16 = 0x6004;
+// これだけ動かしても終わらなければ失敗 (約1分)
+const MAX_FRAMES: u32 = 60 * 60;
+// 0x81を書いたテストはこのくらい待ってからリセットボタンを押してもらう
+const RESET_DELAY_FRAMES: u32 = 10;
+
+// 成功ならメッセージ, 失敗なら結果のコードとメッセージ
+fn run_test_rom<P: AsRef<Path>>(path: P) -> Result<String, String> {
+    let data = std::fs::read(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.as_ref().display(), e))?;
+    run_test_rom_bytes(&data)
+}
+
+fn run_test_rom_bytes(data: &[u8]) -> Result<String, String> {
+    let mut emulator = Emulator::from_bytes(data)?;
+    let mut running = false;
+    let mut reset_at = None;
+    for frame in 0..MAX_FRAMES {
+        emulator.step_frame().map_err(|e| e.to_string())?;
+        let bus = &emulator.cpu().bus;
+        let signature = [
+            bus.peek(STATUS + 1),
+            bus.peek(STATUS + 2),
+            bus.peek(STATUS + 3),
+        ];
+        if signature != SIGNATURE {
+            continue;
+        }
+        match bus.peek(STATUS) {
+            0x80 => running = true,
+            0x81 if reset_at.is_none() => reset_at = Some(frame + RESET_DELAY_FRAMES),
+            0x81 => {}
+            code if running => {
+                let message = read_message(&emulator);
+                return if code == 0 {
+                    Ok(message)
+                } else {
+                    Err(format!("result {}: {}", code, message))
+                };
+            }
+            _ => {}
+        }
+        if reset_at == Some(frame) {
+            reset_at = None;
+            emulator.reset();
+        }
+    }
+    Err(format!(
+        "Test did not finish in {} frames: {}",
+        MAX_FRAMES,
+        read_message(&emulator)
+    ))
+}
+
+fn read_message(emulator: &Emulator) -> String {
+    let bus = &emulator.cpu().bus;
+    (MESSAGE..0x8000)
+        .map(|addr| bus.peek(addr))
+        .take_while(|c| *c != 0)
+        .map(|c| c as char)
+        .collect()
+}
+
+// blarggのテストと同じ手順で結果を書くだけのNROMのROM
+// 署名を書いて0x80 (実行中) にし, messageを$6004~にコピー, 約10フレーム待ってからcodeを書く
+fn result_writer_rom(code: u8, message: &str) -> Vec<u8> {
+    let mut prg = vec![0; 0x8000];
+    #[rustfmt::skip]
+    let program = [
+        0xA9, 0xDE, 0x8D, 0x01, 0x60, // LDA #$DE; STA $6001
+        0xA9, 0xB0, 0x8D, 0x02, 0x60, // LDA #$B0; STA $6002
+        0xA9, 0x61, 0x8D, 0x03, 0x60, // LDA #$61; STA $6003
+        0xA9, 0x80, 0x8D, 0x00, 0x60, // LDA #$80; STA $6000
+        0xA2, 0x00,                   // LDX #0
+        0xBD, 0x00, 0x90,             // copy: LDA $9000,X
+        0x9D, 0x04, 0x60,             // STA $6004,X
+        0xF0, 0x03,                   // BEQ wait
+        0xE8,                         // INX
+        0xD0, 0xF5,                   // BNE copy
+        0xA0, 0x00,                   // wait: LDY #0
+        0xCA,                         // loop: DEX
+        0xD0, 0xFD,                   // BNE loop
+        0x88,                         // DEY
+        0xD0, 0xFA,                   // BNE loop
+        0xA9, code, 0x8D, 0x00, 0x60, // LDA #code; STA $6000
+        0x4C, 0x2E, 0x80,             // JMP *
+    ];
+    prg[..program.len()].copy_from_slice(&program);
+    prg[0x1000..0x1000 + message.len()].copy_from_slice(message.as_bytes());
+    // reset vector = $8000
+    prg[0x7FFC] = 0x00;
+    prg[0x7FFD] = 0x80;
+
+    let mut rom = vec![b'N', b'E', b'S', 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    rom.extend(prg);
+    rom.extend(vec![0; 0x2000]);
+    rom
+}
+
+#[test]
+fn test_result_protocol() {
+    let rom = result_writer_rom(0, "\n01-basic\n\nPassed\n");
+    assert_eq!(
+        run_test_rom_bytes(&rom),
+        Ok("\n01-basic\n\nPassed\n".to_string())
+    );
+
+    let rom = result_writer_rom(3, "ADC #imm\nFailed\n");
+    assert_eq!(
+        run_test_rom_bytes(&rom),
+        Err("result 3: ADC #imm\nFailed\n".to_string())
+    );
+}
+
+// blarggのinstr_test-v5はリポジトリに入れていないので, tests/roms/に置いてから
+// cargo test --test blargg -- --ignored
+#[test]
+#[ignore]
+fn test_instr_basic() {
+    let result = run_test_rom("tests/roms/instr_test-v5/rom_singles/01-basic.nes");
+    assert!(result.is_ok(), "{:?}", result);
+}
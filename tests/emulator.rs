@@ -0,0 +1,71 @@
+use hobby_nes_emulator::emulator::Emulator;
+use hobby_nes_emulator::joypad::JoyPadButton;
+use hobby_nes_emulator::render::frame::Frame;
+use hobby_nes_emulator::render::palette::BuiltinPalette;
+
+#[test]
+fn test_step_frame() {
+    let data = std::fs::read("nestest.nes").unwrap();
+    let mut emulator = Emulator::from_bytes(&data).unwrap();
+    let cycles = emulator.cpu().total_cycles();
+    emulator.step_frame().unwrap();
+    assert_eq!(
+        emulator.frame_buffer().len(),
+        Frame::WIDTH * Frame::HEIGHT * 3
+    );
+    // 1フレームは約29780 CPUサイクル
+    let frame_cycles = emulator.cpu().total_cycles() - cycles;
+    assert!((29700..29900).contains(&frame_cycles), "{}", frame_cycles);
+
+    emulator.set_button(1, JoyPadButton::START, true);
+    emulator.step_frame().unwrap();
+    emulator.reset();
+    emulator.step_frame().unwrap();
+}
+
+#[test]
+fn test_run_until_vblank() {
+    let data = std::fs::read("nestest.nes").unwrap();
+    let mut emulator = Emulator::from_bytes(&data).unwrap();
+    emulator.run_until_vblank().unwrap();
+    assert_ne!(emulator.cpu().bus.ppu().peek_status() & 0b1000_0000, 0);
+
+    // 次のvblankまでは1フレーム分
+    let cycles = emulator.run_until_vblank().unwrap();
+    assert_ne!(emulator.cpu().bus.ppu().peek_status() & 0b1000_0000, 0);
+    assert!((29700..29900).contains(&cycles), "{}", cycles);
+}
+
+#[test]
+fn test_from_bytes_rejects_garbage() {
+    assert!(Emulator::from_bytes(&[0; 16]).is_err());
+}
+
+#[test]
+fn test_from_bytes_rejects_unsupported_mapper() {
+    // mapper 1 (MMC1), PRG 16KB, CHR 8KB
+    let mut data = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x10, 0x00];
+    data.resize(16 + 0x4000 + 0x2000, 0);
+    match Emulator::from_bytes(&data) {
+        Ok(_) => panic!("mapper 1 should not be supported"),
+        Err(e) => assert_eq!(e, "Mapper 1 is not supported"),
+    }
+}
+
+#[test]
+fn test_set_palette() {
+    let data = std::fs::read("nestest.nes").unwrap();
+    let mut emulator = Emulator::from_bytes(&data).unwrap();
+    emulator.set_palette(BuiltinPalette::Grayscale.palette());
+    for _ in 0..10 {
+        emulator.step_frame().unwrap();
+    }
+    // nestestのメニュー画面も全部灰色になる
+    for rgb in emulator.frame_buffer().chunks(3) {
+        assert!(rgb[0] == rgb[1] && rgb[1] == rgb[2]);
+    }
+    assert!(emulator
+        .frame_buffer()
+        .iter()
+        .any(|&v| v != emulator.frame_buffer()[0]));
+}
@@ -0,0 +1,181 @@
+// 画面のゴールデンテスト: ROMをムービーの入力で動かし, 最後の画面のハッシュを記録した値と比べる
+// PNGを置かずに描画とCPUの回帰を見つける用
+use hobby_nes_emulator::bus::RamInit;
+use hobby_nes_emulator::cartridge::Rom;
+use hobby_nes_emulator::emulator::Emulator;
+use hobby_nes_emulator::hash::crc32;
+use hobby_nes_emulator::joypad::{JoyPad, JoyPadButton};
+use hobby_nes_emulator::movie::{MovieHeader, MoviePlayer, MovieRecorder};
+
+// framesフレーム動かして, 最後の画面(RGB24)のCRC32がexpected_hashと同じか確かめる
+// 意図して画面を変えたときは, 失敗メッセージのハッシュに書き換える
+fn golden_frame_test(rom: &[u8], movie: &[u8], frames: u32, expected_hash: u32) {
+    let rom = Rom::new(&rom.to_vec()).unwrap();
+    let mut movie = MoviePlayer::from_bytes(movie).unwrap();
+    movie.header().check_rom(&rom).unwrap();
+
+    let mut emulator = Emulator::new(rom, movie.header().ram_init).unwrap();
+    for _ in 0..frames {
+        emulator.step_frame().unwrap();
+        // Busのフレームごとのcallbackでplayするのと同じタイミング
+        if let Some((player1, player2)) = movie.next_frame() {
+            let bus = &mut emulator.cpu_mut().bus;
+            bus.joypad_mut(1).set_buttons(player1);
+            bus.joypad_mut(2).set_buttons(player2);
+        }
+    }
+    let hash = crc32(emulator.frame_buffer());
+    assert_eq!(
+        hash, expected_hash,
+        "frame hash is {:08X} (expected {:08X})",
+        hash, expected_hash
+    );
+}
+
+// (押し始めるフレーム, 離すフレーム, ボタン) からムービーを作る
+fn movie(rom: &[u8], presses: &[(u32, u32, JoyPadButton)], frames: u32) -> Vec<u8> {
+    let rom = Rom::new(&rom.to_vec()).unwrap();
+    let mut recorder = MovieRecorder::new(MovieHeader::new(&rom, RamInit::Zero));
+    let mut joypad1 = JoyPad::new();
+    let joypad2 = JoyPad::new();
+    for frame in 0..frames {
+        let mut buttons = JoyPadButton::empty();
+        for (start, end, button) in presses {
+            if (*start..*end).contains(&frame) {
+                buttons |= *button;
+            }
+        }
+        joypad1.set_buttons(buttons);
+        recorder.record(&joypad1, &joypad2);
+    }
+    recorder.to_bytes()
+}
+
+// 自作のNROMのROM (PRG 16KB, CHR 8KB)
+// ネームテーブルをタイル0~3の繰り返しで埋め, スプライト0(tile 3)をNMIの中で十字キーの左右で動かす
+// スプライトはOAM DMAで毎フレーム送る
+fn homebrew_rom() -> Vec<u8> {
+    let mut prg = vec![0; 0x4000];
+    #[rustfmt::skip]
+    let program = [
+        0x78,             // reset: SEI
+        0xD8,             // CLD
+        0xA2, 0xFF,       // LDX #$FF
+        0x9A,             // TXS
+        0xA9, 0x00,       // LDA #0
+        0x8D, 0x00, 0x20, // STA $2000
+        0x8D, 0x01, 0x20, // STA $2001
+        0x2C, 0x02, 0x20, // vblank1: BIT $2002
+        0x10, 0xFB,       // BPL vblank1
+        0x2C, 0x02, 0x20, // vblank2: BIT $2002
+        0x10, 0xFB,       // BPL vblank2
+        0xA9, 0x3F,       // LDA #$3F
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA9, 0x00,       // LDA #0
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA2, 0x00,       // LDX #0
+        0xBD, 0x00, 0x81, // palette: LDA $8100,X
+        0x8D, 0x07, 0x20, // STA $2007
+        0xE8,             // INX
+        0xE0, 0x20,       // CPX #32
+        0xD0, 0xF5,       // BNE palette
+        0xA9, 0x20,       // LDA #$20
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA9, 0x00,       // LDA #0
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA0, 0x04,       // LDY #4
+        0xA2, 0x00,       // LDX #0
+        0x8A,             // nametable: TXA
+        0x29, 0x03,       // AND #3
+        0x8D, 0x07, 0x20, // STA $2007
+        0xE8,             // INX
+        0xD0, 0xF7,       // BNE nametable
+        0x88,             // DEY
+        0xD0, 0xF4,       // BNE nametable
+        0xA9, 0xFF,       // LDA #$FF
+        0x9D, 0x00, 0x02, // clear_oam: STA $0200,X
+        0xE8,             // INX
+        0xD0, 0xFA,       // BNE clear_oam
+        0xA9, 0x64,       // LDA #100
+        0x8D, 0x00, 0x02, // STA $0200
+        0xA9, 0x03,       // LDA #3
+        0x8D, 0x01, 0x02, // STA $0201
+        0xA9, 0x00,       // LDA #0
+        0x8D, 0x02, 0x02, // STA $0202
+        0xA9, 0x28,       // LDA #40
+        0x8D, 0x03, 0x02, // STA $0203
+        0xA9, 0x00,       // LDA #0
+        0x8D, 0x05, 0x20, // STA $2005
+        0x8D, 0x05, 0x20, // STA $2005
+        0xA9, 0x80,       // LDA #$80
+        0x8D, 0x00, 0x20, // STA $2000
+        0xA9, 0x1E,       // LDA #$1E
+        0x8D, 0x01, 0x20, // STA $2001
+        0x4C, 0x76, 0x80, // forever: JMP forever
+        0xA9, 0x02,       // nmi: LDA #2
+        0x8D, 0x14, 0x40, // STA $4014
+        0xA9, 0x01,       // LDA #1
+        0x8D, 0x16, 0x40, // STA $4016
+        0xA9, 0x00,       // LDA #0
+        0x8D, 0x16, 0x40, // STA $4016
+        0xA2, 0x06,       // LDX #6
+        0xAD, 0x16, 0x40, // skip: LDA $4016
+        0xCA,             // DEX
+        0xD0, 0xFA,       // BNE skip
+        0xAD, 0x16, 0x40, // LDA $4016
+        0x29, 0x01,       // AND #1
+        0xF0, 0x03,       // BEQ right
+        0xCE, 0x03, 0x02, // DEC $0203
+        0xAD, 0x16, 0x40, // right: LDA $4016
+        0x29, 0x01,       // AND #1
+        0xF0, 0x03,       // BEQ done
+        0xEE, 0x03, 0x02, // INC $0203
+        0xA9, 0x00,       // done: LDA #0
+        0x8D, 0x05, 0x20, // STA $2005
+        0x8D, 0x05, 0x20, // STA $2005
+        0x40,             // irq: RTI
+    ];
+    prg[..program.len()].copy_from_slice(&program);
+    // $8100: パレット (BG 16色, スプライト 16色)
+    #[rustfmt::skip]
+    let palette = [
+        0x0F, 0x00, 0x10, 0x30, 0x0F, 0x06, 0x16, 0x26, 0x0F, 0x09, 0x19, 0x29, 0x0F, 0x01, 0x11, 0x21,
+        0x0F, 0x16, 0x27, 0x18, 0x0F, 0x02, 0x12, 0x22, 0x0F, 0x0A, 0x1A, 0x2A, 0x0F, 0x04, 0x14, 0x24,
+    ];
+    prg[0x100..0x120].copy_from_slice(&palette);
+    // NMI = $8079, RESET = $8000, IRQ = $80AC (RTI)
+    prg[0x3FFA..].copy_from_slice(&[0x79, 0x80, 0x00, 0x80, 0xAC, 0x80]);
+
+    // tile 1: カラー1の市松模様, tile 2: 全部カラー2, tile 3: 全部カラー3
+    let mut chr = vec![0; 0x2000];
+    for y in 0..8 {
+        chr[16 + y] = if y % 2 == 0 { 0xAA } else { 0x55 };
+        chr[32 + 8 + y] = 0xFF;
+        chr[48 + y] = 0xFF;
+        chr[48 + 8 + y] = 0xFF;
+    }
+
+    let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    rom.extend(prg);
+    rom.extend(chr);
+    rom
+}
+
+#[test]
+fn test_homebrew_sprite() {
+    let rom = homebrew_rom();
+    // 右に40フレーム, 左に10フレーム動かす
+    let presses = [(10, 50, JoyPadButton::RIGHT), (60, 70, JoyPadButton::LEFT)];
+    let movie = movie(&rom, &presses, 90);
+    golden_frame_test(&rom, &movie, 90, 0xCC65_76AB);
+}
+
+// nestestはBCDなしの2A03で動かした画面なので, decimal_modeでは比べない
+#[test]
+#[cfg(not(feature = "decimal_mode"))]
+fn test_nestest_menu() {
+    let rom = std::fs::read("nestest.nes").unwrap();
+    // メニューが出たらSTARTで公式命令のテストを走らせる. 全部の行が"OK"になった画面
+    let movie = movie(&rom, &[(30, 34, JoyPadButton::START)], 120);
+    golden_frame_test(&rom, &movie, 120, 0x62B1_AB2F);
+}
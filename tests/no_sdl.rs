@@ -0,0 +1,21 @@
+// cargo test --no-default-features
+// SDLなしでライブラリがビルドでき, 画面まで作れることを確かめる (WASM向けビルドの代わり)
+#![cfg(not(feature = "sdl"))]
+
+use hobby_nes_emulator::emulator::Emulator;
+use hobby_nes_emulator::render::frame::Frame;
+
+#[test]
+fn test_library_without_sdl() {
+    let data = std::fs::read("nestest.nes").unwrap();
+    let mut emulator = Emulator::from_bytes(&data).unwrap();
+    emulator.step_frame().unwrap();
+    assert_eq!(
+        emulator.frame_buffer_len(),
+        Frame::WIDTH * Frame::HEIGHT * 3
+    );
+    assert_eq!(
+        emulator.frame_buffer_ptr(),
+        emulator.frame_buffer().as_ptr()
+    );
+}